@@ -0,0 +1,118 @@
+use log::error;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Malformed packets are counted per IP over windows of this length.
+const MALFORMED_WINDOW: Duration = Duration::from_secs(60);
+
+/// IPs sending malformed packets that are tracked at once, floods of spoofed addresses can't grow it further.
+const MAX_TRACKED_IPS: usize = 65536;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Abuse {
+    /// An invalid REST API token, an unknown or banned passkey, or an announce denied by the authorization hook.
+    AuthFailure,
+    /// `malformed_threshold` malformed packets within a minute.
+    MalformedFlood,
+}
+
+impl Abuse {
+    fn name(&self) -> &'static str {
+        match self {
+            Abuse::AuthFailure => "auth_failure",
+            Abuse::MalformedFlood => "malformed_flood",
+        }
+    }
+}
+
+/// Writes abuse to a file of its own, a line per event, in a format that's kept stable across releases so
+/// fail2ban & co can act on it: `<UTC timestamp> udpt abuse: <kind> from <ip>`.
+pub struct AbuseLog {
+    file: std::sync::Mutex<std::fs::File>,
+    malformed_threshold: u32,
+    /// ip -> (start of its window, malformed packets within it)
+    malformed: std::sync::Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl AbuseLog {
+    pub fn open(path: &str, malformed_threshold: u32) -> Result<AbuseLog, std::io::Error> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AbuseLog {
+            file: std::sync::Mutex::new(file),
+            malformed_threshold,
+            malformed: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn report(&self, abuse: Abuse, ip: IpAddr) {
+        let line = format_line(&chrono::Utc::now(), abuse, ip);
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            error!("failed to write to the abuse log: {}", err);
+        }
+    }
+
+    /// Counts a malformed packet, a flood is reported once an IP sent `malformed_threshold` of them within a window.
+    pub fn count_malformed(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let is_flood = {
+            let mut malformed = self.malformed.lock().unwrap();
+            if malformed.len() >= MAX_TRACKED_IPS && !malformed.contains_key(&ip) {
+                malformed.retain(|_, (start, _)| now.duration_since(*start) < MALFORMED_WINDOW);
+                if malformed.len() >= MAX_TRACKED_IPS {
+                    return;
+                }
+            }
+
+            let (start, count) = malformed.entry(ip).or_insert((now, 0));
+            if now.duration_since(*start) >= MALFORMED_WINDOW {
+                *start = now;
+                *count = 0;
+            }
+            *count += 1;
+            *count == self.malformed_threshold
+        };
+        if is_flood {
+            self.report(Abuse::MalformedFlood, ip);
+        }
+    }
+}
+
+fn format_line(time: &chrono::DateTime<chrono::Utc>, abuse: Abuse, ip: IpAddr) -> String {
+    format!(
+        "{} udpt abuse: {} from {}\n",
+        time.format("%Y-%m-%dT%H:%M:%SZ"),
+        abuse.name(),
+        ip
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn abuse_lines() {
+        let time = chrono::Utc.ymd(2020, 9, 13).and_hms(12, 26, 40);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            format_line(&time, Abuse::AuthFailure, ip),
+            "2020-09-13T12:26:40Z udpt abuse: auth_failure from 203.0.113.7\n"
+        );
+
+        let path = std::env::temp_dir().join(format!("udpt-abuse-{}.log", std::process::id()));
+        let abuse_log = AbuseLog::open(path.to_str().unwrap(), 3).unwrap();
+        for _ in 0..10 {
+            abuse_log.count_malformed(ip);
+        }
+        abuse_log.count_malformed("2001:db8::1".parse().unwrap());
+
+        // a flood is reported once per window.
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.ends_with(" udpt abuse: malformed_flood from 203.0.113.7\n"));
+    }
+}
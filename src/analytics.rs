@@ -0,0 +1,179 @@
+use crate::config::AnalyticsConfig;
+use crate::server::Events;
+use crate::tracker::InfoHash;
+use log::{error, trace, warn};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum AnalyticsEvent {
+    Announce {
+        info_hash: InfoHash,
+        ip_family: &'static str,
+        /// Client software, as parsed from the peer id.
+        client: Option<&'static str>,
+        event: &'static str,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    },
+    Scrape {
+        info_hash: InfoHash,
+        ip_family: &'static str,
+    },
+}
+
+impl AnalyticsEvent {
+    pub fn ip_family(ip: &std::net::IpAddr) -> &'static str {
+        match ip {
+            std::net::IpAddr::V4(_) => "ipv4",
+            std::net::IpAddr::V6(_) => "ipv6",
+        }
+    }
+
+    pub fn event_name(event: Events) -> &'static str {
+        match event {
+            Events::None => "none",
+            Events::Complete => "completed",
+            Events::Started => "started",
+            Events::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    /// Unix timestamp of the event.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: AnalyticsEvent,
+}
+
+/// Exports announce & scrape events in batches, as newline delimited JSON (ClickHouse's `JSONEachRow`).
+/// Events are queued up to `max_queue`; while the endpoint is down or slow, newer events are dropped.
+pub struct Analytics {
+    client: hyper::Client<hyper::client::HttpConnector>,
+    url: String,
+    batch_size: usize,
+    max_queue: usize,
+    queue: std::sync::Mutex<VecDeque<Row>>,
+    dropped: AtomicU64,
+}
+
+impl Analytics {
+    pub fn new(config: &AnalyticsConfig) -> Analytics {
+        Analytics {
+            client: hyper::Client::new(),
+            url: config.get_url().to_string(),
+            batch_size: config.get_batch_size(),
+            max_queue: config.get_max_queue(),
+            queue: std::sync::Mutex::new(VecDeque::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, event: AnalyticsEvent) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or(0);
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_queue {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(Row { timestamp, event });
+    }
+
+    /// Sends the queued events in batches of up to `batch_size`. A failed batch is put back in front of the
+    /// queue for the next flush, as far as `max_queue` allows.
+    pub async fn flush(&self) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("analytics queue is full, dropped {} events", dropped);
+        }
+
+        loop {
+            let batch: Vec<Row> = {
+                let mut queue = self.queue.lock().unwrap();
+                let count = std::cmp::min(queue.len(), self.batch_size);
+                queue.drain(..count).collect()
+            };
+            if batch.is_empty() {
+                return;
+            }
+
+            let mut body = Vec::with_capacity(batch.len() * 160);
+            for row in batch.iter() {
+                if serde_json::to_writer(&mut body, row).is_ok() {
+                    body.push(b'\n');
+                }
+            }
+
+            if let Err(err) = self.send(body).await {
+                error!("failed to export analytics: {}", err);
+                let mut queue = self.queue.lock().unwrap();
+                let room = self.max_queue.saturating_sub(queue.len());
+                for row in batch.into_iter().take(room).rev() {
+                    queue.push_front(row);
+                }
+                return;
+            }
+            trace!("exported {} analytics events", batch.len());
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), String> {
+        let request = hyper::Request::post(self.url.as_str())
+            .header("Content-Type", "application/x-ndjson")
+            .body(hyper::Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        let timeout = std::time::Duration::from_secs(30);
+        match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("responded with {}", response.status())),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err(String::from("timed out")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows() {
+        let config: AnalyticsConfig = toml::from_str("url = \"http://127.0.0.1:8123/\"\nmax_queue = 1").unwrap();
+        let analytics = Analytics::new(&config);
+        analytics.push(AnalyticsEvent::Announce {
+            info_hash: [0u8; 20].into(),
+            ip_family: "ipv4",
+            client: Some("qBittorrent"),
+            event: AnalyticsEvent::event_name(Events::Started),
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+        });
+        analytics.push(AnalyticsEvent::Scrape {
+            info_hash: [0u8; 20].into(),
+            ip_family: "ipv6",
+        });
+        assert_eq!(analytics.dropped.load(Ordering::Relaxed), 1);
+
+        let row = analytics.queue.lock().unwrap().pop_front().unwrap();
+        let row = serde_json::to_value(&Row { timestamp: 1, ..row }).unwrap();
+        assert_eq!(
+            row.to_string(),
+            concat!(
+                r#"{"action":"announce","client":"qBittorrent","downloaded":2,"event":"started","#,
+                r#""info_hash":"0000000000000000000000000000000000000000","ip_family":"ipv4","left":3,"#,
+                r#""timestamp":1,"uploaded":1}"#
+            )
+        );
+    }
+}
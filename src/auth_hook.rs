@@ -0,0 +1,161 @@
+use crate::config::AuthHookConfig;
+use crate::tracker::InfoHash;
+use futures::future::BoxFuture;
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Serialize)]
+struct AuthRequest<'a> {
+    info_hash: &'a InfoHash,
+    passkey: Option<&'a str>,
+    ip: std::net::IpAddr,
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    allowed: bool,
+    reason: Option<String>,
+}
+
+type Decision = Result<(), String>;
+
+/// Decides whether announces are allowed, set on a listener with `UDPTracker::set_authorizer`.
+/// `auth_hook` configures an `AuthHook`, services embedding the tracker can provide their own.
+pub trait Authorizer: Send + Sync {
+    /// Returns Ok if the announce is allowed, or the reason it was rejected, which is sent to the client.
+    fn authorize<'a>(
+        &'a self, info_hash: &'a InfoHash, passkey: Option<&'a str>, ip: std::net::IpAddr,
+    ) -> BoxFuture<'a, Decision>;
+}
+
+/// Asks an external HTTP endpoint whether announces are allowed. Decisions are cached per infohash & passkey.
+pub struct AuthHook {
+    client: crate::http::Client,
+    url: String,
+    cache_ttl: Duration,
+    timeout: Duration,
+    fail_open: bool,
+    cache: std::sync::Mutex<HashMap<(InfoHash, Option<String>), (Instant, Decision)>>,
+}
+
+impl AuthHook {
+    /// Once the cache holds this many decisions, expired ones are dropped.
+    const MAX_CACHED: usize = 65536;
+
+    pub fn new(config: &AuthHookConfig) -> AuthHook {
+        AuthHook {
+            client: crate::http::client(),
+            url: config.get_url().to_string(),
+            cache_ttl: Duration::from_secs(config.get_cache_ttl()),
+            timeout: Duration::from_millis(config.get_timeout_ms()),
+            fail_open: config.get_fail_open(),
+            cache: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns Ok if the announce is allowed, or the reason it was rejected.
+    pub async fn authorize(&self, info_hash: &InfoHash, passkey: Option<&str>, ip: std::net::IpAddr) -> Decision {
+        let key = (info_hash.clone(), passkey.map(String::from));
+        if let Some((cached_at, decision)) = self.cache.lock().unwrap().get(&key) {
+            if cached_at.elapsed() < self.cache_ttl {
+                return decision.clone();
+            }
+        }
+
+        let decision = match self.request(info_hash, passkey, ip).await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("authorization hook failed: {}", err);
+                // failures aren't cached, the next announce asks again.
+                return match self.fail_open {
+                    true => Ok(()),
+                    false => Err(String::from("authorization unavailable.")),
+                };
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= Self::MAX_CACHED {
+            let ttl = self.cache_ttl;
+            cache.retain(|_, (cached_at, _)| cached_at.elapsed() < ttl);
+        }
+        cache.insert(key, (Instant::now(), decision.clone()));
+        decision
+    }
+
+    async fn request(&self, info_hash: &InfoHash, passkey: Option<&str>, ip: std::net::IpAddr) -> Result<Decision, String> {
+        let body = serde_json::to_vec(&AuthRequest { info_hash, passkey, ip }).map_err(|e| e.to_string())?;
+        let request = hyper::Request::post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .body(hyper::Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        let response = match tokio::time::timeout(self.timeout, self.client.request(request)).await {
+            Ok(v) => v.map_err(|e| e.to_string())?,
+            Err(_) => return Err(String::from("timed out")),
+        };
+        if !response.status().is_success() {
+            return Err(format!("responded with {}", response.status()));
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+        let response: AuthResponse = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        trace!("authorization hook: {} allowed={}", info_hash, response.allowed);
+        match response.allowed {
+            true => Ok(Ok(())),
+            false => Ok(Err(response.reason.unwrap_or_else(|| String::from("not authorized.")))),
+        }
+    }
+}
+
+impl Authorizer for AuthHook {
+    fn authorize<'a>(
+        &'a self, info_hash: &'a InfoHash, passkey: Option<&'a str>, ip: std::net::IpAddr,
+    ) -> BoxFuture<'a, Decision> {
+        Box::pin(AuthHook::authorize(self, info_hash, passkey, ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn authorize_and_cache() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let counter = counter.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await?;
+                        let allowed = std::str::from_utf8(&body).unwrap().contains(&"01".repeat(20));
+                        let response = format!(r#"{{"allowed": {}, "reason": "unknown torrent."}}"#, allowed);
+                        Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(response)))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let config: AuthHookConfig = toml::from_str(&format!("url = \"{}\"", url)).unwrap();
+        let hook = AuthHook::new(&config);
+        let ip = "127.0.0.1".parse().unwrap();
+
+        assert_eq!(hook.authorize(&[1u8; 20].into(), None, ip).await, Ok(()));
+        assert_eq!(hook.authorize(&[1u8; 20].into(), None, ip).await, Ok(()));
+        assert_eq!(
+            hook.authorize(&[2u8; 20].into(), Some("key"), ip).await,
+            Err(String::from("unknown torrent."))
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::cidr::Cidr;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Longest peer_id prefix that can be banned, peer ids are 20 bytes.
+const MAX_PEER_ID_PREFIX: usize = 20;
+
+/// What a ban applies to, written as `{"ip": "..."}`, `{"cidr": "..."}` or `{"peer_id_prefix": "..."}`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    Ip(IpAddr),
+    Cidr(Cidr),
+    /// Start of the peer ids of a client, such as `-XX0001-`.
+    PeerIdPrefix(String),
+}
+
+impl BanTarget {
+    fn matches(&self, ip: &IpAddr, peer_id: Option<&[u8]>) -> bool {
+        match self {
+            BanTarget::Ip(v) => v == ip,
+            BanTarget::Cidr(v) => v.contains(ip),
+            BanTarget::PeerIdPrefix(prefix) => matches!(peer_id, Some(id) if id.starts_with(prefix.as_bytes())),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ban {
+    id: u64,
+    #[serde(flatten)]
+    target: BanTarget,
+    /// Name of the access token the ban was created with.
+    created_by: String,
+    /// Unix timestamp.
+    created: u64,
+    /// Unix timestamp, None for bans that last until they're lifted.
+    expires: Option<u64>,
+    reason: Option<String>,
+}
+
+impl Ban {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires.map(|expires| now < expires).unwrap_or(true)
+    }
+}
+
+/// IPs, networks & clients whose announces and scrapes are refused, managed through the REST API.
+/// Bans are kept in memory, like the users added through the API.
+#[derive(Default)]
+pub struct BanList {
+    last_id: AtomicU64,
+    bans: RwLock<Vec<Ban>>,
+}
+
+impl BanList {
+    /// Bans `target` for `duration` seconds (or until it's lifted), returns the new ban.
+    pub fn add(
+        &self, target: BanTarget, created_by: String, duration: Option<u64>, reason: Option<String>, now: u64,
+    ) -> Result<Ban, &'static str> {
+        if let BanTarget::PeerIdPrefix(ref prefix) = target {
+            if prefix.is_empty() || prefix.len() > MAX_PEER_ID_PREFIX {
+                return Err("peer_id prefixes must be 1 to 20 bytes long");
+            }
+        }
+
+        let ban = Ban {
+            id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            target,
+            created_by,
+            created: now,
+            expires: duration.map(|v| now.saturating_add(v)),
+            reason,
+        };
+        self.bans.write().unwrap().push(ban.clone());
+        Ok(ban)
+    }
+
+    /// Lifts the ban `id`, returns false if there's no such ban.
+    pub fn remove(&self, id: u64) -> bool {
+        let mut bans = self.bans.write().unwrap();
+        let count = bans.len();
+        bans.retain(|ban| ban.id != id);
+        bans.len() != count
+    }
+
+    /// Replaces all bans with those of a primary, keeping their ids. Bans added later are numbered after them.
+    pub fn replace(&self, bans: Vec<Ban>) {
+        if let Some(last_id) = bans.iter().map(|ban| ban.id).max() {
+            self.last_id.fetch_max(last_id, Ordering::Relaxed);
+        }
+        *self.bans.write().unwrap() = bans;
+    }
+
+    /// Drops expired bans.
+    pub fn remove_expired(&self, now: u64) {
+        self.bans.write().unwrap().retain(|ban| ban.is_active(now));
+    }
+
+    /// Bans that didn't expire yet, oldest first.
+    pub fn list(&self, now: u64) -> Vec<Ban> {
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|ban| ban.is_active(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether requests from `ip` (by a client with `peer_id`, for announces) are refused.
+    pub fn is_banned(&self, ip: &IpAddr, peer_id: Option<&[u8]>, now: u64) -> bool {
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .any(|ban| ban.is_active(now) && ban.target.matches(ip, peer_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans() {
+        let bans = BanList::default();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let target: BanTarget = serde_json::from_str(r#"{"cidr": "203.0.113.0/24"}"#).unwrap();
+
+        let ban = bans.add(target, "admin".into(), Some(60), None, 1000).unwrap();
+        assert!(bans.is_banned(&ip, None, 1059));
+        assert!(!bans.is_banned(&"198.51.100.1".parse().unwrap(), None, 1059));
+        assert!(!bans.is_banned(&ip, None, 1060));
+        assert_eq!(
+            serde_json::to_value(&ban).unwrap(),
+            serde_json::json!({
+                "id": 1,
+                "cidr": "203.0.113.0/24",
+                "created_by": "admin",
+                "created": 1000,
+                "expires": 1060,
+                "reason": null,
+            })
+        );
+
+        let client = BanTarget::PeerIdPrefix("-XX0001-".into());
+        let ban = bans
+            .add(client, "admin".into(), None, Some("fake uploads".into()), 1000)
+            .unwrap();
+        assert!(bans.is_banned(&ip, Some(b"-XX0001-abcdefghijkl"), 5000));
+        assert!(!bans.is_banned(&ip, Some(b"-XX0002-abcdefghijkl"), 5000));
+        assert!(!bans.is_banned(&ip, None, 5000));
+        assert!(bans
+            .add(BanTarget::PeerIdPrefix(String::new()), "admin".into(), None, None, 1000)
+            .is_err());
+
+        assert_eq!(bans.list(1030).len(), 2);
+        bans.remove_expired(2000);
+        assert_eq!(bans.list(1030), vec![ban]);
+        assert!(bans.remove(2));
+        assert!(!bans.remove(2));
+        assert!(bans.list(1030).is_empty());
+
+        let replicated: Vec<Ban> = serde_json::from_str(
+            r#"[{"id": 7, "ip": "203.0.113.7", "created_by": "admin",
+            "created": 1000, "expires": null, "reason": null}]"#,
+        )
+        .unwrap();
+        bans.replace(replicated);
+        assert!(bans.is_banned(&ip, None, 5000));
+        assert_eq!(bans.add(BanTarget::Ip(ip), "admin".into(), None, None, 1000).unwrap().id, 8);
+    }
+}
@@ -0,0 +1,530 @@
+//! Generates announce & scrape load against a UDP tracker and measures how it keeps up, to size an instance.
+
+use crate::probe::{self, AnnounceRequest, ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use udpt_core::config::Configuration;
+use udpt_core::server::{Events, CONNECTION_TTL};
+use udpt_core::store::{ConcurrentStore, ShardedStore, TorrentStore};
+use udpt_core::tracker::{InfoHash, PeerId, TorrentTracker};
+
+pub struct BenchOptions {
+    /// Torrents announced & scraped.
+    pub torrents: u32,
+    /// Peers spread over the torrents, each announcing to the same torrent.
+    pub peers: u32,
+    /// Requests per second across all workers, 0 to send as fast as possible.
+    pub rate: u32,
+    pub duration: Duration,
+    /// Share of scrapes among the requests, in percent.
+    pub scrape_percent: u32,
+    /// Share of requests sent over IPv6, in percent.
+    pub ipv6_percent: u32,
+    /// Sending threads, each with its own sockets.
+    pub workers: u32,
+    pub passkey: Option<String>,
+    /// How long to wait for responses once sending stopped.
+    pub timeout: Duration,
+}
+
+#[derive(Default)]
+pub struct BenchReport {
+    /// How long requests were sent for.
+    pub elapsed: Duration,
+    pub announces: u64,
+    pub scrapes: u64,
+    pub responses: u64,
+    pub errors: u64,
+    /// The first error the tracker returned.
+    pub first_error: Option<String>,
+    /// Response times, sorted.
+    latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub fn sent(&self) -> u64 {
+        self.announces + self.scrapes
+    }
+
+    /// Requests that were never answered.
+    pub fn lost(&self) -> u64 {
+        self.sent().saturating_sub(self.responses)
+    }
+
+    /// The response time `percent` of the responses arrived within.
+    pub fn percentile(&self, percent: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::default();
+        }
+        let rank = ((percent / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.max(1).min(self.latencies.len()) - 1]
+    }
+
+    fn merge(&mut self, other: BenchReport) {
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.announces += other.announces;
+        self.scrapes += other.scrapes;
+        self.responses += other.responses;
+        self.errors += other.errors;
+        if self.first_error.is_none() {
+            self.first_error = other.first_error;
+        }
+        self.latencies.extend(other.latencies);
+    }
+}
+
+/// Sends the load for `options.duration`, then waits `options.timeout` for the last responses.
+pub fn run(address: &str, options: &BenchOptions) -> Result<BenchReport, String> {
+    let addresses: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .map_err(|err| format!("failed to resolve \"{}\": {}", address, err))?
+        .collect();
+    let ipv4 = addresses.iter().find(|a| a.is_ipv4()).cloned();
+    let ipv6 = addresses.iter().find(|a| a.is_ipv6()).cloned();
+    if options.ipv6_percent < 100 && ipv4.is_none() {
+        return Err(format!("\"{}\" has no IPv4 address, use --ipv6 100", address));
+    }
+    if options.ipv6_percent > 0 && ipv6.is_none() {
+        return Err(format!("\"{}\" has no IPv6 address, use --ipv6 0", address));
+    }
+
+    // torrents differ between runs, so a run doesn't start with the swarms of the previous one.
+    let seed = probe::random_bytes();
+    let started = Instant::now();
+    // all sockets are connected before sending, a loaded tracker could miss the connects of the last ones.
+    let mut workers = Vec::new();
+    for index in 0..options.workers.max(1) {
+        let mut targets = Vec::new();
+        if options.ipv6_percent < 100 {
+            targets.push(Target::open(ipv4.unwrap(), started, options.timeout)?);
+        }
+        if options.ipv6_percent > 0 {
+            targets.push(Target::open(ipv6.unwrap(), started, options.timeout)?);
+        }
+        workers.push(Worker {
+            index,
+            targets,
+            seed,
+            started,
+            options: WorkerOptions::from(options),
+        });
+    }
+    let workers: Vec<_> = workers
+        .into_iter()
+        .map(|worker| std::thread::spawn(move || worker.run()))
+        .collect();
+
+    let mut report = BenchReport::default();
+    for worker in workers {
+        report.merge(worker.join().map_err(|_| String::from("a worker panicked"))??);
+    }
+    report.latencies.sort_unstable();
+    Ok(report)
+}
+
+/// What a worker needs of the options, split evenly across workers.
+#[derive(Clone)]
+struct WorkerOptions {
+    torrents: u32,
+    peers: u32,
+    workers: u32,
+    interval: Option<Duration>,
+    duration: Duration,
+    scrape_percent: u32,
+    ipv6_percent: u32,
+    passkey: Option<String>,
+    timeout: Duration,
+}
+
+impl WorkerOptions {
+    fn from(options: &BenchOptions) -> WorkerOptions {
+        let workers = options.workers.max(1);
+        WorkerOptions {
+            torrents: options.torrents.max(1),
+            peers: options.peers.max(1),
+            workers,
+            interval: match options.rate {
+                0 => None,
+                rate => Some(Duration::from_secs(1) * workers / rate),
+            },
+            duration: options.duration,
+            scrape_percent: options.scrape_percent.min(100),
+            ipv6_percent: options.ipv6_percent.min(100),
+            passkey: options.passkey.clone(),
+            timeout: options.timeout,
+        }
+    }
+}
+
+/// A socket connected to the tracker, with a thread collecting the responses it receives.
+struct Target {
+    socket: UdpSocket,
+    connection_id: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    receiver: std::thread::JoinHandle<BenchReport>,
+}
+
+impl Target {
+    fn open(address: SocketAddr, started: Instant, timeout: Duration) -> Result<Target, String> {
+        let bind = if address.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).map_err(|err| format!("failed to bind a socket: {}", err))?;
+        socket
+            .connect(address)
+            .map_err(|err| format!("failed to reach {}: {}", address, err))?;
+        let receiving = socket
+            .try_clone()
+            .map_err(|err| format!("failed to clone a socket: {}", err))?;
+        receiving
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .map_err(|err| format!("failed to set a socket timeout: {}", err))?;
+
+        let connection_id = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let receiver = {
+            let connection_id = connection_id.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || receive(receiving, started, &connection_id, &stop))
+        };
+        let target = Target {
+            socket,
+            connection_id,
+            stop,
+            receiver,
+        };
+
+        // requests need a connection id.
+        target.send(&probe::encode_connect(transaction_id(started)));
+        let deadline = Instant::now() + timeout;
+        while target.connection_id.load(Ordering::Relaxed) == 0 {
+            if Instant::now() > deadline {
+                target.stop.store(true, Ordering::Relaxed);
+                return Err(format!(
+                    "connect: no response from {} within {}ms",
+                    address,
+                    timeout.as_millis()
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        Ok(target)
+    }
+
+    fn send(&self, packet: &[u8]) {
+        // a full socket buffer shows up as lost requests.
+        let _ = self.socket.send(packet);
+    }
+}
+
+struct Worker {
+    index: u32,
+    targets: Vec<Target>,
+    seed: [u8; 20],
+    started: Instant,
+    options: WorkerOptions,
+}
+
+impl Worker {
+    fn run(self) -> Result<BenchReport, String> {
+        let options = &self.options;
+        let mut report = BenchReport::default();
+        let sending = Instant::now();
+        let mut next_send = sending;
+        let mut connected_at = Instant::now();
+        let mut scrape_share = 0;
+        let mut ipv6_share = 0;
+        let passkey = options.passkey.as_deref();
+
+        // workers take turns, so their announces cover every peer between them.
+        let mut sequence = self.index;
+        while sending.elapsed() < options.duration {
+            if let Some(interval) = options.interval {
+                let now = Instant::now();
+                if next_send > now {
+                    std::thread::sleep(next_send - now);
+                }
+                next_send += interval;
+            }
+
+            ipv6_share += options.ipv6_percent;
+            let target = if ipv6_share >= 100 {
+                ipv6_share -= 100;
+                self.targets.last().unwrap()
+            } else {
+                &self.targets[0]
+            };
+            if connected_at.elapsed() > CONNECTION_TTL {
+                connected_at = Instant::now();
+                for target in self.targets.iter() {
+                    target.send(&probe::encode_connect(transaction_id(self.started)));
+                }
+            }
+            let connection_id = target.connection_id.load(Ordering::Relaxed);
+
+            let peer = sequence % options.peers;
+            let info_hash = info_hash(self.seed, peer % options.torrents);
+            scrape_share += options.scrape_percent;
+            if scrape_share >= 100 {
+                scrape_share -= 100;
+                target.send(&probe::encode_scrape(connection_id, transaction_id(self.started), &info_hash));
+                report.scrapes += 1;
+            } else {
+                let request = AnnounceRequest {
+                    info_hash: &info_hash,
+                    peer_id: &peer_id(peer),
+                    left: left(peer),
+                    event: 0,
+                    port: 1024 + (peer % 64000) as u16,
+                    passkey,
+                };
+                target.send(&probe::encode_announce(connection_id, transaction_id(self.started), &request));
+                report.announces += 1;
+                sequence = sequence.wrapping_add(options.workers);
+            }
+        }
+
+        report.elapsed = sending.elapsed();
+
+        std::thread::sleep(options.timeout);
+        for target in self.targets {
+            target.stop.store(true, Ordering::Relaxed);
+            let received = target.receiver.join().map_err(|_| String::from("a receiver panicked"))?;
+            report.merge(received);
+        }
+        Ok(report)
+    }
+}
+
+fn info_hash(seed: [u8; 20], torrent: u32) -> [u8; 20] {
+    let mut info_hash = seed;
+    info_hash[16..].copy_from_slice(&torrent.to_be_bytes());
+    info_hash
+}
+
+/// A quarter of the peers are seeders.
+fn left(peer: u32) -> u64 {
+    if peer % 4 == 0 {
+        0
+    } else {
+        1 << 20
+    }
+}
+
+fn peer_id(peer: u32) -> [u8; 20] {
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-UB0001-");
+    peer_id[16..].copy_from_slice(&peer.to_be_bytes());
+    peer_id
+}
+
+/// Announces in-process to a tracker with each storage backend, without sockets or the protocol, to compare the
+/// backends on the machine the benchmark runs on. Only `torrents`, `peers`, `duration` & `workers` apply, announces
+/// are sent as fast as possible.
+pub fn run_stores(options: &BenchOptions) -> Result<Vec<(&'static str, BenchReport)>, String> {
+    let config = Arc::new(Configuration::default());
+    let shards = config.get_tracker_config().get_db_shards();
+    let stores: Vec<(&'static str, Box<dyn TorrentStore>)> = vec![
+        ("sharded", Box::new(ShardedStore::new(shards))),
+        ("concurrent", Box::new(ConcurrentStore::new())),
+    ];
+    let mut reports = Vec::new();
+    for (name, store) in stores {
+        let tracker = Arc::new(TorrentTracker::with_store(config.clone(), store));
+        reports.push((name, run_store(tracker, options)?));
+    }
+    Ok(reports)
+}
+
+fn run_store(tracker: Arc<TorrentTracker>, options: &BenchOptions) -> Result<BenchReport, String> {
+    let options = WorkerOptions::from(options);
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .core_threads(options.workers as usize)
+        .enable_all()
+        .build()
+        .map_err(|err| format!("failed to start the runtime: {}", err))?;
+    let seed = probe::random_bytes();
+    let workers: Vec<_> = (0..options.workers)
+        .map(|index| {
+            let tracker = tracker.clone();
+            let options = options.clone();
+            runtime.spawn(async move { announce_store(&tracker, index, seed, &options).await })
+        })
+        .collect();
+
+    runtime.block_on(async move {
+        let mut report = BenchReport::default();
+        for worker in workers {
+            report.merge(worker.await.map_err(|_| String::from("a worker panicked"))?);
+        }
+        Ok(report)
+    })
+}
+
+async fn announce_store(tracker: &TorrentTracker, index: u32, seed: [u8; 20], options: &WorkerOptions) -> BenchReport {
+    let mut report = BenchReport::default();
+    let sending = Instant::now();
+    let mut sequence = index;
+    while sending.elapsed() < options.duration {
+        let peer = sequence % options.peers;
+        let info_hash = InfoHash::from(&info_hash(seed, peer % options.torrents)[..]);
+        let address = SocketAddr::from(([203, 0, 113, 1], 1024 + (peer % 64000) as u16));
+        let peer_id = peer_id(peer);
+        tracker
+            .update_torrent_and_get_stats(
+                &info_hash,
+                PeerId::from_array(&peer_id),
+                &address,
+                0,
+                0,
+                left(peer),
+                Events::None,
+            )
+            .await;
+        report.announces += 1;
+        report.responses += 1;
+        sequence = sequence.wrapping_add(options.workers);
+    }
+    report.elapsed = sending.elapsed();
+    report
+}
+
+/// Transaction ids carry the time the request was sent, in microseconds since the benchmark started.
+/// They wrap after an hour, which is harmless as long as responses take less.
+fn transaction_id(started: Instant) -> u32 {
+    started.elapsed().as_micros() as u32
+}
+
+fn receive(socket: UdpSocket, started: Instant, connection_id: &AtomicU64, stop: &AtomicBool) -> BenchReport {
+    let mut report = BenchReport::default();
+    let mut buffer = [0u8; 2048];
+    while !stop.load(Ordering::Relaxed) {
+        let size = match socket.recv(&mut buffer) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if size < 8 {
+            continue;
+        }
+        let data = &buffer[..size];
+        let sent = probe::read_u32(data, 4);
+        match probe::read_u32(data, 0) {
+            ACTION_CONNECT if size >= 16 => {
+                let mut id = [0u8; 8];
+                id.copy_from_slice(&data[8..16]);
+                connection_id.store(u64::from_be_bytes(id), Ordering::Relaxed);
+                continue;
+            }
+            ACTION_ANNOUNCE | ACTION_SCRAPE => {}
+            ACTION_ERROR => {
+                report.errors += 1;
+                if report.first_error.is_none() {
+                    report.first_error = Some(String::from_utf8_lossy(&data[8..]).into_owned());
+                }
+            }
+            _ => continue,
+        }
+        report.responses += 1;
+        let now = started.elapsed().as_micros() as u32;
+        report.latencies.push(Duration::from_micros(now.wrapping_sub(sent) as u64));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let report = BenchReport {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..BenchReport::default()
+        };
+        assert_eq!(report.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(report.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(100));
+        assert_eq!(BenchReport::default().percentile(50.0), Duration::default());
+    }
+
+    #[test]
+    fn compare_stores() {
+        let options = BenchOptions {
+            torrents: 5,
+            peers: 20,
+            rate: 0,
+            duration: Duration::from_millis(100),
+            scrape_percent: 0,
+            ipv6_percent: 0,
+            workers: 2,
+            passkey: None,
+            timeout: Duration::default(),
+        };
+        let reports = run_stores(&options).unwrap();
+        assert_eq!(reports.iter().map(|(name, _)| *name).collect::<Vec<_>>(), vec![
+            "sharded",
+            "concurrent"
+        ]);
+        for (name, report) in reports {
+            assert!(report.announces > 20, "{} announced {} times", name, report.announces);
+            assert_eq!(report.lost(), 0);
+        }
+    }
+
+    #[test]
+    fn load_tracker() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = tracker.local_addr().unwrap().to_string();
+        let peers = Arc::new(std::sync::Mutex::new(std::collections::HashSet::new()));
+
+        let seen = peers.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 2048];
+            loop {
+                let (_, from) = tracker.recv_from(&mut buffer).unwrap();
+                let mut response = Vec::new();
+                match probe::read_u32(&buffer, 8) {
+                    ACTION_CONNECT => {
+                        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&7u64.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        seen.lock().unwrap().insert(buffer[36..56].to_vec());
+                        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&[0, 0, 0, 120, 0, 0, 0, 0, 0, 0, 0, 1]);
+                    }
+                    ACTION_SCRAPE => {
+                        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(b"no scrapes");
+                    }
+                    _ => continue,
+                }
+                tracker.send_to(&response, from).unwrap();
+            }
+        });
+
+        let options = BenchOptions {
+            torrents: 5,
+            peers: 20,
+            rate: 1000,
+            duration: Duration::from_millis(200),
+            scrape_percent: 25,
+            ipv6_percent: 0,
+            workers: 2,
+            passkey: None,
+            timeout: Duration::from_millis(200),
+        };
+        let report = run(&address, &options).unwrap();
+        assert!(report.sent() >= 100 && report.sent() <= 210, "sent {}", report.sent());
+        // each worker scrapes every fourth request.
+        assert!(report.sent() / 4 - report.scrapes <= 1, "{} scrapes", report.scrapes);
+        assert_eq!(report.responses, report.sent());
+        assert_eq!(report.errors, report.scrapes);
+        assert_eq!(report.first_error.as_deref(), Some("no scrapes"));
+        assert_eq!(peers.lock().unwrap().len(), 20);
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+
+/// Reusable fixed size buffers, so the UDP path doesn't allocate a buffer per packet.
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+}
+
+impl BufferPool {
+    /// Creates a pool of `size` byte buffers, keeping at most `max_idle` of them when they're returned.
+    pub fn new(size: usize, max_idle: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Takes an idle buffer, or allocates one if there are none. Buffers are not cleared when reused.
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let buffer = self.idle.lock().unwrap().pop();
+        PooledBuffer {
+            buffer: Some(buffer.unwrap_or_else(|| vec![0u8; self.size].into_boxed_slice())),
+            pool: self.clone(),
+        }
+    }
+}
+
+/// A buffer that returns to its pool when dropped.
+pub struct PooledBuffer {
+    buffer: Option<Box<[u8]>>,
+    pool: Arc<BufferPool>,
+}
+
+impl std::ops::Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.buffer.as_ref().unwrap()
+    }
+}
+
+impl std::ops::DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.buffer.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            let mut idle = self.pool.idle.lock().unwrap();
+            if idle.len() < self.pool.max_idle {
+                idle.push(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(16, 1);
+        let first = pool.get();
+        let ptr = first.as_ptr();
+        let second = pool.get();
+        assert_eq!(first.len(), 16);
+        assert_ne!(ptr, second.as_ptr());
+
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+        assert_eq!(pool.get().as_ptr(), ptr);
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::net::IpAddr;
+
+/// An IP network, written as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a network of its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                Self::prefix_matches(u32::from(net).into(), u32::from(*ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => Self::prefix_matches(net.into(), (*ip).into(), 128, self.prefix),
+            _ => false,
+        }
+    }
+
+    fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+        prefix == 0 || (net ^ ip) >> (bits - prefix) == 0
+    }
+}
+
+/// Whether peers elsewhere can't reach `ip`: loopback, private, link-local & unspecified addresses.
+pub fn is_unroutable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => {
+            match ip.segments() {
+                [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                    is_unroutable(&IpAddr::V4(((u32::from(high) << 16) | u32::from(low)).into()))
+                }
+                // fc00::/7 unique local & fe80::/10 link-local.
+                [first, ..] => {
+                    ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.find('/') {
+            Some(pos) => (&s[..pos], Some(&s[pos + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid network address \"{}\"", s))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => {
+                match prefix.parse::<u8>() {
+                    Ok(v) if v <= bits => v,
+                    _ => return Err(format!("invalid prefix length in \"{}\"", s)),
+                }
+            }
+            None => bits,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contains() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+
+        let net: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(&"2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db9::1".parse().unwrap()));
+
+        let host: Cidr = "203.0.113.7".parse().unwrap();
+        assert_eq!(host.to_string(), "203.0.113.7/32");
+        assert!(host.contains(&"203.0.113.7".parse().unwrap()));
+        assert!(!host.contains(&"203.0.113.8".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&"198.51.100.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn unroutable() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.6",
+            "169.254.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+        ]
+        .iter()
+        {
+            assert!(is_unroutable(&ip.parse().unwrap()), "{} is unroutable", ip);
+        }
+        for ip in ["203.0.113.7", "2001:db8::1", "::ffff:203.0.113.7"].iter() {
+            assert!(!is_unroutable(&ip.parse().unwrap()), "{} is routable", ip);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+/// Detaches udpt from its terminal, for init systems that don't supervise foreground processes: forks twice around a
+/// new session, and redirects stdin to /dev/null and stdout & stderr to `log_path` (or /dev/null). The working
+/// directory is kept, so relative paths of the configuration still resolve.
+/// Forking only keeps the calling thread, so this must run before the runtime is started.
+#[cfg(unix)]
+pub fn daemonize(log_path: Option<&str>) -> Result<(), std::io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    // opened first, so errors still reach the terminal.
+    let null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let output = match log_path {
+        Some(path) => std::fs::OpenOptions::new().create(true).append(true).open(path)?,
+        None => null.try_clone()?,
+    };
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // the session leader exits, so the daemon can never acquire a controlling terminal.
+    fork_and_exit_parent()?;
+
+    for (file, fd) in [
+        (&null, libc::STDIN_FILENO),
+        (&output, libc::STDOUT_FILENO),
+        (&output, libc::STDERR_FILENO),
+    ]
+    .iter()
+    {
+        if unsafe { libc::dup2(file.as_raw_fd(), *fd) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> Result<(), std::io::Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// A file holding udpt's process id, it's removed when dropped.
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    /// Writes the current process id to `path`. Fails if the file names a process that's still running, unless
+    /// `replace_running`.
+    pub fn create(path: &str, replace_running: bool) -> Result<PidFile, std::io::Error> {
+        if let Ok(pid) = std::fs::read_to_string(path) {
+            if let Ok(pid) = pid.trim().parse::<u32>() {
+                if pid != std::process::id() && !replace_running && is_running(pid) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AlreadyExists,
+                        format!("\"{}\" belongs to the running process {}", path, pid),
+                    ));
+                }
+            }
+        }
+
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path: path.to_string() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // unless another process replaced it.
+        let pid = std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|v| v.trim().parse::<u32>().ok());
+        if pid == Some(std::process::id()) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists; EPERM means it exists, as another user.
+    let res = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file() {
+        let path = std::env::temp_dir().join(format!("udpt-{}.pid", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // a stale file is replaced.
+        std::fs::write(path, "999999999\n").unwrap();
+        let pid_file = PidFile::create(path, false).unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!std::path::Path::new(path).exists());
+
+        #[cfg(unix)]
+        {
+            // pid 1 is always running.
+            std::fs::write(path, "1\n").unwrap();
+            assert!(PidFile::create(path, false).is_err());
+
+            // a replaced file is left to its new process.
+            let pid_file = PidFile::create(path, true).unwrap();
+            std::fs::write(path, "1\n").unwrap();
+            drop(pid_file);
+            assert!(std::path::Path::new(path).exists());
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
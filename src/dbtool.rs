@@ -0,0 +1,473 @@
+//! `udpt db`: inspects, compares & rewrites saved databases without a running tracker.
+
+use std::collections::BTreeMap;
+use udpt_core::sqlite::SqliteStore;
+use udpt_core::tracker::{self, InfoHash, TorrentEntry};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DbFormat {
+    /// bzip2 compressed json lines with a header & footer, as saved with `db_backend = "file"`.
+    File,
+    /// Plain json lines, one torrent per line, for editing & other tools.
+    Json,
+    Sqlite,
+}
+
+impl DbFormat {
+    /// Guesses the format of a file that's to be written from its extension.
+    pub fn from_path(path: &str) -> DbFormat {
+        let path = path.to_ascii_lowercase();
+        if path.ends_with(".json") || path.ends_with(".jsonl") {
+            DbFormat::Json
+        } else if path.ends_with(".sqlite") || path.ends_with(".sqlite3") {
+            DbFormat::Sqlite
+        } else {
+            DbFormat::File
+        }
+    }
+
+    /// Detects the format of an existing file from its first bytes.
+    fn detect(path: &str) -> Result<DbFormat, std::io::Error> {
+        use std::io::Read;
+
+        let mut magic = [0u8; 16];
+        let mut file = std::fs::File::open(path)?;
+        let len = file.read(&mut magic)?;
+        Ok(match &magic[..len] {
+            [b'B', b'Z', b'h', ..] => DbFormat::File,
+            b"SQLite format 3\0" => DbFormat::Sqlite,
+            _ => DbFormat::Json,
+        })
+    }
+}
+
+impl std::fmt::Display for DbFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            DbFormat::File => "file",
+            DbFormat::Json => "json",
+            DbFormat::Sqlite => "sqlite",
+        })
+    }
+}
+
+impl std::str::FromStr for DbFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(DbFormat::File),
+            "json" => Ok(DbFormat::Json),
+            "sqlite" => Ok(DbFormat::Sqlite),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The torrents of a saved database, and what was wrong with it.
+pub struct Snapshot {
+    pub format: DbFormat,
+    /// Version of the file format, files only.
+    pub version: Option<u32>,
+    pub torrents: BTreeMap<InfoHash, TorrentEntry>,
+    pub rows: usize,
+    /// Rows of torrents that appeared earlier, the last one is kept.
+    pub duplicates: usize,
+    /// Rows that couldn't be parsed.
+    pub invalid_rows: Vec<String>,
+    /// The file was altered or truncated.
+    pub problems: Vec<String>,
+}
+
+impl Snapshot {
+    pub async fn read(path: &str) -> Result<Snapshot, std::io::Error> {
+        let format = DbFormat::detect(path)?;
+        let mut snapshot = Snapshot {
+            format,
+            version: None,
+            torrents: BTreeMap::new(),
+            rows: 0,
+            duplicates: 0,
+            invalid_rows: Vec::new(),
+            problems: Vec::new(),
+        };
+
+        if format == DbFormat::Sqlite {
+            let rows = SqliteStore::open(path).await?.load().await?;
+            snapshot.rows = rows.len();
+            for row in rows {
+                match tracker::parse_sqlite_row(row) {
+                    Ok((info_hash, entry)) => snapshot.insert(info_hash, entry),
+                    Err(err) => snapshot.invalid_rows.push(format!("torrent {}", err)),
+                }
+            }
+            return Ok(snapshot);
+        }
+
+        let file = tokio::fs::File::open(path).await?;
+        let mut torrents = Vec::new();
+        let on_row = |info_hash, entry| {
+            torrents.push((info_hash, entry));
+            futures::future::ready(())
+        };
+        let check = match format {
+            DbFormat::File => tracker::read_database(tracker::decompress_database(file), on_row).await?,
+            _ => tracker::read_database(tokio::io::BufReader::new(file), on_row).await?,
+        };
+        for (info_hash, entry) in torrents {
+            snapshot.insert(info_hash, entry);
+        }
+        if format == DbFormat::File || check.version > 1 {
+            snapshot.version = Some(check.version);
+        }
+        snapshot.rows = check.rows;
+        snapshot.invalid_rows = check
+            .invalid_rows
+            .into_iter()
+            .map(|(line, err)| format!("line {}: {}", line, err))
+            .collect();
+        snapshot.problems = check.problems;
+        Ok(snapshot)
+    }
+
+    fn insert(&mut self, info_hash: InfoHash, entry: TorrentEntry) {
+        if self.torrents.insert(info_hash, entry).is_some() {
+            self.duplicates += 1;
+        }
+    }
+
+    /// Aliases that are tracked as torrents of their own, or linked to several torrents.
+    pub fn alias_conflicts(&self) -> Vec<String> {
+        let mut linked: BTreeMap<&InfoHash, &InfoHash> = BTreeMap::new();
+        let mut conflicts = Vec::new();
+        for (info_hash, entry) in self.torrents.iter() {
+            for alias in entry.get_aliases() {
+                if self.torrents.contains_key(alias) {
+                    conflicts.push(format!("alias {} of {} is also a torrent", alias, info_hash));
+                }
+                if let Some(other) = linked.insert(alias, info_hash) {
+                    conflicts.push(format!("alias {} is linked to both {} and {}", alias, other, info_hash));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// Writes the torrents to `path`, replacing the file once it's complete. SQLite databases must not exist yet.
+    pub async fn write(&self, path: &str, format: DbFormat) -> Result<(), std::io::Error> {
+        let rows: Vec<(InfoHash, TorrentEntry)> = self.torrents.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+
+        if format == DbFormat::Sqlite {
+            if std::path::Path::new(path).exists() {
+                let msg = format!("\"{}\" already exists, sqlite databases are only created", path);
+                return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg));
+            }
+            let mut upserts = Vec::with_capacity(rows.len());
+            for (info_hash, entry) in rows.iter() {
+                upserts.push(tracker::sqlite_row(info_hash, entry)?);
+            }
+            return SqliteStore::open(path).await?.write(upserts, Vec::new()).await;
+        }
+
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            match format {
+                DbFormat::File => {
+                    tracker::write_database(&mut file, rows).await?;
+                }
+                _ => {
+                    use tokio::io::AsyncWriteExt;
+
+                    let mut out = Vec::new();
+                    for (info_hash, entry) in rows.iter() {
+                        tracker::serialize_row(&mut out, info_hash, entry);
+                    }
+                    file.write_all(&out).await?;
+                }
+            }
+            file.sync_all().await?;
+        }
+        tokio::fs::rename(&tmp_path, path).await
+    }
+}
+
+fn describe(info_hash: &InfoHash, entry: &TorrentEntry) -> String {
+    let mut line = format!(
+        "{} completed={} flagged={} history={}",
+        info_hash,
+        entry.get_completed(),
+        entry.is_flagged(),
+        entry.get_completion_history().len()
+    );
+    if !entry.get_aliases().is_empty() {
+        let aliases: Vec<String> = entry.get_aliases().iter().map(ToString::to_string).collect();
+        line += &format!(" aliases={}", aliases.join(","));
+    }
+    line
+}
+
+/// Differences between the persisted state of two torrents.
+fn changes(old: &TorrentEntry, new: &TorrentEntry) -> Vec<String> {
+    let mut changes = Vec::new();
+    if old.get_completed() != new.get_completed() {
+        changes.push(format!("completed {} -> {}", old.get_completed(), new.get_completed()));
+    }
+    if old.is_flagged() != new.is_flagged() {
+        changes.push(format!("flagged {} -> {}", old.is_flagged(), new.is_flagged()));
+    }
+    if old.get_completion_history() != new.get_completion_history() {
+        let (old_len, new_len) = (old.get_completion_history().len(), new.get_completion_history().len());
+        changes.push(format!("history {} -> {}", old_len, new_len));
+    }
+    if old.get_aliases() != new.get_aliases() {
+        changes.push(format!("aliases {} -> {}", old.get_aliases().len(), new.get_aliases().len()));
+    }
+    changes
+}
+
+fn read(path: &str) -> Result<Snapshot, i32> {
+    let mut runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+    runtime.block_on(Snapshot::read(path)).map_err(|err| {
+        eprintln!("udpt: failed to read \"{}\": {}", path, err);
+        1
+    })
+}
+
+/// Runs a `udpt db` subcommand, returns the exit code.
+pub fn run(matches: &clap::ArgMatches) -> i32 {
+    match matches.subcommand() {
+        ("list", Some(args)) => list(args),
+        ("validate", Some(args)) => validate(args),
+        ("diff", Some(args)) => diff(args),
+        ("compact", Some(args)) => rewrite(args, true),
+        ("convert", Some(args)) => rewrite(args, false),
+        _ => 1,
+    }
+}
+
+fn list(args: &clap::ArgMatches) -> i32 {
+    let snapshot = match read(args.value_of("path").unwrap()) {
+        Ok(v) => v,
+        Err(code) => return code,
+    };
+    let flagged = args.is_present("flagged");
+    for (info_hash, entry) in snapshot.torrents.iter().filter(|(_, entry)| !flagged || entry.is_flagged()) {
+        println!("{}", describe(info_hash, entry));
+    }
+    0
+}
+
+fn validate(args: &clap::ArgMatches) -> i32 {
+    let snapshot = match read(args.value_of("path").unwrap()) {
+        Ok(v) => v,
+        Err(code) => return code,
+    };
+    match snapshot.version {
+        Some(version) => println!("format: {} version {}, {} rows.", snapshot.format, version, snapshot.rows),
+        None => println!("format: {}, {} rows.", snapshot.format, snapshot.rows),
+    }
+
+    let mut errors = 0;
+    for problem in snapshot.problems.iter() {
+        println!("error: {}", problem);
+        errors += 1;
+    }
+    for row in snapshot.invalid_rows.iter() {
+        println!("error: invalid row, {}", row);
+        errors += 1;
+    }
+    if snapshot.duplicates > 0 {
+        println!("error: {} rows repeat a torrent of an earlier row.", snapshot.duplicates);
+        errors += 1;
+    }
+    for conflict in snapshot.alias_conflicts() {
+        println!("error: {}", conflict);
+        errors += 1;
+    }
+    println!("{} torrents, {} errors.", snapshot.torrents.len(), errors);
+    if errors == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+fn diff(args: &clap::ArgMatches) -> i32 {
+    let (old, new) = match (read(args.value_of("old").unwrap()), read(args.value_of("new").unwrap())) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(code), _) | (_, Err(code)) => return code,
+    };
+
+    let (mut removed, mut added, mut changed) = (0, 0, 0);
+    for (info_hash, entry) in old.torrents.iter() {
+        match new.torrents.get(info_hash) {
+            None => {
+                println!("- {}", describe(info_hash, entry));
+                removed += 1;
+            }
+            Some(new_entry) => {
+                let changes = changes(entry, new_entry);
+                if !changes.is_empty() {
+                    println!("~ {} {}", info_hash, changes.join(", "));
+                    changed += 1;
+                }
+            }
+        }
+    }
+    for (info_hash, entry) in new
+        .torrents
+        .iter()
+        .filter(|(info_hash, _)| !old.torrents.contains_key(info_hash))
+    {
+        println!("+ {}", describe(info_hash, entry));
+        added += 1;
+    }
+    println!("{} added, {} removed, {} changed.", added, removed, changed);
+    if added + removed + changed == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Writes the torrents that could be read to another file. Compacting also drops what a tracker wouldn't keep.
+fn rewrite(args: &clap::ArgMatches, compact: bool) -> i32 {
+    let (input, output) = (args.value_of("input").unwrap(), args.value_of("output").unwrap());
+    let format = match args.value_of("to") {
+        Some(format) => format.parse().unwrap(),
+        None => DbFormat::from_path(output),
+    };
+    let mut snapshot = match read(input) {
+        Ok(v) => v,
+        Err(code) => return code,
+    };
+
+    let mut dropped = Vec::new();
+    if !snapshot.invalid_rows.is_empty() {
+        dropped.push(format!("{} invalid rows", snapshot.invalid_rows.len()));
+    }
+    if snapshot.duplicates > 0 {
+        dropped.push(format!("{} duplicate rows", snapshot.duplicates));
+    }
+    if compact {
+        let history = match args.value_of("history").unwrap().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("udpt: --history must be a number.");
+                return 1;
+            }
+        };
+        for entry in snapshot.torrents.values_mut() {
+            entry.trim_completion_history(history);
+        }
+        if args.is_present("drop-unused") {
+            let before = snapshot.torrents.len();
+            snapshot
+                .torrents
+                .retain(|_, entry| entry.is_flagged() || entry.get_completed() > 0 || !entry.get_aliases().is_empty());
+            if before > snapshot.torrents.len() {
+                dropped.push(format!("{} unused torrents", before - snapshot.torrents.len()));
+            }
+        }
+    }
+
+    let mut runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+    if let Err(err) = runtime.block_on(snapshot.write(output, format)) {
+        eprintln!("udpt: failed to write \"{}\": {}", output, err);
+        return 1;
+    }
+    print!("wrote {} torrents to \"{}\" as {}", snapshot.torrents.len(), output, format);
+    if dropped.is_empty() {
+        println!(".");
+    } else {
+        println!(", dropped {}.", dropped.join(", "));
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: &str) -> TorrentEntry {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn read_and_write() {
+        let dir = std::env::temp_dir().join(format!("udpt-dbtool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let a = InfoHash::from(&[1u8; 20][..]);
+        let b = InfoHash::from(&[2u8; 20][..]);
+        let rows = vec![
+            (
+                a.clone(),
+                entry(r#"{"is_flagged":false,"completed":3,"completion_history":[1,2,3]}"#),
+            ),
+            (
+                b.clone(),
+                entry(&format!(r#"{{"is_flagged":true,"completed":0,"aliases":["{}"]}}"#, a)),
+            ),
+        ];
+        let mut file = tokio::fs::File::create(path("db.bz2")).await.unwrap();
+        tracker::write_database(&mut file, rows.clone()).await.unwrap();
+        // the write may still be in flight on the blocking pool otherwise.
+        file.sync_all().await.unwrap();
+
+        let snapshot = Snapshot::read(&path("db.bz2")).await.unwrap();
+        assert_eq!(
+            (snapshot.format, snapshot.version, snapshot.rows),
+            (DbFormat::File, Some(3), 2)
+        );
+        assert!(snapshot.problems.is_empty() && snapshot.invalid_rows.is_empty());
+        assert_eq!(snapshot.alias_conflicts(), vec![format!(
+            "alias {} of {} is also a torrent",
+            a, b
+        )]);
+        assert_eq!(changes(&rows[0].1, &rows[1].1), vec![
+            "completed 3 -> 0",
+            "flagged false -> true",
+            "history 3 -> 0",
+            "aliases 0 -> 1"
+        ]);
+
+        // exported json can be edited, broken rows are reported and dropped.
+        snapshot.write(&path("db.json"), DbFormat::Json).await.unwrap();
+        let mut json = std::fs::read_to_string(path("db.json")).unwrap();
+        json.push_str("{\"info_hash\":\"nope\"}\n");
+        json.push_str(std::fs::read_to_string(path("db.json")).unwrap().lines().next().unwrap());
+        std::fs::write(path("db.json"), json).unwrap();
+
+        let snapshot = Snapshot::read(&path("db.json")).await.unwrap();
+        assert_eq!((snapshot.format, snapshot.version, snapshot.rows), (DbFormat::Json, None, 4));
+        assert_eq!(
+            (snapshot.torrents.len(), snapshot.duplicates, snapshot.invalid_rows.len()),
+            (2, 1, 1)
+        );
+        assert!(snapshot.invalid_rows[0].starts_with("line 3: "));
+
+        // a truncated file is reported, the rows before the cut are still read.
+        let row = std::fs::read_to_string(path("db.json"))
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        std::fs::write(path("cut.json"), format!("{{\"version\":2}}\n{}\n", row)).unwrap();
+        let snapshot = Snapshot::read(&path("cut.json")).await.unwrap();
+        assert_eq!(snapshot.problems, vec!["database is truncated, footer is missing"]);
+        assert_eq!(snapshot.torrents.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(DbFormat::from_path("database.json.bz2"), DbFormat::File);
+        assert_eq!(DbFormat::from_path("export.json"), DbFormat::Json);
+        assert_eq!(DbFormat::from_path("torrents.SQLITE"), DbFormat::Sqlite);
+    }
+}
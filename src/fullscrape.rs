@@ -0,0 +1,84 @@
+use crate::tracker::InfoHash;
+use futures::io::AsyncWriteExt;
+use tokio_util::compat::Tokio02AsyncWriteCompatExt;
+
+/// Scrape counters of a torrent, as returned by `TorrentEntry::get_stats`.
+pub struct ScrapeEntry {
+    pub info_hash: InfoHash,
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// Writes `torrents` as a gzip compressed bencoded full scrape, the format used by other trackers:
+/// `{"files": {<raw infohash>: {"complete": seeders, "downloaded": completed, "incomplete": leechers}}}`.
+pub async fn write_full_scrape<W: tokio::io::AsyncWrite + Unpin>(
+    mut torrents: Vec<ScrapeEntry>, w: W,
+) -> Result<(), std::io::Error> {
+    // bencoded dictionaries must be sorted by their raw keys.
+    torrents.sort_unstable_by(|a, b| a.info_hash.as_bytes().cmp(b.info_hash.as_bytes()));
+
+    let mut writer = async_compression::futures::write::GzipEncoder::new(w.compat_write());
+    writer.write_all(b"d5:filesd").await?;
+
+    let mut tmp = Vec::with_capacity(4096);
+    for chunk in torrents.chunks(256) {
+        tmp.clear();
+        for torrent in chunk {
+            let info_hash = torrent.info_hash.as_bytes();
+            tmp.extend_from_slice(format!("{}:", info_hash.len()).as_bytes());
+            tmp.extend_from_slice(info_hash);
+            tmp.extend_from_slice(
+                format!(
+                    "d8:completei{}e10:downloadedi{}e10:incompletei{}ee",
+                    torrent.seeders, torrent.completed, torrent.leechers
+                )
+                .as_bytes(),
+            );
+        }
+        writer.write_all(&tmp).await?;
+    }
+
+    writer.write_all(b"ee").await?;
+    writer.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bencode() {
+        use futures::io::AsyncReadExt;
+
+        let torrents = vec![
+            ScrapeEntry {
+                info_hash: [2u8; 20].into(),
+                seeders: 1,
+                completed: 2,
+                leechers: 3,
+            },
+            ScrapeEntry {
+                info_hash: [1u8; 20].into(),
+                seeders: 0,
+                completed: 0,
+                leechers: 0,
+            },
+        ];
+
+        let mut compressed = Vec::new();
+        write_full_scrape(torrents, &mut compressed).await.unwrap();
+
+        let mut data = Vec::new();
+        let mut decoder = async_compression::futures::bufread::GzipDecoder::new(compressed.as_slice());
+        decoder.read_to_end(&mut data).await.unwrap();
+
+        let mut expected = b"d5:filesd20:".to_vec();
+        expected.extend_from_slice(&[1u8; 20]);
+        expected.extend_from_slice(b"d8:completei0e10:downloadedi0e10:incompletei0ee20:");
+        expected.extend_from_slice(&[2u8; 20]);
+        expected.extend_from_slice(b"d8:completei1e10:downloadedi2e10:incompletei3eeee");
+        assert_eq!(data, expected);
+    }
+}
@@ -0,0 +1,96 @@
+use serde::{Serialize, Serializer};
+
+/// ISO 3166-1 alpha-2 country code, such as `DE`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct CountryCode([u8; 2]);
+
+impl CountryCode {
+    pub fn parse(code: &str) -> Option<CountryCode> {
+        match code.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+                Some(CountryCode([a.to_ascii_uppercase(), b.to_ascii_uppercase()]))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ascii letters are accepted by `parse`.
+        std::str::from_utf8(&self.0).unwrap_or("??")
+    }
+}
+
+impl std::fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for CountryCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "geoip")]
+mod imp {
+    use super::CountryCode;
+
+    /// Country lookups in a MaxMind DB file, such as GeoLite2 Country or DB-IP's country database.
+    /// The whole file is held in memory.
+    pub struct GeoIp {
+        reader: maxminddb::Reader<Vec<u8>>,
+    }
+
+    impl GeoIp {
+        pub fn open(path: &str) -> Result<GeoIp, std::io::Error> {
+            let reader = maxminddb::Reader::open_readfile(path)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+            Ok(GeoIp { reader })
+        }
+
+        /// Country of `ip`, or of the network it's registered to. None for private & unknown addresses.
+        pub fn lookup(&self, ip: std::net::IpAddr) -> Option<CountryCode> {
+            let record: maxminddb::geoip2::Country = self.reader.lookup(ip).ok()?;
+            let country = record.country.or(record.registered_country)?;
+            CountryCode::parse(country.iso_code?)
+        }
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+mod imp {
+    use super::CountryCode;
+
+    pub struct GeoIp;
+
+    impl GeoIp {
+        pub fn open(_path: &str) -> Result<GeoIp, std::io::Error> {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "udpt was built without geoip support",
+            ))
+        }
+
+        pub fn lookup(&self, _ip: std::net::IpAddr) -> Option<CountryCode> {
+            None
+        }
+    }
+}
+
+pub use imp::GeoIp;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn country_code() {
+        let code = CountryCode::parse("de").unwrap();
+        assert_eq!(code.to_string(), "DE");
+        assert_eq!(serde_json::to_string(&code).unwrap(), r#""DE""#);
+        assert!(CountryCode::parse("DEU").is_none());
+        assert!(CountryCode::parse("1A").is_none());
+        assert!(CountryCode::parse("").is_none());
+    }
+}
@@ -0,0 +1,183 @@
+use hmac::{Mac, NewMac};
+use log::trace;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+const SIGNATURE_SIZE: usize = 32;
+/// Unix timestamp & sequence number of the datagram, both signed along with the payload.
+const HEADER_SIZE: usize = 16;
+/// Stays below the MTU of common networks & tunnels, so gossip isn't fragmented.
+const MAX_DATAGRAM_SIZE: usize = 1200;
+/// Largest payload `broadcast` sends in a single datagram.
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - SIGNATURE_SIZE - HEADER_SIZE;
+/// Seconds a datagram's timestamp may be off from the receiver's clock, older datagrams are dropped.
+const MAX_CLOCK_SKEW: u64 = 30;
+
+fn unix_now() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(v) => v.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Datagram transport between cluster nodes. Every datagram is prefixed with a HMAC-SHA256 of its timestamp,
+/// sequence number & payload, keyed with the cluster's shared secret. Datagrams with invalid signatures,
+/// timestamps more than `MAX_CLOCK_SKEW` away or that were already received are dropped.
+pub struct Gossip {
+    send: tokio::sync::Mutex<tokio::net::udp::SendHalf>,
+    recv: tokio::sync::Mutex<tokio::net::udp::RecvHalf>,
+    nodes: Vec<SocketAddr>,
+    secret: String,
+    local_addr: SocketAddr,
+    sequence: AtomicU64,
+    /// Signatures of the datagrams received within the clock skew, by their timestamp's `MAX_CLOCK_SKEW` window.
+    seen: std::sync::Mutex<BTreeMap<u64, HashSet<[u8; SIGNATURE_SIZE]>>>,
+}
+
+impl Gossip {
+    pub async fn bind(bind_address: &str, nodes: &[String], secret: &str) -> Result<Gossip, std::io::Error> {
+        let mut addrs = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let addr = tokio::net::lookup_host(node.as_str()).await?.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("failed to resolve \"{}\"", node))
+            })?;
+            addrs.push(addr);
+        }
+
+        let socket = tokio::net::UdpSocket::bind(bind_address).await?;
+        let local_addr = socket.local_addr()?;
+        let (recv, send) = socket.split();
+        Ok(Gossip {
+            send: tokio::sync::Mutex::new(send),
+            recv: tokio::sync::Mutex::new(recv),
+            nodes: addrs,
+            secret: secret.to_string(),
+            local_addr,
+            sequence: AtomicU64::new(0),
+            seen: std::sync::Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Addresses of the other nodes, in the order they were given to `bind`.
+    pub fn nodes(&self) -> &[SocketAddr] {
+        self.nodes.as_slice()
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_varkey(self.secret.as_bytes()).expect("hmac accepts keys of any size")
+    }
+
+    /// Signs `payload` into a datagram sent at `timestamp`.
+    fn seal(&self, payload: &[u8], timestamp: u64) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(SIGNATURE_SIZE + HEADER_SIZE + payload.len());
+        datagram.resize(SIGNATURE_SIZE, 0);
+        datagram.extend_from_slice(&timestamp.to_be_bytes());
+        datagram.extend_from_slice(&self.sequence.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+        datagram.extend_from_slice(payload);
+
+        let mut mac = self.mac();
+        mac.update(&datagram[SIGNATURE_SIZE..]);
+        datagram[..SIGNATURE_SIZE].copy_from_slice(&mac.finalize().into_bytes());
+        datagram
+    }
+
+    /// Sends `payload`, of at most `MAX_PAYLOAD_SIZE` bytes, to every node of the cluster.
+    pub async fn broadcast(&self, payload: &[u8]) {
+        debug_assert!(payload.len() <= MAX_PAYLOAD_SIZE);
+        let datagram = self.seal(payload, unix_now());
+
+        let mut send = self.send.lock().await;
+        for node in self.nodes.iter() {
+            if let Err(err) = send.send_to(&datagram, node).await {
+                trace!("failed to send gossip to {}: {}", node, err);
+            }
+        }
+    }
+
+    /// Sends `payload` to a single node. Payloads above `MAX_PAYLOAD_SIZE` bytes are fragmented by IP.
+    pub async fn send_to(&self, payload: &[u8], node: &SocketAddr) {
+        let datagram = self.seal(payload, unix_now());
+        if let Err(err) = self.send.lock().await.send_to(&datagram, node).await {
+            trace!("failed to send gossip to {}: {}", node, err);
+        }
+    }
+
+    /// Whether a correctly signed datagram is fresh, and wasn't received before.
+    fn is_fresh(&self, signature: &[u8], timestamp: u64, now: u64) -> bool {
+        if timestamp.saturating_add(MAX_CLOCK_SKEW) < now || timestamp > now.saturating_add(MAX_CLOCK_SKEW) {
+            return false;
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        // windows of timestamps that are no longer accepted.
+        let oldest = now.saturating_sub(MAX_CLOCK_SKEW) / MAX_CLOCK_SKEW;
+        *seen = seen.split_off(&oldest);
+
+        let mut key = [0u8; SIGNATURE_SIZE];
+        key.copy_from_slice(signature);
+        seen.entry(timestamp / MAX_CLOCK_SKEW).or_default().insert(key)
+    }
+
+    /// Waits for the next correctly signed, fresh datagram, returning its payload.
+    pub async fn receive(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut buffer = vec![0u8; 0xffff];
+        let mut recv = self.recv.lock().await;
+        loop {
+            let (size, from) = recv.recv_from(&mut buffer).await?;
+            if size < SIGNATURE_SIZE + HEADER_SIZE {
+                continue;
+            }
+            let (signature, signed) = buffer[..size].split_at(SIGNATURE_SIZE);
+
+            let mut mac = self.mac();
+            mac.update(signed);
+            if mac.verify(signature).is_err() {
+                trace!("dropping gossip with an invalid signature from {}", from);
+                continue;
+            }
+
+            let mut timestamp = [0u8; 8];
+            timestamp.copy_from_slice(&signed[..8]);
+            if !self.is_fresh(signature, u64::from_be_bytes(timestamp), unix_now()) {
+                trace!("dropping stale or replayed gossip from {}", from);
+                continue;
+            }
+            return Ok(signed[HEADER_SIZE..].to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signed_datagrams() {
+        let b = Gossip::bind("127.0.0.1:0", &[], "secret").await.unwrap();
+        let nodes = [b.local_addr().to_string()];
+        let a = Gossip::bind("127.0.0.1:0", &nodes, "secret").await.unwrap();
+        let forged = Gossip::bind("127.0.0.1:0", &nodes, "not the secret").await.unwrap();
+
+        forged.broadcast(b"forged").await;
+        a.broadcast(b"hello").await;
+        assert_eq!(b.receive().await.unwrap(), b"hello");
+
+        // replayed & stale datagrams are dropped.
+        let mut socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let datagram = a.seal(b"once", unix_now());
+        socket.send_to(&datagram, b.local_addr()).await.unwrap();
+        socket.send_to(&datagram, b.local_addr()).await.unwrap();
+        let stale = a.seal(b"stale", unix_now() - 2 * MAX_CLOCK_SKEW);
+        socket.send_to(&stale, b.local_addr()).await.unwrap();
+        a.broadcast(b"fresh").await;
+        assert_eq!(b.receive().await.unwrap(), b"once");
+        assert_eq!(b.receive().await.unwrap(), b"fresh");
+    }
+}
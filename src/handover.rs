@@ -0,0 +1,237 @@
+//! Hands the listening sockets of a running udpt over to its replacement, so upgrades don't drop announces.
+//!
+//! The new process connects to the old one's control socket at `handover_path` and asks for its sockets. The old
+//! process stops receiving, lets the packets it's handling drain, saves the database and passes its sockets with
+//! SCM_RIGHTS before exiting. Packets arriving meanwhile queue up in the sockets, which are never closed.
+
+use crate::systemd::ListenFds;
+use log::{debug, warn};
+
+/// Sent by the new process to ask for the sockets, and by the old one along with them.
+#[cfg(unix)]
+const REQUEST: u8 = b'H';
+
+/// The old process passes at most this many sockets.
+#[cfg(unix)]
+const MAX_FDS: usize = 64;
+
+/// How long the new process waits for the sockets, the old one saves its database before passing them.
+#[cfg(unix)]
+const HANDOVER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Asks the udpt listening at `path` for its sockets. Without a running udpt there are none to take over.
+#[cfg(unix)]
+pub fn request(path: &str) -> Result<Option<ListenFds>, std::io::Error> {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    let mut stream = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(v) => v,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound || err.kind() == std::io::ErrorKind::ConnectionRefused => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+    stream.write_all(&[REQUEST])?;
+
+    let fds = recv_fds(stream.as_raw_fd())?;
+    Ok(Some(ListenFds::from_fds(fds)))
+}
+
+#[cfg(not(unix))]
+pub fn request(_path: &str) -> Result<Option<ListenFds>, std::io::Error> {
+    Ok(None)
+}
+
+/// The control socket a running udpt hands its sockets over on.
+pub struct Listener {
+    #[cfg(unix)]
+    listener: tokio::net::UnixListener,
+}
+
+impl Listener {
+    /// Binds the control socket, replacing the one of the previous process. Only udpt's user may connect to it.
+    #[cfg(unix)]
+    pub fn bind(path: &str) -> Result<Listener, std::io::Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        match std::fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Listener { listener })
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(_path: &str) -> Result<Listener, std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "socket handover is only supported on unix",
+        ))
+    }
+
+    /// Resolves once a new process asks for the sockets.
+    #[cfg(unix)]
+    pub async fn accept(&mut self) -> Handover {
+        use tokio::io::AsyncReadExt;
+
+        loop {
+            let mut stream = match self.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("failed to accept a handover connection. error: {}", err);
+                    continue;
+                }
+            };
+            let mut request = [0u8; 1];
+            let timeout = std::time::Duration::from_secs(5);
+            match tokio::time::timeout(timeout, stream.read_exact(&mut request)).await {
+                Ok(Ok(_)) if request[0] == REQUEST => return Handover { stream },
+                _ => debug!("ignoring a handover connection without a request."),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn accept(&mut self) -> Handover {
+        futures::future::pending().await
+    }
+}
+
+/// A pending request for the sockets.
+pub struct Handover {
+    #[cfg(unix)]
+    stream: tokio::net::UnixStream,
+}
+
+impl Handover {
+    /// Passes the sockets to the new process, they keep receiving packets while it starts.
+    #[cfg(unix)]
+    pub fn send(self, udp: &[std::net::UdpSocket], tcp: Option<&std::net::TcpListener>) -> Result<(), std::io::Error> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut fds: Vec<i32> = udp.iter().map(|socket| socket.as_raw_fd()).collect();
+        fds.extend(tcp.map(|listener| listener.as_raw_fd()));
+        send_fds(self.stream.as_raw_fd(), &fds)
+    }
+
+    #[cfg(not(unix))]
+    pub fn send(self, _udp: &[std::net::UdpSocket], _tcp: Option<&std::net::TcpListener>) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn send_fds(socket: i32, fds: &[i32]) -> Result<(), std::io::Error> {
+    if fds.len() > MAX_FDS {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "too many sockets to pass",
+        ));
+    }
+    let payload = [REQUEST];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let len = std::mem::size_of_val(fds);
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(len as u32) } as usize];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    // SCM_RIGHTS can't carry zero descriptors.
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as _;
+            std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut i32, fds.len());
+        }
+    }
+
+    if unsafe { libc::sendmsg(socket, &msg, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn recv_fds(socket: i32) -> Result<Vec<i32>, std::io::Error> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * std::mem::size_of::<i32>()) as u32) } as usize];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+    match unsafe { libc::recvmsg(socket, &mut msg, flags) } {
+        n if n < 0 => return Err(std::io::Error::last_os_error()),
+        0 => {
+            let msg = "udpt exited without passing its sockets";
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, msg));
+        }
+        _ => {}
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<i32>();
+                let data = libc::CMSG_DATA(cmsg) as *const i32;
+                fds.extend((0..count).map(|i| std::ptr::read_unaligned(data.add(i))));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        warn!("some of the passed sockets were dropped.");
+    }
+    Ok(fds)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn pass_sockets() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let (old, new) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        send_fds(old.as_raw_fd(), &[udp.as_raw_fd(), tcp.as_raw_fd()]).unwrap();
+        let mut fds = ListenFds::from_fds(recv_fds(new.as_raw_fd()).unwrap());
+
+        // the passed sockets are the same, packets sent to one arrive at the other.
+        let passed = fds.take_udp(&udp.local_addr().unwrap().to_string()).unwrap();
+        assert!(fds.take_tcp(&tcp.local_addr().unwrap().to_string()).is_some());
+        assert!(fds.is_empty());
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"announce", udp.local_addr().unwrap()).unwrap();
+        drop(udp);
+        let mut buf = [0u8; 16];
+        let len = passed.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"announce");
+    }
+}
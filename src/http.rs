@@ -0,0 +1,27 @@
+use log::warn;
+
+/// Client for outgoing HTTP requests, to `http://` & `https://` URLs.
+pub type Client = hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>;
+
+/// Builds a `Client`, servers are verified against the system's root certificates.
+/// Without a readable certificate store, only `http://` URLs work.
+pub fn client() -> Client {
+    let mut http = hyper::client::HttpConnector::new();
+    http.enforce_http(false);
+
+    let mut tls = rustls::ClientConfig::new();
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    tls.root_store = match rustls_native_certs::load_native_certs() {
+        Ok(store) => store,
+        Err((Some(store), err)) => {
+            warn!("failed to load some of the system's root certificates: {}", err);
+            store
+        }
+        Err((None, err)) => {
+            warn!("failed to load the system's root certificates, https is unavailable: {}", err);
+            rustls::RootCertStore::empty()
+        }
+    };
+
+    hyper::Client::builder().build(hyper_rustls::HttpsConnector::from((http, tls)))
+}
@@ -0,0 +1,157 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+/// 2^12 registers, standard error is about 1.6%.
+const PRECISION: u32 = 12;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch that can be updated concurrently.
+pub struct HyperLogLog {
+    registers: Vec<AtomicU8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        let mut registers = Vec::with_capacity(REGISTERS);
+        registers.resize_with(REGISTERS, || AtomicU8::new(0));
+        HyperLogLog { registers }
+    }
+
+    pub fn insert<T: Hash>(&self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let index = (hash >> (64 - PRECISION)) as usize;
+        let rank = ((hash << PRECISION).leading_zeros() + 1).min(64 - PRECISION + 1) as u8;
+        self.registers[index].fetch_max(rank, Ordering::Relaxed);
+    }
+
+    pub fn clear(&self) {
+        for register in self.registers.iter() {
+            register.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn merge_into(&self, registers: &mut [u8]) {
+        for (dst, src) in registers.iter_mut().zip(self.registers.iter()) {
+            *dst = (*dst).max(src.load(Ordering::Relaxed));
+        }
+    }
+
+    fn estimate_registers(registers: &[u8]) -> u64 {
+        let m = registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for register in registers {
+            sum += 2f64.powi(-(*register as i32));
+            if *register == 0 {
+                zeros += 1;
+            }
+        }
+
+        let estimate = alpha * m * m / sum;
+        if estimate <= 2.5 * m && zeros > 0 {
+            // small range correction
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let mut registers = vec![0u8; REGISTERS];
+        self.merge_into(&mut registers);
+        Self::estimate_registers(&registers)
+    }
+}
+
+/// Estimates distinct values seen within a rolling window.
+/// The window is split into slots, each having its own sketch which is reset when its slot is reused.
+pub struct RollingHyperLogLog {
+    slots: Vec<(AtomicU64, HyperLogLog)>,
+    slot_secs: u64,
+}
+
+impl RollingHyperLogLog {
+    pub fn new(window: std::time::Duration, slot_count: usize) -> RollingHyperLogLog {
+        let slot_count = slot_count.max(1);
+        let mut slots = Vec::with_capacity(slot_count);
+        slots.resize_with(slot_count, || (AtomicU64::new(u64::max_value()), HyperLogLog::new()));
+
+        RollingHyperLogLog {
+            slots,
+            slot_secs: (window.as_secs() / slot_count as u64).max(1),
+        }
+    }
+
+    fn current_epoch(&self) -> u64 {
+        match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(v) => v.as_secs() / self.slot_secs,
+            Err(_) => 0,
+        }
+    }
+
+    pub fn insert<T: Hash>(&self, value: &T) {
+        let epoch = self.current_epoch();
+        let (slot_epoch, sketch) = &self.slots[(epoch % self.slots.len() as u64) as usize];
+
+        let prev = slot_epoch.load(Ordering::Acquire);
+        if prev != epoch
+            && slot_epoch
+                .compare_exchange(prev, epoch, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            // slot is being reused for a new epoch.
+            sketch.clear();
+        }
+        sketch.insert(value);
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let epoch = self.current_epoch();
+        let oldest = epoch.saturating_sub(self.slots.len() as u64 - 1);
+
+        let mut registers = vec![0u8; REGISTERS];
+        for (slot_epoch, sketch) in self.slots.iter() {
+            let slot_epoch = slot_epoch.load(Ordering::Acquire);
+            if slot_epoch >= oldest && slot_epoch <= epoch {
+                sketch.merge_into(&mut registers);
+            }
+        }
+        HyperLogLog::estimate_registers(&registers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hll_estimate() {
+        let hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0);
+
+        for i in 0..50000u32 {
+            hll.insert(&i);
+            // duplicates shouldn't count.
+            hll.insert(&i);
+        }
+
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 50000.0).abs() < 50000.0 * 0.05, "estimate was {}", estimate);
+    }
+
+    #[test]
+    fn rolling_estimate() {
+        let hll = RollingHyperLogLog::new(std::time::Duration::from_secs(3600), 6);
+        for i in 0..1000u32 {
+            hll.insert(&std::net::IpAddr::from(std::net::Ipv4Addr::from(i)));
+        }
+
+        let estimate = hll.estimate() as f64;
+        assert!((estimate - 1000.0).abs() < 1000.0 * 0.05, "estimate was {}", estimate);
+    }
+}
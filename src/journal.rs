@@ -0,0 +1,154 @@
+use crate::tracker::InfoHash;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+/// An administrative change to the torrent database.
+/// Entries are idempotent, so replaying changes that already made it into a snapshot is harmless.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Add { info_hash: InfoHash },
+    Remove { info_hash: InfoHash },
+    Flag { info_hash: InfoHash, is_flagged: bool },
+    Link { info_hash: InfoHash, alias: InfoHash },
+    Unlink { info_hash: InfoHash, alias: InfoHash },
+    Reset { info_hash: InfoHash },
+}
+
+/// Write-ahead journal of changes made since the last database snapshot.
+///
+/// Before a snapshot is written the journal is rotated to `<path>.prev`, which is deleted once the snapshot was saved.
+/// Changes that happen while the snapshot is written land in the new journal, so nothing is lost if saving fails.
+pub struct Journal {
+    path: PathBuf,
+    file: tokio::sync::Mutex<tokio::fs::File>,
+}
+
+impl Journal {
+    /// Journal path for the database at `db_path`.
+    pub fn path_for(db_path: &str) -> PathBuf {
+        let mut path = PathBuf::from(db_path);
+        let mut filename = path.file_name().unwrap_or_default().to_os_string();
+        filename.push("-wal");
+        path.set_file_name(filename);
+        path
+    }
+
+    fn previous_path(&self) -> PathBuf {
+        let mut filename = self.path.as_os_str().to_os_string();
+        filename.push(".prev");
+        PathBuf::from(filename)
+    }
+
+    async fn open_file(path: &PathBuf) -> Result<tokio::fs::File, std::io::Error> {
+        tokio::fs::OpenOptions::new().create(true).append(true).open(path).await
+    }
+
+    pub async fn open(path: PathBuf) -> Result<Journal, std::io::Error> {
+        let file = Self::open_file(&path).await?;
+        Ok(Journal {
+            path,
+            file: tokio::sync::Mutex::new(file),
+        })
+    }
+
+    /// Reads the entries of the previous and current journal, oldest first.
+    pub async fn read_entries(&self) -> Result<Vec<JournalEntry>, std::io::Error> {
+        let mut entries = Vec::new();
+        for path in [self.previous_path(), self.path.clone()].iter() {
+            let data = match tokio::fs::read_to_string(path).await {
+                Ok(v) => v,
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            for line in data.lines() {
+                match serde_json::from_str(line) {
+                    Ok(entry) => entries.push(entry),
+                    // most likely a write that was cut short by a crash.
+                    Err(err) => warn!("ignoring invalid journal entry in {}: {}", path.display(), err),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    pub async fn append(&self, entry: &JournalEntry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to serialize journal entry: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(err) = file.write_all(&line).await {
+            error!("failed to write to journal: {}", err);
+            return;
+        }
+        if let Err(err) = file.sync_data().await {
+            error!("failed to sync journal: {}", err);
+        }
+    }
+
+    /// Starts a new journal, called before a snapshot is written.
+    /// If the previous journal is still around (the last snapshot failed), the current one is kept as is.
+    pub async fn rotate(&self) -> Result<(), std::io::Error> {
+        let previous = self.previous_path();
+        let mut file = self.file.lock().await;
+        if tokio::fs::metadata(&previous).await.is_ok() {
+            return Ok(());
+        }
+
+        tokio::fs::rename(&self.path, &previous).await?;
+        *file = Self::open_file(&self.path).await?;
+        Ok(())
+    }
+
+    /// Removes the previous journal, called once a snapshot was saved.
+    pub async fn remove_previous(&self) {
+        match tokio::fs::remove_file(self.previous_path()).await {
+            Err(ref err) if err.kind() != std::io::ErrorKind::NotFound => {
+                error!("failed to remove previous journal: {}", err);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotate_and_replay() {
+        let dir = std::env::temp_dir().join(format!("udpt-journal-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db.json.bz2");
+        let path = Journal::path_for(db_path.to_str().unwrap());
+        assert_eq!(path, dir.join("db.json.bz2-wal"));
+
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let add = JournalEntry::Add {
+            info_hash: [1u8; 20].into(),
+        };
+        let flag = JournalEntry::Flag {
+            info_hash: [1u8; 20].into(),
+            is_flagged: true,
+        };
+
+        journal.append(&add).await;
+        journal.rotate().await.unwrap();
+        journal.append(&flag).await;
+        assert_eq!(journal.read_entries().await.unwrap(), vec![add, flag]);
+
+        journal.remove_previous().await;
+        assert_eq!(journal.read_entries().await.unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
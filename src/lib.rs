@@ -0,0 +1,79 @@
+//! udpt's tracker, UDP protocol & REST API, for embedding the tracker in another service.
+//!
+//! The `udpt-rs` binary is a thin wrapper around this crate: it parses the command line, loads the
+//! [configuration](config::Configuration), opens the database into a [`TorrentTracker`](tracker::TorrentTracker)
+//! and runs a [`UDPTracker`](server::UDPTracker) & the [REST API](webserver::serve) on it.
+//!
+//! Announces can be authorized by the embedding service, see [`Authorizer`](auth_hook::Authorizer):
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//! use udpt_core::auth_hook::Authorizer;
+//! use udpt_core::config::{AuthCheck, ConfigFormat, Configuration};
+//! use udpt_core::server::UDPTracker;
+//! use udpt_core::tracker::{InfoHash, TorrentTracker};
+//!
+//! struct Passkeys;
+//!
+//! impl Authorizer for Passkeys {
+//!     fn authorize<'a>(
+//!         &'a self, _info_hash: &'a InfoHash, passkey: Option<&'a str>, _ip: std::net::IpAddr,
+//!     ) -> futures::future::BoxFuture<'a, Result<(), String>> {
+//!         let allowed = passkey == Some("secret");
+//!         Box::pin(async move { if allowed { Ok(()) } else { Err(String::from("unknown passkey.")) } })
+//!     }
+//! }
+//!
+//! # async fn run() -> Result<(), std::io::Error> {
+//! let config = Arc::new(Configuration::load_file("udpt.toml", ConfigFormat::Toml).unwrap());
+//! let tracker = Arc::new(TorrentTracker::new(config.clone()));
+//! let mut udp = UDPTracker::new(config, tracker, None).await?;
+//! udp.set_authorizer(Box::new(Passkeys), AuthCheck::All);
+//!
+//! let (_stop, shutdown) = tokio::sync::broadcast::channel(1);
+//! udp.accept_packets(shutdown).await
+//! # }
+//! ```
+//!
+//! Periodic maintenance (reaping peers, saving the database…) is left to the embedding service, `udpt-rs`'s
+//! `main.rs` shows which of the tracker's methods to call and how often.
+
+// declared first, so its macros are available to the other modules.
+#[macro_use]
+pub mod logging;
+
+mod abuse;
+mod analytics;
+pub mod auth_hook;
+mod bans;
+mod bufpool;
+mod cidr;
+pub mod config;
+mod fullscrape;
+mod geoip;
+mod gossip;
+mod http;
+mod hyperloglog;
+mod journal;
+pub mod limits;
+mod negcache;
+mod postgres_store;
+pub mod privileges;
+mod raft;
+mod ratelimit;
+mod redis_store;
+mod replay;
+mod replica;
+pub mod server;
+mod slab;
+pub mod sqlite;
+mod stackvec;
+pub mod statsd;
+pub mod store;
+mod telemetry;
+pub mod tracker;
+mod traffic;
+mod upstream;
+mod users;
+mod webhooks;
+pub mod webserver;
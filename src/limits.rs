@@ -0,0 +1,231 @@
+use crate::config::{LimitAction, LimitsConfig};
+
+/// Outcome of checking the `[limits]` section.
+#[derive(Debug, Default, PartialEq)]
+pub struct LimitsReport {
+    /// Limits that couldn't be met. udpt refuses to start because of them, unless `on_unmet` is "degrade".
+    pub unmet: Vec<String>,
+    /// Rlimits that were raised, and what was lowered to run with less.
+    pub applied: Vec<String>,
+    /// Peers the tracker may hold.
+    pub max_peers: Option<usize>,
+}
+
+impl LimitsReport {
+    /// Whether udpt should refuse to start.
+    pub fn is_fatal(&self, config: &LimitsConfig) -> bool {
+        !self.unmet.is_empty() && *config.get_on_unmet() == LimitAction::Exit
+    }
+}
+
+/// Raises the rlimits to the configured ones & verifies that the limits are met.
+pub fn apply(config: &LimitsConfig) -> LimitsReport {
+    let mut report = LimitsReport {
+        max_peers: config.get_max_peers(),
+        ..LimitsReport::default()
+    };
+
+    if let Some(wanted) = config.get_open_files() {
+        match open_files(wanted) {
+            Ok((available, raised)) => {
+                if raised {
+                    report.applied.push(format!("raised the open files limit to {}", available));
+                }
+                if available < wanted {
+                    let msg = format!(
+                        "`limits.open_files` is {}, but only {} files can be opened",
+                        wanted, available
+                    );
+                    report.unmet.push(msg);
+                }
+            }
+            Err(err) => report.unmet.push(format!("`limits.open_files`: {}", err)),
+        }
+    }
+
+    if let Some(wanted) = config.get_memory() {
+        match memory(wanted) {
+            Ok((available, source, raised)) => {
+                for name in raised {
+                    report
+                        .applied
+                        .push(format!("raised the {} limit to {} MiB", name, wanted / 1024 / 1024));
+                }
+                if available < wanted {
+                    report.unmet.push(format!(
+                        "`limits.memory_mb` is {}, but only {} MiB are available ({})",
+                        wanted / 1024 / 1024,
+                        available / 1024 / 1024,
+                        source
+                    ));
+                    if *config.get_on_unmet() == LimitAction::Degrade {
+                        report.max_peers = report.max_peers.map(|v| scale(v, available, wanted));
+                        if let Some(max_peers) = report.max_peers {
+                            report.applied.push(format!("lowered `limits.max_peers` to {}", max_peers));
+                        }
+                    }
+                }
+            }
+            Err(err) => report.unmet.push(format!("`limits.memory_mb`: {}", err)),
+        }
+    }
+    report
+}
+
+/// `value * available / wanted`, at least 1.
+fn scale(value: usize, available: u64, wanted: u64) -> usize {
+    ((value as u128 * available as u128 / wanted as u128) as usize).max(1)
+}
+
+/// Returns the soft limit of `resource` after raising it towards `wanted`, and whether it was raised.
+/// The hard limit is raised too when permitted, otherwise the soft limit is raised up to it.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t is 32 bits wide on some targets.
+fn raise_rlimit(resource: libc::c_int, wanted: u64) -> Result<(u64, bool), String> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource as _, &mut limit) } != 0 {
+        return Err(std::io::Error::last_os_error().to_string());
+    }
+    let current = limit.rlim_cur as u64;
+    if limit.rlim_cur == libc::RLIM_INFINITY || current >= wanted {
+        return Ok((
+            if limit.rlim_cur == libc::RLIM_INFINITY {
+                u64::MAX
+            } else {
+                current
+            },
+            false,
+        ));
+    }
+
+    let raised = libc::rlimit {
+        rlim_cur: wanted as libc::rlim_t,
+        rlim_max: limit.rlim_max.max(wanted as libc::rlim_t),
+    };
+    if unsafe { libc::setrlimit(resource as _, &raised) } == 0 {
+        return Ok((wanted, true));
+    }
+    let to_hard = libc::rlimit {
+        rlim_cur: limit.rlim_max,
+        rlim_max: limit.rlim_max,
+    };
+    if limit.rlim_cur < limit.rlim_max && unsafe { libc::setrlimit(resource as _, &to_hard) } == 0 {
+        let hard = if limit.rlim_max == libc::RLIM_INFINITY {
+            u64::MAX
+        } else {
+            limit.rlim_max as u64
+        };
+        return Ok((hard, true));
+    }
+    Ok((current, false))
+}
+
+#[cfg(unix)]
+fn open_files(wanted: u64) -> Result<(u64, bool), String> {
+    raise_rlimit(libc::RLIMIT_NOFILE as _, wanted)
+}
+
+/// Returns the memory udpt can use, what limits it, and the rlimits that were raised.
+#[cfg(unix)]
+fn memory(wanted: u64) -> Result<(u64, &'static str, Vec<&'static str>), String> {
+    let mut available = (u64::MAX, "unlimited");
+    let mut raised = Vec::new();
+    for (resource, name) in [
+        (libc::RLIMIT_AS as libc::c_int, "address space"),
+        (libc::RLIMIT_DATA as _, "data"),
+    ]
+    .iter()
+    {
+        let (limit, is_raised) = raise_rlimit(*resource, wanted)?;
+        if is_raised {
+            raised.push(*name);
+        }
+        if limit < available.0 {
+            available = (limit, *name);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(limit) = cgroup_memory_limit() {
+            if limit < available.0 {
+                available = (limit, "cgroup memory.max");
+            }
+        }
+        let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if pages > 0 && page_size > 0 && (pages as u64 * page_size as u64) < available.0 {
+            available = (pages as u64 * page_size as u64, "machine memory");
+        }
+    }
+    Ok((available.0, available.1, raised))
+}
+
+/// Lowest `memory.max` of udpt's cgroup & its parents, with cgroup v2.
+#[cfg(target_os = "linux")]
+fn cgroup_memory_limit() -> Option<u64> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let mut dir = std::path::Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+    let mut lowest: Option<u64> = None;
+    loop {
+        if let Ok(value) = std::fs::read_to_string(dir.join("memory.max")) {
+            if let Ok(limit) = value.trim().parse::<u64>() {
+                lowest = Some(lowest.map_or(limit, |v| v.min(limit)));
+            }
+        }
+        if dir == std::path::Path::new("/sys/fs/cgroup") || !dir.pop() {
+            return lowest;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn open_files(_wanted: u64) -> Result<(u64, bool), String> {
+    Err(String::from("only supported on unix"))
+}
+
+#[cfg(not(unix))]
+fn memory(_wanted: u64) -> Result<(u64, &'static str, Vec<&'static str>), String> {
+    Err(String::from("only supported on unix"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn limits(config: &str) -> LimitsConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn apply_limits() {
+        assert_eq!(apply(&limits("max_peers = 100")), LimitsReport {
+            max_peers: Some(100),
+            ..LimitsReport::default()
+        });
+        assert!(apply(&limits("open_files = 16")).unmet.is_empty());
+
+        // no machine has an exbibyte of memory.
+        let config = limits("memory_mb = 1099511627776\nmax_peers = 100");
+        let report = apply(&config);
+        assert_eq!(report.unmet.len(), 1);
+        assert!(report.is_fatal(&config));
+        assert_eq!(report.max_peers, Some(100));
+
+        let config = limits("memory_mb = 1099511627776\nmax_peers = 100000\non_unmet = \"degrade\"");
+        let report = apply(&config);
+        assert!(!report.is_fatal(&config));
+        assert!(report.max_peers.unwrap() < 100000);
+        assert_eq!(
+            report.applied.last().unwrap(),
+            &format!("lowered `limits.max_peers` to {}", report.max_peers.unwrap())
+        );
+
+        assert_eq!(scale(1000, 1, 4), 250);
+        assert_eq!(scale(1000, 1, 1 << 40), 1);
+    }
+}
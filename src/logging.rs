@@ -0,0 +1,627 @@
+use crate::config::{Configuration, LogFormat, LoggingConfig};
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+/// Separates the structured fields appended to a message by `log_fields!`, it doesn't occur in messages themselves.
+const FIELD_SEPARATOR: char = '\x1f';
+
+/// Logs a message with structured fields, e.g. `log_fields!(Level::Trace, "announce rejected", ip = addr)`.
+/// The text format appends the fields as `key=value`, the JSON format emits them as keys of their own.
+#[macro_export]
+macro_rules! log_fields {
+    ($level:expr, $message:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        log::log!(
+            $level,
+            "{}{}",
+            $message,
+            $crate::logging::Fields(&[$((stringify!($key), &$value as &dyn std::fmt::Display)),*])
+        )
+    };
+}
+
+/// Structured fields of a log message.
+pub struct Fields<'a>(pub &'a [(&'a str, &'a dyn std::fmt::Display)]);
+
+impl<'a> std::fmt::Display for Fields<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (key, value) in self.0.iter() {
+            write!(f, "{}{}={}", FIELD_SEPARATOR, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a formatted message into the message itself and its fields.
+fn split_fields(message: &str) -> (&str, impl Iterator<Item = (&str, &str)>) {
+    let mut parts = message.split(FIELD_SEPARATOR);
+    let message = parts.next().unwrap_or("");
+    (
+        message,
+        parts.map(|field| {
+            let mut kv = field.splitn(2, '=');
+            (kv.next().unwrap_or(""), kv.next().unwrap_or(""))
+        }),
+    )
+}
+
+fn format_text(record: &log::Record) -> String {
+    let message = record.args().to_string();
+    let (message, fields) = split_fields(&message);
+    let mut line = format!(
+        "{} [{}][{}] {}",
+        chrono::Local::now().format("%+"),
+        record.target(),
+        record.level(),
+        message
+    );
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    line
+}
+
+fn format_json(record: &log::Record) -> String {
+    let message = record.args().to_string();
+    let (message, fields) = split_fields(&message);
+
+    let mut object = serde_json::Map::new();
+    object.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+    object.insert("level".into(), record.level().as_str().to_lowercase().into());
+    object.insert("module".into(), record.target().into());
+    object.insert("message".into(), message.into());
+    for (key, value) in fields {
+        object.insert(key.into(), value.into());
+    }
+    serde_json::Value::Object(object).to_string()
+}
+
+/// A log file that's rotated once it grows over `max_size` bytes or gets older than `max_age`: `<path>` is renamed to
+/// `<path>.1`, older files move up by one, and files beyond `retention` are deleted.
+struct RotatingFile {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    size: u64,
+    created: std::time::SystemTime,
+    max_size: Option<u64>,
+    max_age: Option<std::time::Duration>,
+    retention: usize,
+    /// Lines may be written in several parts, files are only rotated between lines.
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn open(path: &str, cfg: &LoggingConfig) -> std::io::Result<RotatingFile> {
+        let mut file = RotatingFile {
+            path: std::path::PathBuf::from(path),
+            file: Self::open_file(std::path::Path::new(path))?,
+            size: 0,
+            created: std::time::SystemTime::now(),
+            max_size: cfg.get_rotate_size(),
+            max_age: cfg.get_rotate_interval(),
+            retention: cfg.get_retention(),
+            at_line_start: true,
+        };
+        // an existing log keeps its age & size, so restarts don't postpone rotation.
+        let metadata = file.file.metadata()?;
+        file.size = metadata.len();
+        file.created = metadata.created().unwrap_or(file.created);
+        Ok(file)
+    }
+
+    fn open_file(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+        std::fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated_path(&self, index: usize) -> std::path::PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.max_size.map(|max_size| self.size >= max_size) == Some(true) {
+            return true;
+        }
+        match (self.max_age, self.created.elapsed()) {
+            (Some(max_age), Ok(age)) => age >= max_age,
+            _ => false,
+        }
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.retention == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(self.rotated_path(self.retention));
+            for index in (1..self.retention).rev() {
+                let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = Self::open_file(&self.path)?;
+        self.size = 0;
+        self.created = std::time::SystemTime::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.at_line_start && self.should_rotate() {
+            if let Err(err) = self.rotate() {
+                eprintln!("udpt: failed to rotate \"{}\": {}", self.path.display(), err);
+                // retried on the next line.
+                self.created = std::time::SystemTime::now();
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        self.at_line_start = buf[..written].ends_with(b"\n");
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Syslog & journald severity of `level`.
+fn severity(level: log::Level) -> u8 {
+    match level {
+        log::Level::Error => 3,
+        log::Level::Warn => 4,
+        log::Level::Info => 6,
+        log::Level::Debug | log::Level::Trace => 7,
+    }
+}
+
+enum SyslogSocket {
+    Udp(std::net::UdpSocket),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+/// Sends RFC 5424 messages to a syslog server, with the daemon facility.
+struct Syslog {
+    socket: SyslogSocket,
+    pid: u32,
+}
+
+impl Syslog {
+    const FACILITY_DAEMON: u8 = 3;
+
+    /// Connects to `udp://<host>:<port>`, or to the unix socket at `address` (usually `/dev/log`).
+    fn connect(address: &str) -> std::io::Result<Syslog> {
+        use std::net::ToSocketAddrs;
+
+        let socket = if address.starts_with("udp://") {
+            let addr = address["udp://".len()..].to_socket_addrs()?.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("failed to resolve \"{}\"", address))
+            })?;
+            let socket = std::net::UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+            socket.connect(addr)?;
+            SyslogSocket::Udp(socket)
+        } else {
+            #[cfg(unix)]
+            {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(address)?;
+                SyslogSocket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "unix sockets aren't supported",
+            ));
+        };
+        Ok(Syslog {
+            socket,
+            pid: std::process::id(),
+        })
+    }
+
+    fn format(&self, record: &log::Record) -> String {
+        let message = record.args().to_string();
+        let (message, fields) = split_fields(&message);
+        let mut line = format!(
+            "<{}>1 {} - udpt {} - - [{}] {}",
+            Self::FACILITY_DAEMON * 8 + severity(record.level()),
+            chrono::Utc::now().to_rfc3339(),
+            self.pid,
+            record.target(),
+            message
+        );
+        for (key, value) in fields {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+}
+
+impl log::Log for Syslog {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = self.format(record);
+        // there's nowhere left to report failures to.
+        let _ = match self.socket {
+            SyslogSocket::Udp(ref socket) => socket.send(line.as_bytes()),
+            #[cfg(unix)]
+            SyslogSocket::Unix(ref socket) => socket.send(line.as_bytes()),
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+/// Reports events to the Windows event log, under the Application log with `udpt` as source.
+#[cfg(windows)]
+struct EventLog {
+    handle: winapi::um::winnt::HANDLE,
+}
+
+// the event log handle can be used from any thread.
+#[cfg(windows)]
+unsafe impl Send for EventLog {}
+#[cfg(windows)]
+unsafe impl Sync for EventLog {}
+
+#[cfg(windows)]
+impl EventLog {
+    fn register() -> std::io::Result<EventLog> {
+        let source: Vec<u16> = "udpt".encode_utf16().chain(std::iter::once(0)).collect();
+        let handle = unsafe { winapi::um::winbase::RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(EventLog { handle })
+    }
+}
+
+#[cfg(windows)]
+impl log::Log for EventLog {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        use winapi::um::winnt::{EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE};
+
+        let kind = match record.level() {
+            log::Level::Error => EVENTLOG_ERROR_TYPE,
+            log::Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = format!("[{}] {}", record.target(), record.args());
+        let message: Vec<u16> = message.encode_utf16().chain(std::iter::once(0)).collect();
+        let mut strings = [message.as_ptr()];
+        // there's nowhere left to report failures to.
+        unsafe {
+            winapi::um::winbase::ReportEventW(
+                self.handle,
+                kind,
+                0,
+                0,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                std::ptr::null_mut(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(windows)]
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winbase::DeregisterEventSource(self.handle) };
+    }
+}
+
+/// Sends entries to systemd-journald over its native protocol, fields of messages become journal fields.
+#[cfg(unix)]
+struct Journald {
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+#[cfg(unix)]
+impl Journald {
+    const SOCKET_PATH: &'static str = "/run/systemd/journal/socket";
+
+    fn connect() -> std::io::Result<Journald> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(Self::SOCKET_PATH)?;
+        Ok(Journald { socket })
+    }
+
+    fn push_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+        // journal field names are upper case letters, digits & underscores.
+        let key: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        entry.extend_from_slice(key.trim_start_matches('_').as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+
+    fn entry(record: &log::Record) -> Vec<u8> {
+        let message = record.args().to_string();
+        let (message, fields) = split_fields(&message);
+
+        let mut entry = Vec::new();
+        Self::push_field(&mut entry, "MESSAGE", message);
+        Self::push_field(&mut entry, "PRIORITY", &severity(record.level()).to_string());
+        Self::push_field(&mut entry, "SYSLOG_IDENTIFIER", "udpt");
+        Self::push_field(&mut entry, "MODULE", record.target());
+        for (key, value) in fields {
+            Self::push_field(&mut entry, key, value);
+        }
+        entry
+    }
+}
+
+#[cfg(unix)]
+impl log::Log for Journald {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = self.socket.send(&Self::entry(record));
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn parse_level(level: Option<&str>) -> Result<log::LevelFilter, String> {
+    match level {
+        None => Ok(log::LevelFilter::Info),
+        Some(level) => {
+            match level {
+                "off" => Ok(log::LevelFilter::Off),
+                "trace" => Ok(log::LevelFilter::Trace),
+                "debug" => Ok(log::LevelFilter::Debug),
+                "info" => Ok(log::LevelFilter::Info),
+                "warn" => Ok(log::LevelFilter::Warn),
+                "error" => Ok(log::LevelFilter::Error),
+                _ => Err(format!("unknown log level encountered '{}'", level)),
+            }
+        }
+    }
+}
+
+/// The configured log level, and the levels of modules that log at another one.
+pub struct Levels {
+    default: log::LevelFilter,
+    /// Module -> level, most specific modules first.
+    modules: Vec<(String, log::LevelFilter)>,
+}
+
+impl Levels {
+    pub fn from_config(cfg: &Configuration) -> Result<Levels, String> {
+        let mut modules = Vec::new();
+        for (module, level) in cfg.get_logging_config().get_levels().iter() {
+            modules.push((module.clone(), parse_level(Some(level.as_str()))?));
+        }
+        modules.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()));
+        Ok(Levels {
+            default: parse_level(cfg.get_log_level().as_deref())?,
+            modules,
+        })
+    }
+
+    /// Modules of udpt are named without the crate, such as `server`, other crates by their name, such as `warp`.
+    fn level(&self, target: &str) -> log::LevelFilter {
+        let crate_name = module_path!().split("::").next().unwrap_or("");
+        let module = match target.starts_with(crate_name) && target[crate_name.len()..].starts_with("::") {
+            true => &target[crate_name.len() + 2..],
+            false => target,
+        };
+        let matches = |name: &str| module == name || (module.starts_with(name) && module[name.len()..].starts_with("::"));
+        self.modules
+            .iter()
+            .find(|(name, _)| matches(name))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> log::LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, std::cmp::max)
+    }
+}
+
+/// The levels the logger filters with, they change when the configuration is reloaded.
+#[derive(Clone)]
+pub struct LogLevels(Arc<RwLock<Levels>>);
+
+impl LogLevels {
+    pub fn set(&self, levels: Levels) {
+        // the global level lets the log macros skip messages no module would log.
+        log::set_max_level(levels.max());
+        *self.0.write().unwrap() = levels;
+    }
+
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.0.read().unwrap().level(metadata.target())
+    }
+}
+
+/// Installs the global logger as configured.
+pub fn setup(cfg: &Configuration) -> Result<LogLevels, String> {
+    let levels = LogLevels(Arc::new(RwLock::new(Levels::from_config(cfg)?)));
+    let logging = cfg.get_logging_config();
+    let format = match logging.get_format() {
+        LogFormat::Text => format_text,
+        LogFormat::Json => format_json,
+    };
+
+    let filter = levels.clone();
+    let mut dispatch = fern::Dispatch::new().filter(move |metadata| filter.enabled(metadata));
+    if let Some(address) = logging.get_syslog() {
+        let syslog =
+            Syslog::connect(address).map_err(|err| format!("failed to connect to syslog \"{}\": {}", address, err))?;
+        dispatch = dispatch.chain(Box::new(syslog) as Box<dyn log::Log>);
+    }
+    if logging.get_journald() {
+        #[cfg(unix)]
+        {
+            let journald = Journald::connect().map_err(|err| format!("failed to connect to journald: {}", err))?;
+            dispatch = dispatch.chain(Box::new(journald) as Box<dyn log::Log>);
+        }
+        #[cfg(not(unix))]
+        return Err(String::from("journald is only available on Linux."));
+    }
+    if logging.get_eventlog() {
+        #[cfg(windows)]
+        {
+            let eventlog = EventLog::register().map_err(|err| format!("failed to open the event log: {}", err))?;
+            dispatch = dispatch.chain(Box::new(eventlog) as Box<dyn log::Log>);
+        }
+        #[cfg(not(windows))]
+        return Err(String::from("the event log is only available on Windows."));
+    }
+
+    // stdout is only logged to when there's no other output.
+    let output: Option<fern::Output> = match logging.get_path() {
+        Some(path) => {
+            let file = RotatingFile::open(path, logging).map_err(|err| format!("failed to open \"{}\": {}", path, err))?;
+            Some(fern::Output::from(Box::new(file) as Box<dyn Write + Send>))
+        }
+        None if logging.get_syslog().is_none() && !logging.get_journald() && !logging.get_eventlog() => {
+            Some(std::io::stdout().into())
+        }
+        None => None,
+    };
+    if let Some(output) = output {
+        dispatch = dispatch.chain(
+            fern::Dispatch::new()
+                .format(move |out, _message, record| out.finish(format_args!("{}", format(record))))
+                .chain(output),
+        );
+    }
+
+    dispatch
+        .apply()
+        .map_err(|err| format!("failed to initialize logging. {}", err))?;
+    let max = levels.0.read().unwrap().max();
+    log::set_max_level(max);
+    Ok(levels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let fields = Fields(&[("ip", &addr), ("reason", &"banned")]);
+        let args = format!("rejected announce{}", fields);
+        let line = format_json(
+            &log::Record::builder()
+                .args(format_args!("{}", args))
+                .level(log::Level::Debug)
+                .target("udpt::server")
+                .build(),
+        );
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["level"], "debug");
+        assert_eq!(value["module"], "udpt::server");
+        assert_eq!(value["message"], "rejected announce");
+        assert_eq!(value["ip"], "127.0.0.1:6881");
+        assert_eq!(value["reason"], "banned");
+    }
+
+    #[test]
+    fn module_levels() {
+        let levels = Levels {
+            default: log::LevelFilter::Info,
+            modules: vec![
+                (String::from("hyper::client"), log::LevelFilter::Trace),
+                (String::from("server"), log::LevelFilter::Debug),
+                (String::from("hyper"), log::LevelFilter::Off),
+            ],
+        };
+        let crate_name = module_path!().split("::").next().unwrap();
+        assert_eq!(levels.level(&format!("{}::server", crate_name)), log::LevelFilter::Debug);
+        assert_eq!(levels.level(&format!("{}::server_stats", crate_name)), log::LevelFilter::Info);
+        assert_eq!(levels.level(&format!("{}::tracker", crate_name)), log::LevelFilter::Info);
+        assert_eq!(levels.level("hyper::proto::h1"), log::LevelFilter::Off);
+        assert_eq!(levels.level("hyper::client::pool"), log::LevelFilter::Trace);
+        assert_eq!(levels.max(), log::LevelFilter::Trace);
+    }
+
+    #[test]
+    fn syslog() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let syslog = Syslog::connect(&format!("udp://{}", server.local_addr().unwrap())).unwrap();
+        let args = format!("torrent flagged{}", Fields(&[("info_hash", &"abcd")]));
+        log::Log::log(
+            &syslog,
+            &log::Record::builder()
+                .args(format_args!("{}", args))
+                .level(log::Level::Warn)
+                .target("udpt_rs::tracker")
+                .build(),
+        );
+
+        let mut buffer = [0u8; 1024];
+        let size = server.recv(&mut buffer).unwrap();
+        let line = std::str::from_utf8(&buffer[..size]).unwrap();
+        assert!(line.starts_with("<28>1 "), "{}", line);
+        let expected = format!(
+            " udpt {} - - [udpt_rs::tracker] torrent flagged info_hash=abcd",
+            std::process::id()
+        );
+        assert!(line.ends_with(&expected), "{}", line);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn journald_fields() {
+        let mut entry = Vec::new();
+        Journald::push_field(&mut entry, "info_hash", "abcd");
+        Journald::push_field(&mut entry, "MESSAGE", "two\nlines");
+        assert_eq!(entry, b"INFO_HASH=abcd\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n".to_vec());
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("udpt-logs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("udpt.log").to_string_lossy().into_owned();
+
+        let mut file = RotatingFile::open(&path, &LoggingConfig::default()).unwrap();
+        file.max_size = Some(10);
+        file.retention = 2;
+        for line in ["first line", "second line", "third line", "fourth line"].iter() {
+            write!(file, "{}{}", line, "\n").unwrap();
+        }
+
+        let read = |path: std::path::PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(file.path.clone()), "fourth line\n");
+        assert_eq!(read(file.rotated_path(1)), "third line\n");
+        assert_eq!(read(file.rotated_path(2)), "second line\n");
+        assert!(!file.rotated_path(3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
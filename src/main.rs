@@ -1,57 +1,131 @@
 use clap;
-use fern;
 use log::{error, info, trace, warn};
 
-mod config;
-mod server;
-mod stackvec;
-mod tracker;
-mod webserver;
+mod bench;
+mod daemon;
+mod dbtool;
+mod handover;
+mod portmap;
+mod probe;
+mod sandbox;
+mod systemd;
+mod winservice;
 
-use config::Configuration;
+use config::{ConfigFormat, ConfigOverrides, ConfigReport, Configuration, DatabaseBackend};
 use std::process::exit;
+use udpt_core::log_fields;
+use udpt_core::{config, limits, logging, privileges, server, statsd, tracker, webserver};
 
-fn setup_logging(cfg: &Configuration) {
-    let log_level = match cfg.get_log_level() {
-        None => log::LevelFilter::Info,
-        Some(level) => {
-            match level.as_str() {
-                "off" => log::LevelFilter::Off,
-                "trace" => log::LevelFilter::Trace,
-                "debug" => log::LevelFilter::Debug,
-                "info" => log::LevelFilter::Info,
-                "warn" => log::LevelFilter::Warn,
-                "error" => log::LevelFilter::Error,
-                _ => {
-                    eprintln!("udpt: unknown log level encountered '{}'", level.as_str());
-                    exit(-1);
-                }
-            }
-        }
-    };
-
-    if let Err(err) = fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} [{}][{}] {}",
-                chrono::Local::now().format("%+"),
-                record.target(),
-                record.level(),
-                message
-            ))
-        })
-        .level(log_level)
-        .chain(std::io::stdout())
-        .apply()
-    {
-        eprintln!("udpt: failed to initialize logging. {}", err);
-        std::process::exit(-1);
+fn setup_logging(cfg: &Configuration) -> logging::LogLevels {
+    match logging::setup(cfg) {
+        Ok(levels) => {
+            info!("logging initialized.");
+            levels
+        }
+        Err(err) => {
+            eprintln!("udpt: {}", err);
+            exit(-1);
+        }
+    }
+}
+
+/// Prints the problems found in the configuration, returns the exit code.
+fn check_config(path: Option<&str>, format: ConfigFormat, overrides: &ConfigOverrides) -> i32 {
+    let report = match path {
+        Some(path) => {
+            match Configuration::check_file(path, format, overrides) {
+                Ok(v) => v,
+                Err(err) => {
+                    println!("error: failed to load \"{}\": {}", path, err);
+                    return 1;
+                }
+            }
+        }
+        None => {
+            let mut cfg = Configuration::default();
+            cfg.apply_overrides(overrides);
+            let mut report = ConfigReport::default();
+            cfg.check(&mut report);
+            report
+        }
+    };
+
+    for err in report.errors.iter() {
+        println!("error: {}", err);
+    }
+    for warning in report.warnings.iter() {
+        println!("warning: {}", warning);
+    }
+    println!("{} errors, {} warnings.", report.errors.len(), report.warnings.len());
+    if report.errors.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Reloads the configuration file, applying the settings that can be changed at runtime.
+/// Command line overrides still take precedence.
+async fn reload_config(
+    tracker: &tracker::TorrentTracker, log_levels: &logging::LogLevels, path: &str, format: ConfigFormat,
+    overrides: &ConfigOverrides,
+) {
+    let new_cfg = match Configuration::load_file(path, format) {
+        Ok(mut v) => {
+            v.apply_overrides(overrides);
+            v
+        }
+        Err(err) => {
+            error!("failed to reload configuration: {}", err);
+            return;
+        }
+    };
+    let levels = match logging::Levels::from_config(&new_cfg) {
+        Ok(v) => v,
+        Err(err) => {
+            error!("failed to reload configuration: {}", err);
+            return;
+        }
+    };
+
+    let changes = tracker.reload_config(&new_cfg);
+    for name in changes.ignored.iter() {
+        warn!("\"{}\" changed, restart udpt to apply it.", name);
+    }
+    if changes.applied.is_empty() {
+        info!("configuration reloaded, nothing to apply.");
+        return;
+    }
+    info!("configuration reloaded, applying: {}.", changes.applied.join(", "));
+
+    log_levels.set(levels);
+    if changes.applied.iter().any(|name| name.starts_with("tracker.blacklist")) {
+        match tracker.load_blacklist().await {
+            Ok(count) => info!("flagged {} blacklisted torrents.", count),
+            Err(err) => error!("failed to load blacklist. error: {}", err),
+        }
     }
-    info!("logging initialized.");
 }
 
-#[tokio::main]
-async fn main() {
+fn db_format_arg<'a>() -> clap::Arg<'a, 'a> {
+    clap::Arg::with_name("to")
+        .takes_value(true)
+        .long("to")
+        .possible_values(&["file", "json", "sqlite"])
+        .help("Format to write. By default, it's detected from the output's extension.")
+}
+
+fn bench_arg<'a>(name: &'a str, value_name: &'a str, default: &'a str, help: &'a str) -> clap::Arg<'a, 'a> {
+    clap::Arg::with_name(name)
+        .takes_value(true)
+        .long(name)
+        .value_name(value_name)
+        .default_value(default)
+        .help(help)
+}
+
+fn main() {
+    let started = std::time::Instant::now();
     let parser = clap::App::new(env!("CARGO_PKG_NAME"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -60,29 +134,626 @@ async fn main() {
             clap::Arg::with_name("config")
                 .takes_value(true)
                 .short("-c")
-                .help("Configuration file to load.")
-                .required(true),
+                .help("Configuration file to load. Without one, the defaults are used."),
+        )
+        .arg(
+            clap::Arg::with_name("config-format")
+                .takes_value(true)
+                .long("config-format")
+                .possible_values(&["toml", "yaml", "json"])
+                .help("Format of the configuration file. By default, it's detected from the file extension."),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("init")
+                .about("Writes a commented configuration file listing every setting.")
+                .arg(
+                    clap::Arg::with_name("path")
+                        .default_value("udpt.toml")
+                        .help("Where to write the configuration."),
+                )
+                .arg(
+                    clap::Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrites an existing file."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Connects, announces & scrapes like a client to check a running tracker, for monitoring.")
+                .arg(
+                    clap::Arg::with_name("address")
+                        .required(true)
+                        .help("The tracker's UDP address, as host:port."),
+                )
+                .arg(
+                    clap::Arg::with_name("info-hash")
+                        .takes_value(true)
+                        .long("info-hash")
+                        .help("Hex encoded torrent to announce, needed in static & private mode. Random by default."),
+                )
+                .arg(
+                    clap::Arg::with_name("passkey")
+                        .takes_value(true)
+                        .long("passkey")
+                        .help("Passkey to announce with, in private mode."),
+                )
+                .arg(
+                    clap::Arg::with_name("timeout")
+                        .takes_value(true)
+                        .long("timeout")
+                        .value_name("MILLISECONDS")
+                        .default_value("5000")
+                        .help("How long to wait for each response."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("bench")
+                .about("Sends announce & scrape load to a tracker and reports its throughput & response times.")
+                .arg(
+                    clap::Arg::with_name("address")
+                        .required_unless("storage")
+                        .help("The tracker's UDP address, as host:port."),
+                )
+                .arg(bench_arg("torrents", "COUNT", "1000", "Torrents to announce & scrape."))
+                .arg(bench_arg(
+                    "peers",
+                    "COUNT",
+                    "10000",
+                    "Peers spread over the torrents, a quarter of them seeding.",
+                ))
+                .arg(bench_arg(
+                    "rate",
+                    "PER_SECOND",
+                    "10000",
+                    "Requests per second, 0 to send as fast as possible.",
+                ))
+                .arg(bench_arg("duration", "SECONDS", "10", "How long to send requests."))
+                .arg(bench_arg("scrapes", "PERCENT", "10", "Share of scrapes among the requests."))
+                .arg(bench_arg(
+                    "ipv6",
+                    "PERCENT",
+                    "0",
+                    "Share of requests sent to the tracker's IPv6 address.",
+                ))
+                .arg(bench_arg(
+                    "workers",
+                    "COUNT",
+                    "4",
+                    "Threads sending requests, each with its own sockets.",
+                ))
+                .arg(bench_arg(
+                    "timeout",
+                    "MILLISECONDS",
+                    "1000",
+                    "How long to wait for the last responses.",
+                ))
+                .arg(
+                    clap::Arg::with_name("passkey")
+                        .takes_value(true)
+                        .long("passkey")
+                        .help("Passkey to announce with, in private mode."),
+                )
+                .arg(
+                    clap::Arg::with_name("storage")
+                        .long("storage")
+                        .conflicts_with("address")
+                        .help("Announces in-process to each storage backend instead, to compare them on this machine."),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("db")
+                .about("Inspects & repairs saved databases, without a running tracker.")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("list")
+                        .about("Lists the torrents of a database.")
+                        .arg(clap::Arg::with_name("path").required(true).help("The database to read."))
+                        .arg(
+                            clap::Arg::with_name("flagged")
+                                .long("flagged")
+                                .help("Only lists flagged torrents."),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("validate")
+                        .about("Checks a database for truncation, invalid rows, duplicates & alias conflicts.")
+                        .arg(clap::Arg::with_name("path").required(true).help("The database to check.")),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("diff")
+                        .about("Lists the torrents added, removed & changed between two databases.")
+                        .arg(clap::Arg::with_name("old").required(true))
+                        .arg(clap::Arg::with_name("new").required(true)),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("compact")
+                        .about("Rewrites a database without invalid rows, duplicates & long completion histories.")
+                        .arg(clap::Arg::with_name("input").required(true))
+                        .arg(clap::Arg::with_name("output").required(true))
+                        .arg(db_format_arg())
+                        .arg(
+                            clap::Arg::with_name("history")
+                                .takes_value(true)
+                                .long("history")
+                                .value_name("ENTRIES")
+                                .default_value("256")
+                                .help("Completions to keep per torrent."),
+                        )
+                        .arg(
+                            clap::Arg::with_name("drop-unused")
+                                .long("drop-unused")
+                                .help("Drops torrents that aren't flagged, never completed & have no aliases."),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("convert")
+                        .about("Rewrites a database in another format, e.g. to export it as json.")
+                        .arg(clap::Arg::with_name("input").required(true))
+                        .arg(clap::Arg::with_name("output").required(true))
+                        .arg(db_format_arg()),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("service")
+                .about("Manages udpt's Windows service.")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("install")
+                        .about("Installs udpt as a service started on boot, loading the configuration given by -c."),
+                )
+                .subcommand(clap::SubCommand::with_name("uninstall").about("Stops & removes the service."))
+                .subcommand(
+                    clap::SubCommand::with_name("run").about("Runs as the service, used by the service control manager."),
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("dump-default-config")
+                .long("dump-default-config")
+                .help("Prints a commented configuration listing every setting and exits."),
+        )
+        .arg(
+            clap::Arg::with_name("check-config")
+                .long("check-config")
+                .help("Checks the configuration and exits, without binding sockets or opening databases."),
+        )
+        .arg(
+            clap::Arg::with_name("mode")
+                .takes_value(true)
+                .long("mode")
+                .possible_values(&["static", "dynamic", "private"])
+                .help("Tracker mode, overrides `mode`."),
+        )
+        .arg(
+            clap::Arg::with_name("udp-bind")
+                .takes_value(true)
+                .long("udp-bind")
+                .value_name("ADDRESS")
+                .help("Address to bind the UDP tracker to, overrides `udp.bind_address`."),
+        )
+        .arg(
+            clap::Arg::with_name("announce-interval")
+                .takes_value(true)
+                .long("announce-interval")
+                .value_name("SECONDS")
+                .help("Announce interval sent to peers, overrides `udp.announce_interval`."),
+        )
+        .arg(
+            clap::Arg::with_name("http-bind")
+                .takes_value(true)
+                .long("http-bind")
+                .value_name("ADDRESS")
+                .help("Address to bind the REST API to, overrides `http.bind_address`."),
+        )
+        .arg(
+            clap::Arg::with_name("db-path")
+                .takes_value(true)
+                .long("db-path")
+                .value_name("PATH")
+                .help("Database path, overrides `db_path`."),
+        )
+        .arg(
+            clap::Arg::with_name("log-level")
+                .takes_value(true)
+                .long("log-level")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("Log level, overrides `log_level`."),
+        )
+        .arg(
+            clap::Arg::with_name("daemon")
+                .long("daemon")
+                .help("Detaches from the terminal and runs in the background, logging to `logging.path`. Unix only."),
+        )
+        .arg(
+            clap::Arg::with_name("pid-file")
+                .takes_value(true)
+                .long("pid-file")
+                .value_name("PATH")
+                .help("File to write udpt's process id to, it's removed when udpt exits."),
+        )
+        .arg(
+            clap::Arg::with_name("import")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .long("import")
+                .help("opentracker whitelist or state dump to import on startup."),
         );
 
     let matches = parser.get_matches();
-    let cfg_path = matches.value_of("config").unwrap();
-
-    let cfg = match Configuration::load_file(cfg_path) {
-        Ok(v) => std::sync::Arc::new(v),
-        Err(e) => {
-            eprintln!("udpt: failed to open configuration: {}", e);
+    if matches.is_present("dump-default-config") {
+        print!("{}", config::DEFAULT_CONFIG);
+        return;
+    }
+    if let Some(init) = matches.subcommand_matches("init") {
+        let path = init.value_of("path").unwrap();
+        if std::path::Path::new(path).exists() && !init.is_present("force") {
+            eprintln!("udpt: \"{}\" already exists, use --force to overwrite it.", path);
+            exit(1);
+        }
+        if let Err(err) = std::fs::write(path, config::DEFAULT_CONFIG) {
+            eprintln!("udpt: failed to write \"{}\": {}", path, err);
+            exit(1);
+        }
+        println!("wrote \"{}\", start udpt with `udpt -c {}`.", path, path);
+        return;
+    }
+    if let Some(check) = matches.subcommand_matches("check") {
+        let info_hash = match check.value_of("info-hash").map(str::parse::<tracker::InfoHash>) {
+            Some(Ok(tracker::InfoHash::V1(v))) => Some(v),
+            None => None,
+            Some(_) => {
+                eprintln!("udpt: --info-hash must be 40 hex characters.");
+                exit(1);
+            }
+        };
+        let timeout = match check.value_of("timeout").unwrap().parse() {
+            Ok(v) => std::time::Duration::from_millis(v),
+            Err(_) => {
+                eprintln!("udpt: --timeout must be a number of milliseconds.");
+                exit(1);
+            }
+        };
+        let options = probe::ProbeOptions {
+            info_hash,
+            passkey: check.value_of("passkey").map(String::from),
+            timeout,
+        };
+        let ms = |duration: std::time::Duration| format!("{:.2}", duration.as_secs_f64() * 1000.0);
+        match probe::run(check.value_of("address").unwrap(), &options) {
+            Ok(report) => {
+                println!(
+                    "ok connect_ms={} announce_ms={} scrape_ms={} interval={} seeders={} leechers={} completed={}",
+                    ms(report.connect),
+                    ms(report.announce),
+                    ms(report.scrape),
+                    report.interval,
+                    report.seeders,
+                    report.leechers,
+                    report.completed
+                );
+                return;
+            }
+            Err(err) => {
+                eprintln!("udpt: check failed, {}", err);
+                exit(1);
+            }
+        }
+    }
+    if let Some(bench) = matches.subcommand_matches("bench") {
+        let number = |name: &str| -> u32 {
+            match bench.value_of(name).unwrap().parse() {
+                Ok(v) => v,
+                Err(_) => {
+                    eprintln!("udpt: --{} must be a number.", name);
+                    exit(1);
+                }
+            }
+        };
+        let options = bench::BenchOptions {
+            torrents: number("torrents"),
+            peers: number("peers"),
+            rate: number("rate"),
+            duration: std::time::Duration::from_secs(number("duration") as u64),
+            scrape_percent: number("scrapes"),
+            ipv6_percent: number("ipv6"),
+            workers: number("workers"),
+            passkey: bench.value_of("passkey").map(String::from),
+            timeout: std::time::Duration::from_millis(number("timeout") as u64),
+        };
+        if bench.is_present("storage") {
+            let reports = match bench::run_stores(&options) {
+                Ok(v) => v,
+                Err(err) => {
+                    eprintln!("udpt: bench failed, {}", err);
+                    exit(1);
+                }
+            };
+            for (name, report) in reports {
+                let secs = report.elapsed.as_secs_f64();
+                let rate = report.announces as f64 / secs;
+                println!("{}: {} announces in {:.1}s ({:.0}/s)", name, report.announces, secs, rate);
+            }
             return;
         }
+        let report = match bench::run(bench.value_of("address").unwrap(), &options) {
+            Ok(v) => v,
+            Err(err) => {
+                eprintln!("udpt: bench failed, {}", err);
+                exit(1);
+            }
+        };
+        let secs = report.elapsed.as_secs_f64();
+        let ms = |percent: f64| format!("{:.2}ms", report.percentile(percent).as_secs_f64() * 1000.0);
+        println!(
+            "sent {} requests in {:.1}s ({:.0}/s): {} announces, {} scrapes",
+            report.sent(),
+            secs,
+            report.sent() as f64 / secs,
+            report.announces,
+            report.scrapes
+        );
+        println!(
+            "received {} responses ({:.0}/s), {} errors, {} lost ({:.2}%)",
+            report.responses,
+            report.responses as f64 / secs,
+            report.errors,
+            report.lost(),
+            report.lost() as f64 * 100.0 / report.sent().max(1) as f64
+        );
+        println!(
+            "latency: p50 {}, p90 {}, p99 {}, p99.9 {}, max {}",
+            ms(50.0),
+            ms(90.0),
+            ms(99.0),
+            ms(99.9),
+            ms(100.0)
+        );
+        if let Some(error) = report.first_error {
+            println!("first error: {}", error);
+        }
+        return;
+    }
+    if let Some(db) = matches.subcommand_matches("db") {
+        exit(dbtool::run(db));
+    }
+    let cfg_path = matches.value_of("config").map(String::from);
+    let service = matches.subcommand_matches("service").and_then(|v| v.subcommand_name());
+    let res = match service {
+        Some("install") => Some(winservice::install(cfg_path.as_deref())),
+        Some("uninstall") => Some(winservice::uninstall()),
+        _ => None,
+    };
+    match res {
+        Some(Ok(_)) => {
+            println!(
+                "{} the service.",
+                if service == Some("install") { "installed" } else { "removed" }
+            );
+            return;
+        }
+        Some(Err(err)) => {
+            eprintln!("udpt: {}", err);
+            exit(1);
+        }
+        None => {}
+    }
+    let cfg_format = match (matches.value_of("config-format"), cfg_path.as_deref()) {
+        (Some(format), _) => format.parse().unwrap(),
+        (None, Some(path)) => ConfigFormat::from_path(path),
+        (None, None) => ConfigFormat::Toml,
     };
+    let import_paths: Vec<String> = matches.values_of("import").into_iter().flatten().map(String::from).collect();
+    let overrides = ConfigOverrides {
+        mode: matches.value_of("mode").map(|mode| {
+            match mode {
+                "static" => tracker::TrackerMode::StaticMode,
+                "private" => tracker::TrackerMode::PrivateMode,
+                _ => tracker::TrackerMode::DynamicMode,
+            }
+        }),
+        udp_bind: matches.value_of("udp-bind").map(String::from),
+        announce_interval: match matches.value_of("announce-interval").map(str::parse) {
+            Some(Ok(v)) => Some(v),
+            Some(Err(_)) => {
+                eprintln!("udpt: --announce-interval must be a number of seconds.");
+                return;
+            }
+            None => None,
+        },
+        http_bind: matches.value_of("http-bind").map(String::from),
+        db_path: matches.value_of("db-path").map(String::from),
+        log_level: matches.value_of("log-level").map(String::from),
+    };
+
+    if matches.is_present("check-config") {
+        exit(check_config(cfg_path.as_deref(), cfg_format, &overrides));
+    }
+
+    let mut cfg = match cfg_path {
+        Some(ref path) => {
+            match Configuration::load_file(path, cfg_format) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("udpt: failed to open configuration: {}", e);
+                    return;
+                }
+            }
+        }
+        None => Configuration::default(),
+    };
+    cfg.apply_overrides(&overrides);
+
+    if matches.is_present("daemon") {
+        #[cfg(unix)]
+        {
+            if let Err(err) = daemon::daemonize(cfg.get_logging_config().get_path()) {
+                eprintln!("udpt: failed to daemonize: {}", err);
+                exit(1);
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            eprintln!("udpt: --daemon is only supported on unix.");
+            exit(1);
+        }
+    }
+    // removed once the tracker stopped.
+    // with handover_path, the running udpt is about to hand over and exit.
+    let replace_running = cfg.get_handover_path().is_some();
+    let _pid_file = match matches
+        .value_of("pid-file")
+        .map(|path| daemon::PidFile::create(path, replace_running))
+    {
+        Some(Ok(v)) => Some(v),
+        Some(Err(err)) => {
+            eprintln!("udpt: failed to write the pid file: {}", err);
+            exit(1);
+        }
+        None => None,
+    };
+
+    if cfg.get_sandbox_config().is_landlock() {
+        let pid_path = matches.value_of("pid-file");
+        let (read_paths, write_dirs) = landlock_paths(&cfg, cfg_path.as_deref(), &import_paths, pid_path);
+        let read_paths: Vec<&str> = read_paths.iter().map(String::as_str).collect();
+        let write_dirs: Vec<&str> = write_dirs.iter().map(String::as_str).collect();
+        if let Err(err) = sandbox::restrict_paths(&read_paths, &write_dirs) {
+            eprintln!("udpt: {}", err);
+            exit(1);
+        }
+    }
+
+    // started after daemonizing & sandboxing, forking only keeps the calling thread and Landlock only covers the
+    // threads started after it.
+    let mut runtime = match tokio::runtime::Runtime::new() {
+        Ok(v) => v,
+        Err(err) => {
+            eprintln!("udpt: failed to start the runtime: {}", err);
+            exit(1);
+        }
+    };
+    let tracker = move |stop| {
+        let cfg = std::sync::Arc::new(cfg);
+        runtime.block_on(run(cfg, cfg_path, cfg_format, overrides, import_paths, started, stop))
+    };
+    if service == Some("run") {
+        if let Err(err) = winservice::run(move |stop| tracker(Some(stop))) {
+            eprintln!("udpt: {}", err);
+            exit(1);
+        }
+    } else {
+        tracker(None);
+    }
+}
+
+/// Paths udpt reads, and directories it writes to, once it's confined by Landlock.
+fn landlock_paths(
+    cfg: &Configuration, cfg_path: Option<&str>, import_paths: &[String], pid_path: Option<&str>,
+) -> (Vec<String>, Vec<String>) {
+    let dir_of = |path: &str| {
+        match std::path::Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy().into_owned(),
+            _ => String::from("."),
+        }
+    };
+
+    // the resolver, time zones & shared libraries, and the process' statistics.
+    let mut read_paths: Vec<String> = ["/etc", "/proc", "/usr", "/lib", "/lib64", "/dev/urandom"]
+        .iter()
+        .map(|v| String::from(*v))
+        .collect();
+    // included files & reloads.
+    read_paths.extend(cfg_path.map(dir_of));
+    let tracker_cfg = cfg.get_tracker_config();
+    let lists = tracker_cfg
+        .get_whitelist_path()
+        .into_iter()
+        .chain(tracker_cfg.get_blacklist_path());
+    let lists = lists.chain(tracker_cfg.get_geoip_path());
+    read_paths.extend(lists.map(String::from));
+    read_paths.extend(
+        cfg.get_listeners()
+            .iter()
+            .filter_map(|v| v.get_blacklist_path())
+            .map(String::from),
+    );
+    read_paths.extend(import_paths.iter().cloned());
+    read_paths.extend(cfg.get_sandbox_config().get_read_paths().iter().cloned());
+
+    let logging = cfg.get_logging_config();
+    let outputs = [
+        cfg.get_db_path().as_deref(),
+        cfg.get_full_scrape_path().as_deref(),
+        logging.get_path(),
+        logging.get_abuse_path(),
+        logging.get_dump_path(),
+        cfg.get_handover_path(),
+        cfg.get_raft_config().map(|v| v.get_log_path()),
+        pid_path,
+    ];
+    let write_dirs = outputs.iter().filter_map(|path| path.map(dir_of)).collect();
+    (read_paths, write_dirs)
+}
+
+async fn run(
+    cfg: std::sync::Arc<Configuration>, cfg_path: Option<String>, cfg_format: ConfigFormat, overrides: ConfigOverrides,
+    import_paths: Vec<String>, started: std::time::Instant, service_stop: Option<tokio::sync::oneshot::Receiver<()>>,
+) {
+    // only used to reload the configuration, on unix.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    let log_levels = setup_logging(&cfg);
+
+    // checked before anything is loaded, a tracker without the resources it needs fails later & less predictably.
+    let limits = limits::apply(cfg.get_limits_config());
+    for msg in limits.applied.iter() {
+        info!("{}.", msg);
+    }
+    for msg in limits.unmet.iter() {
+        if limits.is_fatal(cfg.get_limits_config()) {
+            error!("{}.", msg);
+        } else {
+            warn!("{}, running with less.", msg);
+        }
+    }
+    if limits.is_fatal(cfg.get_limits_config()) {
+        panic!("resource limits can't be met. check logs.");
+    }
 
-    setup_logging(&cfg);
+    let mut listen_fds = systemd::ListenFds::take();
+    if !listen_fds.is_empty() {
+        info!("using {} sockets passed by systemd.", listen_fds.len());
+    } else if let Some(path) = cfg.get_handover_path() {
+        // blocks until the running udpt saved its database, nothing else runs yet.
+        match handover::request(path) {
+            Ok(Some(fds)) => {
+                info!("took over {} sockets from the running udpt.", fds.len());
+                listen_fds = fds;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!("failed to take over the sockets of the running udpt. error: {}", err);
+                panic!("failed to take over sockets. check logs.");
+            }
+        }
+    }
 
-    let tracker_obj = match cfg.get_db_path() {
+    let mut tracker_obj = match cfg.get_db_path() {
+        Some(path) if *cfg.get_db_backend() == DatabaseBackend::Sqlite => {
+            let mut tracker = tracker::TorrentTracker::new(cfg.clone());
+            match tracker.open_sqlite(path).await {
+                Ok(count) => info!("loaded {} torrents from sqlite.", count),
+                Err(err) => {
+                    error!("failed to open sqlite database \"{}\". error: {}", path, err);
+                    panic!("failed to open sqlite database. check logs.");
+                }
+            }
+            tracker
+        }
         Some(path) => {
             let file_path = std::path::Path::new(path);
             if !file_path.exists() {
                 warn!("database file \"{}\" doesn't exist.", path);
-                tracker::TorrentTracker::new(cfg.get_mode().clone())
+                tracker::TorrentTracker::new(cfg.clone())
             } else {
                 let mut input_file = match tokio::fs::File::open(file_path).await {
                     Ok(v) => v,
@@ -91,7 +762,7 @@ async fn main() {
                         panic!("error opening file. check logs.");
                     }
                 };
-                match tracker::TorrentTracker::load_database(cfg.get_mode().clone(), &mut input_file).await {
+                match tracker::TorrentTracker::load_database(cfg.clone(), &mut input_file).await {
                     Ok(v) => {
                         info!("database loaded.");
                         v
@@ -103,50 +774,561 @@ async fn main() {
                 }
             }
         }
-        None => tracker::TorrentTracker::new(cfg.get_mode().clone()),
+        None => tracker::TorrentTracker::new(cfg.clone()),
     };
 
+    // lowered from `limits.max_peers` when running with less memory.
+    tracker_obj.set_peer_limit(limits.max_peers);
+
+    if let Some(path) = cfg.get_tracker_config().get_geoip_path() {
+        if let Err(err) = tracker_obj.open_geoip(path) {
+            error!("failed to open geoip database \"{}\". error: {}", path, err);
+            panic!("failed to open geoip database. check logs.");
+        }
+        info!("tagging peers with their country from \"{}\".", path);
+    }
+
+    if let Some(path) = cfg.get_tracker_config().get_whitelist_path() {
+        match tracker_obj.load_whitelist(path).await {
+            Ok(count) => info!("registered {} torrents from \"{}\".", count, path),
+            Err(err) => {
+                error!("failed to load whitelist \"{}\". error: {}", path, err);
+                panic!("failed to load whitelist. check logs.");
+            }
+        }
+    }
+
+    if let Some(postgres_cfg) = cfg.get_postgres_config() {
+        if let Err(err) = tracker_obj.open_postgres(postgres_cfg.get_url()).await {
+            error!("failed to connect to postgres. error: {}", err);
+            panic!("failed to connect to postgres. check logs.");
+        }
+        if let Err(err) = tracker_obj.sync_postgres().await {
+            error!("failed to load torrents from postgres. error: {}", err);
+            panic!("failed to load torrents from postgres. check logs.");
+        }
+        info!("connected to postgres.");
+    }
+
+    if let Some(replica_cfg) = cfg.get_replica_config() {
+        tracker_obj.open_replica(replica_cfg);
+        if let Err(err) = tracker_obj.sync_replica().await {
+            error!("failed to sync from primary. error: {}", err);
+            panic!("failed to sync from primary. check logs.");
+        }
+        info!("synced from primary.");
+    }
+
+    for path in import_paths.iter() {
+        match tokio::fs::read_to_string(path).await {
+            Ok(data) => {
+                let count = tracker_obj.import_torrents(tracker::parse_state_dump(&data)).await;
+                info!("imported {} torrents from \"{}\".", count, path);
+            }
+            Err(err) => {
+                error!("failed to import \"{}\". error: {}", path, err);
+                panic!("failed to import torrents. check logs.");
+            }
+        }
+    }
+
+    match tracker_obj.load_blacklist().await {
+        Ok(0) => {}
+        Ok(count) => info!("flagged {} blacklisted torrents.", count),
+        Err(err) => {
+            error!("failed to load blacklist. error: {}", err);
+            panic!("failed to load blacklist. check logs.");
+        }
+    }
+
+    if let (Some(path), DatabaseBackend::File) = (cfg.get_db_path(), cfg.get_db_backend()) {
+        match tracker_obj.open_journal(path).await {
+            Ok(0) => {}
+            Ok(count) => info!("replayed {} journal entries.", count),
+            Err(err) => {
+                error!("failed to open journal. error: {}", err);
+                panic!("failed to open journal. check logs.");
+            }
+        }
+    }
+
+    if let Some(redis_cfg) = cfg.get_redis_config() {
+        if let Err(err) = tracker_obj.open_redis(redis_cfg).await {
+            error!("failed to connect to redis. error: {}", err);
+            panic!("failed to connect to redis. check logs.");
+        }
+        // pick up the swarms other instances are tracking.
+        tracker_obj.sync_redis().await;
+        info!("connected to redis.");
+    }
+
+    if let Some(cluster_cfg) = cfg.get_cluster_config() {
+        match tracker_obj.open_cluster(cluster_cfg).await {
+            Ok(addr) => info!("gossiping on {} with {} cluster nodes.", addr, cluster_cfg.get_nodes().len()),
+            Err(err) => {
+                error!("failed to start cluster gossip. error: {}", err);
+                panic!("failed to start cluster gossip. check logs.");
+            }
+        }
+    }
+
+    if let Some(raft_cfg) = cfg.get_raft_config() {
+        match tracker_obj.open_raft(raft_cfg).await {
+            Ok(addr) => {
+                info!(
+                    "raft node {} on {} with {} other nodes.",
+                    raft_cfg.get_id(),
+                    addr,
+                    raft_cfg.get_nodes().len()
+                )
+            }
+            Err(err) => {
+                error!("failed to start raft. error: {}", err);
+                panic!("failed to start raft. check logs.");
+            }
+        }
+    }
+
+    if let Some(upstream_cfg) = cfg.get_upstream_config() {
+        if let Err(err) = tracker_obj.open_upstream(upstream_cfg).await {
+            error!("failed to open upstream tracker. error: {}", err);
+            panic!("failed to open upstream tracker. check logs.");
+        }
+        info!("forwarding announces to \"{}\".", upstream_cfg.get_address());
+    }
+
+    if let Some(webhook_cfg) = cfg.get_webhook_config() {
+        tracker_obj.enable_webhooks(webhook_cfg);
+    }
+
+    if let Some(analytics_cfg) = cfg.get_analytics_config() {
+        tracker_obj.enable_analytics(analytics_cfg);
+    }
+
+    let logging_cfg = cfg.get_logging_config();
+    if let Some(path) = logging_cfg.get_abuse_path() {
+        if let Err(err) = tracker_obj.open_abuse_log(path, logging_cfg.get_malformed_threshold()) {
+            error!("failed to open abuse log. error: {}", err);
+            panic!("failed to open abuse log. check logs.");
+        }
+    }
+
+    if let Some(telemetry_cfg) = cfg.get_telemetry_config() {
+        tracker_obj.enable_telemetry(telemetry_cfg);
+        info!("exporting traces to \"{}\".", telemetry_cfg.get_endpoint());
+    }
+
     let tracker = std::sync::Arc::new(tracker_obj);
 
-    if cfg.get_http_config().is_some() {
+    // bound here rather than by the server, so it's bound before privileges are dropped.
+    let http_listener = cfg.get_http_config().map(|http_cfg| {
+        let address = http_cfg.get_address();
+        listen_fds.take_tcp(address).unwrap_or_else(|| {
+            match std::net::TcpListener::bind(address) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to bind http server to {}. error: {}", address, err);
+                    panic!("failed to bind http server. check logs.");
+                }
+            }
+        })
+    });
+
+    // with handover_path, copies of the sockets are kept to pass them to the next udpt.
+    let mut handover_udp = cfg.get_handover_path().map(|_| Vec::new());
+    let handover_http = match http_listener {
+        Some(ref listener) if handover_udp.is_some() => Some(listener.try_clone().expect("failed to copy http socket")),
+        _ => None,
+    };
+
+    let address = cfg.get_udp_config().get_address();
+    let udp_server = match take_udp_socket(&mut listen_fds, address, handover_udp.as_mut()) {
+        Ok(socket) => server::UDPTracker::new(cfg.clone(), tracker.clone(), socket).await,
+        Err(err) => Err(err),
+    };
+    let udp_server = udp_server.expect("failed to bind udp socket");
+
+    let mut udp_servers = vec![udp_server];
+    for listener in cfg.get_listeners() {
+        let server = match take_udp_socket(&mut listen_fds, listener.get_address(), handover_udp.as_mut()) {
+            Ok(socket) => server::UDPTracker::with_listener(cfg.clone(), listener, tracker.clone(), socket).await,
+            Err(err) => Err(err),
+        };
+        match server {
+            Ok(v) => udp_servers.push(v),
+            Err(err) => {
+                error!("failed to start listener on {}. error: {}", listener.get_address(), err);
+                panic!("failed to start listener. check logs.");
+            }
+        }
+    }
+
+    for addr in listen_fds.unused() {
+        warn!("passed socket {} doesn't match any bind_address, closing it.", addr);
+    }
+    drop(listen_fds);
+
+    if cfg.get_user().is_some() || cfg.get_group().is_some() {
+        if let Err(err) = privileges::drop_privileges(cfg.get_user(), cfg.get_group()) {
+            error!("failed to drop privileges. error: {}", err);
+            panic!("failed to drop privileges. check logs.");
+        }
+        let (user, group) = (cfg.get_user().unwrap_or("unchanged"), cfg.get_group().unwrap_or("default"));
+        info!("dropped privileges. user: {}, group: {}", user, group);
+    }
+
+    if cfg.get_sandbox_config().is_seccomp() {
+        if let Err(err) = sandbox::deny_syscalls() {
+            error!("failed to sandbox udpt. error: {}", err);
+            panic!("failed to sandbox udpt. check logs.");
+        }
+        info!("seccomp filter installed.");
+    }
+
+    if let Some(http_listener) = http_listener {
         let https_tracker = tracker.clone();
         let http_cfg = cfg.clone();
 
         info!("Starting http server");
         tokio::spawn(async move {
             let http_cfg = http_cfg.get_http_config().unwrap();
-            let bind_addr = http_cfg.get_address();
-            let tokens = http_cfg.get_access_tokens();
+            let bind_addr = http_cfg.get_address().parse::<std::net::SocketAddr>().unwrap();
 
-            let server = webserver::build_server(https_tracker, tokens.clone());
-            server.bind(bind_addr.parse::<std::net::SocketAddr>().unwrap()).await;
+            if let Err(err) = webserver::serve(https_tracker, bind_addr, Some(http_listener)).await {
+                error!("http server failed. error: {}", err);
+            }
         });
     }
 
-    let udp_server = server::UDPTracker::new(cfg.clone(), tracker.clone())
-        .await
-        .expect("failed to bind udp socket");
-
+    let udp_addresses: Vec<std::net::SocketAddr> = udp_servers.iter().map(|v| v.local_addr()).collect();
     trace!("Waiting for UDP packets");
-    let udp_server = tokio::spawn(async move {
-        if let Err(err) = udp_server.accept_packets().await {
-            eprintln!("error: {}", err);
-        }
-    });
+    let (shutdown, _) = tokio::sync::broadcast::channel(1);
+    let mut udp_servers = futures::future::select_all(udp_servers.into_iter().map(|udp_server| {
+        let shutdown = shutdown.subscribe();
+        tokio::spawn(async move {
+            if let Err(err) = udp_server.accept_packets(shutdown).await {
+                eprintln!("error: {}", err);
+            }
+        })
+    }));
 
     let weak_tracker = std::sync::Arc::downgrade(&tracker);
-    if let Some(db_path) = cfg.get_db_path() {
-        let db_path = db_path.clone();
+    {
         let interval = cfg.get_cleanup_interval().unwrap_or(600);
+        let memory_pressure = tracker.get_memory_pressure_notify();
+        let weak_tracker = weak_tracker.clone();
 
+        tracker
+            .get_tasks()
+            .register("cleanup", std::time::Duration::from_secs(interval));
         tokio::spawn(async move {
             let interval = std::time::Duration::from_secs(interval);
             let mut interval = tokio::time::interval(interval);
             interval.tick().await; // first tick is immediate...
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    _ = memory_pressure.notified() => {},
+                }
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("cleanup");
+                    tracker.cleanup().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(db_path) = cfg.get_db_path().clone() {
+        let interval = cfg.get_save_interval().or(cfg.get_cleanup_interval()).unwrap_or(600);
+
+        tracker.get_tasks().register("save", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let interval = std::time::Duration::from_secs(interval);
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("save");
+                    tracker.save_database_file(&db_path).await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    {
+        let interval = cfg.get_stats_interval().unwrap_or(60);
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("stats", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            loop {
+                // the first tick is immediate, so stats are available right after startup.
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("stats");
+                    tracker.summarize().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(interval) = cfg.get_logging_config().get_summary_interval() {
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            let mut last_tick = std::time::Instant::now();
+            let mut last_totals = (0, 0, 0);
+            loop {
+                interval.tick().await;
+                let tracker = match weak_tracker.upgrade() {
+                    Some(tracker) => tracker,
+                    None => break,
+                };
+
+                let now = std::time::Instant::now();
+                let secs = now.duration_since(last_tick).as_secs_f64();
+                let (announces, scrapes, dropped) = tracker.get_requests().totals();
+                let (last_announces, last_scrapes, last_dropped) = last_totals;
+                last_tick = now;
+                last_totals = (announces, scrapes, dropped);
+
+                log_fields!(
+                    log::Level::Info,
+                    "stats",
+                    announces_per_sec = format!("{:.1}", (announces - last_announces) as f64 / secs),
+                    scrapes_per_sec = format!("{:.1}", (scrapes - last_scrapes) as f64 / secs),
+                    torrents = tracker.get_torrent_count(),
+                    peers = tracker.get_peer_count(),
+                    dropped = dropped - last_dropped,
+                    memory_mb = tracker.get_memory_usage() / 1024 / 1024,
+                );
+            }
+        });
+    }
+
+    if let Some(full_scrape_path) = cfg.get_full_scrape_path().clone() {
+        let interval = cfg.get_full_scrape_interval().unwrap_or(600);
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("full_scrape", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            loop {
+                // the first tick is immediate, so the file is written on startup as well.
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("full_scrape");
+                    tracker.save_full_scrape(&full_scrape_path).await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(webhook_cfg) = cfg.get_webhook_config() {
+        let interval = webhook_cfg.get_batch_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("webhooks", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("webhooks");
+                    tracker.flush_webhooks().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(analytics_cfg) = cfg.get_analytics_config() {
+        let interval = analytics_cfg.get_flush_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("analytics", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("analytics");
+                    tracker.flush_analytics().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(telemetry_cfg) = cfg.get_telemetry_config() {
+        let interval = telemetry_cfg.get_flush_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("telemetry", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("telemetry");
+                    tracker.flush_telemetry().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(statsd_cfg) = cfg.get_statsd_config() {
+        let interval = statsd_cfg.get_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+        let mut statsd = match statsd::StatsD::connect(statsd_cfg).await {
+            Ok(statsd) => statsd,
+            Err(err) => {
+                error!("failed to connect to statsd. error: {}", err);
+                panic!("failed to connect to statsd. check logs.");
+            }
+        };
+        info!("pushing metrics to statsd at \"{}\".", statsd_cfg.get_address());
+
+        tracker
+            .get_tasks()
+            .register("statsd", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("statsd");
+                    statsd.push(&tracker).await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(redis_cfg) = cfg.get_redis_config() {
+        let interval = redis_cfg.get_sync_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("redis", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("redis");
+                    tracker.sync_redis().await;
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(cluster_cfg) = cfg.get_cluster_config() {
+        let interval = cluster_cfg.get_gossip_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("gossip", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("gossip");
+                    tracker.send_gossip().await;
+                } else {
+                    break;
+                }
+            }
+        });
+
+        let gossip_tracker = tracker.clone();
+        tokio::spawn(async move {
+            if let Err(err) = gossip_tracker.receive_gossip().await {
+                error!("cluster gossip stopped. error: {}", err);
+            }
+        });
+    }
+
+    if let Some(raft_cfg) = cfg.get_raft_config() {
+        // ticks twice per heartbeat, so heartbeats & elections aren't late by a whole interval.
+        let interval = std::cmp::max(raft_cfg.get_heartbeat_interval_ms() / 2, 1);
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(interval));
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    tracker.tick_raft().await;
+                } else {
+                    break;
+                }
+            }
+        });
+
+        let raft_tracker = tracker.clone();
+        tokio::spawn(async move {
+            if let Err(err) = raft_tracker.receive_raft().await {
+                error!("raft stopped. error: {}", err);
+            }
+        });
+    }
+
+    if let Some(replica_cfg) = cfg.get_replica_config() {
+        let interval = replica_cfg.get_sync_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("replica", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
             loop {
                 interval.tick().await;
                 if let Some(tracker) = weak_tracker.upgrade() {
-                    tracker.periodic_task(&db_path).await;
+                    let _run = tracker.get_tasks().start("replica");
+                    if let Err(err) = tracker.sync_replica().await {
+                        error!("failed to sync from primary: {}", err);
+                    }
                 } else {
                     break;
                 }
@@ -154,17 +1336,339 @@ async fn main() {
         });
     }
 
-    let ctrl_c = tokio::signal::ctrl_c();
+    if let Some(upstream_cfg) = cfg.get_upstream_config() {
+        let interval = upstream_cfg.get_announce_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("upstream", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("upstream");
+                    if let Err(err) = tracker.sync_upstream().await {
+                        error!("failed to announce upstream: {}", err);
+                    }
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(postgres_cfg) = cfg.get_postgres_config() {
+        let interval = postgres_cfg.get_refresh_interval();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker
+            .get_tasks()
+            .register("postgres", std::time::Duration::from_secs(interval));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval));
+            interval.tick().await; // first tick is immediate...
+            loop {
+                interval.tick().await;
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    let _run = tracker.get_tasks().start("postgres");
+                    if let Err(err) = tracker.sync_postgres().await {
+                        error!("failed to refresh from postgres: {}", err);
+                    }
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(cfg_path) = cfg_path {
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tokio::spawn(async move {
+            let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to listen for SIGHUP, configuration can't be reloaded. error: {}", err);
+                    return;
+                }
+            };
+            while hangup.recv().await.is_some() {
+                if let Some(tracker) = weak_tracker.upgrade() {
+                    info!("SIGHUP, reloading configuration from \"{}\"...", cfg_path);
+                    systemd::notify("RELOADING=1");
+                    reload_config(&tracker, &log_levels, &cfg_path, cfg_format, &overrides).await;
+                    systemd::notify("READY=1");
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    {
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+        let dump_path = cfg.get_logging_config().get_dump_path().map(String::from);
+
+        tokio::spawn(async move {
+            let mut usr1 = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to listen for SIGUSR1, diagnostics can't be dumped. error: {}", err);
+                    return;
+                }
+            };
+            while usr1.recv().await.is_some() {
+                let tracker = match weak_tracker.upgrade() {
+                    Some(tracker) => tracker,
+                    None => break,
+                };
+                let dump = tracker.diagnostics(started.elapsed());
+                match dump_path {
+                    Some(ref path) => {
+                        match tokio::fs::write(path, dump + "\n").await {
+                            Ok(_) => info!("SIGUSR1, diagnostics written to \"{}\".", path),
+                            Err(err) => error!("failed to write diagnostics to \"{}\". error: {}", path, err),
+                        }
+                    }
+                    None => info!("SIGUSR1, diagnostics:\n{}", dump),
+                }
+            }
+        });
+    }
+
+    let port_mapping = std::sync::Arc::new(tokio::sync::Mutex::new(None::<portmap::Mapping>));
+    if let Some(port_mapping_cfg) = cfg.get_port_mapping_config() {
+        // only the first listener is mapped, it's the one announced to peers outside the router.
+        let internal_port = udp_addresses[0].port();
+        let port_mapping_cfg = port_mapping_cfg.clone();
+        let interval = port_mapping_cfg.get_lifetime() / 2;
+        let port_mapping = port_mapping.clone();
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
+
+        tracker.get_tasks().register("port_mapping", interval);
+        tokio::spawn(async move {
+            // the first tick is immediate, mapping the port at startup.
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let tracker = match weak_tracker.upgrade() {
+                    Some(tracker) => tracker,
+                    None => break,
+                };
+                let _run = tracker.get_tasks().start("port_mapping");
+                let mut port_mapping = port_mapping.lock().await;
+                let res = match port_mapping.as_mut() {
+                    Some(mapping) => mapping.renew().await.map(|changed| (changed, None)),
+                    None => portmap::map(&port_mapping_cfg, internal_port).await.map(|v| (true, Some(v))),
+                };
+                match res {
+                    Ok((changed, mapping)) => {
+                        if let Some(mapping) = mapping {
+                            *port_mapping = Some(mapping);
+                        }
+                        if let (true, Some(mapping)) = (changed, port_mapping.as_ref()) {
+                            let method = mapping.method_name();
+                            info!("mapped udp port {} to {} with {}.", internal_port, mapping.external, method);
+                        }
+                    }
+                    Err(err) => {
+                        // mapped again from scratch on the next tick.
+                        error!("failed to map udp port {}. error: {}", internal_port, err);
+                        *port_mapping = None;
+                    }
+                }
+            }
+        });
+    }
+
+    {
+        // a packet looped through every listener, so a wedged socket or interface makes udpt unready.
+        const PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+        const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+        let weak_tracker = std::sync::Arc::downgrade(&tracker);
 
-    tokio::select! {
-        _ = udp_server => { warn!("udp server exited.") },
-        _ = ctrl_c => { info!("CTRL-C, exiting...") },
+        tracker.get_tasks().register("readiness", PROBE_INTERVAL);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROBE_INTERVAL);
+            let mut is_failing = false;
+            loop {
+                interval.tick().await;
+                let tracker = match weak_tracker.upgrade() {
+                    Some(tracker) => tracker,
+                    None => break,
+                };
+                let _run = tracker.get_tasks().start("readiness");
+                let probes = udp_addresses.iter().map(|address| server::probe(*address, PROBE_TIMEOUT));
+                let probes: Vec<tracker::ListenerProbe> = futures::future::join_all(probes)
+                    .await
+                    .into_iter()
+                    .zip(udp_addresses.iter())
+                    .map(|(res, address)| {
+                        tracker::ListenerProbe {
+                            address: *address,
+                            rtt_ms: res.as_ref().ok().map(|rtt| rtt.as_secs_f64() * 1000.0),
+                            error: res.err(),
+                        }
+                    })
+                    .collect();
+
+                let was_ready = tracker.get_readiness().is_ready();
+                let failed: Vec<String> = probes
+                    .iter()
+                    .filter_map(|v| v.error.as_ref().map(|err| format!("{} {}", v.address, err)))
+                    .collect();
+                tracker.get_readiness().update(probes);
+                if failed.is_empty() {
+                    if !was_ready {
+                        info!("udp listeners answer, ready.");
+                        systemd::notify("READY=1\nSTATUS=ready");
+                    }
+                    is_failing = false;
+                } else if !is_failing {
+                    // logged once, until the listeners answer again.
+                    warn!("udp listeners don't answer, not ready: {}.", failed.join(", "));
+                    systemd::notify(&format!(
+                        "STATUS=not ready, udp listeners don't answer: {}",
+                        failed.join(", ")
+                    ));
+                    is_failing = true;
+                }
+            }
+        });
+    }
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        // a stuck runtime stops pinging, and systemd restarts udpt.
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                systemd::notify("WATCHDOG=1");
+            }
+        });
+    }
+
+    let mut handover_listener = cfg.get_handover_path().and_then(|path| {
+        match handover::Listener::bind(path) {
+            Ok(v) => Some(v),
+            Err(err) => {
+                error!("failed to listen for handovers on \"{}\". error: {}", path, err);
+                None
+            }
+        }
+    });
+
+    let (exited, handover) = tokio::select! {
+        _ = &mut udp_servers => {
+            warn!("udp server exited.");
+            (true, None)
+        },
+        signal = shutdown_signal(service_stop) => {
+            info!("{}, shutting down...", signal);
+            (false, None)
+        },
+        handover = accept_handover(&mut handover_listener) => {
+            info!("a new udpt asked for the sockets, shutting down to hand them over...");
+            (false, Some(handover))
+        },
+    };
+    systemd::notify("STOPPING=1");
+
+    // stop accepting packets, and let the listeners finish the ones they're handling.
+    let _ = shutdown.send(());
+    if !exited {
+        futures::future::join_all(udp_servers.into_inner()).await;
+    }
+
+    // the new udpt keeps the port mapped.
+    if let (None, Some(mapping)) = (handover.as_ref(), port_mapping.lock().await.take()) {
+        if let Err(err) = mapping.unmap().await {
+            warn!("failed to remove the port mapping. error: {}", err);
+        }
     }
 
     if let Some(path) = cfg.get_db_path() {
         info!("saving database...");
-        tracker.periodic_task(path).await;
+        tracker.periodic_task(Some(path)).await;
+    }
+
+    // the database is saved, the new udpt can load it & serve.
+    if let Some(handover) = handover {
+        let udp = handover_udp.unwrap_or_default();
+        match handover.send(&udp, handover_http.as_ref()) {
+            Ok(_) => info!("handed {} sockets over.", udp.len() + handover_http.iter().count()),
+            Err(err) => error!("failed to hand the sockets over. error: {}", err),
+        }
+    }
+
+    // deliver the events of the last batch interval.
+    tracker.flush_webhooks().await;
+    tracker.flush_analytics().await;
+    tracker.flush_telemetry().await;
+
+    let (announces, scrapes, _) = tracker.get_requests().totals();
+    log_fields!(
+        log::Level::Info,
+        "goodbye.",
+        uptime_secs = started.elapsed().as_secs(),
+        announces = announces,
+        scrapes = scrapes,
+        torrents = tracker.get_torrent_count(),
+        peers = tracker.get_peer_count(),
+        saved = cfg.get_db_path().is_some(),
+    );
+}
+
+/// The UDP socket for `address`, if it was passed. With `kept`, the socket is bound here if it wasn't, and a copy is
+/// kept to hand it over later.
+fn take_udp_socket(
+    listen_fds: &mut systemd::ListenFds, address: &str, kept: Option<&mut Vec<std::net::UdpSocket>>,
+) -> Result<Option<std::net::UdpSocket>, std::io::Error> {
+    let socket = listen_fds.take_udp(address);
+    let kept = match kept {
+        Some(v) => v,
+        None => return Ok(socket),
+    };
+    let socket = match socket {
+        Some(v) => v,
+        None => std::net::UdpSocket::bind(address)?,
+    };
+    kept.push(socket.try_clone()?);
+    Ok(Some(socket))
+}
+
+async fn accept_handover(listener: &mut Option<handover::Listener>) -> handover::Handover {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => futures::future::pending().await,
     }
+}
 
-    info!("goodbye.");
+/// Resolves once udpt is asked to exit, with the name of the signal: SIGTERM, SIGINT (CTRL-C), or the stop of
+/// the Windows service.
+async fn shutdown_signal(service_stop: Option<tokio::sync::oneshot::Receiver<()>>) -> &'static str {
+    if let Some(service_stop) = service_stop {
+        let _ = service_stop.await;
+        return "service stopped";
+    }
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => return "SIGTERM",
+                    _ = tokio::signal::ctrl_c() => return "SIGINT",
+                }
+            }
+            Err(err) => error!("failed to listen for SIGTERM. error: {}", err),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
 }
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Keys that are remembered at once, floods of distinct keys can't grow it further.
+const MAX_KEYS: usize = 65536;
+
+/// Misses by when they were remembered, in two spans of half the ttl each: `previous` spans from `started - ttl / 2`
+/// to `started`, `current` from `started` on. Spans are dropped whole once they're older than the ttl.
+struct Generations<K> {
+    current: HashSet<K>,
+    previous: HashSet<K>,
+    started: Instant,
+}
+
+impl<K: Hash + Eq> Generations<K> {
+    /// Which spans are younger than the ttl: (current, previous).
+    fn live(&self, ttl: Duration, now: Instant) -> (bool, bool) {
+        let age = now.saturating_duration_since(self.started);
+        (age < ttl, age < ttl / 2)
+    }
+
+    /// Starts a new span once the current one is `ttl / 2` old.
+    fn rotate(&mut self, ttl: Duration, now: Instant) {
+        let span = ttl / 2;
+        let age = now.saturating_duration_since(self.started);
+        if age < span {
+            return;
+        }
+        if age < ttl {
+            self.previous = std::mem::take(&mut self.current);
+            self.started += span;
+        } else {
+            self.previous.clear();
+            self.current.clear();
+            self.started = now;
+        }
+    }
+}
+
+/// Recent lookups that found nothing, so repeated lookups of the same keys can be answered without the lookup.
+/// Misses are remembered for half the ttl to the full ttl. Keys are forgotten when they're inserted where they were
+/// looked up, misses that raced with an insert aren't remembered (see `generation`).
+pub struct NegativeCache<K> {
+    misses: RwLock<Generations<K>>,
+    /// Bumped on every insert.
+    generation: AtomicU64,
+}
+
+impl<K: Hash + Eq> Default for NegativeCache<K> {
+    fn default() -> NegativeCache<K> {
+        NegativeCache {
+            misses: RwLock::new(Generations {
+                current: HashSet::new(),
+                previous: HashSet::new(),
+                started: Instant::now(),
+            }),
+            generation: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K: Hash + Eq> NegativeCache<K> {
+    /// Taken before a lookup, and passed to `insert` if the lookup found nothing.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Whether a lookup of `key` found nothing within `ttl`.
+    pub fn contains(&self, key: &K, ttl: Duration, now: Instant) -> bool {
+        let misses = self.misses.read().unwrap();
+        let (current, previous) = misses.live(ttl, now);
+        (current && misses.current.contains(key)) || (previous && misses.previous.contains(key))
+    }
+
+    /// Remembers a lookup of `key` that found nothing, unless something was inserted since `generation`. While
+    /// `MAX_KEYS / 2` misses were remembered in the current span, further misses aren't.
+    pub fn insert(&self, key: K, generation: u64, ttl: Duration, now: Instant) {
+        let mut misses = self.misses.write().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+        misses.rotate(ttl, now);
+        if misses.current.len() < MAX_KEYS / 2 {
+            misses.current.insert(key);
+        }
+    }
+
+    /// Forgets `key`, after it was inserted.
+    pub fn remove(&self, key: &K) {
+        // bumped first, so lookups that raced with the insert don't remember their miss.
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let is_missed = {
+            let misses = self.misses.read().unwrap();
+            misses.current.contains(key) || misses.previous.contains(key)
+        };
+        if is_missed {
+            let mut misses = self.misses.write().unwrap();
+            misses.current.remove(key);
+            misses.previous.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_cache() {
+        let cache = NegativeCache::default();
+        let ttl = Duration::from_secs(30);
+        let now = Instant::now();
+
+        let generation = cache.generation();
+        cache.insert("unknown", generation, ttl, now);
+        assert!(cache.contains(&"unknown", ttl, now + Duration::from_secs(29)));
+        assert!(!cache.contains(&"unknown", ttl, now + ttl));
+        assert!(!cache.contains(&"other", ttl, now));
+
+        cache.remove(&"unknown");
+        assert!(!cache.contains(&"unknown", ttl, now));
+
+        // inserted while it was being looked up.
+        let generation = cache.generation();
+        cache.remove(&"added");
+        cache.insert("added", generation, ttl, now);
+        assert!(!cache.contains(&"added", ttl, now));
+    }
+
+    #[test]
+    fn generations() {
+        let cache = NegativeCache::default();
+        let ttl = Duration::from_secs(30);
+        let now = Instant::now();
+        let at = |secs| now + Duration::from_secs(secs);
+
+        cache.insert(0, cache.generation(), ttl, now);
+        // starts the second span, the first one is kept until it's 30 seconds old.
+        cache.insert(1, cache.generation(), ttl, at(20));
+        assert!(cache.contains(&0, ttl, at(20)));
+        assert!(cache.contains(&1, ttl, at(20)));
+        assert!(!cache.contains(&0, ttl, at(30)));
+        assert!(cache.contains(&1, ttl, at(30)));
+        assert!(!cache.contains(&1, ttl, at(45)));
+
+        // a full span doesn't remember more misses.
+        for key in 2..(MAX_KEYS / 2 + 10) {
+            cache.insert(key, cache.generation(), ttl, at(31));
+        }
+        assert!(cache.contains(&(MAX_KEYS / 2), ttl, at(31)));
+        assert!(!cache.contains(&(MAX_KEYS / 2 + 5), ttl, at(31)));
+        cache.insert(MAX_KEYS, cache.generation(), ttl, at(46));
+        assert!(cache.contains(&MAX_KEYS, ttl, at(46)));
+    }
+}
@@ -0,0 +1,525 @@
+//! Maps the UDP tracker's port on home routers, with NAT-PMP (RFC 6886) or UPnP IGD.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use udpt_core::config::{PortMappingConfig, PortMappingProtocol};
+
+const NATPMP_PORT: u16 = 5351;
+/// NAT-PMP's retransmission schedule, shortened from RFC 6886's 9 attempts so startup isn't held up.
+const NATPMP_RETRIES: [u64; 4] = [250, 500, 1000, 2000];
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const SSDP_TIMEOUT: Duration = Duration::from_secs(2);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+/// WAN connection services that can map ports, most preferred first.
+const UPNP_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+/// UPnP error for routers that only accept mappings without a lease duration.
+const UPNP_ONLY_PERMANENT_LEASES: &str = "725";
+
+enum Method {
+    NatPmp {
+        gateway: SocketAddr,
+    },
+    Upnp {
+        control_url: String,
+        service: &'static str,
+        internal_ip: Ipv4Addr,
+    },
+}
+
+/// A port mapped on the router, to renew before its lifetime runs out & remove on shutdown.
+pub struct Mapping {
+    method: Method,
+    internal_port: u16,
+    lifetime: Duration,
+    /// Address peers on the internet reach the tracker at.
+    pub external: SocketAddr,
+}
+
+/// Asks the router to forward `external_port` (`internal_port` if None) to `internal_port`.
+pub async fn map(config: &PortMappingConfig, internal_port: u16) -> Result<Mapping, String> {
+    let external_port = config.get_external_port().unwrap_or(internal_port);
+    let lifetime = config.get_lifetime();
+
+    let natpmp_err = match config.get_protocol() {
+        PortMappingProtocol::Upnp => None,
+        _ => {
+            let gateway = match config.get_gateway().or_else(default_gateway) {
+                Some(gateway) => gateway,
+                None => return Err(String::from("no default gateway, set `port_mapping.gateway`")),
+            };
+            match Mapping::natpmp(
+                SocketAddr::new(gateway.into(), NATPMP_PORT),
+                internal_port,
+                external_port,
+                lifetime,
+            )
+            .await
+            {
+                Ok(mapping) => return Ok(mapping),
+                Err(err) if *config.get_protocol() == PortMappingProtocol::NatPmp => return Err(err),
+                Err(err) => Some(err),
+            }
+        }
+    };
+
+    let location = discover().await;
+    let location = match (location, natpmp_err) {
+        (Ok(v), _) => v,
+        (Err(err), Some(natpmp_err)) => return Err(format!("NAT-PMP: {}, UPnP: {}", natpmp_err, err)),
+        (Err(err), None) => return Err(err),
+    };
+    Mapping::upnp(&location, internal_port, external_port, lifetime).await
+}
+
+impl Mapping {
+    async fn natpmp(
+        gateway: SocketAddr, internal_port: u16, external_port: u16, lifetime: Duration,
+    ) -> Result<Mapping, String> {
+        let response = natpmp_request(gateway, &[0, 0], 12).await?;
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        let mut mapping = Mapping {
+            method: Method::NatPmp { gateway },
+            internal_port,
+            lifetime,
+            external: SocketAddr::new(external_ip.into(), external_port),
+        };
+        mapping.external.set_port(mapping.natpmp_map(external_port, lifetime).await?);
+        Ok(mapping)
+    }
+
+    /// Returns the external port the gateway mapped, it may differ from the requested one.
+    async fn natpmp_map(&self, external_port: u16, lifetime: Duration) -> Result<u16, String> {
+        let gateway = match self.method {
+            Method::NatPmp { gateway } => gateway,
+            Method::Upnp { .. } => unreachable!(),
+        };
+        let mut request = vec![0, 1, 0, 0];
+        request.extend_from_slice(&self.internal_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&(lifetime.as_secs().min(u32::MAX as u64) as u32).to_be_bytes());
+        let response = natpmp_request(gateway, &request, 16).await?;
+        Ok(u16::from_be_bytes([response[10], response[11]]))
+    }
+
+    async fn upnp(location: &str, internal_port: u16, external_port: u16, lifetime: Duration) -> Result<Mapping, String> {
+        let description = http_get(location).await?;
+        let (service, control_url) =
+            control_url(location, &description).ok_or_else(|| format!("{} has no WAN connection service", location))?;
+
+        // the address the gateway sees udpt at.
+        let gateway = control_url
+            .parse::<hyper::Uri>()
+            .ok()
+            .and_then(|uri| uri.authority().map(|v| v.to_string()))
+            .ok_or_else(|| format!("invalid control url \"{}\"", control_url))?;
+        let internal_ip = local_ip(&gateway)?;
+
+        let response = soap(&control_url, service, "GetExternalIPAddress", &[]).await?;
+        let external_ip = xml_value(&response, "NewExternalIPAddress")
+            .and_then(|v| v.trim().parse::<Ipv4Addr>().ok())
+            .ok_or_else(|| String::from("GetExternalIPAddress returned no address"))?;
+
+        let mut mapping = Mapping {
+            method: Method::Upnp {
+                control_url,
+                service,
+                internal_ip,
+            },
+            internal_port,
+            lifetime,
+            external: SocketAddr::new(external_ip.into(), external_port),
+        };
+        mapping.upnp_map().await?;
+        Ok(mapping)
+    }
+
+    async fn upnp_map(&mut self) -> Result<(), String> {
+        let (control_url, service, internal_ip) = match &self.method {
+            Method::Upnp {
+                control_url,
+                service,
+                internal_ip,
+            } => (control_url, *service, *internal_ip),
+            Method::NatPmp { .. } => unreachable!(),
+        };
+        let mut args = vec![
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", self.external.port().to_string()),
+            ("NewProtocol", String::from("UDP")),
+            ("NewInternalPort", self.internal_port.to_string()),
+            ("NewInternalClient", internal_ip.to_string()),
+            ("NewEnabled", String::from("1")),
+            ("NewPortMappingDescription", String::from("udpt")),
+            ("NewLeaseDuration", self.lifetime.as_secs().to_string()),
+        ];
+        match soap(control_url, service, "AddPortMapping", &args).await {
+            Err(err) if err.contains(UPNP_ONLY_PERMANENT_LEASES) => {
+                args.last_mut().unwrap().1 = String::from("0");
+                soap(control_url, service, "AddPortMapping", &args).await.map(|_| ())
+            }
+            res => res.map(|_| ()),
+        }
+    }
+
+    pub fn method_name(&self) -> &'static str {
+        match self.method {
+            Method::NatPmp { .. } => "NAT-PMP",
+            Method::Upnp { .. } => "UPnP",
+        }
+    }
+
+    /// Extends the mapping's lifetime. Returns whether the external address changed.
+    pub async fn renew(&mut self) -> Result<bool, String> {
+        let previous = self.external;
+        match self.method {
+            Method::NatPmp { gateway } => {
+                let response = natpmp_request(gateway, &[0, 0], 12).await?;
+                let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+                let port = self.natpmp_map(self.external.port(), self.lifetime).await?;
+                self.external = SocketAddr::new(external_ip.into(), port);
+            }
+            Method::Upnp { .. } => self.upnp_map().await?,
+        }
+        Ok(self.external != previous)
+    }
+
+    /// Removes the mapping from the router.
+    pub async fn unmap(&self) -> Result<(), String> {
+        match &self.method {
+            // a lifetime of 0 deletes the mapping.
+            Method::NatPmp { .. } => self.natpmp_map(0, Duration::from_secs(0)).await.map(|_| ()),
+            Method::Upnp {
+                control_url, service, ..
+            } => {
+                soap(control_url, service, "DeletePortMapping", &[
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", self.external.port().to_string()),
+                    ("NewProtocol", String::from("UDP")),
+                ])
+                .await
+                .map(|_| ())
+            }
+        }
+    }
+}
+
+/// Sends a NAT-PMP request until the gateway answers it, returns the response.
+async fn natpmp_request(gateway: SocketAddr, request: &[u8], response_len: usize) -> Result<Vec<u8>, String> {
+    let mut socket = UdpSocket::bind("0.0.0.0:0").await.map_err(|e| e.to_string())?;
+    let opcode = request[1] | 0x80;
+    let mut buf = [0u8; 64];
+    for timeout in NATPMP_RETRIES.iter() {
+        socket.send_to(request, &gateway).await.map_err(|e| e.to_string())?;
+        let started = std::time::Instant::now();
+        let timeout = Duration::from_millis(*timeout);
+        loop {
+            let remaining = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+            let (len, from) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+                Ok(v) => v.map_err(|e| e.to_string())?,
+                Err(_) => break,
+            };
+            // stray packets are skipped.
+            if from != gateway || len < 4 || buf[0] != 0 || buf[1] != opcode {
+                continue;
+            }
+            match u16::from_be_bytes([buf[2], buf[3]]) {
+                0 if len >= response_len => return Ok(buf[..len].to_vec()),
+                0 => return Err(format!("gateway sent a {} byte response", len)),
+                code => return Err(format!("gateway refused the request, result code {}", code)),
+            }
+        }
+    }
+    Err(format!("{} doesn't answer NAT-PMP", gateway.ip()))
+}
+
+/// Gateway of the default route.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            // addresses are printed as u32s in the kernel's byte order.
+            [_, "00000000", gateway, ..] => {
+                u32::from_str_radix(gateway, 16)
+                    .ok()
+                    .map(|v| Ipv4Addr::from(v.to_ne_bytes()))
+                    .filter(|v| !v.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+/// Searches for an internet gateway device with SSDP, returns the url of its description.
+async fn discover() -> Result<String, String> {
+    let mut socket = UdpSocket::bind("0.0.0.0:0").await.map_err(|e| e.to_string())?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {}\r\n\r\n",
+        SSDP_ADDRESS, "urn:schemas-upnp-org:device:InternetGatewayDevice:1"
+    );
+    let target: SocketAddr = SSDP_ADDRESS.parse().unwrap();
+    socket.send_to(request.as_bytes(), &target).await.map_err(|e| e.to_string())?;
+
+    let started = std::time::Instant::now();
+    let mut buf = [0u8; 2048];
+    loop {
+        let remaining = SSDP_TIMEOUT.checked_sub(started.elapsed()).unwrap_or_default();
+        let (len, _) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(v) => v.map_err(|e| e.to_string())?,
+            Err(_) => return Err(String::from("no internet gateway device answered SSDP")),
+        };
+        if let Some(location) = ssdp_location(&String::from_utf8_lossy(&buf[..len])) {
+            return Ok(location);
+        }
+    }
+}
+
+fn ssdp_location(response: &str) -> Option<String> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_at(line.find(':')?);
+        match name.trim().eq_ignore_ascii_case("location") {
+            true => Some(value[1..].trim().to_string()),
+            false => None,
+        }
+    })
+}
+
+/// Finds the preferred WAN connection service in a device description, returns it with its absolute control url.
+fn control_url(location: &str, description: &str) -> Option<(&'static str, String)> {
+    let services: Vec<&str> = description.split("<service>").skip(1).collect();
+    let (service, control) = UPNP_SERVICES.iter().find_map(|service| {
+        services.iter().find_map(|v| {
+            match xml_value(v, "serviceType").map(str::trim) == Some(*service) {
+                true => xml_value(v, "controlURL").map(|url| (*service, url.trim())),
+                false => None,
+            }
+        })
+    })?;
+    if control.starts_with("http://") || control.starts_with("https://") {
+        return Some((service, control.to_string()));
+    }
+
+    let base = match xml_value(description, "URLBase") {
+        Some(base) => base.trim().to_string(),
+        None => {
+            let uri = location.parse::<hyper::Uri>().ok()?;
+            format!("http://{}", uri.authority()?)
+        }
+    };
+    Some((
+        service,
+        format!("{}/{}", base.trim_end_matches('/'), control.trim_start_matches('/')),
+    ))
+}
+
+/// Text between the first `<tag>` & `</tag>`.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..start + end])
+}
+
+/// Local address of the interface that routes to `host` ("ip:port").
+fn local_ip(host: &str) -> Result<Ipv4Addr, String> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
+    let host = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    socket
+        .connect(&host)
+        .map_err(|err| format!("failed to reach {}: {}", host, err))?;
+    match socket.local_addr().map_err(|e| e.to_string())?.ip() {
+        std::net::IpAddr::V4(ip) => Ok(ip),
+        std::net::IpAddr::V6(ip) => Err(format!("{} isn't reachable over IPv4, from {}", host, ip)),
+    }
+}
+
+async fn http_get(url: &str) -> Result<String, String> {
+    let uri = url.parse::<hyper::Uri>().map_err(|e| e.to_string())?;
+    let response = match tokio::time::timeout(HTTP_TIMEOUT, hyper::Client::new().get(uri)).await {
+        Ok(v) => v.map_err(|e| e.to_string())?,
+        Err(_) => return Err(format!("{} timed out", url)),
+    };
+    if !response.status().is_success() {
+        return Err(format!("{} responded with {}", url, response.status()));
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Calls a UPnP action, returns the response's body.
+async fn soap(control_url: &str, service: &str, action: &str, args: &[(&str, String)]) -> Result<String, String> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>\r\n",
+        action, service, args
+    );
+    let request = hyper::Request::post(control_url)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service, action))
+        .body(hyper::Body::from(body))
+        .map_err(|e| e.to_string())?;
+
+    let response = match tokio::time::timeout(HTTP_TIMEOUT, hyper::Client::new().request(request)).await {
+        Ok(v) => v.map_err(|e| e.to_string())?,
+        Err(_) => return Err(format!("{} timed out", action)),
+    };
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(|e| e.to_string())?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+    if !status.is_success() {
+        return Err(match (xml_value(&body, "errorCode"), xml_value(&body, "errorDescription")) {
+            (Some(code), Some(description)) => format!("{} failed with error {}: {}", action, code, description),
+            (Some(code), None) => format!("{} failed with error {}", action, code),
+            _ => format!("{} responded with {}", action, status),
+        });
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn natpmp() {
+        let mut gateway = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = gateway.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            loop {
+                let (len, from) = gateway.recv_from(&mut buf).await.unwrap();
+                let mut response = vec![0, buf[1] | 0x80, 0, 0, 0, 0, 0, 1];
+                match (len, buf[1]) {
+                    (2, 0) => response.extend_from_slice(&[203, 0, 113, 7]),
+                    (12, 1) => {
+                        // the requested external port is taken, the next one is mapped.
+                        response.extend_from_slice(&buf[4..6]);
+                        let external = u16::from_be_bytes([buf[6], buf[7]]);
+                        response.extend_from_slice(&(external + u16::from(external != 0)).to_be_bytes());
+                        response.extend_from_slice(&buf[8..12]);
+                    }
+                    _ => continue,
+                }
+                gateway.send_to(&response, &from).await.unwrap();
+            }
+        });
+
+        let mut mapping = Mapping::natpmp(address, 6969, 7000, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(mapping.method_name(), "NAT-PMP");
+        assert_eq!(mapping.external, "203.0.113.7:7001".parse().unwrap());
+        // renewing asks for the mapped port, which is taken again.
+        assert_eq!(mapping.renew().await, Ok(true));
+        assert_eq!(mapping.external, "203.0.113.7:7002".parse().unwrap());
+        assert_eq!(mapping.unmap().await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn upnp() {
+        let actions = Arc::new(Mutex::new(Vec::new()));
+        let calls = actions.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            let calls = calls.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| {
+                    let calls = calls.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let action = req.headers().get("SOAPAction").map(|v| v.to_str().unwrap().to_string());
+                        let body = String::from_utf8(hyper::body::to_bytes(req.into_body()).await?.to_vec()).unwrap();
+                        let mut response = hyper::Response::new(hyper::Body::empty());
+                        match (path.as_str(), action) {
+                            ("/desc.xml", None) => {
+                                *response.body_mut() = hyper::Body::from(
+                                    "<root><device><serviceList>\
+                                     <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+                                     <controlURL>/l3f</controlURL></service>\
+                                     <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                                     <controlURL>/ctl/IPConn</controlURL></service>\
+                                     </serviceList></device></root>",
+                                );
+                            }
+                            ("/ctl/IPConn", Some(action)) => {
+                                calls.lock().unwrap().push(body.clone());
+                                if action.ends_with("#GetExternalIPAddress\"") {
+                                    *response.body_mut() = hyper::Body::from(
+                                        "<u:GetExternalIPAddressResponse>\
+                                         <NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>\
+                                         </u:GetExternalIPAddressResponse>",
+                                    );
+                                } else if body.contains("<NewLeaseDuration>60</NewLeaseDuration>") {
+                                    *response.status_mut() = hyper::StatusCode::INTERNAL_SERVER_ERROR;
+                                    *response.body_mut() = hyper::Body::from(
+                                        "<errorCode>725</errorCode>\
+                                         <errorDescription>OnlyPermanentLeasesSupported</errorDescription>",
+                                    );
+                                }
+                            }
+                            _ => *response.status_mut() = hyper::StatusCode::NOT_FOUND,
+                        }
+                        Ok::<_, hyper::Error>(response)
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let location = format!("http://{}/desc.xml", server.local_addr());
+        tokio::spawn(server);
+
+        let mut mapping = Mapping::upnp(&location, 6969, 6969, Duration::from_secs(60)).await.unwrap();
+        assert_eq!(mapping.method_name(), "UPnP");
+        assert_eq!(mapping.external, "203.0.113.7:6969".parse().unwrap());
+        assert_eq!(mapping.renew().await, Ok(false));
+        assert_eq!(mapping.unmap().await, Ok(()));
+
+        let actions = actions.lock().unwrap();
+        assert_eq!(actions.len(), 6);
+        // the router only supports permanent mappings, the lease is dropped.
+        assert!(actions[2].contains("<NewLeaseDuration>0</NewLeaseDuration>"));
+        assert!(actions[2].contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+        assert!(actions[5].contains("<u:DeletePortMapping"));
+    }
+
+    #[test]
+    fn discovery() {
+        let response =
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            ssdp_location(response),
+            Some(String::from("http://192.168.1.1:5000/rootDesc.xml"))
+        );
+
+        let description = "<URLBase>http://192.168.1.1:49152/</URLBase><service>\
+                           <serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType>\
+                           <controlURL>upnp/control/WANPPPConn1</controlURL></service>";
+        assert_eq!(
+            control_url("http://192.168.1.1:5000/rootDesc.xml", description),
+            Some((
+                UPNP_SERVICES[2],
+                String::from("http://192.168.1.1:49152/upnp/control/WANPPPConn1")
+            ))
+        );
+        assert_eq!(control_url("http://192.168.1.1/", "<service></service>"), None);
+    }
+}
@@ -0,0 +1,109 @@
+/// A row of the configured torrents query.
+pub struct PostgresTorrent {
+    pub info_hash: String,
+    pub is_flagged: bool,
+}
+
+/// A row of the configured users query.
+pub struct PostgresUser {
+    pub name: String,
+    pub passkey: String,
+    pub is_banned: bool,
+}
+
+#[cfg(feature = "postgres")]
+mod imp {
+    use super::{PostgresTorrent, PostgresUser};
+    use std::sync::{Arc, Mutex};
+
+    fn to_io_error(err: postgres::Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, err)
+    }
+
+    /// Read-only access to an existing PostgreSQL database. Queries run on the blocking thread pool.
+    pub struct PostgresStore {
+        url: Arc<String>,
+        client: Arc<Mutex<Option<postgres::Client>>>,
+    }
+
+    impl PostgresStore {
+        pub async fn open(url: &str) -> Result<PostgresStore, std::io::Error> {
+            let store = PostgresStore {
+                url: Arc::new(url.to_string()),
+                client: Arc::new(Mutex::new(None)),
+            };
+            // fail early if the database can't be reached.
+            store.query(String::from("SELECT 1"), |_| Ok(())).await?;
+            Ok(store)
+        }
+
+        /// Runs `query`, reconnecting first if the connection was lost.
+        async fn query<T, F>(&self, query: String, f: F) -> Result<Vec<T>, std::io::Error>
+        where
+            T: Send + 'static,
+            F: Fn(&postgres::Row) -> Result<T, postgres::Error> + Send + 'static, {
+            let url = self.url.clone();
+            let client = self.client.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut client = client.lock().unwrap();
+                if client.as_ref().map(|c| c.is_closed()).unwrap_or(true) {
+                    *client = Some(postgres::Client::connect(&url, postgres::NoTls)?);
+                }
+                let rows = client.as_mut().unwrap().query(query.as_str(), &[])?;
+                rows.iter().map(f).collect::<Result<Vec<_>, _>>()
+            })
+            .await?
+            .map_err(to_io_error)
+        }
+
+        /// `query` must return the hex encoded infohash and whether the torrent is flagged.
+        pub async fn torrents(&self, query: &str) -> Result<Vec<PostgresTorrent>, std::io::Error> {
+            self.query(query.to_string(), |row| {
+                Ok(PostgresTorrent {
+                    info_hash: row.try_get(0)?,
+                    is_flagged: row.try_get(1)?,
+                })
+            })
+            .await
+        }
+
+        /// `query` must return the user name, passkey and whether the user is banned.
+        pub async fn users(&self, query: &str) -> Result<Vec<PostgresUser>, std::io::Error> {
+            self.query(query.to_string(), |row| {
+                Ok(PostgresUser {
+                    name: row.try_get(0)?,
+                    passkey: row.try_get(1)?,
+                    is_banned: row.try_get(2)?,
+                })
+            })
+            .await
+        }
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+mod imp {
+    use super::{PostgresTorrent, PostgresUser};
+
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, "udpt was built without postgres support")
+    }
+
+    pub struct PostgresStore;
+
+    impl PostgresStore {
+        pub async fn open(_url: &str) -> Result<PostgresStore, std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn torrents(&self, _query: &str) -> Result<Vec<PostgresTorrent>, std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn users(&self, _query: &str) -> Result<Vec<PostgresUser>, std::io::Error> {
+            Err(unsupported())
+        }
+    }
+}
+
+pub use imp::PostgresStore;
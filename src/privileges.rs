@@ -0,0 +1,122 @@
+/// Switches to an unprivileged account once the sockets are bound, so udpt can serve privileged ports without
+/// handling traffic as root. `group` defaults to the user's primary group, supplementary groups are cleared.
+/// Does nothing when udpt already runs as them.
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), String> {
+    let (uid, user_gid) = match user {
+        Some(name) => {
+            let (uid, gid) = lookup_user(name)?;
+            (Some(uid), Some(gid))
+        }
+        None => (None, None),
+    };
+    let gid = match group {
+        Some(name) => Some(lookup_group(name)?),
+        None => user_gid,
+    };
+
+    let (current_uid, current_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    if uid.unwrap_or(current_uid) == current_uid && gid.unwrap_or(current_gid) == current_gid {
+        return Ok(());
+    }
+    if current_uid != 0 {
+        return Err(String::from("udpt must be started as root to switch to `user` & `group`"));
+    }
+
+    // glibc & musl apply these to every thread of the process, the runtime's included.
+    if let Some(gid) = gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 || unsafe { libc::setgid(gid) } != 0 {
+            return Err(format!(
+                "failed to switch to group {}: {}",
+                gid,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(format!(
+                "failed to switch to user {}: {}",
+                uid,
+                std::io::Error::last_os_error()
+            ));
+        }
+        // root can't be regained.
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(String::from("root privileges could be regained after switching users"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> Result<(), String> {
+    Err(String::from("`user` & `group` are only supported on unix"))
+}
+
+/// Buffers of getpwnam_r & getgrnam_r grow up to this size for accounts with large entries.
+#[cfg(unix)]
+const MAX_ENTRY_SIZE: usize = 1 << 20;
+
+/// Returns the uid & primary gid of `name`.
+#[cfg(unix)]
+pub fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("invalid user \"{}\"", name))?;
+    let mut buf: Vec<libc::c_char> = vec![0; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    loop {
+        let res = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match res {
+            libc::ERANGE if buf.len() < MAX_ENTRY_SIZE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => return Err(format!("user \"{}\" doesn't exist", name)),
+            0 => return Ok((passwd.pw_uid, passwd.pw_gid)),
+            err => {
+                return Err(format!(
+                    "failed to look up user \"{}\": {}",
+                    name,
+                    std::io::Error::from_raw_os_error(err)
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn lookup_group(name: &str) -> Result<libc::gid_t, String> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| format!("invalid group \"{}\"", name))?;
+    let mut buf: Vec<libc::c_char> = vec![0; 4096];
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    loop {
+        let res = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match res {
+            libc::ERANGE if buf.len() < MAX_ENTRY_SIZE => buf.resize(buf.len() * 2, 0),
+            0 if result.is_null() => return Err(format!("group \"{}\" doesn't exist", name)),
+            0 => return Ok(group.gr_gid),
+            err => {
+                return Err(format!(
+                    "failed to look up group \"{}\": {}",
+                    name,
+                    std::io::Error::from_raw_os_error(err)
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(lookup_user("root"), Ok((0, 0)));
+        assert!(lookup_user("udpt-no-such-user").is_err());
+        assert!(lookup_group("udpt-no-such-group").is_err());
+
+        // switching to the current account does nothing.
+        let root = unsafe { libc::geteuid() } == 0;
+        assert_eq!(drop_privileges(if root { Some("root") } else { None }, None), Ok(()));
+    }
+}
@@ -0,0 +1,351 @@
+//! Checks a running UDP tracker from the outside: connects, announces & scrapes like a client, for monitoring.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+const EVENT_STARTED: u32 = 2;
+const EVENT_STOPPED: u32 = 3;
+
+pub struct ProbeOptions {
+    /// Torrent to announce, a random one if None. Trackers that only track registered torrents need one.
+    pub info_hash: Option<[u8; 20]>,
+    /// Sent as BEP 41 URL data, for private trackers.
+    pub passkey: Option<String>,
+    /// How long to wait for each response.
+    pub timeout: Duration,
+}
+
+/// Results of a successful check.
+pub struct ProbeReport {
+    pub connect: Duration,
+    pub announce: Duration,
+    pub scrape: Duration,
+    pub interval: u32,
+    pub seeders: u32,
+    pub leechers: u32,
+    pub completed: u32,
+}
+
+/// Announces as a seeder of the torrent, scrapes it and expects to be counted, then announces that it stopped.
+/// Errors name the step that failed.
+pub fn run(address: &str, options: &ProbeOptions) -> Result<ProbeReport, String> {
+    let target = address
+        .to_socket_addrs()
+        .map_err(|err| format!("failed to resolve \"{}\": {}", address, err))?
+        .next()
+        .ok_or_else(|| format!("failed to resolve \"{}\"", address))?;
+    let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).map_err(|err| format!("failed to bind a socket: {}", err))?;
+    socket
+        .connect(target)
+        .map_err(|err| format!("failed to reach {}: {}", target, err))?;
+
+    let mut probe = Probe {
+        socket,
+        timeout: options.timeout,
+        next_transaction: random_u64() as u32,
+    };
+    let info_hash = options.info_hash.unwrap_or_else(random_bytes);
+    let peer_id = random_bytes();
+
+    let started = Instant::now();
+    let connection_id = probe.connect().map_err(|err| format!("connect: {}", err))?;
+    let connect = started.elapsed();
+
+    let started = Instant::now();
+    let announce = probe
+        .announce(connection_id, &info_hash, &peer_id, EVENT_STARTED, options.passkey.as_deref())
+        .map_err(|err| format!("announce: {}", err))?;
+    let announce_latency = started.elapsed();
+    if announce.interval == 0 {
+        return Err(String::from("announce: the tracker returned an interval of 0"));
+    }
+    if announce.seeders == 0 {
+        return Err(String::from("announce: the tracker didn't count the announced seeder"));
+    }
+
+    let started = Instant::now();
+    let scrape = probe
+        .scrape(connection_id, &info_hash)
+        .map_err(|err| format!("scrape: {}", err))?;
+    let scrape_latency = started.elapsed();
+    if scrape.seeders == 0 {
+        return Err(String::from("scrape: the tracker didn't count the announced seeder"));
+    }
+
+    // don't leave a peer behind in the tracker.
+    probe
+        .announce(connection_id, &info_hash, &peer_id, EVENT_STOPPED, options.passkey.as_deref())
+        .map_err(|err| format!("stopped announce: {}", err))?;
+
+    Ok(ProbeReport {
+        connect,
+        announce: announce_latency,
+        scrape: scrape_latency,
+        interval: announce.interval,
+        seeders: scrape.seeders,
+        leechers: scrape.leechers,
+        completed: scrape.completed,
+    })
+}
+
+struct Probe {
+    socket: UdpSocket,
+    timeout: Duration,
+    next_transaction: u32,
+}
+
+struct Announced {
+    interval: u32,
+    seeders: u32,
+}
+
+struct Scraped {
+    seeders: u32,
+    completed: u32,
+    leechers: u32,
+}
+
+impl Probe {
+    fn connect(&mut self) -> Result<u64, String> {
+        let transaction_id = self.transaction_id();
+        let packet = encode_connect(transaction_id);
+        let response = self.request(&packet, transaction_id, ACTION_CONNECT, 16)?;
+        let mut connection_id = [0u8; 8];
+        connection_id.copy_from_slice(&response[8..16]);
+        Ok(u64::from_be_bytes(connection_id))
+    }
+
+    fn announce(
+        &mut self, connection_id: u64, info_hash: &[u8; 20], peer_id: &[u8; 20], event: u32, passkey: Option<&str>,
+    ) -> Result<Announced, String> {
+        let transaction_id = self.transaction_id();
+        let port = self.socket.local_addr().map(|a| a.port()).unwrap_or(0);
+        let request = AnnounceRequest {
+            info_hash,
+            peer_id,
+            left: 0,
+            event,
+            port,
+            passkey,
+        };
+        let packet = encode_announce(connection_id, transaction_id, &request);
+        let response = self.request(&packet, transaction_id, ACTION_ANNOUNCE, 20)?;
+        Ok(Announced {
+            interval: read_u32(&response, 8),
+            seeders: read_u32(&response, 16),
+        })
+    }
+
+    fn scrape(&mut self, connection_id: u64, info_hash: &[u8; 20]) -> Result<Scraped, String> {
+        let transaction_id = self.transaction_id();
+        let packet = encode_scrape(connection_id, transaction_id, info_hash);
+        let response = self.request(&packet, transaction_id, ACTION_SCRAPE, 20)?;
+        Ok(Scraped {
+            seeders: read_u32(&response, 8),
+            completed: read_u32(&response, 12),
+            leechers: read_u32(&response, 16),
+        })
+    }
+
+    /// Sends `packet` and waits for the response to it, of at least `min_size` bytes.
+    fn request(&mut self, packet: &[u8], transaction_id: u32, action: u32, min_size: usize) -> Result<Vec<u8>, String> {
+        self.socket.send(packet).map_err(|err| format!("failed to send: {}", err))?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buffer = [0u8; 2048];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(format!("no response within {}ms", self.timeout.as_millis()));
+            }
+            self.socket.set_read_timeout(Some(remaining)).map_err(|err| err.to_string())?;
+            let size = match self.socket.recv(&mut buffer) {
+                Ok(v) => v,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock || err.kind() == std::io::ErrorKind::TimedOut => {
+                    continue
+                }
+                Err(err) => return Err(format!("failed to receive: {}", err)),
+            };
+            let data = &buffer[..size];
+            // skip responses to other requests.
+            if size < 8 || read_u32(data, 4) != transaction_id {
+                continue;
+            }
+            match read_u32(data, 0) {
+                ACTION_ERROR => {
+                    return Err(format!(
+                        "the tracker returned an error: {}",
+                        String::from_utf8_lossy(&data[8..])
+                    ));
+                }
+                v if v == action && size >= min_size => return Ok(data.to_vec()),
+                v if v == action => return Err(format!("truncated response of {} bytes", size)),
+                v => return Err(format!("unexpected action {} in response", v)),
+            }
+        }
+    }
+
+    fn transaction_id(&mut self) -> u32 {
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        self.next_transaction
+    }
+}
+
+/// An announce (BEP 15), from the sender's address.
+pub struct AnnounceRequest<'a> {
+    pub info_hash: &'a [u8; 20],
+    pub peer_id: &'a [u8; 20],
+    /// Bytes left to download, 0 for a seeder.
+    pub left: u64,
+    pub event: u32,
+    pub port: u16,
+    /// Sent as BEP 41 URL data, for private trackers.
+    pub passkey: Option<&'a str>,
+}
+
+pub fn encode_connect(transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+pub fn encode_announce(connection_id: u64, transaction_id: u32, request: &AnnounceRequest) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(request.info_hash);
+    packet.extend_from_slice(request.peer_id);
+    packet.extend_from_slice(&0u64.to_be_bytes()); // downloaded
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&0u64.to_be_bytes()); // uploaded
+    packet.extend_from_slice(&request.event.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes()); // ip, the sender's
+    packet.extend_from_slice(&transaction_id.to_be_bytes()); // key
+    packet.extend_from_slice(&0i32.to_be_bytes()); // num_want
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    if let Some(passkey) = request.passkey {
+        let url = format!("/announce/{}", passkey);
+        // each URL data option holds up to 255 bytes.
+        for chunk in url.as_bytes().chunks(255) {
+            packet.push(0x2);
+            packet.push(chunk.len() as u8);
+            packet.extend_from_slice(chunk);
+        }
+    }
+    packet
+}
+
+pub fn encode_scrape(connection_id: u64, transaction_id: u32, info_hash: &[u8; 20]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(36);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(info_hash);
+    packet
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Random enough for a peer id, without pulling in a RNG: std's hasher keys are randomly seeded.
+pub fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.finish()
+}
+
+pub fn random_bytes() -> [u8; 20] {
+    let mut bytes = [0u8; 20];
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_be_bytes()[..chunk.len()]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tracker that counts the last announce's peer as a seeder, or returns `error` to announces.
+    fn fake_tracker(error: Option<&'static str>) -> String {
+        let tracker = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = tracker.local_addr().unwrap().to_string();
+
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 2048];
+            let mut seeders = 0u32;
+            loop {
+                let (size, from) = tracker.recv_from(&mut buffer).unwrap();
+                let mut response = Vec::new();
+                match (read_u32(&buffer, 8), error) {
+                    (ACTION_CONNECT, _) => {
+                        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&7u64.to_be_bytes());
+                    }
+                    (ACTION_ANNOUNCE, Some(error)) => {
+                        response.extend_from_slice(&ACTION_ERROR.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(error.as_bytes());
+                    }
+                    (ACTION_ANNOUNCE, None) => {
+                        assert_eq!(&buffer[..8], &7u64.to_be_bytes());
+                        assert_eq!(&buffer[98..size], b"\x02\x0c/announce/pk");
+                        seeders = if read_u32(&buffer, 80) == EVENT_STOPPED { 0 } else { 1 };
+                        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&[0, 0, 0, 120, 0, 0, 0, 0]);
+                        response.extend_from_slice(&seeders.to_be_bytes());
+                    }
+                    (ACTION_SCRAPE, _) => {
+                        response.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&seeders.to_be_bytes());
+                        response.extend_from_slice(&[0, 0, 0, 3, 0, 0, 0, 0]);
+                    }
+                    _ => continue,
+                }
+                tracker.send_to(&response, from).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn check_tracker() {
+        let options = ProbeOptions {
+            info_hash: None,
+            passkey: Some(String::from("pk")),
+            timeout: Duration::from_secs(5),
+        };
+        let report = run(&fake_tracker(None), &options).unwrap();
+        assert_eq!(
+            (report.interval, report.seeders, report.leechers, report.completed),
+            (120, 1, 0, 3)
+        );
+
+        let err = run(&fake_tracker(Some("unregistered torrent")), &options).err().unwrap();
+        assert_eq!(err, "announce: the tracker returned an error: unregistered torrent");
+
+        let options = ProbeOptions {
+            timeout: Duration::from_millis(50),
+            ..options
+        };
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let err = run(&silent.local_addr().unwrap().to_string(), &options).err().unwrap();
+        assert_eq!(err, "connect: no response within 50ms");
+    }
+}
@@ -0,0 +1,1028 @@
+use crate::config::RaftConfig;
+use crate::gossip::Gossip;
+use crate::tracker::{ControlAction, ControlOutcome};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+/// Serialized entries sent in a single `AppendEntries`, at least one entry is always sent.
+const MAX_APPEND_SIZE: usize = 16 * 1024;
+/// Outcomes of applied entries kept for forwarded actions whose index arrives after they were applied.
+const MAX_RECENT_OUTCOMES: usize = 256;
+/// How long the leader has to answer a forwarded action.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Applied entries that every node has, dropped from the log & its file at once.
+const COMPACT_AFTER: u64 = 1000;
+
+/// A log entry, `action` is None for the no-op a new leader commits to learn which entries are committed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    term: u64,
+    action: Option<ControlAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+        /// Last index every node has, entries up to it can be dropped from the logs once they're applied.
+        replicated_index: u64,
+    },
+    AppendResult {
+        term: u64,
+        success: bool,
+        /// Last index known to match the leader's log, or a hint of where to retry from.
+        match_index: u64,
+    },
+    /// An action submitted to a follower, forwarded to the leader.
+    Propose {
+        id: u64,
+        action: ControlAction,
+    },
+    /// Index & term the leader appended a forwarded action at, None if it isn't the leader.
+    Proposed {
+        id: u64,
+        index: Option<(u64, u64)>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    from: u64,
+    message: Message,
+}
+
+/// A line of the log file. Entries are appended as they're written; an entry at an index that was already written
+/// replaces it and drops the entries after it, which is how a follower's conflicting entries are truncated. Once
+/// entries were compacted, the file is rewritten starting with a `Compacted` record.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Vote {
+        term: u64,
+        voted_for: Option<u64>,
+    },
+    Entry {
+        index: u64,
+        entry: Entry,
+    },
+    /// Entries up to `index` were dropped from the log, the entry at `index` is of `term`.
+    Compacted {
+        index: u64,
+        term: u64,
+    },
+    /// Entries up to `index` were applied, they aren't applied again on startup.
+    Applied {
+        index: u64,
+    },
+}
+
+/// The state read from the log file.
+#[derive(Default, Debug, PartialEq)]
+struct Saved {
+    term: u64,
+    voted_for: Option<u64>,
+    /// Index & term of the last compacted entry.
+    compacted: (u64, u64),
+    log: Vec<Entry>,
+    applied: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Messages to send and records to write, produced by the node's state changes.
+#[derive(Default)]
+struct Output {
+    messages: Vec<(u64, Message)>,
+    records: Vec<Record>,
+    /// Answers to forwarded actions, by their id.
+    proposed: Vec<(u64, Option<(u64, u64)>)>,
+    /// Whether entries were compacted, the file is rewritten instead of appending `records`.
+    compacted: bool,
+}
+
+/// Raft's state machine, without I/O: messages & records it produces are collected in `out`, to be sent & written
+/// by `Raft` before any of the messages leave the node.
+struct Node {
+    id: u64,
+    peers: Vec<u64>,
+    term: u64,
+    voted_for: Option<u64>,
+    /// Entry `i` is at `log[i - compacted_index - 1]`, indices start at 1.
+    log: Vec<Entry>,
+    compacted_index: u64,
+    compacted_term: u64,
+    commit_index: u64,
+    /// Last entry taken to be applied.
+    last_applied: u64,
+    /// Last entry the tracker applied, written to the file.
+    applied: u64,
+    replicated_index: u64,
+    role: Role,
+    leader: Option<u64>,
+    votes: HashSet<u64>,
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    election_timeout: Duration,
+    heartbeat_interval: Duration,
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+    random: u64,
+    out: Output,
+}
+
+impl Node {
+    fn new(
+        id: u64, peers: Vec<u64>, saved: Saved, election_timeout: Duration, heartbeat_interval: Duration, now: Instant,
+    ) -> Node {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|v| v.as_nanos() as u64)
+            .unwrap_or(0);
+        // applied entries were committed, the ones after them are committed again by the leader.
+        let applied = saved.applied.min(saved.compacted.0 + saved.log.len() as u64);
+        let mut node = Node {
+            id,
+            peers,
+            term: saved.term,
+            voted_for: saved.voted_for,
+            log: saved.log,
+            compacted_index: saved.compacted.0,
+            compacted_term: saved.compacted.1,
+            commit_index: applied,
+            last_applied: applied,
+            applied,
+            replicated_index: saved.compacted.0,
+            role: Role::Follower,
+            leader: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_timeout,
+            heartbeat_interval,
+            election_deadline: now,
+            heartbeat_due: now,
+            random: (seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+            out: Output::default(),
+        };
+        node.reset_election(now);
+        node
+    }
+
+    fn last_index(&self) -> u64 {
+        self.compacted_index + self.log.len() as u64
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index.checked_sub(self.compacted_index) {
+            Some(0) => self.compacted_term,
+            Some(i) => self.log.get(i as usize - 1).map(|v| v.term).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let size = self.peers.len() + 1;
+        size / 2 + 1
+    }
+
+    /// Elections start after a random timeout between `election_timeout` and twice that, so nodes rarely split
+    /// the vote.
+    fn reset_election(&mut self, now: Instant) {
+        // xorshift64.
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        let timeout_ms = self.election_timeout.as_millis() as u64;
+        let jitter = Duration::from_millis(self.random % timeout_ms.max(1));
+        self.election_deadline = now + self.election_timeout + jitter;
+    }
+
+    fn persist_vote(&mut self) {
+        self.out.records.push(Record::Vote {
+            term: self.term,
+            voted_for: self.voted_for,
+        });
+    }
+
+    fn append(&mut self, entry: Entry) -> u64 {
+        self.log.push(entry.clone());
+        let index = self.last_index();
+        self.out.records.push(Record::Entry { index, entry });
+        index
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_vote();
+        }
+        if self.role == Role::Leader {
+            info!("raft: stepping down in term {}.", self.term);
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+    }
+
+    fn start_election(&mut self, now: Instant) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.persist_vote();
+        self.votes = std::iter::once(self.id).collect();
+        self.reset_election(now);
+        debug!("raft: starting an election for term {}.", self.term);
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader(now);
+            return;
+        }
+        let (last_log_index, last_log_term) = (self.last_index(), self.term_at(self.last_index()));
+        for peer in self.peers.clone() {
+            self.out.messages.push((peer, Message::RequestVote {
+                term: self.term,
+                last_log_index,
+                last_log_term,
+            }));
+        }
+    }
+
+    fn become_leader(&mut self, now: Instant) {
+        info!("raft: elected leader of term {}.", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let next = self.last_index() + 1;
+        self.next_index = self.peers.iter().map(|peer| (*peer, next)).collect();
+        self.match_index = self.peers.iter().map(|peer| (*peer, 0)).collect();
+        // entries of previous terms are only known to be committed once an entry of this term is.
+        self.append(Entry {
+            term: self.term,
+            action: None,
+        });
+        self.replicate();
+        self.advance_commit();
+        self.heartbeat_due = now + self.heartbeat_interval;
+    }
+
+    fn replicate(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: u64) {
+        // every node has the compacted entries.
+        let next = self.next_index.get(&peer).copied().unwrap_or(1).max(self.compacted_index + 1);
+        let prev_log_index = next - 1;
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in self.log.iter().skip((prev_log_index - self.compacted_index) as usize) {
+            size += serde_json::to_vec(entry).map(|v| v.len()).unwrap_or(0);
+            if !entries.is_empty() && size > MAX_APPEND_SIZE {
+                break;
+            }
+            entries.push(entry.clone());
+        }
+        self.out.messages.push((peer, Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit_index,
+            replicated_index: self.replicated_index,
+        }));
+    }
+
+    /// Commits the latest entry of the current term that a majority of the nodes has.
+    fn advance_commit(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != self.term {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|v| **v >= index).count();
+            if replicas >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+        let replicated = self.match_index.values().copied().min().unwrap_or(u64::MAX);
+        self.replicated_index = self.replicated_index.max(replicated.min(self.last_index()));
+    }
+
+    fn tick(&mut self, now: Instant) {
+        match self.role {
+            Role::Leader if now >= self.heartbeat_due => {
+                self.replicate();
+                self.heartbeat_due = now + self.heartbeat_interval;
+            }
+            Role::Leader => {}
+            _ if now >= self.election_deadline => self.start_election(now),
+            _ => {}
+        }
+    }
+
+    /// Appends `action` if this node is the leader, returning its index & term.
+    fn propose(&mut self, action: ControlAction) -> Option<(u64, u64)> {
+        if self.role != Role::Leader {
+            return None;
+        }
+        let index = self.append(Entry {
+            term: self.term,
+            action: Some(action),
+        });
+        self.replicate();
+        self.advance_commit();
+        Some((index, self.term))
+    }
+
+    fn handle(&mut self, from: u64, message: Message, now: Instant) {
+        if !self.peers.contains(&from) {
+            warn!("raft: ignoring a message from unknown node {}.", from);
+            return;
+        }
+        let message_term = match message {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResult { term, .. } => Some(term),
+            _ => None,
+        };
+        if let Some(term) = message_term.filter(|term| *term > self.term) {
+            self.become_follower(term, None);
+        }
+
+        match message {
+            Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let my_last_term = self.term_at(self.last_index());
+                let is_up_to_date =
+                    last_log_term > my_last_term || (last_log_term == my_last_term && last_log_index >= self.last_index());
+                let granted = term == self.term && self.voted_for.map(|v| v == from).unwrap_or(true) && is_up_to_date;
+                if granted && self.voted_for.is_none() {
+                    self.voted_for = Some(from);
+                    self.persist_vote();
+                }
+                if granted {
+                    self.reset_election(now);
+                }
+                self.out.messages.push((from, Message::Vote {
+                    term: self.term,
+                    granted,
+                }));
+            }
+            Message::Vote { term, granted } => {
+                if self.role == Role::Candidate && term == self.term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader(now);
+                    }
+                }
+            }
+            Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                replicated_index,
+            } => {
+                if term < self.term {
+                    self.out.messages.push((from, Message::AppendResult {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                    }));
+                    return;
+                }
+                self.become_follower(term, Some(from));
+                self.reset_election(now);
+
+                // this node has the entries up to its compacted index, they were committed.
+                let (prev_log_index, prev_log_term, entries) = if prev_log_index < self.compacted_index {
+                    let skip = (self.compacted_index - prev_log_index) as usize;
+                    (
+                        self.compacted_index,
+                        self.compacted_term,
+                        entries.into_iter().skip(skip).collect(),
+                    )
+                } else {
+                    (prev_log_index, prev_log_term, entries)
+                };
+
+                if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+                    self.out.messages.push((from, Message::AppendResult {
+                        term: self.term,
+                        success: false,
+                        match_index: self.last_index().min(prev_log_index.saturating_sub(1)),
+                    }));
+                    return;
+                }
+
+                let last_new = prev_log_index + entries.len() as u64;
+                for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                    if index <= self.last_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // committed entries never conflict, the leader has all of them.
+                        self.log.truncate((index - self.compacted_index) as usize - 1);
+                    }
+                    self.append(entry);
+                }
+                if leader_commit > self.commit_index {
+                    // a reordered message may be about entries before the commit index.
+                    self.commit_index = leader_commit.min(last_new).max(self.commit_index);
+                }
+                self.replicated_index = self.replicated_index.max(replicated_index.min(last_new));
+                self.out.messages.push((from, Message::AppendResult {
+                    term: self.term,
+                    success: true,
+                    match_index: last_new,
+                }));
+            }
+            Message::AppendResult {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_insert(0);
+                    *matched = (*matched).max(match_index);
+                    let matched = *matched;
+                    self.next_index.insert(from, matched + 1);
+                    self.advance_commit();
+                    if matched < self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.get(&from).copied().unwrap_or(1);
+                    self.next_index
+                        .insert(from, next.saturating_sub(1).min(match_index + 1).max(1));
+                    self.send_append(from);
+                }
+            }
+            Message::Propose { id, action } => {
+                let index = self.propose(action);
+                self.out.messages.push((from, Message::Proposed { id, index }));
+            }
+            Message::Proposed { id, index } => self.out.proposed.push((id, index)),
+        }
+    }
+
+    /// Entries that were committed since the last call, with their indices.
+    fn take_committed(&mut self) -> Vec<(u64, Entry)> {
+        let start = self.last_applied;
+        self.last_applied = self.commit_index;
+        let offset = self.compacted_index;
+        (start + 1..=self.commit_index)
+            .zip(
+                self.log[(start - offset) as usize..(self.commit_index - offset) as usize]
+                    .iter()
+                    .cloned(),
+            )
+            .collect()
+    }
+
+    /// Records that the entries up to `index` were applied, and compacts the applied entries every node has once
+    /// there are `COMPACT_AFTER` of them.
+    fn mark_applied(&mut self, index: u64) {
+        self.applied = index;
+        self.out.records.push(Record::Applied { index });
+        let compactable = index.min(self.replicated_index);
+        if compactable >= self.compacted_index + COMPACT_AFTER {
+            debug!("raft: compacting the log up to {}.", compactable);
+            self.compacted_term = self.term_at(compactable);
+            self.log.drain(..(compactable - self.compacted_index) as usize);
+            self.compacted_index = compactable;
+            self.out.compacted = true;
+        }
+    }
+
+    /// The records of a rewritten log file.
+    fn snapshot(&self) -> Vec<Record> {
+        let mut records = vec![
+            Record::Vote {
+                term: self.term,
+                voted_for: self.voted_for,
+            },
+            Record::Compacted {
+                index: self.compacted_index,
+                term: self.compacted_term,
+            },
+        ];
+        for (index, entry) in (self.compacted_index + 1..).zip(self.log.iter()) {
+            records.push(Record::Entry {
+                index,
+                entry: entry.clone(),
+            });
+        }
+        records.push(Record::Applied { index: self.applied });
+        records
+    }
+}
+
+/// Reads the state written to a log file.
+fn load(data: &str) -> Saved {
+    let mut saved = Saved::default();
+    for line in data.lines() {
+        match serde_json::from_str(line) {
+            Ok(Record::Vote { term, voted_for }) => {
+                saved.term = term;
+                saved.voted_for = voted_for;
+            }
+            Ok(Record::Entry { index, entry })
+                if index > saved.compacted.0 && index <= saved.compacted.0 + saved.log.len() as u64 + 1 =>
+            {
+                saved.log.truncate((index - saved.compacted.0) as usize - 1);
+                saved.log.push(entry);
+            }
+            Ok(Record::Entry { index, .. }) => warn!("raft: ignoring entry {} of the log, it has a gap before it.", index),
+            Ok(Record::Compacted { index, term }) => {
+                saved.compacted = (index, term);
+                saved.log.clear();
+            }
+            Ok(Record::Applied { index }) => saved.applied = saved.applied.max(index),
+            // most likely a write that was cut short by a crash, it wasn't acknowledged.
+            Err(err) => warn!("raft: ignoring an invalid log record: {}", err),
+        }
+    }
+    saved
+}
+
+/// Forwarded actions waiting for the leader's answer, by id.
+type Forwarded = HashMap<u64, tokio::sync::oneshot::Sender<Option<(u64, u64)>>>;
+
+/// Actions waiting to be applied, by index.
+struct Pending {
+    waiting: HashMap<u64, (u64, tokio::sync::oneshot::Sender<ControlOutcome>)>,
+    /// (index, term, outcome) of the latest applied entries.
+    recent: VecDeque<(u64, u64, ControlOutcome)>,
+}
+
+/// Replicates admin actions between nodes with the Raft consensus algorithm, so they're applied by every node in
+/// the same order. Actions submitted to a follower are forwarded to the leader, and are only applied once a majority
+/// of the nodes wrote them to their log. Messages go over the signed datagrams of `Gossip`.
+pub struct Raft {
+    id: u64,
+    state: tokio::sync::Mutex<(Node, tokio::fs::File)>,
+    transport: Gossip,
+    peers: HashMap<u64, SocketAddr>,
+    log_path: PathBuf,
+    next_id: AtomicU64,
+    forwarded: std::sync::Mutex<Forwarded>,
+    pending: std::sync::Mutex<Pending>,
+    /// Held while committed entries are applied, so they're applied one at a time & in order.
+    apply: tokio::sync::Mutex<()>,
+}
+
+impl Raft {
+    pub async fn open(config: &RaftConfig) -> Result<Raft, std::io::Error> {
+        let addresses: Vec<String> = config.get_nodes().iter().map(|v| v.get_address().to_string()).collect();
+        let transport = Gossip::bind(config.get_bind_address(), &addresses, config.get_secret()).await?;
+        let ids = config.get_nodes().iter().map(|v| v.get_id());
+        let peers: HashMap<u64, SocketAddr> = ids.zip(transport.nodes().iter().copied()).collect();
+
+        let data = match tokio::fs::read_to_string(config.get_log_path()).await {
+            Ok(v) => v,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let saved = load(&data);
+        info!(
+            "raft: loaded {} log entries, term {}, applied up to {}.",
+            saved.log.len(),
+            saved.term,
+            saved.applied
+        );
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.get_log_path())
+            .await?;
+
+        let node = Node::new(
+            config.get_id(),
+            peers.keys().copied().collect(),
+            saved,
+            Duration::from_millis(config.get_election_timeout_ms()),
+            Duration::from_millis(config.get_heartbeat_interval_ms()),
+            Instant::now(),
+        );
+        Ok(Raft {
+            id: config.get_id(),
+            state: tokio::sync::Mutex::new((node, file)),
+            transport,
+            peers,
+            log_path: PathBuf::from(config.get_log_path()),
+            next_id: AtomicU64::new(1),
+            forwarded: std::sync::Mutex::new(HashMap::new()),
+            pending: std::sync::Mutex::new(Pending {
+                waiting: HashMap::new(),
+                recent: VecDeque::new(),
+            }),
+            apply: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.transport.local_addr()
+    }
+
+    /// Writes the node's records, then sends its messages: a node may only answer once its state is durable.
+    async fn flush(&self, node: &mut Node, file: &mut tokio::fs::File) -> Result<(), std::io::Error> {
+        let out = std::mem::take(&mut node.out);
+        if out.compacted {
+            // written next to the log & renamed over it, so a crash leaves either file whole.
+            let mut path = self.log_path.clone().into_os_string();
+            path.push(".compacting");
+            let mut data = Vec::new();
+            for record in node.snapshot().iter() {
+                serde_json::to_writer(&mut data, record)?;
+                data.push(b'\n');
+            }
+            let mut compacted = tokio::fs::File::create(&path).await?;
+            compacted.write_all(&data).await?;
+            compacted.sync_all().await?;
+            tokio::fs::rename(&path, &self.log_path).await?;
+            *file = tokio::fs::OpenOptions::new().append(true).open(&self.log_path).await?;
+        } else if !out.records.is_empty() {
+            let mut data = Vec::new();
+            for record in out.records.iter() {
+                serde_json::to_writer(&mut data, record)?;
+                data.push(b'\n');
+            }
+            file.write_all(&data).await?;
+            file.sync_data().await?;
+        }
+
+        for (id, index) in out.proposed {
+            if let Some(sender) = self.forwarded.lock().unwrap().remove(&id) {
+                let _ = sender.send(index);
+            }
+        }
+        for (peer, message) in out.messages {
+            let addr = match self.peers.get(&peer) {
+                Some(v) => v,
+                None => continue,
+            };
+            match serde_json::to_vec(&Envelope { from: node.id, message }) {
+                Ok(payload) => self.transport.send_to(&payload, addr).await,
+                Err(err) => warn!("raft: failed to serialize a message: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends heartbeats & starts elections when they're due, should run a few times per heartbeat interval.
+    pub async fn tick(&self) -> Result<(), std::io::Error> {
+        let mut state = self.state.lock().await;
+        let (ref mut node, ref mut file) = *state;
+        node.tick(Instant::now());
+        self.flush(node, file).await
+    }
+
+    /// Waits for the next message of another node and handles it.
+    pub async fn receive(&self) -> Result<(), std::io::Error> {
+        let payload = self.transport.receive().await?;
+        let envelope: Envelope = match serde_json::from_slice(&payload) {
+            Ok(v) => v,
+            Err(err) => {
+                warn!("raft: ignoring an invalid message: {}", err);
+                return Ok(());
+            }
+        };
+
+        let mut state = self.state.lock().await;
+        let (ref mut node, ref mut file) = *state;
+        node.handle(envelope.from, envelope.message, Instant::now());
+        self.flush(node, file).await
+    }
+
+    /// Submits `action` to the leader, returning a receiver of its outcome once it was applied by this node.
+    pub async fn submit(&self, action: ControlAction) -> Result<tokio::sync::oneshot::Receiver<ControlOutcome>, String> {
+        let leader = {
+            let mut state = self.state.lock().await;
+            let (ref mut node, ref mut file) = *state;
+            if let Some(index) = node.propose(action.clone()) {
+                let receiver = self.wait_for(index);
+                self.flush(node, file)
+                    .await
+                    .map_err(|err| format!("failed to write the raft log: {}", err))?;
+                return Ok(receiver);
+            }
+            node.leader
+        };
+        let leader = leader.ok_or_else(|| String::from("no raft leader, try again later."))?;
+        let addr = self
+            .peers
+            .get(&leader)
+            .ok_or_else(|| String::from("the raft leader is unknown."))?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        self.forwarded.lock().unwrap().insert(id, sender);
+        match serde_json::to_vec(&Envelope {
+            from: self.id,
+            message: Message::Propose { id, action },
+        }) {
+            Ok(payload) => self.transport.send_to(&payload, addr).await,
+            Err(err) => return Err(format!("failed to serialize the action: {}", err)),
+        }
+
+        let answer = tokio::time::timeout(FORWARD_TIMEOUT, receiver).await;
+        self.forwarded.lock().unwrap().remove(&id);
+        match answer {
+            Ok(Ok(Some(index))) => Ok(self.wait_for(index)),
+            Ok(Ok(None)) => Err(String::from("the raft leader changed, try again.")),
+            Ok(Err(_)) | Err(_) => Err(String::from("the raft leader didn't answer, try again.")),
+        }
+    }
+
+    /// Returns a receiver of the outcome of the entry at `index`, expected to be of `term`.
+    fn wait_for(&self, (index, term): (u64, u64)) -> tokio::sync::oneshot::Receiver<ControlOutcome> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        match pending.recent.iter().find(|(i, _, _)| *i == index) {
+            Some((_, t, outcome)) if *t == term => {
+                let _ = sender.send(outcome.clone());
+            }
+            Some(_) => {
+                let _ = sender.send(Err(String::from(
+                    "the action was overwritten by a new raft leader, try again.",
+                )));
+            }
+            None => {
+                pending.waiting.insert(index, (term, sender));
+            }
+        }
+        receiver
+    }
+
+    /// Takes the entries committed since the last call, to be applied in order. Actions are applied while
+    /// the returned guard is held.
+    pub async fn take_committed(&self) -> (tokio::sync::MutexGuard<'_, ()>, Vec<(u64, Entry)>) {
+        let guard = self.apply.lock().await;
+        let entries = self.state.lock().await.0.take_committed();
+        (guard, entries)
+    }
+
+    /// Records that the entry at `index` was applied so it isn't applied again after a restart, and passes its
+    /// outcome to the request that submitted it.
+    pub async fn applied(&self, index: u64, entry: &Entry, outcome: ControlOutcome) -> Result<(), std::io::Error> {
+        let written = {
+            let mut state = self.state.lock().await;
+            let (ref mut node, ref mut file) = *state;
+            node.mark_applied(index);
+            self.flush(node, file).await
+        };
+
+        let mut pending = self.pending.lock().unwrap();
+        if let Some((term, sender)) = pending.waiting.remove(&index) {
+            let _ = match term == entry.term {
+                true => sender.send(outcome.clone()),
+                false => {
+                    sender.send(Err(String::from(
+                        "the action was overwritten by a new raft leader, try again.",
+                    )))
+                }
+            };
+        }
+        if pending.recent.len() >= MAX_RECENT_OUTCOMES {
+            pending.recent.pop_front();
+        }
+        pending.recent.push_back((index, entry.term, outcome));
+        written
+    }
+}
+
+impl Entry {
+    pub fn get_action(&self) -> Option<&ControlAction> {
+        self.action.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(size: u64, now: Instant) -> Vec<Node> {
+        (1..=size)
+            .map(|id| {
+                let peers = (1..=size).filter(|v| *v != id).collect();
+                let timeout = Duration::from_millis(100 * id);
+                Node::new(id, peers, Saved::default(), timeout, Duration::from_millis(10), now)
+            })
+            .collect()
+    }
+
+    /// Delivers messages between the nodes until there are none left, except to & from the `down` nodes.
+    fn deliver(nodes: &mut [Node], down: &[u64], now: Instant) {
+        loop {
+            let mut messages = Vec::new();
+            for node in nodes.iter_mut() {
+                for (to, message) in std::mem::take(&mut node.out.messages) {
+                    messages.push((node.id, to, message));
+                }
+                node.out.records.clear();
+            }
+            if messages.is_empty() {
+                return;
+            }
+            for (from, to, message) in messages {
+                if !down.contains(&from) && !down.contains(&to) {
+                    nodes[to as usize - 1].handle(from, message, now);
+                }
+            }
+        }
+    }
+
+    fn flag(byte: u8) -> ControlAction {
+        ControlAction::SetFlag {
+            info_hash: [byte; 20].into(),
+            is_flagged: true,
+        }
+    }
+
+    fn actions(node: &mut Node) -> Vec<ControlAction> {
+        node.take_committed()
+            .into_iter()
+            .filter_map(|(_, entry)| entry.action)
+            .collect()
+    }
+
+    #[test]
+    fn replication() {
+        let now = Instant::now();
+        let mut nodes = cluster(3, now);
+
+        // node 1 has the shortest election timeout.
+        let now = now + Duration::from_millis(200);
+        nodes[0].tick(now);
+        deliver(&mut nodes, &[], now);
+        assert_eq!(nodes[0].role, Role::Leader);
+        assert!(nodes[1..].iter().all(|v| v.leader == Some(1)));
+        assert!(nodes[1].propose(flag(1)).is_none());
+
+        assert_eq!(nodes[0].propose(flag(1)), Some((2, 1)));
+        deliver(&mut nodes, &[], now);
+        // the followers learn about the commit with the next heartbeat.
+        let now = now + Duration::from_millis(10);
+        nodes[0].tick(now);
+        deliver(&mut nodes, &[], now);
+        for node in nodes.iter_mut() {
+            assert_eq!(actions(node), vec![flag(1)]);
+        }
+
+        // entries a leader couldn't replicate to a majority aren't committed, and are replaced by the next leader's.
+        nodes[0].propose(flag(2));
+        deliver(&mut nodes, &[1], now);
+        assert!(actions(&mut nodes[0]).is_empty());
+        let now = now + Duration::from_millis(1000);
+        nodes[1].tick(now);
+        deliver(&mut nodes, &[1], now);
+        assert_eq!(nodes[1].role, Role::Leader);
+        nodes[1].propose(flag(3));
+        deliver(&mut nodes, &[1], now);
+        assert_eq!(actions(&mut nodes[1]), vec![flag(3)]);
+
+        // the previous leader steps down & drops its uncommitted entry.
+        let now = now + Duration::from_millis(10);
+        nodes[1].tick(now);
+        deliver(&mut nodes, &[], now);
+        assert_eq!(nodes[0].role, Role::Follower);
+        assert_eq!(actions(&mut nodes[0]), vec![flag(3)]);
+        assert_eq!(nodes[0].log, nodes[1].log);
+    }
+
+    #[test]
+    fn log_records() {
+        let now = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let mut node = Node::new(1, vec![2], Saved::default(), timeout, Duration::from_millis(10), now);
+        node.handle(
+            2,
+            Message::AppendEntries {
+                term: 3,
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![Entry { term: 2, action: None }, Entry {
+                    term: 2,
+                    action: Some(flag(1)),
+                }],
+                leader_commit: 0,
+                replicated_index: 0,
+            },
+            now,
+        );
+        node.handle(
+            2,
+            Message::AppendEntries {
+                term: 3,
+                prev_log_index: 1,
+                prev_log_term: 2,
+                entries: vec![Entry {
+                    term: 3,
+                    action: Some(flag(2)),
+                }],
+                leader_commit: 2,
+                replicated_index: 0,
+            },
+            now,
+        );
+
+        let mut data = String::new();
+        for record in node.out.records.iter() {
+            data.push_str(&serde_json::to_string(record).unwrap());
+            data.push('\n');
+        }
+        data.push_str("{\"record\": \"entry\", \"ind");
+        assert_eq!(load(&data), Saved {
+            term: 3,
+            log: node.log.clone(),
+            ..Saved::default()
+        });
+        assert_eq!(node.log.len(), 2);
+        assert_eq!(node.take_committed(), vec![
+            (1, Entry { term: 2, action: None }),
+            (2, node.log[1].clone())
+        ]);
+    }
+
+    #[test]
+    fn compaction() {
+        let now = Instant::now();
+        let mut nodes = cluster(3, now);
+        let now = now + Duration::from_millis(200);
+        nodes[0].tick(now);
+        deliver(&mut nodes, &[], now);
+        for i in 0..COMPACT_AFTER {
+            nodes[0].propose(flag(i as u8));
+        }
+        deliver(&mut nodes, &[], now);
+        let now = now + Duration::from_millis(10);
+        nodes[0].tick(now);
+        deliver(&mut nodes, &[], now);
+
+        // entries are only compacted once they were applied.
+        let last = COMPACT_AFTER + 1;
+        assert!(nodes.iter().all(|v| v.commit_index == last && v.replicated_index == last));
+        for node in nodes.iter_mut() {
+            assert_eq!(node.take_committed().len() as u64, last);
+            node.mark_applied(COMPACT_AFTER - 1);
+            assert!(!node.out.compacted);
+            node.mark_applied(last);
+            assert!(node.out.compacted);
+            assert!(node.log.is_empty());
+        }
+
+        // restarted from the rewritten file, nothing is applied again & the node still follows the leader.
+        let mut data = String::new();
+        for record in nodes[1].snapshot().iter() {
+            data.push_str(&serde_json::to_string(record).unwrap());
+            data.push('\n');
+        }
+        let saved = load(&data);
+        assert_eq!((saved.compacted, saved.applied), ((last, 1), last));
+        let timeout = Duration::from_millis(200);
+        nodes[1] = Node::new(2, vec![1, 3], saved, timeout, Duration::from_millis(10), now);
+        assert!(nodes[1].take_committed().is_empty());
+        nodes[0].propose(flag(1));
+        deliver(&mut nodes, &[], now);
+        let now = now + Duration::from_millis(10);
+        nodes[0].tick(now);
+        deliver(&mut nodes, &[], now);
+        assert_eq!(actions(&mut nodes[1]), vec![flag(1)]);
+    }
+}
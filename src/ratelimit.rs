@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// IPs that are tracked at once, floods of distinct addresses can't grow it further.
+const MAX_TRACKED_IPS: usize = 65536;
+
+/// Counts requests per IP over fixed windows, for the endpoints that are served without an access token.
+/// IPv6 addresses are counted per /64, the smallest prefix hosts usually get.
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    /// ip -> (start of its window, requests within it)
+    requests: std::sync::Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> RateLimiter {
+        RateLimiter {
+            limit,
+            window,
+            requests: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `ip`. Requests over the limit are refused with the time left until the window ends.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let ip = match ip {
+            IpAddr::V6(ip) => {
+                let segments = ip.segments();
+                IpAddr::V6(std::net::Ipv6Addr::new(
+                    segments[0],
+                    segments[1],
+                    segments[2],
+                    segments[3],
+                    0,
+                    0,
+                    0,
+                    0,
+                ))
+            }
+            ip => ip,
+        };
+
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() >= MAX_TRACKED_IPS && !requests.contains_key(&ip) {
+            let window = self.window;
+            requests.retain(|_, (start, _)| now.saturating_duration_since(*start) < window);
+            if requests.len() >= MAX_TRACKED_IPS {
+                return Err(self.window);
+            }
+        }
+
+        let (start, count) = requests.entry(ip).or_insert((now, 0));
+        if now.saturating_duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Err(self.window - now.saturating_duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert_eq!(limiter.check(ip, now), Ok(()));
+        assert_eq!(limiter.check(ip, now), Ok(()));
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(20)), Err(Duration::from_secs(40)));
+        assert_eq!(limiter.check("203.0.113.8".parse().unwrap(), now), Ok(()));
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(60)), Ok(()));
+
+        // addresses of a /64 share their limit.
+        assert_eq!(limiter.check("2001:db8::1".parse().unwrap(), now), Ok(()));
+        assert_eq!(limiter.check("2001:db8::2".parse().unwrap(), now), Ok(()));
+        assert!(limiter.check("2001:db8::ffff".parse().unwrap(), now).is_err());
+        assert_eq!(limiter.check("2001:db8:0:1::1".parse().unwrap(), now), Ok(()));
+    }
+}
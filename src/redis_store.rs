@@ -0,0 +1,188 @@
+/// A peer as stored in redis: `<prefix>:peers:<info_hash>` is a hash of peer ids to json encoded peers.
+pub struct RedisPeer {
+    pub info_hash: String,
+    pub peer_id: String,
+    pub data: String,
+}
+
+#[cfg(feature = "redis")]
+mod imp {
+    use super::RedisPeer;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Torrents updated this many seconds before the previous fetch are fetched again, publishes read redis' clock
+    /// a round trip before they write.
+    const FETCH_MARGIN: u64 = 5;
+
+    fn to_io_error(err: redis::RedisError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, err)
+    }
+
+    /// Redis' clock, in seconds since the unix epoch. Instances' clocks may differ, redis' is the same for all.
+    fn server_time(conn: &mut redis::Connection) -> redis::RedisResult<u64> {
+        let (secs, _micros): (u64, u64) = redis::cmd("TIME").query(conn)?;
+        Ok(secs)
+    }
+
+    /// Peers shared between udpt instances through redis. Requests run on the blocking thread pool, over a single
+    /// connection that's reopened after errors.
+    ///
+    /// Besides the peer hashes, `<prefix>:updated` is a sorted set of infohashes by when their peers were last
+    /// published, so instances only fetch the torrents that changed since their previous fetch.
+    pub struct RedisStore {
+        client: redis::Client,
+        connection: Arc<Mutex<Option<redis::Connection>>>,
+        prefix: String,
+        /// Redis' clock when peers were last fetched, 0 before the first fetch.
+        fetched: AtomicU64,
+    }
+
+    impl RedisStore {
+        pub async fn open(url: &str, prefix: &str) -> Result<RedisStore, std::io::Error> {
+            let client = redis::Client::open(url).map_err(to_io_error)?;
+            let store = RedisStore {
+                client,
+                connection: Arc::new(Mutex::new(None)),
+                prefix: prefix.to_string(),
+                fetched: AtomicU64::new(0),
+            };
+
+            // fail early if redis can't be reached.
+            store.run(|_, _| Ok(())).await?;
+            Ok(store)
+        }
+
+        /// Runs `f` with the connection & key prefix on the blocking thread pool, connecting first if needed.
+        async fn run<T, F>(&self, f: F) -> Result<T, std::io::Error>
+        where
+            T: Send + 'static,
+            F: FnOnce(&mut redis::Connection, &str) -> redis::RedisResult<T> + Send + 'static, {
+            let client = self.client.clone();
+            let connection = self.connection.clone();
+            let prefix = self.prefix.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut connection = connection.lock().unwrap();
+                if connection.is_none() {
+                    *connection = Some(client.get_connection()?);
+                }
+                let result = f(connection.as_mut().unwrap(), &prefix);
+                if result.is_err() {
+                    // the connection may be broken, the next request reconnects.
+                    *connection = None;
+                }
+                result
+            })
+            .await?
+            .map_err(to_io_error)
+        }
+
+        /// Writes `peers`, each torrent's hash expires after `ttl` seconds without updates.
+        pub async fn publish(&self, peers: Vec<RedisPeer>, ttl: usize) -> Result<(), std::io::Error> {
+            self.run(move |conn, prefix| {
+                let now = server_time(conn)?;
+                let updated = format!("{}:updated", prefix);
+                let mut pipe = redis::pipe();
+                for peer in peers.iter() {
+                    let key = format!("{}:peers:{}", prefix, peer.info_hash);
+                    pipe.hset(&key, &peer.peer_id, &peer.data).ignore();
+                    pipe.expire(&key, ttl).ignore();
+                    pipe.zadd(&updated, &peer.info_hash, now).ignore();
+                }
+                // torrents whose hashes expired.
+                pipe.cmd("ZREMRANGEBYSCORE")
+                    .arg(&updated)
+                    .arg("-inf")
+                    .arg(now.saturating_sub(ttl as u64))
+                    .ignore();
+                pipe.query::<()>(conn)
+            })
+            .await
+        }
+
+        /// Deletes `peers`, only `info_hash` & `peer_id` are used.
+        pub async fn remove(&self, peers: Vec<RedisPeer>) -> Result<(), std::io::Error> {
+            self.run(move |conn, prefix| {
+                let mut pipe = redis::pipe();
+                for peer in peers.iter() {
+                    pipe.hdel(format!("{}:peers:{}", prefix, peer.info_hash), &peer.peer_id)
+                        .ignore();
+                }
+                pipe.query::<()>(conn)
+            })
+            .await
+        }
+
+        /// Reads the peers of the torrents published since the previous fetch, or of every torrent on the first.
+        pub async fn fetch(&self) -> Result<Vec<RedisPeer>, std::io::Error> {
+            let since = match self.fetched.load(Ordering::Relaxed) {
+                0 => String::from("-inf"),
+                fetched => fetched.saturating_sub(FETCH_MARGIN).to_string(),
+            };
+            let (peers, now) = self
+                .run(move |conn, prefix| {
+                    let now = server_time(conn)?;
+                    let info_hashes: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+                        .arg(format!("{}:updated", prefix))
+                        .arg(since)
+                        .arg("+inf")
+                        .query(conn)?;
+                    if info_hashes.is_empty() {
+                        return Ok((Vec::new(), now));
+                    }
+
+                    let mut pipe = redis::pipe();
+                    for info_hash in info_hashes.iter() {
+                        pipe.cmd("HGETALL").arg(format!("{}:peers:{}", prefix, info_hash));
+                    }
+                    let torrents: Vec<Vec<(String, String)>> = pipe.query(conn)?;
+
+                    let mut peers = Vec::new();
+                    for (info_hash, fields) in info_hashes.into_iter().zip(torrents) {
+                        for (peer_id, data) in fields {
+                            peers.push(RedisPeer {
+                                info_hash: info_hash.clone(),
+                                peer_id,
+                                data,
+                            });
+                        }
+                    }
+                    Ok((peers, now))
+                })
+                .await?;
+            self.fetched.store(now, Ordering::Relaxed);
+            Ok(peers)
+        }
+    }
+}
+
+#[cfg(not(feature = "redis"))]
+mod imp {
+    use super::RedisPeer;
+
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, "udpt was built without redis support")
+    }
+
+    pub struct RedisStore;
+
+    impl RedisStore {
+        pub async fn open(_url: &str, _prefix: &str) -> Result<RedisStore, std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn publish(&self, _peers: Vec<RedisPeer>, _ttl: usize) -> Result<(), std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn remove(&self, _peers: Vec<RedisPeer>) -> Result<(), std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn fetch(&self) -> Result<Vec<RedisPeer>, std::io::Error> {
+            Err(unsupported())
+        }
+    }
+}
+
+pub use imp::RedisStore;
@@ -0,0 +1,172 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Responses that are held at once, floods of distinct requests can't grow it further.
+const MAX_RESPONSES: usize = 65536;
+/// Requests are spread over shards by transaction id, so they don't all wait for one lock.
+const SHARDS: usize = 16;
+
+/// A request as it's matched against earlier ones.
+#[derive(Clone, Copy)]
+pub struct Request {
+    from: SocketAddr,
+    transaction_id: u32,
+    /// Hash of the whole request, transaction ids that are reused for another request aren't replayed.
+    digest: u64,
+}
+
+impl Request {
+    pub fn new(from: &SocketAddr, transaction_id: u32, payload: &[u8]) -> Request {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        Request {
+            from: *from,
+            transaction_id,
+            digest: hasher.finish(),
+        }
+    }
+}
+
+struct CachedResponse {
+    stored: Instant,
+    digest: u64,
+    to: SocketAddr,
+    response: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Shard {
+    responses: HashMap<(SocketAddr, u32), CachedResponse>,
+    /// Keys in the order they were stored, the oldest first.
+    stored: VecDeque<((SocketAddr, u32), Instant)>,
+}
+
+impl Shard {
+    /// Drops the responses that expired, from the oldest until one that didn't.
+    fn expire(&mut self, ttl: Duration, now: Instant) {
+        while let Some((key, stored)) = self.stored.front() {
+            if now.saturating_duration_since(*stored) < ttl {
+                break;
+            }
+            // the response may have been replaced since.
+            if self.responses.get(key).map(|v| v.stored) == Some(*stored) {
+                self.responses.remove(key);
+            }
+            self.stored.pop_front();
+        }
+    }
+}
+
+/// Recent announce & scrape responses by (client, transaction id). BEP 15 clients resend a request with the same
+/// transaction id when its response is lost, those are answered with the response that was sent before, so events
+/// such as `completed` aren't counted twice.
+pub struct ResponseCache {
+    shards: Vec<std::sync::Mutex<Shard>>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> ResponseCache {
+        ResponseCache {
+            shards: (0..SHARDS).map(|_| std::sync::Mutex::new(Shard::default())).collect(),
+        }
+    }
+}
+
+impl ResponseCache {
+    fn shard(&self, request: &Request) -> std::sync::MutexGuard<'_, Shard> {
+        self.shards[request.transaction_id as usize % SHARDS].lock().unwrap()
+    }
+
+    /// The response to an identical request that was answered within `ttl`, and the address it was sent to.
+    pub fn get(&self, request: &Request, ttl: Duration, now: Instant) -> Option<(SocketAddr, Vec<u8>)> {
+        let shard = self.shard(request);
+        match shard.responses.get(&(request.from, request.transaction_id)) {
+            Some(cached) if cached.digest == request.digest && now.saturating_duration_since(cached.stored) < ttl => {
+                Some((cached.to, cached.response.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Stores a response for `ttl`. While the request's shard is full of responses that didn't expire yet, responses
+    /// aren't cached; their requests are answered again if they're resent.
+    pub fn insert(&self, request: &Request, to: &SocketAddr, response: &[u8], ttl: Duration, now: Instant) {
+        let mut shard = self.shard(request);
+        shard.expire(ttl, now);
+        if shard.stored.len() >= MAX_RESPONSES / SHARDS {
+            return;
+        }
+        let key = (request.from, request.transaction_id);
+        shard.stored.push_back((key, now));
+        shard.responses.insert(key, CachedResponse {
+            stored: now,
+            digest: request.digest,
+            to: *to,
+            response: response.to_vec(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let cache = ResponseCache::default();
+        let ttl = Duration::from_secs(20);
+        let now = Instant::now();
+        let from: SocketAddr = "203.0.113.7:6881".parse().unwrap();
+        let request = Request::new(&from, 1, b"announce");
+
+        assert_eq!(cache.get(&request, ttl, now), None);
+        cache.insert(&request, &from, b"response", ttl, now);
+        assert_eq!(
+            cache.get(&request, ttl, now + Duration::from_secs(15)),
+            Some((from, b"response".to_vec()))
+        );
+        assert_eq!(cache.get(&request, ttl, now + ttl), None);
+
+        // another request, transaction or client.
+        assert_eq!(cache.get(&Request::new(&from, 1, b"scrape"), ttl, now), None);
+        assert_eq!(cache.get(&Request::new(&from, 2, b"announce"), ttl, now), None);
+        let other: SocketAddr = "203.0.113.7:6882".parse().unwrap();
+        assert_eq!(cache.get(&Request::new(&other, 1, b"announce"), ttl, now), None);
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = ResponseCache::default();
+        let ttl = Duration::from_secs(20);
+        let now = Instant::now();
+        let from: SocketAddr = "203.0.113.7:6881".parse().unwrap();
+        let request = |transaction_id| Request::new(&from, transaction_id, b"announce");
+
+        // a full shard doesn't cache more responses until its oldest expire.
+        let count = (MAX_RESPONSES / SHARDS) as u32;
+        for i in 0..count {
+            cache.insert(
+                &request(i * SHARDS as u32),
+                &from,
+                b"response",
+                ttl,
+                now + Duration::from_millis(i.into()),
+            );
+        }
+        let later = now + Duration::from_secs(1);
+        cache.insert(&request(count * SHARDS as u32), &from, b"response", ttl, later);
+        assert_eq!(cache.get(&request(count * SHARDS as u32), ttl, later), None);
+        assert!(cache.get(&request(SHARDS as u32), ttl, later).is_some());
+        // other shards still cache responses.
+        cache.insert(&request(1), &from, b"response", ttl, later);
+        assert!(cache.get(&request(1), ttl, later).is_some());
+
+        let expired = now + ttl + Duration::from_millis(1);
+        cache.insert(&request(count * SHARDS as u32), &from, b"response", ttl, expired);
+        assert!(cache.get(&request(count * SHARDS as u32), ttl, expired).is_some());
+        assert_eq!(cache.shard(&request(0)).responses.len(), count as usize - 1);
+    }
+}
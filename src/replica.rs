@@ -0,0 +1,67 @@
+use crate::bans::Ban;
+use crate::config::ReplicaConfig;
+use crate::tracker::InfoHash;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisteredTorrent {
+    pub info_hash: InfoHash,
+    pub is_flagged: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegisteredUser {
+    pub name: String,
+    pub passkey: String,
+    pub is_banned: bool,
+}
+
+/// The moderated state of a tracker, as served by a primary to its replicas.
+#[derive(Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub torrents: Vec<RegisteredTorrent>,
+    pub users: Vec<RegisteredUser>,
+    /// Missing from the snapshots of primaries that predate replicated bans.
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+}
+
+/// Pulls the registry of a primary through its REST API.
+/// The access token is sent in an `Authorization` header, so it doesn't end up in the primary's access logs.
+pub struct Replica {
+    client: crate::http::Client,
+    url: String,
+    token: String,
+}
+
+impl Replica {
+    pub fn new(config: &ReplicaConfig) -> Replica {
+        Replica {
+            client: crate::http::client(),
+            url: format!("{}/replication", config.get_primary_url().trim_end_matches('/')),
+            token: config.get_token().to_string(),
+        }
+    }
+
+    pub async fn fetch(&self) -> Result<RegistrySnapshot, std::io::Error> {
+        let to_io_error = |err: String| std::io::Error::new(std::io::ErrorKind::Other, err);
+
+        let request = hyper::Request::get(self.url.as_str())
+            .header(hyper::header::AUTHORIZATION, format!("Bearer {}", self.token))
+            .body(hyper::Body::empty())
+            .map_err(|e| to_io_error(e.to_string()))?;
+        let timeout = std::time::Duration::from_secs(30);
+        let response = match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Ok(v) => v.map_err(|e| to_io_error(e.to_string()))?,
+            Err(_) => return Err(to_io_error(String::from("primary timed out"))),
+        };
+        if !response.status().is_success() {
+            return Err(to_io_error(format!("primary responded with {}", response.status())));
+        }
+
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| to_io_error(e.to_string()))?;
+        serde_json::from_slice(&body).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,353 @@
+//! Confines udpt, so a bug in the packet handlers can't be escalated into running programs or touching files
+//! outside of the ones udpt uses. Linux only.
+
+/// Restricts filesystem access with Landlock, see landlock(7): `read_paths` become read-only and the directories
+/// `write_dirs` writable, everything else (executing programs included) is denied.
+/// Landlock only confines the calling thread & the threads it starts, so this must run before the runtime is started.
+#[cfg(target_os = "linux")]
+pub fn restrict_paths(read_paths: &[&str], write_dirs: &[&str]) -> Result<(), String> {
+    use landlock::*;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(format!("Landlock isn't available: {}", std::io::Error::last_os_error()));
+    }
+    // rights of later ABIs are only handled, and granted, by kernels that know them.
+    let mut write_access = ACCESS_WRITE;
+    if abi >= 2 {
+        write_access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        write_access |= ACCESS_FS_TRUNCATE;
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_V1 | write_access,
+    };
+
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        return Err(format!(
+            "failed to create a Landlock ruleset: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let ruleset = unsafe { <std::fs::File as std::os::unix::io::FromRawFd>::from_raw_fd(ruleset as i32) };
+
+    let reads = read_paths.iter().map(|path| (*path, ACCESS_READ));
+    let rules = reads.chain(write_dirs.iter().map(|dir| (*dir, write_access)));
+    for (path, access) in rules {
+        let file = match std::fs::OpenOptions::new().read(true).custom_flags(libc::O_PATH).open(path) {
+            Ok(v) => v,
+            // nothing to allow.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(format!("failed to open \"{}\": {}", path, err)),
+        };
+        let is_dir = file.metadata().map(|v| v.is_dir()).unwrap_or(false);
+        let rule = PathBeneathAttr {
+            allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+            parent_fd: file.as_raw_fd(),
+        };
+        let res = unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), RULE_PATH_BENEATH, &rule, 0) };
+        if res != 0 {
+            return Err(format!("failed to allow \"{}\": {}", path, std::io::Error::last_os_error()));
+        }
+    }
+
+    set_no_new_privs()?;
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(format!(
+            "failed to enforce the Landlock ruleset: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_paths(_read_paths: &[&str], _write_dirs: &[&str]) -> Result<(), String> {
+    Err(String::from("Landlock is only supported on Linux"))
+}
+
+/// Constants & structures of the Landlock ABI, see linux/landlock.h.
+#[cfg(target_os = "linux")]
+mod landlock {
+    pub const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    pub const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    pub const ACCESS_FS_REFER: u64 = 1 << 13;
+    pub const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    /// Every right of the first ABI: the above, and creating directories, devices, sockets, fifos & symlinks.
+    pub const ACCESS_V1: u64 = (1 << 13) - 1;
+    pub const ACCESS_READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    /// Saving writes a temporary file next to the target and renames it, logs are rotated, sqlite keeps journals,
+    /// the handover socket is replaced.
+    pub const ACCESS_WRITE: u64 =
+        ACCESS_READ | ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_MAKE_SOCK | ACCESS_FS_REMOVE_FILE;
+    /// Rights that apply to files, rather than directories.
+    pub const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+    #[repr(C)]
+    pub struct RulesetAttr {
+        pub handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    pub struct PathBeneathAttr {
+        pub allowed_access: u64,
+        pub parent_fd: i32,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_no_new_privs() -> Result<(), String> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(format!("failed to set no_new_privs: {}", std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Installs a seccomp filter denying syscalls udpt never needs, see seccomp(2). It applies to every thread, and
+/// can't be removed.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn deny_syscalls() -> Result<(), String> {
+    let denied: Vec<u32> = seccomp::DENIED_SYSCALLS.iter().map(|nr| *nr as u32).collect();
+    let filter = seccomp::build_filter(seccomp::AUDIT_ARCH, &denied);
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr() as *mut libc::sock_filter,
+    };
+
+    set_no_new_privs()?;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        -1 => {
+            Err(format!(
+                "failed to install the seccomp filter: {}",
+                std::io::Error::last_os_error()
+            ))
+        }
+        thread => Err(format!("failed to install the seccomp filter on thread {}", thread)),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn deny_syscalls() -> Result<(), String> {
+    Err(String::from(
+        "seccomp filters are only supported on Linux, on x86_64 & aarch64",
+    ))
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    /// Syscalls udpt never makes once it's running: running programs, debugging other processes, changing
+    /// credentials, namespaces or the system's configuration, and loading kernel code.
+    pub const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_personality,
+        libc::SYS_userfaultfd,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_io_uring_setup,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_adjtimex,
+        libc::SYS_open_by_handle_at,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    pub const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    pub const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscalls of the x32 ABI have this bit set, they're denied as they'd bypass the filter.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    // classic BPF opcodes, see linux/filter.h.
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    #[cfg(any(test, target_arch = "x86_64"))]
+    const BPF_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+    /// A classic BPF instruction, as `struct sock_filter`.
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct Instruction {
+        code: u16,
+        jt: u8,
+        jf: u8,
+        k: u32,
+    }
+
+    fn statement(code: u16, k: u32) -> Instruction {
+        Instruction { code, jt: 0, jf: 0, k }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Instruction {
+        Instruction { code, jt, jf, k }
+    }
+
+    /// A seccomp filter failing `denied` syscalls with EPERM. Syscalls of other architectures kill the process.
+    pub fn build_filter(arch: u32, denied: &[u32]) -> Vec<Instruction> {
+        // offsets of struct seccomp_data.
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+
+        let mut filter = vec![
+            statement(BPF_LD_W_ABS, ARCH),
+            jump(BPF_JEQ_K, arch, 1, 0),
+            statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, NR),
+        ];
+        // every check jumps to the final instruction, which denies.
+        #[cfg(target_arch = "x86_64")]
+        filter.push(jump(BPF_JGE_K, X32_SYSCALL_BIT, denied.len() as u8 + 1, 0));
+        for (i, nr) in denied.iter().enumerate() {
+            filter.push(jump(BPF_JEQ_K, *nr, (denied.len() - i) as u8, 0));
+        }
+        filter.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+        filter.push(statement(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        filter
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Runs a filter built by `build_filter`, which only uses loads, jumps & returns.
+        fn run(filter: &[Instruction], arch: u32, nr: u32) -> u32 {
+            let (mut pc, mut acc) = (0, 0);
+            loop {
+                let ins = filter[pc];
+                pc += 1;
+                match ins.code {
+                    BPF_LD_W_ABS => acc = if ins.k == 4 { arch } else { nr },
+                    BPF_JEQ_K => pc += if acc == ins.k { ins.jt } else { ins.jf } as usize,
+                    BPF_JGE_K => pc += if acc >= ins.k { ins.jt } else { ins.jf } as usize,
+                    BPF_RET_K => return ins.k,
+                    code => panic!("unexpected instruction {:#x}", code),
+                }
+            }
+        }
+
+        #[test]
+        fn seccomp_filter() {
+            let filter = build_filter(AUDIT_ARCH, &[59, 101, 322]);
+            let denied = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+            for nr in [59, 101, 322].iter() {
+                assert_eq!(run(&filter, AUDIT_ARCH, *nr), denied);
+            }
+            for nr in [0, 1, 60, 321, 323].iter() {
+                assert_eq!(run(&filter, AUDIT_ARCH, *nr), SECCOMP_RET_ALLOW);
+            }
+            // i386 syscalls.
+            assert_eq!(run(&filter, 0x4000_0003, 1), SECCOMP_RET_KILL_PROCESS);
+            #[cfg(target_arch = "x86_64")]
+            assert_eq!(run(&filter, AUDIT_ARCH, X32_SYSCALL_BIT | 1), denied);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn landlock() {
+        let dir = std::env::temp_dir().join(format!("udpt-sandbox-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir_path = dir.to_str().unwrap().to_string();
+
+        // Landlock confines this thread only.
+        let res = std::thread::spawn(move || {
+            restrict_paths(&[], &[&dir_path])?;
+            let inside = std::fs::write(format!("{}/db", dir_path), b"ok").is_ok();
+            let outside = std::fs::read("/etc/hostname").is_ok() || std::fs::read_dir("/").is_ok();
+            Ok::<_, String>((inside, outside))
+        })
+        .join()
+        .unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        match res {
+            Ok((inside, outside)) => assert!(inside && !outside),
+            // kernels without Landlock.
+            Err(err) => assert!(err.starts_with("Landlock isn't available"), "{}", err),
+        }
+    }
+}
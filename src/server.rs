@@ -1,5 +1,6 @@
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use std;
+use std::convert::TryFrom;
 use std::io::Write;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -8,15 +9,27 @@ use tokio::net::UdpSocket;
 use bincode;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Configuration;
+use crate::abuse::Abuse;
+use crate::analytics::AnalyticsEvent;
+use crate::auth_hook::{AuthHook, Authorizer};
+use crate::bufpool::BufferPool;
+use crate::config::{AuthCheck, Configuration, ListenerConfig, Parsing};
+use crate::replay::{Request, ResponseCache};
 use crate::stackvec::StackVec;
 use crate::tracker;
+use crate::tracker::ParseQuirk;
 
 // maximum MTU is usually 1500, but our stack allows us to allocate the maximum - so why not?
 const MAX_PACKET_SIZE: usize = 0xffff;
+// idle packet buffers kept for reuse, 64KiB each.
+const MAX_IDLE_BUFFERS: usize = 256;
+// maximum amount of peers in an announce response.
+const MAX_ANNOUNCE_PEERS: usize = 74;
 
 // protocol contants
 const PROTOCOL_ID: u64 = 0x0000041727101980;
+/// How long connection ids are reused as a client, they're valid for a minute (BEP 15) so they're renewed a bit earlier.
+pub const CONNECTION_TTL: std::time::Duration = std::time::Duration::from_secs(50);
 
 #[repr(u32)]
 #[derive(Serialize, Deserialize)]
@@ -100,6 +113,70 @@ struct UDPAnnounceResponse {
     seeders: u32,
 }
 
+/// Concatenates the URLData options of a BEP 41 options payload.
+/// Also returns the first quirk of the options: options cut short, bytes other than padding after EndOfOptions, or
+/// options of a reserved type.
+fn parse_url_data(mut options: &[u8]) -> (Vec<u8>, Option<ParseQuirk>) {
+    const END_OF_OPTIONS: u8 = 0x0;
+    const NOP: u8 = 0x1;
+    const URL_DATA: u8 = 0x2;
+
+    let mut url = Vec::new();
+    let mut quirk = None;
+    while let Some((&option, rest)) = options.split_first() {
+        options = rest;
+        match option {
+            END_OF_OPTIONS => {
+                if options.iter().any(|v| *v != 0) {
+                    quirk = quirk.or(Some(ParseQuirk::TrailingData));
+                }
+                break;
+            }
+            NOP => continue,
+            _ => {
+                if option != URL_DATA {
+                    quirk = quirk.or(Some(ParseQuirk::ReservedBits));
+                }
+                // unknown options have a length byte too, they're skipped.
+                let (&len, rest) = match options.split_first() {
+                    Some(v) => v,
+                    None => return (url, quirk.or(Some(ParseQuirk::TrailingData))),
+                };
+                if len as usize > rest.len() {
+                    quirk = quirk.or(Some(ParseQuirk::TrailingData));
+                }
+                let len = (len as usize).min(rest.len());
+                if option == URL_DATA {
+                    url.extend_from_slice(&rest[..len]);
+                }
+                options = &rest[len..];
+            }
+        }
+    }
+    (url, quirk)
+}
+
+/// Extracts a passkey from an announce URL's path & query.
+/// Accepts `?passkey=<key>`, `/<key>/announce` and `/announce/<key>`, other paths carry no passkey.
+fn parse_passkey(url: &str) -> Option<&str> {
+    let (path, query) = match url.find('?') {
+        Some(pos) => (&url[..pos], &url[pos + 1..]),
+        None => (url, ""),
+    };
+
+    for param in query.split('&') {
+        if param.starts_with("passkey=") && param.len() > 8 {
+            return Some(&param[8..]);
+        }
+    }
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [passkey, "announce"] | ["announce", passkey] if !passkey.is_empty() && *passkey != "announce" => Some(*passkey),
+        _ => None,
+    }
+}
+
 #[derive(Serialize)]
 struct UDPScrapeResponseEntry {
     seeders: u32,
@@ -107,54 +184,175 @@ struct UDPScrapeResponseEntry {
     leechers: u32,
 }
 
+/// Overrides of a listener, settings that aren't overridden are taken from the tracker's configuration.
+#[derive(Default)]
+struct ListenerPolicy {
+    mode: Option<tracker::TrackerMode>,
+    announce_interval: Option<u32>,
+    max_peers: Option<usize>,
+    /// Torrents refused on this listener only.
+    blacklist: std::collections::HashSet<tracker::InfoHash>,
+}
+
+impl ListenerPolicy {
+    async fn load(listener: &ListenerConfig) -> Result<ListenerPolicy, std::io::Error> {
+        let mut blacklist: std::collections::HashSet<_> = listener.get_blacklist().iter().cloned().collect();
+        if let Some(path) = listener.get_blacklist_path() {
+            blacklist.extend(tracker::parse_infohash_list(&tokio::fs::read_to_string(path).await?));
+        }
+        Ok(ListenerPolicy {
+            mode: listener.get_mode().cloned(),
+            announce_interval: listener.get_announce_interval(),
+            max_peers: listener.get_max_peers(),
+            blacklist,
+        })
+    }
+}
+
 pub struct UDPTracker {
     srv_send: tokio::net::udp::SendHalf,
     srv_recv: Option<tokio::net::udp::RecvHalf>,
     tracker: std::sync::Arc<tracker::TorrentTracker>,
-    config: Arc<Configuration>,
+    local_addr: SocketAddr,
+    policy: ListenerPolicy,
+    authorizer: Option<Box<dyn Authorizer>>,
+    auth_check: AuthCheck,
+    /// Request & response buffers.
+    buffers: Arc<BufferPool>,
+    responses: ResponseCache,
 }
 
 impl UDPTracker {
+    /// Binds the listener of the `[udp]` section.
+    /// `socket` is a socket passed by systemd for `udp.bind_address`, it's used instead of binding one.
     pub async fn new(
-        config: Arc<Configuration>, tracker: std::sync::Arc<tracker::TorrentTracker>,
+        config: Arc<Configuration>, tracker: std::sync::Arc<tracker::TorrentTracker>, socket: Option<std::net::UdpSocket>,
+    ) -> Result<UDPTracker, std::io::Error> {
+        let address = config.get_udp_config().get_address().to_string();
+        Self::bind(config, &address, ListenerPolicy::default(), tracker, socket).await
+    }
+
+    /// Binds an additional listener, which shares the torrents of `tracker`.
+    pub async fn with_listener(
+        config: Arc<Configuration>, listener: &ListenerConfig, tracker: std::sync::Arc<tracker::TorrentTracker>,
+        socket: Option<std::net::UdpSocket>,
     ) -> Result<UDPTracker, std::io::Error> {
-        let cfg = config.clone();
+        let policy = ListenerPolicy::load(listener).await?;
+        Self::bind(config, listener.get_address(), policy, tracker, socket).await
+    }
 
-        let server = UdpSocket::bind(cfg.get_udp_config().get_address()).await?;
+    async fn bind(
+        config: Arc<Configuration>, address: &str, policy: ListenerPolicy, tracker: std::sync::Arc<tracker::TorrentTracker>,
+        socket: Option<std::net::UdpSocket>,
+    ) -> Result<UDPTracker, std::io::Error> {
+        let server = match socket {
+            Some(socket) => {
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)?
+            }
+            None => UdpSocket::bind(address).await?,
+        };
+        let local_addr = server.local_addr()?;
         let (srv_recv, srv_send) = server.split();
+        let auth_hook = config.get_auth_hook_config();
 
         Ok(UDPTracker {
             srv_send,
             srv_recv: Some(srv_recv),
+            local_addr,
             tracker,
-            config: cfg,
+            authorizer: auth_hook.map(|v| Box::new(AuthHook::new(v)) as Box<dyn Authorizer>),
+            auth_check: auth_hook.map(|v| v.get_check().clone()).unwrap_or(AuthCheck::UnknownTorrents),
+            buffers: BufferPool::new(MAX_PACKET_SIZE, MAX_IDLE_BUFFERS),
+            responses: ResponseCache::default(),
+            policy,
         })
     }
 
+    /// Authorizes announces with `authorizer` instead of `auth_hook`. `check` picks the announces that are checked.
+    pub fn set_authorizer(&mut self, authorizer: Box<dyn Authorizer>, check: AuthCheck) {
+        self.authorizer = Some(authorizer);
+        self.auth_check = check;
+    }
+
+    /// The address the listener is bound to, for probing it with `probe`.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    fn mode(&self) -> tracker::TrackerMode {
+        self.policy.mode.unwrap_or_else(|| self.tracker.get_mode())
+    }
+
+    fn announce_interval(&self) -> u32 {
+        let config = self.tracker.get_config();
+        let interval = self
+            .policy
+            .announce_interval
+            .unwrap_or_else(|| config.get_announce_interval());
+        interval.max(config.get_tracker_config().get_min_announce_interval().unwrap_or(0))
+    }
+
     async fn handle_packet(&self, remote_address: &SocketAddr, payload: &[u8]) {
-        let header: UDPRequestHeader = match unpack(payload) {
+        let header: Option<UDPRequestHeader> = unpack(payload);
+        let is_connect = matches!(
+            header,
+            Some(UDPRequestHeader {
+                action: Actions::Connect,
+                ..
+            })
+        );
+        self.tracker
+            .get_traffic()
+            .record(remote_address.ip(), payload.len(), is_connect);
+        let header = match header {
             Some(val) => val,
             None => {
                 trace!("failed to parse packet from {}", remote_address);
+                self.tracker.get_requests().count_drop();
+                self.tracker.count_malformed(remote_address.ip());
                 return;
             }
         };
 
         match header.action {
             Actions::Connect => self.handle_connect(remote_address, &header, payload).await,
-            Actions::Announce => self.handle_announce(remote_address, &header, payload).await,
-            Actions::Scrape => self.handle_scrape(remote_address, &header, payload).await,
+            Actions::Announce => {
+                self.tracker.get_requests().count_announce();
+                let request = Request::new(remote_address, header.transaction_id, payload);
+                if self.replay(&request).await {
+                    return;
+                }
+                let started = std::time::Instant::now();
+                self.handle_announce(remote_address, &header, payload, &request).await;
+                self.tracker.log_if_slow("announce", remote_address, started);
+            }
+            Actions::Scrape => {
+                self.tracker.get_requests().count_scrape();
+                let request = Request::new(remote_address, header.transaction_id, payload);
+                if self.replay(&request).await {
+                    return;
+                }
+                self.handle_scrape(remote_address, &header, payload, &request).await
+            }
             _ => {
                 trace!("invalid action from {}", remote_address);
+                self.tracker.get_requests().count_drop();
+                self.tracker.count_malformed(remote_address.ip());
                 // someone is playing around... ignore request.
                 return;
             }
         }
     }
 
-    async fn handle_connect(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, _payload: &[u8]) {
+    async fn handle_connect(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, payload: &[u8]) {
         if header.connection_id != PROTOCOL_ID {
             trace!("Bad protocol magic from {}", remote_addr);
+            self.tracker.get_requests().count_drop();
+            self.tracker.count_malformed(remote_addr.ip());
+            return;
+        }
+        if payload.len() > 16 && !self.tolerate(remote_addr, ParseQuirk::TrailingData) {
             return;
         }
 
@@ -169,16 +367,18 @@ impl UDPTracker {
             connection_id: conn_id,
         };
 
-        let mut payload_buffer = vec![0u8; MAX_PACKET_SIZE];
-        let mut payload = StackVec::from(payload_buffer.as_mut_slice());
+        let mut payload_buffer = self.buffers.get();
+        let mut payload = StackVec::from(&mut payload_buffer[..]);
 
         if let Ok(_) = pack_into(&mut payload, &response) {
             let _ = self.send_packet(remote_addr, payload.as_slice()).await;
         }
     }
 
-    async fn handle_announce(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, payload: &[u8]) {
+    async fn handle_announce(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, payload: &[u8], request: &Request) {
         if header.connection_id != self.get_connection_id(remote_addr) {
+            self.tracker.get_requests().count_drop();
+            self.tracker.get_traffic().count_invalid_connection_id();
             return;
         }
 
@@ -186,19 +386,53 @@ impl UDPTracker {
             Some(v) => v,
             None => {
                 trace!("failed to unpack announce request from {}", remote_addr);
+                self.tracker.count_malformed(remote_addr.ip());
                 return;
             }
         };
 
+        if self
+            .tracker
+            .is_banned(&remote_addr.ip(), Some(tracker::PeerId::from_array(&packet.peer_id)))
+        {
+            log_fields!(log::Level::Trace, "rejected announce", ip = remote_addr, reason = "banned");
+            self.send_error(remote_addr, &packet.header, "banned.").await;
+            return;
+        }
+
+        let mut url_data = Vec::new();
         if let Ok(_plen) = bincode::serialized_size(&packet) {
             let plen = _plen as usize;
             if payload.len() > plen {
                 let bep41_payload = &payload[plen..];
-
-                // TODO: process BEP0041 payload.
                 trace!("BEP0041 payload of {} bytes from {}", bep41_payload.len(), remote_addr);
+                let (url, quirk) = parse_url_data(bep41_payload);
+                if let Some(quirk) = quirk {
+                    if !self.tolerate(remote_addr, quirk) {
+                        return;
+                    }
+                }
+                url_data = url;
             }
         }
+        if packet.port == 0 && !self.tolerate(remote_addr, ParseQuirk::ZeroPort) {
+            return;
+        }
+
+        let passkey = std::str::from_utf8(&url_data).ok().and_then(parse_passkey);
+        let user = if self.mode() == tracker::TrackerMode::PrivateMode {
+            match self.tracker.get_users().authenticate(passkey) {
+                Ok(name) => Some(name),
+                Err(reason) => {
+                    log_fields!(log::Level::Trace, "rejected announce", ip = remote_addr, reason = reason);
+                    self.tracker.report_abuse(Abuse::AuthFailure, remote_addr.ip());
+                    self.send_error(remote_addr, &packet.header, reason).await;
+                    return;
+                }
+            }
+        } else {
+            None
+        };
 
         if packet.ip_address != 0 {
             // TODO: allow configurability of ip address
@@ -208,16 +442,65 @@ impl UDPTracker {
         }
 
         let client_addr = SocketAddr::new(remote_addr.ip(), packet.port);
+        // peers on the tracker's own host would be handed out with an address that's unreachable for others.
+        let peer_addr = match self
+            .tracker
+            .get_config()
+            .get_tracker_config()
+            .get_external_ip(&remote_addr.ip())
+        {
+            Some(ip) if remote_addr.ip().is_loopback() => SocketAddr::new(ip, packet.port),
+            _ => client_addr,
+        };
         let info_hash = packet.info_hash.into();
 
         let peer_id: &tracker::PeerId = tracker::PeerId::from_array(&packet.peer_id);
 
+        if let Some(ref authorizer) = self.authorizer {
+            let is_unknown = self.tracker.with_torrent(&info_hash, |_| ()).await.is_none();
+            if is_unknown || self.auth_check == AuthCheck::All {
+                if let Err(reason) = authorizer.authorize(&info_hash, passkey, remote_addr.ip()).await {
+                    log_fields!(
+                        log::Level::Trace,
+                        "announce rejected by authorization hook",
+                        info_hash = info_hash,
+                        ip = remote_addr,
+                        reason = reason,
+                    );
+                    self.tracker.report_abuse(Abuse::AuthFailure, remote_addr.ip());
+                    self.send_error(remote_addr, &packet.header, &reason).await;
+                    return;
+                }
+                if is_unknown && self.mode() != tracker::TrackerMode::DynamicMode {
+                    if self.tracker.add_torrent(&info_hash).await.is_err() {
+                        warn!("failed to register authorized torrent {}, it's an alias.", info_hash);
+                    }
+                }
+            }
+        }
+
+        if self.policy.blacklist.contains(&info_hash) {
+            let config = self.tracker.get_config();
+            self.send_error(remote_addr, &packet.header, &config.get_tracker_config().get_flagged_error())
+                .await;
+            return;
+        }
+        // a listener in another mode than the tracker only serves the torrents it knows: a `dynamic` one on a
+        // `static` or `private` tracker doesn't register them, they'd be served by every listener.
+        if self.policy.mode.is_some()
+            && self.mode() != self.tracker.get_mode()
+            && self.tracker.with_torrent(&info_hash, |_| ()).await.is_none()
+        {
+            self.send_error(remote_addr, &packet.header, "torrent not registered.").await;
+            return;
+        }
+
         match self
             .tracker
             .update_torrent_and_get_stats(
                 &info_hash,
                 peer_id,
-                &client_addr,
+                &peer_addr,
                 packet.uploaded,
                 packet.downloaded,
                 packet.left,
@@ -227,18 +510,29 @@ impl UDPTracker {
         {
             tracker::TorrentStats::Stats {
                 leechers,
-                complete: _,
                 seeders,
+                uploaded,
+                downloaded,
+                peers,
+                ..
             } => {
-                let peers = match self.tracker.get_torrent_peers(&info_hash, &client_addr).await {
-                    Some(v) => v,
-                    None => {
-                        return;
-                    }
-                };
+                if let Some(ref user) = user {
+                    self.tracker.get_users().record_announce(user, uploaded, downloaded);
+                }
+                if self.tracker.is_tracking() {
+                    self.tracker.track(AnalyticsEvent::Announce {
+                        info_hash: info_hash.clone(),
+                        ip_family: AnalyticsEvent::ip_family(&remote_addr.ip()),
+                        client: peer_id.get_client_name(),
+                        event: AnalyticsEvent::event_name(packet.event),
+                        uploaded: packet.uploaded,
+                        downloaded: packet.downloaded,
+                        left: packet.left,
+                    });
+                }
 
-                let mut payload_buffer = vec![0u8; MAX_PACKET_SIZE];
-                let mut payload = StackVec::from(&mut payload_buffer);
+                let mut payload_buffer = self.buffers.get();
+                let mut payload = StackVec::from(&mut payload_buffer[..]);
 
                 match pack_into(&mut payload, &UDPAnnounceResponse {
                     header: UDPResponseHeader {
@@ -246,7 +540,7 @@ impl UDPTracker {
                         transaction_id: packet.header.transaction_id,
                     },
                     seeders,
-                    interval: self.config.get_udp_config().get_announce_interval(),
+                    interval: self.announce_interval(),
                     leechers,
                 }) {
                     Ok(_) => {}
@@ -255,24 +549,45 @@ impl UDPTracker {
                     }
                 };
 
-                for peer in peers {
-                    match peer {
-                        SocketAddr::V4(ipv4) => {
-                            let _ = payload.write(&ipv4.ip().octets());
-                        }
-                        SocketAddr::V6(ipv6) => {
-                            let _ = payload.write(&ipv6.ip().octets());
-                        }
-                    };
-
-                    let port_hton = client_addr.port().to_be();
-                    let _ = payload.write(&[(port_hton & 0xff) as u8, ((port_hton >> 8) & 0xff) as u8]);
+                // a negative num_want leaves it up to the tracker.
+                let num_want = usize::try_from(packet.num_want).ok();
+                let config = self.tracker.get_config();
+                let max_peers = config.get_tracker_config().get_numwant(num_want);
+                let max_peers = max_peers
+                    .min(self.policy.max_peers.unwrap_or(MAX_ANNOUNCE_PEERS))
+                    .min(MAX_ANNOUNCE_PEERS);
+                let include_self = config.get_tracker_config().get_include_self();
+                let count = peers.write_to(&mut payload, &peer_addr, max_peers, include_self);
+                for peer in self.tracker.get_upstream_peers(&info_hash, &peer_addr, max_peers - count) {
+                    if let (SocketAddr::V4(ipv4), false) = (peer, peers.contains(&peer)) {
+                        let _ = payload.write(&ipv4.ip().octets());
+                        let _ = payload.write(&ipv4.port().to_be_bytes());
+                    }
                 }
 
-                let _ = self.send_packet(&client_addr, payload.as_slice()).await;
+                self.send_response(request, &client_addr, payload.as_slice()).await;
+            }
+            tracker::TorrentStats::Duplicate { seeders, leechers, .. } => {
+                // re-announced too soon, the peer already got the swarm's peers.
+                let mut payload_buffer = self.buffers.get();
+                let mut payload = StackVec::from(&mut payload_buffer[..]);
+                let response = UDPAnnounceResponse {
+                    header: UDPResponseHeader {
+                        action: Actions::Announce,
+                        transaction_id: packet.header.transaction_id,
+                    },
+                    seeders,
+                    interval: self.announce_interval(),
+                    leechers,
+                };
+                if pack_into(&mut payload, &response).is_ok() {
+                    self.send_response(request, &client_addr, payload.as_slice()).await;
+                }
             }
             tracker::TorrentStats::TorrentFlagged => {
-                self.send_error(&client_addr, &packet.header, "torrent flagged.").await;
+                let config = self.tracker.get_config();
+                self.send_error(&client_addr, &packet.header, &config.get_tracker_config().get_flagged_error())
+                    .await;
                 return;
             }
             tracker::TorrentStats::TorrentNotRegistered => {
@@ -282,8 +597,21 @@ impl UDPTracker {
         }
     }
 
-    async fn handle_scrape(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, payload: &[u8]) {
+    async fn handle_scrape(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, payload: &[u8], request: &Request) {
         if header.connection_id != self.get_connection_id(remote_addr) {
+            self.tracker.get_requests().count_drop();
+            self.tracker.get_traffic().count_invalid_connection_id();
+            return;
+        }
+
+        if self.tracker.is_banned(&remote_addr.ip(), None) {
+            self.send_error(remote_addr, header, "banned.").await;
+            return;
+        }
+        let config = self.tracker.get_config();
+        let scrape = config.get_tracker_config().get_scrape();
+        if !scrape.allows(&remote_addr.ip()) {
+            self.send_error(remote_addr, header, "scrape not allowed.").await;
             return;
         }
 
@@ -305,52 +633,72 @@ impl UDPTracker {
 
         // skip first 16 bytes for header...
         let info_hash_array = &payload[16..];
+        let is_single = info_hash_array.len() / 20 == 1;
 
         if info_hash_array.len() % 20 != 0 {
             trace!("received weird length for scrape info_hash array (!mod20).");
+            if !self.tolerate(remote_addr, ParseQuirk::TrailingData) {
+                return;
+            }
         }
 
-        {
-            let db = self.tracker.get_database().await;
-
-            for torrent_index in 0..MAX_SCRAPE {
-                let info_hash_start = torrent_index * 20;
-                let info_hash_end = (torrent_index + 1) * 20;
+        for torrent_index in 0..MAX_SCRAPE {
+            let info_hash_start = torrent_index * 20;
+            let info_hash_end = (torrent_index + 1) * 20;
 
-                if info_hash_end > info_hash_array.len() {
-                    break;
-                }
+            if info_hash_end > info_hash_array.len() {
+                break;
+            }
 
-                let info_hash = &info_hash_array[info_hash_start..info_hash_end];
-                let ih = tracker::InfoHash::from(info_hash);
-                let result = match db.get(&ih) {
-                    Some(torrent_info) => {
-                        let (seeders, completed, leechers) = torrent_info.get_stats();
-
-                        UDPScrapeResponseEntry {
-                            seeders,
-                            completed,
-                            leechers,
-                        }
-                    }
-                    None => {
-                        UDPScrapeResponseEntry {
-                            seeders: 0,
-                            completed: 0,
-                            leechers: 0,
-                        }
+            let info_hash = &info_hash_array[info_hash_start..info_hash_end];
+            let ih = tracker::InfoHash::from(info_hash);
+            if self.tracker.is_tracking() {
+                self.tracker.track(AnalyticsEvent::Scrape {
+                    info_hash: ih.clone(),
+                    ip_family: AnalyticsEvent::ip_family(&remote_addr.ip()),
+                });
+            }
+            let result = match self.tracker.with_torrent(&ih, |torrent_info| torrent_info.get_stats()).await {
+                Some((seeders, completed, leechers)) => {
+                    UDPScrapeResponseEntry {
+                        seeders,
+                        completed,
+                        leechers,
                     }
-                };
-
-                if pack_into(&mut response, &result).is_err() {
-                    debug!("failed to encode scrape entry.");
+                }
+                // scrapes of several torrents get empty counts for those that aren't tracked, as the others are.
+                None if scrape.is_registered_only() && is_single => {
+                    self.send_error(remote_addr, header, "torrent not registered.").await;
                     return;
                 }
+                None => {
+                    UDPScrapeResponseEntry {
+                        seeders: 0,
+                        completed: 0,
+                        leechers: 0,
+                    }
+                }
+            };
+
+            if pack_into(&mut response, &result).is_err() {
+                debug!("failed to encode scrape entry.");
+                return;
             }
         }
 
-        // if sending fails, not much we can do...
-        let _ = self.send_packet(&remote_addr, &response.as_slice()).await;
+        self.send_response(request, remote_addr, response.as_slice()).await;
+    }
+
+    /// Counts a request's deviation from BEP 15, returns false if the request is dropped for it.
+    fn tolerate(&self, remote_addr: &SocketAddr, quirk: ParseQuirk) -> bool {
+        self.tracker.get_requests().count_quirk(quirk);
+        if self.tracker.get_config().get_tracker_config().get_parsing() == Parsing::Lenient {
+            return true;
+        }
+        trace!("dropped request with {:?} from {}", quirk, remote_addr);
+        self.tracker.get_requests().count_drop();
+        self.tracker.count_malformed(remote_addr.ip());
+        false
     }
 
     fn get_connection_id(&self, remote_address: &SocketAddr) -> u64 {
@@ -363,13 +711,39 @@ impl UDPTracker {
     async fn send_packet(&self, remote_addr: &SocketAddr, payload: &[u8]) -> Result<usize, std::io::Error> {
         self.srv_send.try_send_to(payload, remote_addr).await.map_err(|e| {
             debug!("failed to send a packet: {}", e);
+            self.tracker.get_requests().count_drop();
             e
         })
     }
 
+    /// Sends the response to `request`, and keeps it for replaying to retransmits of the request.
+    async fn send_response(&self, request: &Request, to: &SocketAddr, response: &[u8]) {
+        if let Some(ttl) = self.tracker.get_config().get_tracker_config().get_response_cache_ttl() {
+            self.responses.insert(request, to, response, ttl, std::time::Instant::now());
+        }
+        // if sending fails, not much we can do...
+        let _ = self.send_packet(to, response).await;
+    }
+
+    /// Answers a retransmitted request with the response it got before, returns false if there's none.
+    async fn replay(&self, request: &Request) -> bool {
+        let ttl = match self.tracker.get_config().get_tracker_config().get_response_cache_ttl() {
+            Some(v) => v,
+            None => return false,
+        };
+        match self.responses.get(request, ttl, std::time::Instant::now()) {
+            Some((to, response)) => {
+                self.tracker.get_requests().count_replay();
+                let _ = self.send_packet(&to, &response).await;
+                true
+            }
+            None => false,
+        }
+    }
+
     async fn send_error(&self, remote_addr: &SocketAddr, header: &UDPRequestHeader, error_msg: &str) {
-        let mut payload_buffer = vec![0u8; MAX_PACKET_SIZE];
-        let mut payload = StackVec::from(&mut payload_buffer);
+        let mut payload_buffer = self.buffers.get();
+        let mut payload = StackVec::from(&mut payload_buffer[..]);
 
         if let Ok(_) = pack_into(&mut payload, &UDPResponseHeader {
             transaction_id: header.transaction_id,
@@ -382,13 +756,21 @@ impl UDPTracker {
         }
     }
 
-    pub async fn accept_packets(mut self) -> Result<(), std::io::Error> {
+    /// Handles packets until `shutdown` fires, then waits (for up to `DRAIN_TIMEOUT`) for the packets that are
+    /// still being handled.
+    pub async fn accept_packets(mut self, mut shutdown: tokio::sync::broadcast::Receiver<()>) -> Result<(), std::io::Error> {
+        const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
         let mut recv = self.srv_recv.take().unwrap();
+        let buffers = self.buffers.clone();
         let tracker = Arc::new(self);
 
         loop {
-            let mut packet = vec![0u8; MAX_PACKET_SIZE];
-            let (size, remote_address) = recv.recv_from(packet.as_mut_slice()).await?;
+            let mut packet = buffers.get();
+            let (size, remote_address) = tokio::select! {
+                res = recv.recv_from(&mut packet) => res?,
+                _ = shutdown.recv() => break,
+            };
 
             let tracker = tracker.clone();
             tokio::spawn(async move {
@@ -396,6 +778,54 @@ impl UDPTracker {
                 tracker.handle_packet(&remote_address, &packet[..size]).await;
             });
         }
+
+        // every packet that's being handled holds a reference to the tracker.
+        let deadline = std::time::Instant::now() + DRAIN_TIMEOUT;
+        while Arc::strong_count(&tracker) > 1 && std::time::Instant::now() < deadline {
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+}
+
+/// Sends a connect request to the listener bound to `address` and waits for its response, returns the round trip
+/// time. Listeners bound to an unspecified address are probed over loopback.
+pub async fn probe(address: SocketAddr, timeout: std::time::Duration) -> Result<std::time::Duration, String> {
+    let loopback: Option<std::net::IpAddr> = match address.ip() {
+        std::net::IpAddr::V4(ip) if ip.is_unspecified() => Some(std::net::Ipv4Addr::LOCALHOST.into()),
+        std::net::IpAddr::V6(ip) if ip.is_unspecified() => Some(std::net::Ipv6Addr::LOCALHOST.into()),
+        _ => None,
+    };
+    let target = loopback.map_or(address, |ip| SocketAddr::new(ip, address.port()));
+    let bind_address: SocketAddr = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let mut socket = UdpSocket::bind(bind_address).await.map_err(|e| e.to_string())?;
+
+    let transaction_id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.subsec_nanos())
+        .unwrap_or_default();
+    let mut request = Vec::new();
+    let _ = pack_into(&mut request, &UDPRequestHeader {
+        connection_id: PROTOCOL_ID,
+        action: Actions::Connect,
+        transaction_id,
+    });
+
+    let started = std::time::Instant::now();
+    socket.send_to(&request, &target).await.map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 64];
+    loop {
+        let remaining = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+        let (len, from) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(v) => v.map_err(|e| e.to_string())?,
+            Err(_) => return Err(String::from("timed out")),
+        };
+        // stray packets are skipped.
+        if let (true, Some(response)) = (from == target, unpack::<UDPConnectionResponse>(&buf[..len])) {
+            if let (Actions::Connect, true) = (response.header.action, response.header.transaction_id == transaction_id) {
+                return Ok(started.elapsed());
+            }
+        }
     }
 }
 
@@ -417,6 +847,296 @@ mod tests {
         assert_eq!(payload.as_slice(), &[0, 0, 0, 0, 0, 0, 0, 200u8, 0, 0, 0, 0, 0, 1, 47, 203]);
     }
 
+    #[test]
+    fn bep41_passkey() {
+        // NOP, URLData "/abc", URLData "/announce", EndOfOptions, garbage.
+        let options = b"\x01\x02\x04/abc\x02\x09/announce\x00\x02\xff";
+        let (url, quirk) = parse_url_data(options);
+        assert_eq!(url.as_slice(), b"/abc/announce");
+        assert_eq!(quirk, Some(ParseQuirk::TrailingData));
+
+        assert_eq!(parse_url_data(b"\x02\x04/abc\x00\x00"), (b"/abc".to_vec(), None));
+        assert_eq!(parse_url_data(b"\x02\x04/abc"), (b"/abc".to_vec(), None));
+        assert_eq!(parse_url_data(b"\x02\x09/abc").1, Some(ParseQuirk::TrailingData));
+        assert_eq!(parse_url_data(b"\x07\x01x\x02\x04/abc").1, Some(ParseQuirk::ReservedBits));
+
+        assert_eq!(parse_passkey("/abc/announce"), Some("abc"));
+        assert_eq!(parse_passkey("/announce/abc"), Some("abc"));
+        assert_eq!(parse_passkey("/announce?info=1&passkey=abc"), Some("abc"));
+        assert_eq!(parse_passkey("/announce"), None);
+        assert_eq!(parse_passkey("/abc"), None);
+        assert_eq!(parse_passkey("/abc/def/announce"), None);
+        assert_eq!(parse_passkey("/announce/abc/scrape"), None);
+        assert_eq!(parse_passkey("//announce"), None);
+    }
+
+    fn announce(server: &UDPTracker, client_addr: &SocketAddr, info_hash: u8) -> [u8; 98] {
+        let mut buffer = [0u8; 98];
+        let mut packet = StackVec::from(&mut buffer);
+        pack_into(&mut packet, &UDPAnnounceRequest {
+            header: UDPRequestHeader {
+                connection_id: server.get_connection_id(client_addr),
+                action: Actions::Announce,
+                transaction_id: 1,
+            },
+            info_hash: [info_hash; 20],
+            // a peer per port.
+            peer_id: [client_addr.port() as u8; 20],
+            downloaded: 0,
+            left: 0,
+            uploaded: 0,
+            event: Events::Started,
+            ip_address: 0,
+            key: 0,
+            num_want: -1,
+            port: client_addr.port(),
+        })
+        .unwrap();
+        buffer
+    }
+
+    #[tokio::test]
+    async fn listener_policy() {
+        let config = Arc::new(
+            Configuration::load(
+                br#"
+                mode = "dynamic"
+                [udp]
+                bind_address = "127.0.0.1:0"
+                announce_interval = 60
+                [tracker]
+                min_announce_interval = 100
+                [[listeners]]
+                bind_address = "127.0.0.1:0"
+                mode = "static"
+                announce_interval = 300
+                "#,
+            )
+            .unwrap(),
+        );
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::with_listener(config.clone(), &config.get_listeners()[0], tracker.clone(), None)
+            .await
+            .unwrap();
+        let client_addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert_eq!(server.announce_interval(), 300);
+
+        let info_hash = [1u8; 20].into();
+
+        // unknown torrents aren't tracked through a static listener, even though the tracker is dynamic.
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+        assert!(tracker.with_torrent(&info_hash, |_| ()).await.is_none());
+
+        tracker.add_torrent(&info_hash).await.unwrap();
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+        assert_eq!(tracker.with_torrent(&info_hash, |t| t.get_peer_count()).await, Some(1));
+
+        // while the `[udp]` listener keeps registering them.
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+        assert_eq!(server.announce_interval(), 100);
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 2)).await;
+        assert!(tracker.with_torrent(&[2u8; 20].into(), |_| ()).await.is_some());
+
+        // a dynamic listener doesn't register torrents once the tracker is static.
+        let listener: ListenerConfig = toml::from_str("bind_address = \"127.0.0.1:0\"\nmode = \"dynamic\"").unwrap();
+        let server = UDPTracker::with_listener(config.clone(), &listener, tracker.clone(), None)
+            .await
+            .unwrap();
+        tracker.set_mode(tracker::TrackerMode::StaticMode);
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 3)).await;
+        assert!(tracker.with_torrent(&[3u8; 20].into(), |_| ()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn mode_switch() {
+        let config = Arc::new(Configuration::load(b"mode = \"dynamic\"\n[udp]\nbind_address = \"127.0.0.1:0\"").unwrap());
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+        let client_addr: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+
+        // closing registration keeps the swarms that are tracked already.
+        tracker.set_mode(tracker::TrackerMode::StaticMode);
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 2)).await;
+        assert!(tracker.with_torrent(&[2u8; 20].into(), |_| ()).await.is_none());
+        let client_addr: SocketAddr = "198.51.100.2:6882".parse().unwrap();
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+        assert_eq!(tracker.with_torrent(&[1u8; 20].into(), |t| t.get_peer_count()).await, Some(2));
+
+        tracker.set_mode(tracker::TrackerMode::DynamicMode);
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 2)).await;
+        assert!(tracker.with_torrent(&[2u8; 20].into(), |_| ()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn external_ip() {
+        let config = Arc::new(
+            Configuration::load(
+                br#"
+                mode = "dynamic"
+                [udp]
+                bind_address = "127.0.0.1:0"
+                [tracker]
+                external_ipv4 = "203.0.113.7"
+                "#,
+            )
+            .unwrap(),
+        );
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+
+        // peers on the tracker's host are handed out with its external address, others as they are.
+        for client_addr in ["127.0.0.1:6881", "198.51.100.1:6882"].iter() {
+            let client_addr: SocketAddr = client_addr.parse().unwrap();
+            server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+        }
+        let peers = tracker
+            .with_torrent(&[1u8; 20].into(), |t| {
+                serde_json::to_string(&t.get_peers_iter().collect::<Vec<_>>())
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(peers.contains("\"203.0.113.7:6881\""));
+        assert!(peers.contains("\"198.51.100.1:6882\""));
+        assert!(!peers.contains("127.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn request_counters() {
+        let config = Arc::new(Configuration::load(b"mode = \"dynamic\"\n[udp]\nbind_address = \"127.0.0.1:0\"").unwrap());
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+
+        let client_addr: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let packet = announce(&server, &client_addr, 1);
+        server.handle_packet(&client_addr, &packet).await;
+
+        // unparseable, and carrying a connection id that wasn't handed out.
+        server.handle_packet(&client_addr, &[0u8; 4]).await;
+        let mut packet = packet;
+        packet[..8].copy_from_slice(&[0u8; 8]);
+        server.handle_packet(&client_addr, &packet).await;
+
+        assert_eq!(tracker.get_requests().totals(), (2, 0, 2));
+    }
+
+    #[tokio::test]
+    async fn retransmits() {
+        let config = Arc::new(Configuration::load(b"mode = \"dynamic\"\n[udp]\nbind_address = \"127.0.0.1:0\"").unwrap());
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+
+        let client_addr: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        let mut packet = announce(&server, &client_addr, 1);
+        // a completed event.
+        packet[83] = 1;
+        server.handle_packet(&client_addr, &packet).await;
+        server.handle_packet(&client_addr, &packet).await;
+        assert_eq!(tracker.get_requests().replayed(), 1);
+        let (_, completed, _) = tracker.with_torrent(&[1u8; 20].into(), |t| t.get_stats()).await.unwrap();
+        assert_eq!(completed, 1);
+
+        // a new transaction is handled again.
+        packet[15] = 2;
+        server.handle_packet(&client_addr, &packet).await;
+        assert_eq!(tracker.get_requests().replayed(), 1);
+    }
+
+    #[tokio::test]
+    async fn registered_only_scrape() {
+        let config = b"mode = \"dynamic\"\n[udp]\nbind_address = \"127.0.0.1:0\"\n[tracker.scrape]\nregistered_only = true";
+        let config = Arc::new(Configuration::load(config).unwrap());
+        let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+        let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+        let client_addr: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+        server.handle_packet(&client_addr, &announce(&server, &client_addr, 1)).await;
+
+        let scrape = |transaction_id: u8, info_hashes: &[u8]| {
+            let mut packet = server.get_connection_id(&client_addr).to_be_bytes().to_vec();
+            packet.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, transaction_id]);
+            for info_hash in info_hashes {
+                packet.extend_from_slice(&[*info_hash; 20]);
+            }
+            packet
+        };
+        // the response that was sent, as it's kept for retransmits.
+        let response = |packet: &[u8]| {
+            let request = Request::new(&client_addr, u32::from(packet[15]), packet);
+            let ttl = std::time::Duration::from_secs(20);
+            server
+                .responses
+                .get(&request, ttl, std::time::Instant::now())
+                .map(|(_, response)| response)
+        };
+
+        // the unregistered torrent is reported as empty.
+        let packet = scrape(1, &[1, 2]);
+        server.handle_packet(&client_addr, &packet).await;
+        let mut expected = vec![0, 0, 0, 2, 0, 0, 0, 1];
+        // a leecher, then the empty entry.
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(response(&packet), Some(expected));
+
+        // scraping it alone is refused with an error.
+        let packet = scrape(2, &[2]);
+        server.handle_packet(&client_addr, &packet).await;
+        assert_eq!(response(&packet), None);
+    }
+
+    #[tokio::test]
+    async fn parsing() {
+        for (parsing, is_tracked) in [("lenient", true), ("strict", false)].iter() {
+            let config = format!(
+                "mode = \"dynamic\"\n[udp]\nbind_address = \"127.0.0.1:0\"\n[tracker]\nparsing = \"{}\"",
+                parsing
+            );
+            let config = Arc::new(Configuration::load(config.as_bytes()).unwrap());
+            let tracker = Arc::new(tracker::TorrentTracker::new(config.clone()));
+            let server = UDPTracker::new(config.clone(), tracker.clone(), None).await.unwrap();
+
+            // announcing port 0.
+            let client_addr: SocketAddr = "198.51.100.1:6881".parse().unwrap();
+            let mut packet = announce(&server, &client_addr, 1);
+            packet[96..].copy_from_slice(&[0, 0]);
+            server.handle_packet(&client_addr, &packet).await;
+
+            assert_eq!(tracker.with_torrent(&[1u8; 20].into(), |_| ()).await.is_some(), *is_tracked);
+            let quirks = serde_json::to_value(tracker.get_requests().quirks()).unwrap();
+            assert_eq!(
+                quirks,
+                serde_json::json!({"trailing_data": 0, "zero_port": 1, "reserved_bits": 0})
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn probe_listener() {
+        let mut listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            let (_, from) = listener.recv_from(&mut buf).await.unwrap();
+            // a stray packet, then the response.
+            listener.send_to(&[1u8; 16], &from).await.unwrap();
+            let mut response = vec![0u8; 4];
+            response.extend_from_slice(&buf[12..16]);
+            response.extend_from_slice(&[7u8; 8]);
+            listener.send_to(&response, &from).await.unwrap();
+        });
+
+        let unspecified = SocketAddr::new("0.0.0.0".parse().unwrap(), address.port());
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(probe(unspecified, timeout).await.is_ok());
+
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let timeout = std::time::Duration::from_millis(50);
+        assert_eq!(
+            probe(silent.local_addr().unwrap(), timeout).await,
+            Err(String::from("timed out"))
+        );
+    }
+
     #[test]
     fn unpack() {
         let buf = [0u8, 0, 0, 0, 0, 0, 0, 200, 0, 0, 0, 1, 0, 1, 47, 203];
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+/// A map that keeps its values in one contiguous vector, reusing the slots of removed values.
+/// Swarms churn constantly, this avoids an allocation per joining peer and keeps peers close together
+/// while building responses & reaping.
+#[derive(Clone)]
+pub struct Slab<K, V> {
+    /// Key -> slot
+    index: BTreeMap<K, u32>,
+    slots: Vec<Option<(K, V)>>,
+    free: Vec<u32>,
+}
+
+impl<K: Ord + Copy, V> Default for Slab<K, V> {
+    fn default() -> Self {
+        Slab {
+            index: BTreeMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<K: Ord + Copy, V> Slab<K, V> {
+    /// Slabs with fewer slots aren't compacted.
+    const MIN_COMPACT_SLOTS: usize = 64;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let slot = *self.index.get(key)?;
+        self.slots[slot as usize].as_ref().map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let slot = *self.index.get(key)?;
+        self.slots[slot as usize].as_mut().map(|(_, v)| v)
+    }
+
+    /// Inserts `value`, returning the value it replaced.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(&slot) = self.index.get(&key) {
+            return self.slots[slot as usize].replace((key, value)).map(|(_, v)| v);
+        }
+
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot as usize] = Some((key, value));
+                slot
+            }
+            None => {
+                self.slots.push(Some((key, value)));
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.index.insert(key, slot);
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let slot = self.index.remove(key)?;
+        let removed = self.slots[slot as usize].take().map(|(_, v)| v);
+        self.free.push(slot);
+
+        // once a swarm shrank to a quarter of its slots, give the memory back.
+        if self.slots.len() >= Self::MIN_COMPACT_SLOTS && self.index.len() * 4 < self.slots.len() {
+            self.compact();
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.slots = Vec::new();
+        self.free = Vec::new();
+    }
+
+    fn compact(&mut self) {
+        let slots = std::mem::take(&mut self.slots);
+        self.slots = slots.into_iter().filter(Option::is_some).collect();
+        self.free = Vec::new();
+        for (slot, entry) in self.slots.iter().enumerate() {
+            if let Some((key, _)) = entry {
+                self.index.insert(*key, slot as u32);
+            }
+        }
+    }
+
+    /// Iterates in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().filter_map(|slot| slot.as_ref().map(|(k, v)| (k, v)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, v)| v)
+    }
+}
+
+impl<K, V> IntoIterator for Slab<K, V> {
+    type Item = (K, V);
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Option<(K, V)>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.slots.into_iter().flatten()
+    }
+}
+
+impl<K: Ord + Copy, V> std::ops::Index<&K> for Slab<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_slots() {
+        let mut slab = Slab::new();
+        for i in 0..100u32 {
+            assert!(slab.insert(i, i * 10).is_none());
+        }
+        assert_eq!(slab.insert(5, 0), Some(50));
+        assert_eq!(slab.remove(&6), Some(60));
+        assert_eq!(slab.get(&6), None);
+
+        // the freed slot is reused.
+        slab.insert(1000, 1);
+        assert_eq!(slab.slots.len(), 100);
+        assert_eq!(slab.keys().nth(6), Some(&1000));
+
+        for i in 0..90u32 {
+            slab.remove(&i);
+        }
+        assert!(slab.slots.len() < 100);
+        assert_eq!(slab.len(), 11);
+        assert_eq!(slab[&99], 990);
+        assert_eq!(slab[&1000], 1);
+        assert_eq!(slab.into_iter().count(), 11);
+    }
+}
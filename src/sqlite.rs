@@ -0,0 +1,145 @@
+/// A torrent as stored in the `torrents` table.
+/// `is_flagged` & `completed` are kept in their own columns so they can be queried and edited with standard tools,
+/// `entry` holds the remaining persisted state as json.
+pub struct SqliteRow {
+    pub info_hash: String,
+    pub is_flagged: bool,
+    pub completed: u32,
+    pub entry: String,
+}
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use super::SqliteRow;
+    use std::sync::{Arc, Mutex};
+
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS torrents (
+        info_hash TEXT PRIMARY KEY NOT NULL,
+        is_flagged INTEGER NOT NULL DEFAULT 0,
+        completed INTEGER NOT NULL DEFAULT 0,
+        entry TEXT NOT NULL DEFAULT '{}'
+    )";
+
+    fn to_io_error(err: rusqlite::Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, err)
+    }
+
+    /// Torrents persisted in a SQLite database. Queries run on the blocking thread pool.
+    pub struct SqliteStore {
+        conn: Arc<Mutex<rusqlite::Connection>>,
+    }
+
+    impl SqliteStore {
+        pub async fn open(path: &str) -> Result<SqliteStore, std::io::Error> {
+            let path = path.to_string();
+            let conn = tokio::task::spawn_blocking(move || {
+                let conn = rusqlite::Connection::open(path)?;
+                conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+                conn.execute(SCHEMA, rusqlite::NO_PARAMS)?;
+                Ok(conn)
+            })
+            .await?
+            .map_err(to_io_error)?;
+
+            Ok(SqliteStore {
+                conn: Arc::new(Mutex::new(conn)),
+            })
+        }
+
+        pub async fn load(&self) -> Result<Vec<SqliteRow>, std::io::Error> {
+            let conn = self.conn.clone();
+            tokio::task::spawn_blocking(move || {
+                let conn = conn.lock().unwrap();
+                let mut stmt = conn.prepare("SELECT info_hash, is_flagged, completed, entry FROM torrents")?;
+                let rows = stmt.query_map(rusqlite::NO_PARAMS, |row| {
+                    Ok(SqliteRow {
+                        info_hash: row.get(0)?,
+                        is_flagged: row.get(1)?,
+                        completed: row.get(2)?,
+                        entry: row.get(3)?,
+                    })
+                })?;
+                rows.collect::<Result<Vec<_>, _>>()
+            })
+            .await?
+            .map_err(to_io_error)
+        }
+
+        /// Inserts or replaces `upserts` and deletes `deletes` in a single transaction.
+        pub async fn write(&self, upserts: Vec<SqliteRow>, deletes: Vec<String>) -> Result<(), std::io::Error> {
+            let conn = self.conn.clone();
+            tokio::task::spawn_blocking(move || {
+                let mut conn = conn.lock().unwrap();
+                let tx = conn.transaction()?;
+                {
+                    let mut upsert = tx.prepare_cached(
+                        "INSERT OR REPLACE INTO torrents (info_hash, is_flagged, completed, entry) VALUES (?1, ?2, ?3, ?4)",
+                    )?;
+                    for row in upserts.iter() {
+                        upsert.execute(rusqlite::params![row.info_hash, row.is_flagged, row.completed, row.entry])?;
+                    }
+
+                    let mut delete = tx.prepare_cached("DELETE FROM torrents WHERE info_hash = ?1")?;
+                    for info_hash in deletes.iter() {
+                        delete.execute(rusqlite::params![info_hash])?;
+                    }
+                }
+                tx.commit()
+            })
+            .await?
+            .map_err(to_io_error)
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod imp {
+    use super::SqliteRow;
+
+    fn unsupported() -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Other, "udpt was built without sqlite support")
+    }
+
+    pub struct SqliteStore;
+
+    impl SqliteStore {
+        pub async fn open(_path: &str) -> Result<SqliteStore, std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn load(&self) -> Result<Vec<SqliteRow>, std::io::Error> {
+            Err(unsupported())
+        }
+
+        pub async fn write(&self, _upserts: Vec<SqliteRow>, _deletes: Vec<String>) -> Result<(), std::io::Error> {
+            Err(unsupported())
+        }
+    }
+}
+
+pub use imp::SqliteStore;
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn write_and_load() {
+        let store = SqliteStore::open(":memory:").await.unwrap();
+        let row = |info_hash: &str, completed| {
+            SqliteRow {
+                info_hash: info_hash.to_string(),
+                is_flagged: false,
+                completed,
+                entry: String::from("{}"),
+            }
+        };
+
+        store.write(vec![row("a", 1), row("b", 2)], Vec::new()).await.unwrap();
+        store.write(vec![row("a", 3)], vec![String::from("b")]).await.unwrap();
+
+        let rows = store.load().await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].info_hash.as_str(), rows[0].completed), ("a", 3));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::StatsdConfig;
+use crate::tracker::TorrentTracker;
+use log::debug;
+
+/// Stays below the MTU of common networks, so metrics aren't fragmented.
+const MAX_PACKET_SIZE: usize = 1432;
+
+enum Metric {
+    /// Amount since the previous push.
+    Counter(usize),
+    Gauge(usize),
+}
+
+/// Pushes the tracker's metrics to a StatsD server, e.g. to feed Graphite.
+pub struct StatsD {
+    socket: tokio::net::UdpSocket,
+    prefix: String,
+    /// Announces, scrapes & dropped packets as of the previous push.
+    last_requests: (usize, usize, usize),
+    last_invalid_connection_ids: u64,
+}
+
+impl StatsD {
+    pub async fn connect(config: &StatsdConfig) -> Result<StatsD, std::io::Error> {
+        let addr = tokio::net::lookup_host(config.get_address()).await?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("failed to resolve \"{}\"", config.get_address()),
+            )
+        })?;
+        let bind_address = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = tokio::net::UdpSocket::bind(bind_address).await?;
+        socket.connect(addr).await?;
+
+        Ok(StatsD {
+            socket,
+            prefix: config.get_prefix().to_string(),
+            last_requests: (0, 0, 0),
+            last_invalid_connection_ids: 0,
+        })
+    }
+
+    pub async fn push(&mut self, tracker: &TorrentTracker) {
+        let (announces, scrapes, dropped) = tracker.get_requests().totals();
+        let (last_announces, last_scrapes, last_dropped) = self.last_requests;
+        self.last_requests = (announces, scrapes, dropped);
+        let traffic = tracker.get_traffic().get_report();
+        let invalid_connection_ids = traffic.get_invalid_connection_ids();
+        let last_invalid_connection_ids = std::mem::replace(&mut self.last_invalid_connection_ids, invalid_connection_ids);
+
+        let metrics = [
+            ("announces", Metric::Counter(announces - last_announces)),
+            ("scrapes", Metric::Counter(scrapes - last_scrapes)),
+            ("dropped", Metric::Counter(dropped - last_dropped)),
+            (
+                "invalid_connection_ids",
+                Metric::Counter((invalid_connection_ids - last_invalid_connection_ids) as usize),
+            ),
+            ("connect_only_ips", Metric::Gauge(traffic.get_connect_only_ips())),
+            ("torrents", Metric::Gauge(tracker.get_torrent_count())),
+            ("peers", Metric::Gauge(tracker.get_peer_count())),
+            ("seeders", Metric::Gauge(tracker.get_seeder_count())),
+            ("completed", Metric::Gauge(tracker.get_completed_count())),
+            ("memory_bytes", Metric::Gauge(tracker.get_memory_usage())),
+        ];
+        for packet in encode(&self.prefix, &metrics) {
+            if let Err(err) = self.socket.send(&packet).await {
+                debug!("failed to send metrics to statsd: {}", err);
+            }
+        }
+    }
+}
+
+/// Encodes metrics as `<prefix>.<name>:<value>|<type>` lines, packed into as few packets as possible.
+fn encode(prefix: &str, metrics: &[(&str, Metric)]) -> Vec<Vec<u8>> {
+    let mut packets: Vec<Vec<u8>> = Vec::new();
+    for (name, metric) in metrics.iter() {
+        let (value, kind) = match metric {
+            Metric::Counter(value) => (value, "c"),
+            Metric::Gauge(value) => (value, "g"),
+        };
+        let line = if prefix.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}.{}:{}|{}", prefix, name, value, kind)
+        };
+
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET_SIZE => {
+                packet.push(b'\n');
+                packet.extend_from_slice(line.as_bytes());
+            }
+            _ => packets.push(line.into_bytes()),
+        }
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_packets() {
+        let packets = encode("udpt", &[("announces", Metric::Counter(12)), ("peers", Metric::Gauge(3))]);
+        assert_eq!(packets, vec![b"udpt.announces:12|c\nudpt.peers:3|g".to_vec()]);
+
+        assert_eq!(encode("", &[("torrents", Metric::Gauge(1))]), vec![b"torrents:1|g".to_vec()]);
+
+        // metrics that don't fit in a packet start the next one.
+        let prefix = "p".repeat(MAX_PACKET_SIZE - 20);
+        let packets = encode(&prefix, &[("torrents", Metric::Gauge(1)), ("peers", Metric::Gauge(2))]);
+        assert_eq!(packets.len(), 2);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET_SIZE));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::tracker::{InfoHash, TorrentEntry};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Holds the torrents of a tracker.
+///
+/// Callbacks are called while the store holds whatever lock protects the torrent, they never block.
+/// Torrent & peer counts are kept by the tracker, so stores only need to hold the torrents.
+pub trait TorrentStore: Send + Sync {
+    /// Inserts or replaces a torrent, returns the replaced torrent.
+    fn insert(&self, info_hash: InfoHash, entry: TorrentEntry) -> BoxFuture<'_, Option<TorrentEntry>>;
+
+    /// Calls `f` with the torrent, returns false if it doesn't exist.
+    fn get<'a>(&'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&TorrentEntry) + Send)) -> BoxFuture<'a, bool>;
+
+    /// Calls `f` with the torrent, returns false if it doesn't exist.
+    fn get_mut<'a>(
+        &'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool>;
+
+    /// Calls `f` with the torrent, inserting the torrent returned by `create` if it doesn't exist.
+    /// Returns false if the torrent didn't exist and `create` returned None.
+    fn get_or_insert_with<'a>(
+        &'a self, info_hash: &'a InfoHash, create: &'a mut (dyn FnMut() -> Option<TorrentEntry> + Send),
+        f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool>;
+
+    /// Removes the torrent if `predicate` returns true. Returns the removed torrent.
+    fn remove_if<'a>(
+        &'a self, info_hash: &'a InfoHash, predicate: &'a mut (dyn FnMut(&TorrentEntry) -> bool + Send),
+    ) -> BoxFuture<'a, Option<TorrentEntry>>;
+
+    /// Keeps only the torrents for which `f` returns true.
+    fn retain<'a>(&'a self, f: &'a mut (dyn FnMut(&InfoHash, &mut TorrentEntry) -> bool + Send)) -> BoxFuture<'a, ()>;
+
+    /// Calls `f` for up to `limit` torrents, starting at `offset`. Torrents are listed in the same order on every
+    /// call, so pages neither skip nor repeat torrents unless torrents were added or removed in between.
+    fn for_each<'a>(
+        &'a self, offset: usize, limit: usize, f: &'a mut (dyn FnMut(&InfoHash, &TorrentEntry) + Send),
+    ) -> BoxFuture<'a, ()>;
+
+    /// Lock contention of each shard, stores that don't lock per shard have none to report.
+    fn lock_stats(&self) -> Vec<LockStats> {
+        Vec::new()
+    }
+}
+
+/// How often a shard's lock was taken, and how often it was held by someone else already.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LockStats {
+    pub acquired: u64,
+    pub contended: u64,
+    /// Total time spent waiting for the lock while it was contended.
+    pub waited: std::time::Duration,
+}
+
+struct TorrentShard {
+    torrents: RwLock<BTreeMap<InfoHash, TorrentEntry>>,
+    acquired: AtomicU64,
+    contended: AtomicU64,
+    waited_us: AtomicU64,
+}
+
+impl TorrentShard {
+    fn new() -> TorrentShard {
+        TorrentShard {
+            torrents: RwLock::new(BTreeMap::new()),
+            acquired: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            waited_us: AtomicU64::new(0),
+        }
+    }
+
+    async fn read(&self) -> RwLockReadGuard<'_, BTreeMap<InfoHash, TorrentEntry>> {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        // tokio's RwLock can't be tried, polling it once tells whether it's free.
+        if let Some(guard) = self.torrents.read().now_or_never() {
+            return guard;
+        }
+        let started = std::time::Instant::now();
+        let guard = self.torrents.read().await;
+        self.record_wait(started);
+        guard
+    }
+
+    async fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<InfoHash, TorrentEntry>> {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        if let Some(guard) = self.torrents.write().now_or_never() {
+            return guard;
+        }
+        let started = std::time::Instant::now();
+        let guard = self.torrents.write().await;
+        self.record_wait(started);
+        guard
+    }
+
+    fn record_wait(&self, started: std::time::Instant) {
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.waited_us
+            .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> LockStats {
+        LockStats {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            contended: self.contended.load(Ordering::Relaxed),
+            waited: std::time::Duration::from_micros(self.waited_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Torrents split across shards by the leading bytes of their infohash, each shard having its own lock.
+pub struct ShardedStore {
+    shards: Vec<TorrentShard>,
+}
+
+impl ShardedStore {
+    pub fn new(shard_count: usize) -> ShardedStore {
+        let mut shards = Vec::with_capacity(shard_count);
+        shards.resize_with(shard_count.max(1), TorrentShard::new);
+        ShardedStore { shards }
+    }
+
+    fn shard(&self, info_hash: &InfoHash) -> &TorrentShard {
+        let bytes = info_hash.as_bytes();
+        let key = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        &self.shards[key % self.shards.len()]
+    }
+}
+
+impl TorrentStore for ShardedStore {
+    fn insert(&self, info_hash: InfoHash, entry: TorrentEntry) -> BoxFuture<'_, Option<TorrentEntry>> {
+        Box::pin(async move { self.shard(&info_hash).write().await.insert(info_hash, entry) })
+    }
+
+    fn get<'a>(&'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&TorrentEntry) + Send)) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.shard(info_hash).read().await.get(info_hash).map(f).is_some() })
+    }
+
+    fn get_mut<'a>(
+        &'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.shard(info_hash).write().await.get_mut(info_hash).map(f).is_some() })
+    }
+
+    fn get_or_insert_with<'a>(
+        &'a self, info_hash: &'a InfoHash, create: &'a mut (dyn FnMut() -> Option<TorrentEntry> + Send),
+        f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool> {
+        use std::collections::btree_map::Entry;
+
+        Box::pin(async move {
+            let mut shard = self.shard(info_hash).write().await;
+            let entry = match shard.entry(info_hash.clone()) {
+                Entry::Vacant(vacant) => {
+                    match create() {
+                        Some(entry) => vacant.insert(entry),
+                        None => return false,
+                    }
+                }
+                Entry::Occupied(occupied) => occupied.into_mut(),
+            };
+            f(entry);
+            true
+        })
+    }
+
+    fn remove_if<'a>(
+        &'a self, info_hash: &'a InfoHash, predicate: &'a mut (dyn FnMut(&TorrentEntry) -> bool + Send),
+    ) -> BoxFuture<'a, Option<TorrentEntry>> {
+        use std::collections::btree_map::Entry;
+
+        Box::pin(async move {
+            let mut shard = self.shard(info_hash).write().await;
+            match shard.entry(info_hash.clone()) {
+                Entry::Occupied(entry) if predicate(entry.get()) => Some(entry.remove()),
+                _ => None,
+            }
+        })
+    }
+
+    /// Only one shard is locked at a time.
+    fn retain<'a>(&'a self, f: &'a mut (dyn FnMut(&InfoHash, &mut TorrentEntry) -> bool + Send)) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            for shard in self.shards.iter() {
+                let mut shard = shard.write().await;
+                let mut torrents_to_remove = Vec::new();
+
+                for (k, v) in shard.iter_mut() {
+                    if !f(k, v) {
+                        torrents_to_remove.push(k.clone());
+                    }
+                }
+
+                for info_hash in torrents_to_remove {
+                    shard.remove(&info_hash);
+                }
+            }
+        })
+    }
+
+    /// Only one shard is locked at a time.
+    fn for_each<'a>(
+        &'a self, offset: usize, limit: usize, f: &'a mut (dyn FnMut(&InfoHash, &TorrentEntry) + Send),
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            let mut skip = offset;
+            let mut remaining = limit;
+
+            for shard in self.shards.iter() {
+                if remaining == 0 {
+                    break;
+                }
+
+                let shard = shard.read().await;
+                if skip >= shard.len() {
+                    skip -= shard.len();
+                    continue;
+                }
+
+                for (info_hash, entry) in shard.iter().skip(skip).take(remaining) {
+                    f(info_hash, entry);
+                    remaining -= 1;
+                }
+                skip = 0;
+            }
+        })
+    }
+
+    fn lock_stats(&self) -> Vec<LockStats> {
+        self.shards.iter().map(TorrentShard::stats).collect()
+    }
+}
+
+/// Torrents kept in a concurrent hashmap, each behind its own lock. Buckets of the map are only locked for writing
+/// when torrents are added or removed, so announces to different torrents update their peers concurrently.
+pub struct ConcurrentStore {
+    map: dashmap::DashMap<InfoHash, Mutex<TorrentEntry>>,
+}
+
+impl ConcurrentStore {
+    pub fn new() -> ConcurrentStore {
+        ConcurrentStore {
+            map: dashmap::DashMap::new(),
+        }
+    }
+}
+
+impl TorrentStore for ConcurrentStore {
+    fn insert(&self, info_hash: InfoHash, entry: TorrentEntry) -> BoxFuture<'_, Option<TorrentEntry>> {
+        Box::pin(async move { self.map.insert(info_hash, Mutex::new(entry)).map(|v| v.into_inner().unwrap()) })
+    }
+
+    fn get<'a>(&'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&TorrentEntry) + Send)) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.map.get(info_hash).map(|entry| f(&entry.lock().unwrap())).is_some() })
+    }
+
+    fn get_mut<'a>(
+        &'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool> {
+        Box::pin(async move { self.map.get(info_hash).map(|entry| f(&mut entry.lock().unwrap())).is_some() })
+    }
+
+    fn get_or_insert_with<'a>(
+        &'a self, info_hash: &'a InfoHash, create: &'a mut (dyn FnMut() -> Option<TorrentEntry> + Send),
+        f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+    ) -> BoxFuture<'a, bool> {
+        use dashmap::mapref::entry::Entry;
+
+        Box::pin(async move {
+            // existing torrents only need their own lock.
+            if let Some(entry) = self.map.get(info_hash) {
+                f(&mut entry.lock().unwrap());
+                return true;
+            }
+            let mut entry = match self.map.entry(info_hash.clone()) {
+                Entry::Vacant(vacant) => {
+                    match create() {
+                        Some(entry) => vacant.insert(Mutex::new(entry)),
+                        None => return false,
+                    }
+                }
+                Entry::Occupied(occupied) => occupied.into_ref(),
+            };
+            f(entry.value_mut().get_mut().unwrap());
+            true
+        })
+    }
+
+    fn remove_if<'a>(
+        &'a self, info_hash: &'a InfoHash, predicate: &'a mut (dyn FnMut(&TorrentEntry) -> bool + Send),
+    ) -> BoxFuture<'a, Option<TorrentEntry>> {
+        Box::pin(async move {
+            self.map
+                .remove_if(info_hash, |_, entry| predicate(&entry.lock().unwrap()))
+                .map(|(_, v)| v.into_inner().unwrap())
+        })
+    }
+
+    fn retain<'a>(&'a self, f: &'a mut (dyn FnMut(&InfoHash, &mut TorrentEntry) -> bool + Send)) -> BoxFuture<'a, ()> {
+        Box::pin(async move { self.map.retain(|k, v| f(k, v.get_mut().unwrap())) })
+    }
+
+    /// Torrents are listed by infohash, hashmap order changes as the map grows.
+    fn for_each<'a>(
+        &'a self, offset: usize, limit: usize, f: &'a mut (dyn FnMut(&InfoHash, &TorrentEntry) + Send),
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            // listing everything doesn't need an order, pages do.
+            if offset == 0 && limit >= self.map.len() {
+                for item in self.map.iter() {
+                    f(item.key(), &item.value().lock().unwrap());
+                }
+                return;
+            }
+            let mut keys: Vec<InfoHash> = self.map.iter().map(|item| item.key().clone()).collect();
+            if offset >= keys.len() {
+                return;
+            }
+            // only the page is sorted.
+            keys.select_nth_unstable(offset);
+            let page = &mut keys[offset..];
+            let end = limit.min(page.len());
+            if end < page.len() {
+                page.select_nth_unstable(end);
+            }
+            let page = &mut page[..end];
+            page.sort_unstable();
+
+            for info_hash in page.iter() {
+                // torrents removed meanwhile are skipped.
+                if let Some(entry) = self.map.get(info_hash) {
+                    f(info_hash, &entry.lock().unwrap());
+                }
+            }
+        })
+    }
+}
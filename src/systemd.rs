@@ -0,0 +1,187 @@
+use log::{debug, warn};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+
+/// File descriptor of the first socket passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sockets passed by systemd socket activation, so udpt can serve privileged ports without running as root and
+/// keep its sockets (and their queued packets) across restarts.
+#[derive(Default)]
+pub struct ListenFds {
+    udp: Vec<UdpSocket>,
+    tcp: Vec<TcpListener>,
+}
+
+impl ListenFds {
+    /// Takes the sockets systemd passed to this process, see sd_listen_fds(3). Without socket activation there are
+    /// none, and every socket is bound as configured.
+    #[cfg(unix)]
+    pub fn take() -> ListenFds {
+        let is_ours = std::env::var("LISTEN_PID").ok().and_then(|v| v.parse::<u32>().ok()) == Some(std::process::id());
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|v| v.parse::<i32>().ok())
+            .unwrap_or(0);
+        // child processes mustn't take them again.
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter() {
+            std::env::remove_var(name);
+        }
+        if !is_ours {
+            return ListenFds::default();
+        }
+        ListenFds::from_fds(LISTEN_FDS_START..LISTEN_FDS_START + count)
+    }
+
+    /// Takes ownership of the sockets `fds`, e.g. passed by another process.
+    #[cfg(unix)]
+    pub fn from_fds(fds: impl IntoIterator<Item = i32>) -> ListenFds {
+        use std::os::unix::io::FromRawFd;
+
+        let mut sockets = ListenFds::default();
+        for fd in fds {
+            let mut kind: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let res = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_TYPE,
+                    &mut kind as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if res != 0 {
+                warn!("passed file descriptor {} isn't a socket.", fd);
+                continue;
+            }
+            // the descriptors are handed to us, nobody else closes them.
+            match kind {
+                libc::SOCK_DGRAM => sockets.udp.push(unsafe { UdpSocket::from_raw_fd(fd) }),
+                libc::SOCK_STREAM => sockets.tcp.push(unsafe { TcpListener::from_raw_fd(fd) }),
+                _ => warn!("passed socket {} is of an unsupported type.", fd),
+            }
+        }
+        sockets
+    }
+
+    #[cfg(not(unix))]
+    pub fn take() -> ListenFds {
+        ListenFds::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.udp.is_empty() && self.tcp.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.udp.len() + self.tcp.len()
+    }
+
+    /// Takes the passed UDP socket for `address`, matched by port.
+    pub fn take_udp(&mut self, address: &str) -> Option<UdpSocket> {
+        let port = port_of(address)?;
+        let index = self
+            .udp
+            .iter()
+            .position(|socket| local_port(socket.local_addr()) == Some(port))?;
+        Some(self.udp.remove(index))
+    }
+
+    /// Takes the passed TCP socket for `address`, matched by port.
+    pub fn take_tcp(&mut self, address: &str) -> Option<TcpListener> {
+        let port = port_of(address)?;
+        let index = self
+            .tcp
+            .iter()
+            .position(|socket| local_port(socket.local_addr()) == Some(port))?;
+        Some(self.tcp.remove(index))
+    }
+
+    /// Addresses of the passed sockets that weren't taken.
+    pub fn unused(&self) -> Vec<SocketAddr> {
+        let udp = self.udp.iter().filter_map(|v| v.local_addr().ok());
+        udp.chain(self.tcp.iter().filter_map(|v| v.local_addr().ok())).collect()
+    }
+}
+
+fn port_of(address: &str) -> Option<u16> {
+    address.to_socket_addrs().ok()?.next().map(|addr| addr.port())
+}
+
+fn local_port(addr: std::io::Result<SocketAddr>) -> Option<u16> {
+    addr.ok().map(|addr| addr.port())
+}
+
+/// Reports the service's state to systemd (`READY=1`, `STOPPING=1`, `WATCHDOG=1`...), see sd_notify(3).
+/// Does nothing unless systemd asked for notifications with `Type=notify`.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return,
+        };
+        if path.to_str().map(|v| v.starts_with('@')) == Some(true) {
+            debug!("abstract NOTIFY_SOCKET isn't supported, not notifying systemd.");
+            return;
+        }
+        let res = std::os::unix::net::UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+        if let Err(err) = res {
+            warn!("failed to notify systemd: {}", err);
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = state;
+    }
+}
+
+/// Interval to send `WATCHDOG=1` at when systemd's watchdog is enabled (`WatchdogSec=`), half its timeout.
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    let is_ours = match std::env::var("WATCHDOG_PID") {
+        Ok(pid) => pid.parse::<u32>().ok() == Some(std::process::id()),
+        Err(_) => true,
+    };
+    if !is_ours || usec == 0 {
+        return None;
+    }
+    Some(std::time::Duration::from_micros(usec / 2))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_socket() {
+        let path = std::env::temp_dir().join(format!("udpt-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        std::env::remove_var("NOTIFY_SOCKET");
+
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn match_sockets() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        let mut fds = ListenFds {
+            udp: vec![udp],
+            tcp: Vec::new(),
+        };
+
+        assert!(fds.take_tcp(&format!("127.0.0.1:{}", port)).is_none());
+        assert!(fds.take_udp("127.0.0.1:1").is_none());
+        assert!(fds.take_udp(&format!("0.0.0.0:{}", port)).is_some());
+        assert!(fds.is_empty());
+    }
+}
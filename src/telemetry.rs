@@ -0,0 +1,319 @@
+use crate::config::TelemetryConfig;
+use log::{error, trace, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+tokio::task_local! {
+    /// Span of the request a task is serving, spans started by the task become its children.
+    static CURRENT: SpanContext;
+}
+
+/// Identifies a span within its trace.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+}
+
+impl SpanContext {
+    /// Parses a W3C trace context header, `traceparent: 00-<trace id>-<span id>-<flags>`.
+    pub fn from_traceparent(header: &str) -> Option<SpanContext> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" || parts[1].len() != 32 || parts[2].len() != 16 {
+            return None;
+        }
+
+        let mut context = SpanContext {
+            trace_id: [0u8; 16],
+            span_id: [0u8; 8],
+        };
+        binascii::hex2bin(parts[1].as_bytes(), &mut context.trace_id).ok()?;
+        binascii::hex2bin(parts[2].as_bytes(), &mut context.span_id).ok()?;
+        if context.trace_id == [0u8; 16] || context.span_id == [0u8; 8] {
+            return None;
+        }
+        Some(context)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Random enough for span ids, without pulling in a RNG: std's hasher keys are randomly seeded.
+fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+fn unix_nanos() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|v| v.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Runs `f` as part of the span `context`, spans started by `f` become its children.
+pub async fn in_span<F: std::future::Future>(context: SpanContext, f: F) -> F::Output {
+    CURRENT.scope(context, f).await
+}
+
+#[derive(Clone, Copy)]
+pub enum SpanKind {
+    /// An operation of the tracker itself.
+    Internal = 1,
+    /// A request served by udpt.
+    Server = 2,
+}
+
+/// A span in OTLP's JSON encoding.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    parent_span_id: String,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Attribute>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct Attribute {
+    key: &'static str,
+    value: AttributeValue,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum AttributeValue {
+    StringValue(String),
+    IntValue(String),
+}
+
+#[derive(Serialize)]
+struct Status {
+    /// 0 = unset, 2 = error.
+    code: u8,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+}
+
+/// A running span, it's queued for export when dropped.
+pub struct Span<'a> {
+    tracer: &'a Tracer,
+    context: SpanContext,
+    /// Taken when the span ends.
+    data: Option<SpanData>,
+}
+
+impl<'a> Span<'a> {
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        self.push_attribute(key, AttributeValue::StringValue(value.to_string()));
+    }
+
+    pub fn set_int_attribute(&mut self, key: &'static str, value: u64) {
+        // 64 bit integers are strings in OTLP's JSON encoding.
+        self.push_attribute(key, AttributeValue::IntValue(value.to_string()));
+    }
+
+    fn push_attribute(&mut self, key: &'static str, value: AttributeValue) {
+        if let Some(ref mut data) = self.data {
+            data.attributes.push(Attribute { key, value });
+        }
+    }
+
+    pub fn set_error(&mut self, message: impl ToString) {
+        if let Some(ref mut data) = self.data {
+            data.status = Status {
+                code: 2,
+                message: message.to_string(),
+            };
+        }
+    }
+}
+
+impl<'a> Drop for Span<'a> {
+    fn drop(&mut self) {
+        if let Some(mut data) = self.data.take() {
+            data.end_time_unix_nano = unix_nanos().to_string();
+            self.tracer.push(data);
+        }
+    }
+}
+
+/// Records spans of API requests & expensive tracker operations, and exports them to an OpenTelemetry collector
+/// over OTLP/HTTP, as JSON. Spans are queued up to `max_queue`; while the collector is down, newer spans are dropped.
+pub struct Tracer {
+    client: hyper::Client<hyper::client::HttpConnector>,
+    endpoint: String,
+    service_name: String,
+    max_queue: usize,
+    queue: std::sync::Mutex<Vec<SpanData>>,
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    pub fn new(config: &TelemetryConfig) -> Tracer {
+        Tracer {
+            client: hyper::Client::new(),
+            endpoint: config.get_endpoint().to_string(),
+            service_name: config.get_service_name().to_string(),
+            max_queue: config.get_max_queue(),
+            queue: std::sync::Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts a span, as a child of `parent` or else of the task's current span.
+    pub fn start(&self, name: impl Into<String>, kind: SpanKind, parent: Option<SpanContext>) -> Span<'_> {
+        let parent = parent.or_else(|| CURRENT.try_with(|context| *context).ok());
+        let trace_id = match parent {
+            Some(parent) => parent.trace_id,
+            None => {
+                let mut trace_id = [0u8; 16];
+                trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+                trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+                trace_id
+            }
+        };
+        let context = SpanContext {
+            trace_id,
+            span_id: random_u64().to_be_bytes(),
+        };
+
+        Span {
+            tracer: self,
+            context,
+            data: Some(SpanData {
+                trace_id: hex(&context.trace_id),
+                span_id: hex(&context.span_id),
+                parent_span_id: parent.map(|v| hex(&v.span_id)).unwrap_or_default(),
+                name: name.into(),
+                kind: kind as u8,
+                start_time_unix_nano: unix_nanos().to_string(),
+                end_time_unix_nano: String::new(),
+                attributes: Vec::new(),
+                status: Status {
+                    code: 0,
+                    message: String::new(),
+                },
+            }),
+        }
+    }
+
+    fn push(&self, span: SpanData) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_queue {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push(span);
+    }
+
+    /// Exports the queued spans, they're dropped if the collector can't be reached.
+    pub async fn flush(&self) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("telemetry queue is full, dropped {} spans", dropped);
+        }
+
+        let spans = std::mem::replace(&mut *self.queue.lock().unwrap(), Vec::new());
+        if spans.is_empty() {
+            return;
+        }
+        let count = spans.len();
+        match self.send(self.encode(spans)).await {
+            Ok(_) => trace!("exported {} spans", count),
+            Err(err) => error!("failed to export {} spans: {}", count, err),
+        }
+    }
+
+    /// An OTLP `ExportTraceServiceRequest`.
+    fn encode(&self, spans: Vec<SpanData>) -> Vec<u8> {
+        let request = serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{"key": "service.name", "value": {"stringValue": self.service_name}}],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "udpt", "version": env!("CARGO_PKG_VERSION")},
+                    "spans": spans,
+                }],
+            }],
+        });
+        serde_json::to_vec(&request).unwrap_or_default()
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), String> {
+        let request = hyper::Request::post(self.endpoint.as_str())
+            .header("Content-Type", "application/json")
+            .body(hyper::Body::from(body))
+            .map_err(|e| e.to_string())?;
+
+        let timeout = std::time::Duration::from_secs(30);
+        match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => Ok(()),
+            Ok(Ok(response)) => Err(format!("responded with {}", response.status())),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err(String::from("timed out")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent() {
+        let context = SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(hex(&context.trace_id), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(hex(&context.span_id), "00f067aa0ba902b7");
+
+        assert!(SpanContext::from_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa-01").is_none());
+        assert!(SpanContext::from_traceparent("garbage").is_none());
+    }
+
+    #[tokio::test]
+    async fn spans() {
+        let config: TelemetryConfig = toml::from_str("endpoint = \"http://127.0.0.1:4318/v1/traces\"").unwrap();
+        let tracer = Tracer::new(&config);
+
+        let parent = SpanContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        let mut request = tracer.start("GET /stats", SpanKind::Server, parent);
+        request.set_attribute("http.method", "GET");
+        let context = request.context();
+        in_span(context, async {
+            let mut cleanup = tracer.start("cleanup", SpanKind::Internal, None);
+            cleanup.set_int_attribute("torrents", 3);
+            cleanup.set_error("failed");
+        })
+        .await;
+        drop(request);
+
+        let queue = tracer.queue.lock().unwrap();
+        let spans = serde_json::to_value(&*queue).unwrap();
+        assert_eq!(spans[0]["name"], "cleanup");
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["parentSpanId"], hex(&context.span_id));
+        assert_eq!(spans[0]["attributes"][0]["value"]["intValue"], "3");
+        assert_eq!(spans[0]["status"]["code"], 2);
+        assert_eq!(spans[1]["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(spans[1]["kind"], 2);
+        assert_eq!(spans[1]["attributes"][0]["value"]["stringValue"], "GET");
+    }
+}
@@ -1,25 +1,50 @@
+use crate::abuse::{Abuse, AbuseLog};
+use crate::analytics::{Analytics, AnalyticsEvent};
+use crate::bans::{Ban, BanList, BanTarget};
+use crate::config::{
+    AnalyticsConfig, ClusterConfig, ConfigChanges, Configuration, RaftConfig, RedisConfig, ReplicaConfig, SeederPolicy,
+    TelemetryConfig, UpstreamConfig, WebhookConfig,
+};
+use crate::fullscrape::ScrapeEntry;
+use crate::geoip::{CountryCode, GeoIp};
+use crate::gossip::{Gossip, MAX_PAYLOAD_SIZE};
+use crate::hyperloglog::RollingHyperLogLog;
+use crate::journal::{Journal, JournalEntry};
+use crate::negcache::NegativeCache;
+use crate::postgres_store::PostgresStore;
+use crate::raft::Raft;
+use crate::redis_store::{RedisPeer, RedisStore};
+use crate::replica::{RegisteredTorrent, RegisteredUser, RegistrySnapshot, Replica};
 use crate::server::Events;
-use log::{error, trace};
+use crate::slab::Slab;
+use crate::sqlite::{SqliteRow, SqliteStore};
+use crate::store::{ConcurrentStore, LockStats, ShardedStore, TorrentStore};
+use crate::telemetry::{Span, SpanKind, Tracer};
+use crate::traffic::{TrafficMonitor, TrafficReport};
+use crate::upstream::{Upstream, UpstreamAnnounce};
+use crate::users::{UserRegistry, UserUpdate};
+use crate::webhooks::{WebhookEvent, Webhooks};
+use log::{error, trace, warn};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::io::AsyncBufReadExt;
 use tokio::stream::StreamExt;
-use tokio::sync::RwLock;
 
-const TWO_HOURS: std::time::Duration = std::time::Duration::from_secs(3600 * 2);
-
-#[derive(Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum TrackerMode {
     /// In static mode torrents are tracked only if they were added ahead of time.
-    #[serde(rename = "static")]
+    /// Announces for unknown torrents result in a tracking error.
+    #[serde(rename = "static", alias = "closed")]
     StaticMode,
 
     /// In dynamic mode, torrents are tracked being added ahead of time.
     #[serde(rename = "dynamic")]
     DynamicMode,
 
-    /// Tracker will only serve authenticated peers.
+    /// Tracker will only serve peers authenticated by a passkey, torrents must be added ahead of time.
     #[serde(rename = "private")]
     PrivateMode,
 }
@@ -33,22 +58,87 @@ pub struct TorrentPeer {
     event: Events,
     #[serde(serialize_with = "ser_instant")]
     updated: std::time::Instant,
+
+    /// Transfer since the previous announce of this peer.
+    uploaded_delta: u64,
+    downloaded_delta: u64,
+    /// Milliseconds since the previous announce of this peer.
+    delta_ms: u64,
+
+    /// Consecutive announces in which the peer reported having the torrent.
+    #[serde(skip)]
+    complete_streak: u32,
+    /// Classified when the peer announced, by the seeder policy in effect then.
+    #[serde(skip)]
+    seeder: bool,
+    /// Country of `ip`, when a GeoIP database is configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<CountryCode>,
+}
+
+impl TorrentPeer {
+    fn is_seeder(&self) -> bool {
+        self.seeder
+    }
+}
+
+/// Clients reset their counters when restarted, in which case the whole value is the delta.
+fn counter_delta(prev: u64, current: u64) -> u64 {
+    if current >= prev {
+        current - prev
+    } else {
+        current
+    }
 }
 
 fn ser_instant<S: serde::Serializer>(inst: &std::time::Instant, ser: S) -> Result<S::Ok, S::Error> {
     ser.serialize_u64(inst.elapsed().as_millis() as u64)
 }
 
-#[derive(Ord, PartialEq, Eq, Clone)]
-pub struct InfoHash {
-    info_hash: [u8; 20],
+/// Torrents are identified by their v1 (SHA-1) or v2 (SHA-256) infohash.
+/// BEP 52 clients announce v2 torrents using the first 20 bytes of the v2 infohash over the UDP protocol.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Hash, Clone)]
+pub enum InfoHash {
+    V1([u8; 20]),
+    V2([u8; 32]),
+}
+
+impl InfoHash {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            InfoHash::V1(v) => v,
+            InfoHash::V2(v) => v,
+        }
+    }
+
+    fn from_hex(s: &str) -> Result<InfoHash, binascii::ConvertError> {
+        match s.len() {
+            40 => {
+                let mut v = [0u8; 20];
+                binascii::hex2bin(s.as_bytes(), &mut v)?;
+                Ok(InfoHash::V1(v))
+            }
+            64 => {
+                let mut v = [0u8; 32];
+                binascii::hex2bin(s.as_bytes(), &mut v)?;
+                Ok(InfoHash::V2(v))
+            }
+            _ => Err(binascii::ConvertError::InvalidInputLength),
+        }
+    }
 }
 
 impl std::fmt::Display for InfoHash {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let mut chars = [0u8; 40];
-        binascii::bin2hex(&self.info_hash, &mut chars).expect("failed to hexlify");
-        write!(f, "{}", std::str::from_utf8(&chars).unwrap())
+        let mut chars = [0u8; 64];
+        let chars = binascii::bin2hex(self.as_bytes(), &mut chars).expect("failed to hexlify");
+        write!(f, "{}", std::str::from_utf8(chars).unwrap())
+    }
+}
+
+impl std::fmt::Debug for InfoHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "InfoHash({})", self)
     }
 }
 
@@ -56,40 +146,44 @@ impl std::str::FromStr for InfoHash {
     type Err = binascii::ConvertError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut i = Self { info_hash: [0u8; 20] };
-        if s.len() != 40 {
-            return Err(binascii::ConvertError::InvalidInputLength);
-        }
-        binascii::hex2bin(s.as_bytes(), &mut i.info_hash)?;
-        Ok(i)
-    }
-}
-
-impl std::cmp::PartialOrd<InfoHash> for InfoHash {
-    fn partial_cmp(&self, other: &InfoHash) -> Option<std::cmp::Ordering> {
-        self.info_hash.partial_cmp(&other.info_hash)
+        InfoHash::from_hex(s)
     }
 }
 
 impl std::convert::From<&[u8]> for InfoHash {
     fn from(data: &[u8]) -> InfoHash {
-        assert_eq!(data.len(), 20);
-        let mut ret = InfoHash { info_hash: [0u8; 20] };
-        ret.info_hash.clone_from_slice(data);
-        return ret;
+        match data.len() {
+            20 => {
+                let mut ret = [0u8; 20];
+                ret.clone_from_slice(data);
+                InfoHash::V1(ret)
+            }
+            32 => {
+                let mut ret = [0u8; 32];
+                ret.clone_from_slice(data);
+                InfoHash::V2(ret)
+            }
+            len => panic!("invalid infohash length: {}", len),
+        }
     }
 }
 
 impl std::convert::Into<InfoHash> for [u8; 20] {
     fn into(self) -> InfoHash {
-        InfoHash { info_hash: self }
+        InfoHash::V1(self)
+    }
+}
+
+impl std::convert::Into<InfoHash> for [u8; 32] {
+    fn into(self) -> InfoHash {
+        InfoHash::V2(self)
     }
 }
 
 impl serde::ser::Serialize for InfoHash {
     fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut buffer = [0u8; 40];
-        let bytes_out = binascii::bin2hex(&self.info_hash, &mut buffer).ok().unwrap();
+        let mut buffer = [0u8; 64];
+        let bytes_out = binascii::bin2hex(self.as_bytes(), &mut buffer).ok().unwrap();
         let str_out = std::str::from_utf8(bytes_out).unwrap();
 
         serializer.serialize_str(str_out)
@@ -102,26 +196,25 @@ impl<'v> serde::de::Visitor<'v> for InfoHashVisitor {
     type Value = InfoHash;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "a 40 character long hash")
+        write!(formatter, "a 40 or 64 character long hash")
     }
 
     fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
-        if v.len() != 40 {
+        if v.len() != 40 && v.len() != 64 {
             return Err(serde::de::Error::invalid_value(
                 serde::de::Unexpected::Str(v),
-                &"expected a 40 character long string",
+                &"expected a 40 or 64 character long string",
             ));
         }
 
-        let mut res = InfoHash { info_hash: [0u8; 20] };
-
-        if let Err(_) = binascii::hex2bin(v.as_bytes(), &mut res.info_hash) {
-            return Err(serde::de::Error::invalid_value(
-                serde::de::Unexpected::Str(v),
-                &"expected a hexadecimal string",
-            ));
-        } else {
-            return Ok(res);
+        match InfoHash::from_hex(v) {
+            Err(_) => {
+                Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Str(v),
+                    &"expected a hexadecimal string",
+                ))
+            }
+            Ok(res) => Ok(res),
         }
     }
 }
@@ -133,7 +226,7 @@ impl<'de> serde::de::Deserialize<'de> for InfoHash {
 }
 
 #[repr(transparent)]
-#[derive(Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+#[derive(Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Hash)]
 pub struct PeerId([u8; 20]);
 impl PeerId {
     pub fn from_array(v: &[u8; 20]) -> &PeerId {
@@ -143,6 +236,20 @@ impl PeerId {
         }
     }
 
+    fn to_hex(&self) -> String {
+        let mut tmp = [0u8; 40];
+        binascii::bin2hex(&self.0, &mut tmp).unwrap();
+        String::from_utf8_lossy(&tmp).into_owned()
+    }
+
+    fn from_hex(s: &str) -> Option<PeerId> {
+        let mut v = [0u8; 20];
+        if s.len() != 40 || binascii::hex2bin(s.as_bytes(), &mut v).is_err() {
+            return None;
+        }
+        Some(PeerId(v))
+    }
+
     pub fn get_client_name(&self) -> Option<&'static str> {
         if self.0[0] == b'M' {
             return Some("BitTorrent");
@@ -220,6 +327,33 @@ impl PeerId {
             None
         }
     }
+
+    /// Parses the client version from Azureus-style (`-qB4250-`) and Mainline-style (`M4-3-6--`) peer ids.
+    pub fn get_client_version(&self) -> Option<String> {
+        match self.0[0] {
+            b'-' if self.0[7] == b'-' => {
+                let digits = &self.0[3..7];
+                if !digits.iter().all(|c| c.is_ascii_alphanumeric()) {
+                    return None;
+                }
+                let parts: Vec<String> = digits.iter().map(|c| (*c as char).to_string()).collect();
+                Some(parts.join("."))
+            }
+            b'M' => {
+                let parts: Vec<&str> = self.0[1..8]
+                    .split(|c| *c == b'-')
+                    .filter(|part| !part.is_empty())
+                    .map(std::str::from_utf8)
+                    .collect::<Result<_, _>>()
+                    .ok()?;
+                if parts.is_empty() || !parts.iter().all(|part| part.bytes().all(|c| c.is_ascii_digit())) {
+                    return None;
+                }
+                Some(parts.join("."))
+            }
+            _ => None,
+        }
+    }
 }
 impl Serialize for PeerId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -243,26 +377,269 @@ impl Serialize for PeerId {
     }
 }
 
+/// A counter that decays exponentially with a time constant of one minute.
+/// Incrementing it on every event makes its value approximate the events per minute.
+#[derive(Clone, Copy, Default)]
+pub struct DecayingCounter {
+    value: f64,
+    updated: Option<std::time::Instant>,
+}
+
+impl DecayingCounter {
+    const TIME_CONSTANT_SECS: f64 = 60.0;
+
+    fn decayed(&self, now: std::time::Instant) -> f64 {
+        match self.updated {
+            Some(updated) => {
+                let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+                self.value * (-elapsed / Self::TIME_CONSTANT_SECS).exp()
+            }
+            None => 0.0,
+        }
+    }
+
+    pub fn add(&mut self, now: std::time::Instant, amount: f64) {
+        self.value = self.decayed(now) + amount;
+        self.updated = Some(now);
+    }
+
+    /// Events per minute, as of `now`.
+    pub fn get(&self, now: std::time::Instant) -> f64 {
+        self.decayed(now)
+    }
+}
+
+/// How well a swarm is doing, as of the last cleanup. Each factor is between 0 & 1, higher is better.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct SwarmHealth {
+    /// The weighted factors, from 0 to 100.
+    score: u8,
+    status: HealthStatus,
+    /// Seeders per leecher, up to one seeder each. Swarms without seeders score 0.
+    seeds: f64,
+    /// Share of the joining peers in the swarm's churn, shrinking swarms score low.
+    churn: f64,
+    /// Distinct IPs per peer, low when a few hosts make up the swarm.
+    diversity: f64,
+    /// How recently the peers announced, relative to the peer timeout.
+    freshness: f64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Dying,
+}
+
+impl SwarmHealth {
+    const SEEDS_WEIGHT: f64 = 0.4;
+    const CHURN_WEIGHT: f64 = 0.2;
+    const DIVERSITY_WEIGHT: f64 = 0.2;
+    const FRESHNESS_WEIGHT: f64 = 0.2;
+
+    fn compute(entry: &TorrentEntry, now: std::time::Instant, peer_timeout: std::time::Duration) -> SwarmHealth {
+        let peers = entry.peers.len();
+        if peers == 0 {
+            return SwarmHealth::from_factors(0.0, 0.0, 0.0, 0.0);
+        }
+
+        let (seeders, _, leechers) = entry.get_stats();
+        let seeds = (f64::from(seeders) / f64::from(leechers.max(1))).min(1.0);
+
+        let (joins, departures) = (entry.joins.get(now), entry.departures.get(now));
+        // a quiet swarm isn't shrinking.
+        let churn = if joins + departures < 0.01 {
+            1.0
+        } else {
+            joins / (joins + departures)
+        };
+
+        let ips: std::collections::HashSet<_> = entry.peers.values().map(|peer| peer.ip.ip()).collect();
+        let diversity = ips.len() as f64 / peers as f64;
+
+        let timeout = peer_timeout.as_secs_f64().max(1.0);
+        let freshness = entry
+            .peers
+            .values()
+            .map(|peer| 1.0 - (now.saturating_duration_since(peer.updated).as_secs_f64() / timeout).min(1.0))
+            .sum::<f64>()
+            / peers as f64;
+
+        SwarmHealth::from_factors(seeds, churn, diversity, freshness)
+    }
+
+    fn from_factors(seeds: f64, churn: f64, diversity: f64, freshness: f64) -> SwarmHealth {
+        let score = seeds * Self::SEEDS_WEIGHT
+            + churn * Self::CHURN_WEIGHT
+            + diversity * Self::DIVERSITY_WEIGHT
+            + freshness * Self::FRESHNESS_WEIGHT;
+        let score = (score * 100.0).round() as u8;
+        let status = match score {
+            60..=100 => HealthStatus::Healthy,
+            30..=59 => HealthStatus::Degraded,
+            _ => HealthStatus::Dying,
+        };
+        SwarmHealth {
+            score,
+            status,
+            seeds,
+            churn,
+            diversity,
+            freshness,
+        }
+    }
+
+    pub fn get_score(&self) -> u8 {
+        self.score
+    }
+}
+
+/// Peer addresses of a swarm, encoded as sent in announce responses: the address followed by the port in network
+/// byte order (6 bytes per IPv4 peer, 18 bytes per IPv6 peer).
+pub struct CompactPeers {
+    built: std::time::Instant,
+    v4: Vec<u8>,
+    v6: Vec<u8>,
+}
+
+impl CompactPeers {
+    /// Peers encoded per address family, one more than the response limit so excluding the announcing peer still
+    /// leaves enough.
+    const MAX_PEERS: usize = 75;
+
+    fn build<'a, I: Iterator<Item = &'a TorrentPeer>>(peers: I) -> CompactPeers {
+        let mut compact = CompactPeers {
+            built: std::time::Instant::now(),
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        for peer in peers {
+            let buffer = match peer.ip {
+                std::net::SocketAddr::V4(addr) if compact.v4.len() < Self::MAX_PEERS * 6 => {
+                    compact.v4.extend_from_slice(&addr.ip().octets());
+                    &mut compact.v4
+                }
+                std::net::SocketAddr::V6(addr) if compact.v6.len() < Self::MAX_PEERS * 18 => {
+                    compact.v6.extend_from_slice(&addr.ip().octets());
+                    &mut compact.v6
+                }
+                _ => continue,
+            };
+            buffer.extend_from_slice(&peer.ip.port().to_be_bytes());
+        }
+        compact
+    }
+
+    /// The peers of `addr`'s address family, and `addr` as encoded among them.
+    fn encoded(&self, addr: &std::net::SocketAddr) -> (&[u8], Vec<u8>) {
+        let (buffer, mut encoded) = match addr {
+            std::net::SocketAddr::V4(v4) => (&self.v4, v4.ip().octets().to_vec()),
+            std::net::SocketAddr::V6(v6) => (&self.v6, v6.ip().octets().to_vec()),
+        };
+        encoded.extend_from_slice(&addr.port().to_be_bytes());
+        (buffer, encoded)
+    }
+
+    pub fn contains(&self, addr: &std::net::SocketAddr) -> bool {
+        let (buffer, encoded) = self.encoded(addr);
+        buffer.chunks_exact(encoded.len()).any(|peer| peer == encoded.as_slice())
+    }
+
+    /// Writes up to `max` peers of `remote_addr`'s address family, except `remote_addr` itself unless
+    /// `include_self` is set. Returns the amount of peers written.
+    pub fn write_to<W: std::io::Write>(
+        &self, w: &mut W, remote_addr: &std::net::SocketAddr, max: usize, include_self: bool,
+    ) -> usize {
+        let (buffer, own) = self.encoded(remote_addr);
+
+        let mut count = 0;
+        for peer in buffer.chunks_exact(own.len()) {
+            if count >= max {
+                break;
+            }
+            if !include_self && peer == own.as_slice() {
+                continue;
+            }
+            if w.write_all(peer).is_err() {
+                break;
+            }
+            count += 1;
+        }
+        count
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TorrentEntry {
     is_flagged: bool,
 
     #[serde(skip)]
-    peers: std::collections::BTreeMap<PeerId, TorrentPeer>,
+    peers: Slab<PeerId, TorrentPeer>,
 
     completed: u32,
 
+    /// Unix timestamps of the most recent completed events, oldest first.
+    #[serde(default)]
+    completion_history: std::collections::VecDeque<u64>,
+
+    /// Infohashes that were linked to this torrent, announces to them land in this swarm.
+    #[serde(default)]
+    aliases: Vec<InfoHash>,
+
+    /// Unix timestamps of when the torrent was first announced or added, and of its latest announce.
+    /// Unknown for torrents tracked by older versions.
+    #[serde(default)]
+    first_seen: Option<u64>,
+    #[serde(default)]
+    last_activity: Option<u64>,
+
     #[serde(skip)]
     seeders: u32,
+
+    #[serde(skip)]
+    empty_since: Option<std::time::Instant>,
+
+    #[serde(skip)]
+    last_announce: Option<std::time::Instant>,
+
+    #[serde(skip)]
+    announce_rate: DecayingCounter,
+
+    /// Peers that joined & left the swarm, per minute.
+    #[serde(skip)]
+    joins: DecayingCounter,
+    #[serde(skip)]
+    departures: DecayingCounter,
+
+    /// Encoded peers of a large swarm, until the swarm changes or `COMPACT_PEERS_TTL` passes.
+    #[serde(skip)]
+    compact_peers: Option<Arc<CompactPeers>>,
+
+    /// Computed by the cleanup task, None until it first ran since the torrent was added.
+    #[serde(skip)]
+    health: Option<SwarmHealth>,
 }
 
 impl TorrentEntry {
     pub fn new() -> TorrentEntry {
         TorrentEntry {
             is_flagged: false,
-            peers: std::collections::BTreeMap::new(),
+            peers: Slab::new(),
             completed: 0,
+            completion_history: std::collections::VecDeque::new(),
+            aliases: Vec::new(),
+            first_seen: Some(unix_now()),
+            last_activity: None,
             seeders: 0,
+            empty_since: None,
+            last_announce: None,
+            announce_rate: DecayingCounter::default(),
+            joins: DecayingCounter::default(),
+            departures: DecayingCounter::default(),
+            compact_peers: None,
+            health: None,
         }
     }
 
@@ -270,27 +647,115 @@ impl TorrentEntry {
         self.is_flagged
     }
 
+    pub fn get_completed(&self) -> u32 {
+        self.completed
+    }
+
+    /// Unix timestamps of the most recent completed events, oldest first.
+    pub fn get_completion_history(&self) -> &std::collections::VecDeque<u64> {
+        &self.completion_history
+    }
+
+    /// Infohashes linked to this torrent.
+    pub fn get_aliases(&self) -> &[InfoHash] {
+        &self.aliases
+    }
+
+    /// Drops the oldest completed events beyond `max_entries`.
+    pub fn trim_completion_history(&mut self, max_entries: usize) {
+        while self.completion_history.len() > max_entries {
+            self.completion_history.pop_front();
+        }
+    }
+
+    fn clear_peers(&mut self) {
+        self.peers.clear();
+        self.seeders = 0;
+        self.compact_peers = None;
+    }
+
+    /// Zeroes the completed count & drops the peers, the flag & aliases are kept.
+    fn reset_stats(&mut self) {
+        self.clear_peers();
+        self.completed = 0;
+        self.completion_history.clear();
+        self.announce_rate = DecayingCounter::default();
+        self.joins = DecayingCounter::default();
+        self.departures = DecayingCounter::default();
+        self.health = None;
+    }
+
+    /// Copies the state that's persisted, peers & other runtime state are left out.
+    fn persisted_copy(&self) -> TorrentEntry {
+        TorrentEntry {
+            is_flagged: self.is_flagged,
+            completed: self.completed,
+            completion_history: self.completion_history.clone(),
+            aliases: self.aliases.clone(),
+            first_seen: self.first_seen,
+            last_activity: self.last_activity,
+            ..TorrentEntry::new()
+        }
+    }
+
+    /// Returns true if the announce was counted as a completed download.
+    #[allow(clippy::too_many_arguments)]
     pub fn update_peer(
         &mut self, peer_id: &PeerId, remote_address: &std::net::SocketAddr, uploaded: u64, downloaded: u64, left: u64,
-        event: Events,
-    ) {
-        let is_seeder = left == 0 && uploaded > 0;
+        event: Events, policy: &SeederPolicy,
+    ) -> bool {
         let mut was_seeder = false;
         let mut is_completed = left == 0 && (event as u32) == (Events::Complete as u32);
+        let now = std::time::Instant::now();
+        self.last_announce = Some(now);
+        self.last_activity = Some(unix_now());
+        self.announce_rate.add(now, 1.0);
+
+        let (uploaded_delta, downloaded_delta, delta_ms, complete_streak, country) = match self.peers.get(peer_id) {
+            Some(prev) => {
+                (
+                    counter_delta(prev.uploaded, uploaded),
+                    counter_delta(prev.downloaded, downloaded),
+                    now.saturating_duration_since(prev.updated).as_millis() as u64,
+                    prev.complete_streak,
+                    prev.country.filter(|_| prev.ip.ip() == remote_address.ip()),
+                )
+            }
+            None => (0, 0, 0, 0, None),
+        };
+        let complete_streak = if policy.is_complete(downloaded, left) {
+            complete_streak.saturating_add(1)
+        } else {
+            0
+        };
+        let is_seeder = uploaded > 0 && complete_streak >= policy.get_confirmations();
+
         if let Some(prev) = self.peers.insert(*peer_id, TorrentPeer {
-            updated: std::time::Instant::now(),
+            updated: now,
             left,
             downloaded,
             uploaded,
             ip: *remote_address,
             event,
+            uploaded_delta,
+            downloaded_delta,
+            delta_ms,
+            complete_streak,
+            seeder: is_seeder,
+            country,
         }) {
-            was_seeder = prev.left == 0 && prev.uploaded > 0;
+            was_seeder = prev.is_seeder();
+            if prev.ip != *remote_address {
+                self.compact_peers = None;
+            }
 
             if is_completed && (prev.event as u32) == (Events::Complete as u32) {
                 // don't update count again. a torrent should only be updated once per peer.
                 is_completed = false;
             }
+        } else {
+            self.compact_peers = None;
+            self.joins.add(now, 1.0);
         }
 
         if is_seeder && !was_seeder {
@@ -302,340 +767,4065 @@ impl TorrentEntry {
         if is_completed {
             self.completed += 1;
         }
+        is_completed
     }
 
-    pub fn get_peers(&self, remote_addr: &std::net::SocketAddr) -> Vec<std::net::SocketAddr> {
-        let mut list = Vec::new();
-        for (_, peer) in self
-            .peers
-            .iter()
-            .filter(|e| e.1.ip.is_ipv4() == remote_addr.is_ipv4())
-            .take(74)
-        {
-            if peer.ip == *remote_addr {
-                continue;
-            }
-
-            list.push(peer.ip);
+    /// Refreshes a peer that re-announced within `window` of its previous announce, from the same address & with
+    /// nothing new to report. Returns false if the announce has to be handled in full.
+    fn touch_duplicate(
+        &mut self, peer_id: &PeerId, remote_address: &std::net::SocketAddr, left: u64, event: Events,
+        window: std::time::Duration, now: std::time::Instant,
+    ) -> bool {
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        let is_duplicate = (event as u32) == (Events::None as u32)
+            && peer.ip == *remote_address
+            && peer.left == left
+            && now.saturating_duration_since(peer.updated) < window;
+        if is_duplicate {
+            peer.updated = now;
         }
-        list
-    }
-
-    pub fn get_peers_iter(&self) -> impl Iterator<Item = (&PeerId, &TorrentPeer)> {
-        self.peers.iter()
-    }
-
-    pub fn get_stats(&self) -> (u32, u32, u32) {
-        let leechers = (self.peers.len() as u32) - self.seeders;
-        (self.seeders, self.completed, leechers)
+        is_duplicate
     }
-}
 
-struct TorrentDatabase {
-    torrent_peers: tokio::sync::RwLock<std::collections::BTreeMap<InfoHash, TorrentEntry>>,
-}
-
-impl Default for TorrentDatabase {
-    fn default() -> Self {
-        TorrentDatabase {
-            torrent_peers: tokio::sync::RwLock::new(std::collections::BTreeMap::new()),
+    /// Records a completed event, keeping at most `max_entries` of the most recent ones.
+    pub fn record_completion(&mut self, max_entries: usize) {
+        if max_entries == 0 {
+            return;
         }
-    }
-}
-
-pub struct TorrentTracker {
-    mode: TrackerMode,
-    database: TorrentDatabase,
-}
-
-#[derive(Serialize, Deserialize)]
-struct DatabaseRow<'a> {
-    info_hash: InfoHash,
-    entry: Cow<'a, TorrentEntry>,
-}
 
-pub enum TorrentStats {
-    TorrentFlagged,
-    TorrentNotRegistered,
-    Stats { seeders: u32, leechers: u32, complete: u32 },
-}
+        let now = match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(v) => v.as_secs(),
+            Err(_) => return,
+        };
 
-impl TorrentTracker {
-    pub fn new(mode: TrackerMode) -> TorrentTracker {
-        TorrentTracker {
-            mode,
-            database: TorrentDatabase {
-                torrent_peers: RwLock::new(std::collections::BTreeMap::new()),
-            },
+        while self.completion_history.len() >= max_entries {
+            self.completion_history.pop_front();
         }
+        self.completion_history.push_back(now);
     }
 
-    pub async fn load_database<R: tokio::io::AsyncRead + Unpin>(
-        mode: TrackerMode, reader: &mut R,
-    ) -> Result<TorrentTracker, std::io::Error> {
-        use tokio_util::compat::{Tokio02AsyncReadCompatExt, FuturesAsyncReadCompatExt};
-
-        let reader = tokio::io::BufReader::new(reader).compat();
-        let reader = async_compression::futures::bufread::BzDecoder::new(reader).compat();
-        let reader = tokio::io::BufReader::new(reader);
-
-        let mut tmp: Vec<u8> = Vec::with_capacity(4096);
-        tmp.resize(tmp.capacity(), 0);
+    /// Removes peers that didn't announce within `timeout` of `now`, keeping the seeder count in sync.
+    pub fn reap_peers(&mut self, now: std::time::Instant, timeout: std::time::Duration) {
+        let mut peers_to_remove = Vec::new();
 
-        let res = TorrentTracker::new(mode);
-        let mut db = res.database.torrent_peers.write().await;
+        for (peer_id, state) in self.peers.iter() {
+            if now.saturating_duration_since(state.updated) > timeout {
+                peers_to_remove.push(*peer_id);
+            }
+        }
 
-        let mut records = reader
-            .lines()
-            .filter_map(|res: Result<String, _>| {
-                if let Err(ref err) = res {
-                    error!("failed to read lines! {}", err);
-                }
-                res.ok()
-            })
-            .map(|line: String| serde_json::from_str::<DatabaseRow>(&line))
-            .filter_map(|jsr| {
-                if let Err(ref err) = jsr {
-                    error!("failed to parse json: {}", err);
+        for peer_id in peers_to_remove.iter() {
+            if let Some(peer) = self.peers.remove(peer_id) {
+                if peer.is_seeder() {
+                    self.seeders -= 1;
                 }
-                jsr.ok()
-            });
-
-        while let Some(entry) = records.next().await {
-            let x = || (entry.entry, entry.info_hash);
-            let (a, b) = x();
-            let a = a.into_owned();
-            db.insert(b, a);
+            }
+        }
+        if !peers_to_remove.is_empty() {
+            self.compact_peers = None;
+            self.departures.add(now, peers_to_remove.len() as f64);
         }
-        trace!("loaded {} entries from database", db.len());
-
-        drop(db);
-
-        Ok(res)
     }
 
-    /// Adding torrents is not relevant to dynamic trackers.
-    pub async fn add_torrent(&self, info_hash: &InfoHash) -> Result<(), ()> {
-        let mut write_lock = self.database.torrent_peers.write().await;
-        match write_lock.entry(info_hash.clone()) {
-            std::collections::btree_map::Entry::Vacant(ve) => {
-                ve.insert(TorrentEntry::new());
-                return Ok(());
-            }
-            std::collections::btree_map::Entry::Occupied(_entry) => {
-                return Err(());
-            }
+    /// Evicts the peers with the oldest announces once more than `max_peers` are tracked.
+    /// Peers are evicted in batches of 1% of `max_peers`, so a swarm at its cap isn't scanned on every announce.
+    pub fn evict_peers(&mut self, max_peers: usize) {
+        let count = self.peers.len();
+        if count <= max_peers {
+            return;
         }
-    }
 
-    /// If the torrent is flagged, it will not be removed unless force is set to true.
-    pub async fn remove_torrent(&self, info_hash: &InfoHash, force: bool) -> Result<(), ()> {
-        use std::collections::btree_map::Entry;
-        let mut entry_lock = self.database.torrent_peers.write().await;
-        let torrent_entry = entry_lock.entry(info_hash.clone());
-        match torrent_entry {
-            Entry::Vacant(_) => {
-                // no entry, nothing to do...
-                return Err(());
-            }
-            Entry::Occupied(entry) => {
-                if force || !entry.get().is_flagged() {
-                    entry.remove();
-                    return Ok(());
+        let batch = (count - max_peers).max(max_peers / 100).max(1);
+        let mut by_age: Vec<(std::time::Instant, PeerId)> =
+            self.peers.iter().map(|(peer_id, peer)| (peer.updated, *peer_id)).collect();
+        if batch < count {
+            by_age.select_nth_unstable(batch);
+        }
+        for (_, peer_id) in by_age.iter().take(batch) {
+            if let Some(peer) = self.peers.remove(peer_id) {
+                if peer.is_seeder() {
+                    self.seeders -= 1;
                 }
-                return Err(());
             }
         }
+        self.departures.add(std::time::Instant::now(), batch as f64);
+        self.compact_peers = None;
     }
 
-    /// flagged torrents will result in a tracking error. This is to allow enforcement against piracy.
-    pub async fn set_torrent_flag(&self, info_hash: &InfoHash, is_flagged: bool) -> bool {
-        if let Some(entry) = self.database.torrent_peers.write().await.get_mut(info_hash) {
-            if is_flagged && !entry.is_flagged {
-                // empty peer list.
-                entry.peers.clear();
+    /// Tags the peer with the country of its IP, unless it was already tagged at that IP.
+    fn locate_peer(&mut self, peer_id: &PeerId, geoip: &GeoIp) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            if peer.country.is_none() {
+                peer.country = geoip.lookup(peer.ip.ip());
             }
-            entry.is_flagged = is_flagged;
-            true
-        } else {
-            false
         }
     }
 
-    pub async fn get_torrent_peers(
-        &self, info_hash: &InfoHash, remote_addr: &std::net::SocketAddr,
-    ) -> Option<Vec<std::net::SocketAddr>> {
-        let read_lock = self.database.torrent_peers.read().await;
-        match read_lock.get(info_hash) {
-            None => {
-                return None;
-            }
-            Some(entry) => {
-                return Some(entry.get_peers(remote_addr));
-            }
-        };
+    /// Peers by country, peers whose country isn't known are left out.
+    pub fn get_countries(&self) -> BTreeMap<CountryCode, usize> {
+        let mut countries = BTreeMap::new();
+        for country in self.peers.values().filter_map(|peer| peer.country) {
+            *countries.entry(country).or_insert(0) += 1;
+        }
+        countries
     }
 
-    pub async fn update_torrent_and_get_stats(
-        &self, info_hash: &InfoHash, peer_id: &PeerId, remote_address: &std::net::SocketAddr, uploaded: u64,
-        downloaded: u64, left: u64, event: Events,
-    ) -> TorrentStats {
-        use std::collections::btree_map::Entry;
-        let mut torrent_peers = self.database.torrent_peers.write().await;
-        let torrent_entry = match torrent_peers.entry(info_hash.clone()) {
-            Entry::Vacant(vacant) => {
-                match self.mode {
-                    TrackerMode::DynamicMode => vacant.insert(TorrentEntry::new()),
-                    _ => {
-                        return TorrentStats::TorrentNotRegistered;
-                    }
+    /// Adds or replaces a peer, unless a more recent announce of the peer was already seen.
+    fn merge_peer(&mut self, peer_id: PeerId, peer: TorrentPeer) {
+        let was_seeder = match self.peers.get(&peer_id) {
+            Some(prev) if prev.updated >= peer.updated => return,
+            Some(prev) => {
+                if prev.ip != peer.ip {
+                    self.compact_peers = None;
                 }
+                prev.is_seeder()
             }
-            Entry::Occupied(entry) => {
-                if entry.get().is_flagged() {
-                    return TorrentStats::TorrentFlagged;
-                }
-                entry.into_mut()
+            None => {
+                self.compact_peers = None;
+                self.joins.add(std::time::Instant::now(), 1.0);
+                false
             }
         };
 
-        torrent_entry.update_peer(peer_id, remote_address, uploaded, downloaded, left, event);
+        let is_seeder = peer.is_seeder();
+        self.peers.insert(peer_id, peer);
+        // announced to another node.
+        self.last_activity = Some(unix_now());
+        if is_seeder && !was_seeder {
+            self.seeders += 1;
+        } else if was_seeder && !is_seeder {
+            self.seeders -= 1;
+        }
+    }
 
-        let (seeders, complete, leechers) = torrent_entry.get_stats();
+    /// Moves the peers & counters of `other` into this torrent. For peers present in both, the latest announce is kept.
+    pub fn merge(&mut self, other: TorrentEntry) {
+        for (peer_id, peer) in other.peers {
+            self.merge_peer(peer_id, peer);
+        }
 
-        return TorrentStats::Stats {
-            seeders,
-            leechers,
-            complete,
+        self.completed += other.completed;
+        if !other.completion_history.is_empty() {
+            let mut history: Vec<u64> = self.completion_history.drain(..).chain(other.completion_history).collect();
+            history.sort_unstable();
+            self.completion_history = history.into();
+        }
+        self.is_flagged |= other.is_flagged;
+        self.last_announce = self.last_announce.max(other.last_announce);
+        self.last_activity = self.last_activity.max(other.last_activity);
+        self.first_seen = match (self.first_seen, other.first_seen) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
         };
     }
 
-    pub(crate) async fn get_database<'a>(&'a self) -> tokio::sync::RwLockReadGuard<'a, BTreeMap<InfoHash, TorrentEntry>> {
-        self.database.torrent_peers.read().await
+    /// Unix timestamp of the torrent's latest announce, None if it wasn't announced (or by an older version).
+    pub fn get_last_activity(&self) -> Option<u64> {
+        self.last_activity
     }
 
-    pub async fn save_database<W: tokio::io::AsyncWrite + Unpin>(&self, w: W) -> Result<(), std::io::Error> {
-        use futures::io::AsyncWriteExt;
-        use tokio_util::compat::Tokio02AsyncWriteCompatExt;
+    /// Announces per minute, exponentially decayed.
+    pub fn get_announce_rate(&self) -> f64 {
+        self.announce_rate.get(std::time::Instant::now())
+    }
 
-        let mut writer = async_compression::futures::write::BzEncoder::new(w.compat_write());
+    /// Peers that joined & left the swarm per minute, exponentially decayed. Peers leave once they time out or are
+    /// evicted.
+    pub fn get_churn(&self) -> (f64, f64) {
+        let now = std::time::Instant::now();
+        (self.joins.get(now), self.departures.get(now))
+    }
 
-        let db_lock = self.database.torrent_peers.read().await;
+    pub fn get_health(&self) -> Option<&SwarmHealth> {
+        self.health.as_ref()
+    }
 
-        let db: &BTreeMap<InfoHash, TorrentEntry> = &*db_lock;
-        let mut tmp = Vec::with_capacity(4096);
+    pub fn get_peer_count(&self) -> usize {
+        self.peers.len()
+    }
 
-        for row in db {
-            let entry = DatabaseRow {
-                info_hash: row.0.clone(),
-                entry: Cow::Borrowed(row.1),
-            };
-            tmp.clear();
-            if let Err(err) = serde_json::to_writer(&mut tmp, &entry) {
-                error!("failed to serialize: {}", err);
-                continue;
-            };
-            tmp.push(b'\n');
-            writer.write_all(&tmp).await?;
-        }
+    /// Encoded addresses of the peers `is_handed_out` accepts. Swarms of at least `COMPACT_PEERS_MIN_SWARM` peers
+    /// keep them for `COMPACT_PEERS_TTL`, so announces to hot torrents copy bytes instead of encoding every peer.
+    pub fn get_compact_peers<F: Fn(&std::net::IpAddr) -> bool>(&mut self, is_handed_out: F) -> Arc<CompactPeers> {
+        const COMPACT_PEERS_MIN_SWARM: usize = 32;
+        const COMPACT_PEERS_TTL: std::time::Duration = std::time::Duration::from_secs(5);
 
-        writer.close().await?;
+        if let Some(ref compact) = self.compact_peers {
+            if compact.built.elapsed() < COMPACT_PEERS_TTL {
+                return compact.clone();
+            }
+        }
+
+        let peers = self.peers.values().filter(|peer| is_handed_out(&peer.ip.ip()));
+        let compact = Arc::new(CompactPeers::build(peers));
+        self.compact_peers = match self.peers.len() >= COMPACT_PEERS_MIN_SWARM {
+            true => Some(compact.clone()),
+            false => None,
+        };
+        compact
+    }
+
+    pub fn get_peers_iter(&self) -> impl Iterator<Item = (&PeerId, &TorrentPeer)> {
+        self.peers.iter()
+    }
+
+    pub fn get_stats(&self) -> (u32, u32, u32) {
+        let leechers = (self.peers.len() as u32) - self.seeders;
+        (self.seeders, self.completed, leechers)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum StorageBackend {
+    /// Torrents are split across shards by the leading bytes of their infohash, each shard having its own lock.
+    #[serde(rename = "sharded")]
+    Sharded,
+
+    /// Torrents are kept in a concurrent hashmap, locking is handled internally per bucket.
+    #[serde(rename = "concurrent")]
+    Concurrent,
+}
+
+/// Peer, seeder & completed counts summed over all torrents.
+#[derive(Default)]
+struct Totals {
+    peers: AtomicUsize,
+    seeders: AtomicUsize,
+    completed: AtomicUsize,
+}
+
+impl Totals {
+    fn counts(entry: &TorrentEntry) -> [usize; 3] {
+        [entry.peers.len(), entry.seeders as usize, entry.completed as usize]
+    }
+
+    fn counters(&self) -> [&AtomicUsize; 3] {
+        [&self.peers, &self.seeders, &self.completed]
+    }
+
+    fn add(&self, entry: &TorrentEntry) {
+        for (counter, count) in self.counters().iter().zip(Self::counts(entry).iter()) {
+            counter.fetch_add(*count, Ordering::Relaxed);
+        }
+    }
+
+    fn sub(&self, entry: &TorrentEntry) {
+        for (counter, count) in self.counters().iter().zip(Self::counts(entry).iter()) {
+            counter.fetch_sub(*count, Ordering::Relaxed);
+        }
+    }
+
+    /// Runs `f` on a torrent, keeping the totals in sync with the changes `f` made to it.
+    fn track<R>(&self, entry: &mut TorrentEntry, f: impl FnOnce(&mut TorrentEntry) -> R) -> R {
+        let before = Self::counts(entry);
+        let res = f(entry);
+        let after = Self::counts(entry);
+        for ((counter, before), after) in self.counters().iter().zip(before.iter()).zip(after.iter()) {
+            if after > before {
+                counter.fetch_add(after - before, Ordering::Relaxed);
+            } else if before > after {
+                counter.fetch_sub(before - after, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+/// Ways UDP requests deviate from BEP 15. Tolerated or refused depending on `tracker.parsing`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParseQuirk {
+    /// Bytes after an announce that aren't BEP 41 options, scrapes listing a partial infohash & connect requests
+    /// longer than 16 bytes.
+    TrailingData,
+    /// Announces for port 0.
+    ZeroPort,
+    /// BEP 41 options of the reserved types, 0x3 to 0xff.
+    ReservedBits,
+}
+
+/// Requests with each `ParseQuirk`, whether they were tolerated or refused.
+#[derive(Serialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct QuirkCounts {
+    trailing_data: usize,
+    zero_port: usize,
+    reserved_bits: usize,
+}
+
+/// UDP requests handled since startup.
+#[derive(Default)]
+pub struct RequestCounters {
+    announces: AtomicUsize,
+    scrapes: AtomicUsize,
+    /// Packets that were ignored, or whose response couldn't be sent.
+    dropped: AtomicUsize,
+    /// Retransmitted requests that were answered with the cached response, see `tracker.response_cache_ttl`.
+    replayed: AtomicUsize,
+    /// By `ParseQuirk`.
+    quirks: [AtomicUsize; 3],
+}
+
+impl RequestCounters {
+    pub fn count_announce(&self) {
+        self.announces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_scrape(&self) {
+        self.scrapes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_replay(&self) {
+        self.replayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn replayed(&self) -> usize {
+        self.replayed.load(Ordering::Relaxed)
+    }
+
+    pub fn count_quirk(&self, quirk: ParseQuirk) {
+        self.quirks[quirk as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn quirks(&self) -> QuirkCounts {
+        let count = |quirk: ParseQuirk| self.quirks[quirk as usize].load(Ordering::Relaxed);
+        QuirkCounts {
+            trailing_data: count(ParseQuirk::TrailingData),
+            zero_port: count(ParseQuirk::ZeroPort),
+            reserved_bits: count(ParseQuirk::ReservedBits),
+        }
+    }
+
+    /// Announces, scrapes & dropped packets.
+    pub fn totals(&self) -> (usize, usize, usize) {
+        (
+            self.announces.load(Ordering::Relaxed),
+            self.scrapes.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TaskState {
+    interval: std::time::Duration,
+    registered: std::time::Instant,
+    runs: u64,
+    running_since: Option<std::time::Instant>,
+    last_finished: Option<std::time::Instant>,
+    last_duration: std::time::Duration,
+}
+
+/// Runs of the periodic background tasks, to tell a stuck or stalled task apart from an idle one.
+#[derive(Default)]
+pub struct BackgroundTasks {
+    tasks: std::sync::Mutex<BTreeMap<&'static str, TaskState>>,
+}
+
+/// Marks a task as running until it's dropped.
+pub struct TaskRun<'a> {
+    tasks: &'a BackgroundTasks,
+    name: &'static str,
+    started: std::time::Instant,
+}
+
+impl BackgroundTasks {
+    /// Adds a task that runs every `interval`.
+    pub fn register(&self, name: &'static str, interval: std::time::Duration) {
+        let state = TaskState {
+            interval,
+            registered: std::time::Instant::now(),
+            runs: 0,
+            running_since: None,
+            last_finished: None,
+            last_duration: std::time::Duration::default(),
+        };
+        self.tasks.lock().unwrap().insert(name, state);
+    }
+
+    pub fn start(&self, name: &'static str) -> TaskRun<'_> {
+        let started = std::time::Instant::now();
+        if let Some(state) = self.tasks.lock().unwrap().get_mut(name) {
+            state.running_since = Some(started);
+        }
+        TaskRun {
+            tasks: self,
+            name,
+            started,
+        }
+    }
+
+    /// One line per task: how often it ran, when it last finished, and whether it's running or overdue.
+    pub fn describe(&self, now: std::time::Instant) -> Vec<String> {
+        let tasks = self.tasks.lock().unwrap();
+        tasks
+            .iter()
+            .map(|(name, state)| {
+                let mut line = format!("{}: every {}s, {} runs", name, state.interval.as_secs(), state.runs);
+                if let Some(finished) = state.last_finished {
+                    let ago = now.saturating_duration_since(finished).as_secs();
+                    line += &format!(", last finished {}s ago in {}ms", ago, state.last_duration.as_millis());
+                }
+                let idle_since = state.last_finished.unwrap_or(state.registered);
+                if let Some(since) = state.running_since {
+                    line += &format!(", running for {}s", now.saturating_duration_since(since).as_secs());
+                } else if now.saturating_duration_since(idle_since) > state.interval * 2 {
+                    line += ", overdue";
+                }
+                line
+            })
+            .collect()
+    }
+}
+
+impl Drop for TaskRun<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.tasks.tasks.lock().unwrap().get_mut(self.name) {
+            let now = std::time::Instant::now();
+            state.runs += 1;
+            state.running_since = None;
+            state.last_finished = Some(now);
+            state.last_duration = now.duration_since(self.started);
+        }
+    }
+}
+
+/// Outcome of the latest probe of a UDP listener.
+#[derive(Clone, Serialize)]
+pub struct ListenerProbe {
+    pub address: std::net::SocketAddr,
+    /// Round trip time of the connect request, in milliseconds.
+    pub rtt_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// Whether the UDP listeners answer, checked by sending each of them a connect request (see `server::probe`).
+/// It's the readiness of the tracker, while answering on the REST API only shows that it's alive.
+#[derive(Default)]
+pub struct Readiness {
+    listeners: std::sync::RwLock<Vec<ListenerProbe>>,
+}
+
+impl Readiness {
+    pub fn update(&self, probes: Vec<ListenerProbe>) {
+        *self.listeners.write().unwrap() = probes;
+    }
+
+    pub fn get_listeners(&self) -> Vec<ListenerProbe> {
+        self.listeners.read().unwrap().clone()
+    }
+
+    /// Ready once every listener answered its latest probe.
+    pub fn is_ready(&self) -> bool {
+        let listeners = self.listeners.read().unwrap();
+        !listeners.is_empty() && listeners.iter().all(|v| v.error.is_none())
+    }
+}
+
+/// The torrents of a tracker, keeps torrent count & totals on top of a `TorrentStore`.
+struct TorrentDatabase {
+    store: Box<dyn TorrentStore>,
+    torrent_count: AtomicUsize,
+    totals: Totals,
+    /// Infohashes that were recently announced but aren't tracked, see `tracker.unknown_torrent_cache_ttl`.
+    unknown: NegativeCache<InfoHash>,
+}
+
+impl TorrentDatabase {
+    fn new(store: Box<dyn TorrentStore>) -> TorrentDatabase {
+        TorrentDatabase {
+            store,
+            torrent_count: AtomicUsize::new(0),
+            totals: Totals::default(),
+            unknown: NegativeCache::default(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.torrent_count.load(Ordering::Relaxed)
+    }
+
+    fn lock_stats(&self) -> Vec<LockStats> {
+        self.store.lock_stats()
+    }
+
+    fn peer_count(&self) -> usize {
+        self.totals.peers.load(Ordering::Relaxed)
+    }
+
+    fn seeder_count(&self) -> usize {
+        self.totals.seeders.load(Ordering::Relaxed)
+    }
+
+    fn completed_count(&self) -> usize {
+        self.totals.completed.load(Ordering::Relaxed)
+    }
+
+    /// Inserts or replaces a torrent, returns true if the torrent didn't exist.
+    async fn insert(&self, info_hash: InfoHash, entry: TorrentEntry) -> bool {
+        self.totals.add(&entry);
+        match self.store.insert(info_hash.clone(), entry).await {
+            Some(prev) => {
+                self.totals.sub(&prev);
+                false
+            }
+            None => {
+                self.torrent_count.fetch_add(1, Ordering::Relaxed);
+                self.unknown.remove(&info_hash);
+                true
+            }
+        }
+    }
+
+    async fn get<R: Send, F: FnOnce(&TorrentEntry) -> R + Send>(&self, info_hash: &InfoHash, f: F) -> Option<R> {
+        let mut f = Some(f);
+        let mut res = None;
+        self.store.get(info_hash, &mut |entry| res = f.take().map(|f| f(entry))).await;
+        res
+    }
+
+    async fn get_mut<R: Send, F: FnOnce(&mut TorrentEntry) -> R + Send>(&self, info_hash: &InfoHash, f: F) -> Option<R> {
+        let totals = &self.totals;
+        let mut f = Some(f);
+        let mut res = None;
+        self.store
+            .get_mut(info_hash, &mut |entry| {
+                res = f.take().map(|f| totals.track(entry, f));
+            })
+            .await;
+        res
+    }
+
+    /// Runs `f` on the torrent, creating it with `create` if it doesn't exist.
+    /// If `create` returns None, nothing is inserted and None is returned.
+    async fn get_or_insert_with<R, C, F>(&self, info_hash: &InfoHash, create: C, f: F) -> Option<R>
+    where
+        R: Send,
+        C: FnOnce() -> Option<TorrentEntry> + Send,
+        F: FnOnce(&mut TorrentEntry) -> R + Send, {
+        let torrent_count = &self.torrent_count;
+        let totals = &self.totals;
+        let mut create = Some(create);
+        let mut f = Some(f);
+        let mut res = None;
+        let mut inserted = false;
+        self.store
+            .get_or_insert_with(
+                info_hash,
+                &mut || {
+                    let entry = create.take().and_then(|create| create())?;
+                    torrent_count.fetch_add(1, Ordering::Relaxed);
+                    inserted = true;
+                    Some(entry)
+                },
+                &mut |entry| res = f.take().map(|f| totals.track(entry, f)),
+            )
+            .await;
+        if inserted {
+            self.unknown.remove(info_hash);
+        }
+        res
+    }
+
+    /// Removes the torrent if `predicate` returns true. Returns the removed torrent.
+    async fn remove_if<F>(&self, info_hash: &InfoHash, predicate: F) -> Option<TorrentEntry>
+    where
+        F: FnOnce(&TorrentEntry) -> bool + Send, {
+        let mut predicate = Some(predicate);
+        let removed = self
+            .store
+            .remove_if(info_hash, &mut |entry| predicate.take().map(|p| p(entry)).unwrap_or(false))
+            .await;
+        if let Some(ref entry) = removed {
+            self.torrent_count.fetch_sub(1, Ordering::Relaxed);
+            self.totals.sub(entry);
+        }
+        removed
+    }
+
+    /// Keeps only the torrents for which `f` returns true.
+    async fn retain<F: FnMut(&InfoHash, &mut TorrentEntry) -> bool + Send>(&self, mut f: F) {
+        let torrent_count = &self.torrent_count;
+        let totals = &self.totals;
+        self.store
+            .retain(&mut |k, v| {
+                let keep = totals.track(v, |v| f(k, v));
+                if !keep {
+                    torrent_count.fetch_sub(1, Ordering::Relaxed);
+                    totals.sub(v);
+                }
+                keep
+            })
+            .await
+    }
+
+    /// Calls `f` for up to `limit` torrents, starting at `offset`.
+    async fn for_each<F: FnMut(&InfoHash, &TorrentEntry) + Send>(&self, offset: usize, limit: usize, mut f: F) {
+        self.store.for_each(offset, limit, &mut f).await
+    }
+
+    /// Removes up to `count` unflagged & unlinked torrents, least recently announced first.
+    /// Returns the removed torrents.
+    async fn evict_oldest(&self, count: usize) -> Vec<InfoHash> {
+        use std::collections::BinaryHeap;
+
+        // max-heap holding the `count` oldest torrents seen so far.
+        let mut oldest: BinaryHeap<(Option<std::time::Instant>, InfoHash)> = BinaryHeap::with_capacity(count + 1);
+        self.for_each(0, usize::max_value(), |info_hash, entry| {
+            if entry.is_flagged() || !entry.aliases.is_empty() {
+                return;
+            }
+            oldest.push((entry.last_announce, info_hash.clone()));
+            if oldest.len() > count {
+                oldest.pop();
+            }
+        })
+        .await;
+
+        let mut removed = Vec::with_capacity(oldest.len());
+        for (last_announce, info_hash) in oldest {
+            // the torrent may have been announced since it was picked.
+            let entry = self
+                .remove_if(&info_hash, |entry| {
+                    !entry.is_flagged() && entry.aliases.is_empty() && entry.last_announce == last_announce
+                })
+                .await;
+            if entry.is_some() {
+                removed.push(info_hash);
+            }
+        }
+        removed
+    }
+
+    /// Copies every torrent without its peers, one shard at a time. Saving serializes the copy once the locks are
+    /// released, so announces only wait for the copy, not for the serialization.
+    async fn snapshot(&self) -> Vec<(InfoHash, TorrentEntry)> {
+        let mut snapshot = Vec::with_capacity(self.len());
+        self.for_each(0, usize::max_value(), |info_hash, entry| {
+            snapshot.push((info_hash.clone(), entry.persisted_copy()))
+        })
+        .await;
+        snapshot
+    }
+}
+
+/// An admin action of the REST API. With `[raft]`, actions are applied by every node in the same order.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ControlAction {
+    RemoveTorrent {
+        info_hash: InfoHash,
+    },
+    /// Zeroes a torrent's stats & drops its peers.
+    ResetTorrent {
+        info_hash: InfoHash,
+    },
+    LinkTorrent {
+        info_hash: InfoHash,
+        alias: InfoHash,
+    },
+    UnlinkTorrent {
+        info_hash: InfoHash,
+        alias: InfoHash,
+    },
+    /// Flags or unflags a torrent, unknown torrents are registered to be flagged.
+    SetFlag {
+        info_hash: InfoHash,
+        is_flagged: bool,
+    },
+    UpdateUser {
+        name: String,
+        passkey: Option<String>,
+        is_banned: Option<bool>,
+    },
+    RemoveUser {
+        name: String,
+    },
+    Ban {
+        target: BanTarget,
+        created_by: String,
+        /// Unix timestamp, so every node computes the same expiry.
+        created: u64,
+        duration: Option<u64>,
+        reason: Option<String>,
+    },
+    LiftBan {
+        id: u64,
+    },
+}
+
+/// The ban an action created, or the reason it failed.
+pub type ControlOutcome = Result<Option<Ban>, String>;
+
+pub struct TorrentTracker {
+    config: std::sync::RwLock<Arc<Configuration>>,
+    database: TorrentDatabase,
+    memory_pressure: Arc<tokio::sync::Notify>,
+    /// Peers held over all torrents, see `limits.max_peers`.
+    peer_limit: AtomicUsize,
+    /// Announces of new peers that weren't stored because of `peer_limit`.
+    peers_refused: AtomicUsize,
+    /// Re-announces answered without peers, see `tracker.duplicate_announce_window`.
+    duplicate_announces: AtomicUsize,
+    cleanup_requested: AtomicBool,
+    /// Held while saving, so the final save on shutdown doesn't overlap a periodic one.
+    save_lock: tokio::sync::Mutex<()>,
+    requests: RequestCounters,
+    traffic: TrafficMonitor,
+    tasks: BackgroundTasks,
+    readiness: Readiness,
+    client_stats: std::sync::RwLock<Arc<ClientStats>>,
+    swarm_sizes: std::sync::RwLock<SwarmSizeHistogram>,
+    top_torrents: std::sync::RwLock<Vec<TopTorrent>>,
+    /// Peers by country, as seen during the last summary.
+    countries: std::sync::RwLock<BTreeMap<CountryCode, u64>>,
+    unique_ips_hour: RollingHyperLogLog,
+    unique_ips_day: RollingHyperLogLog,
+    /// Linked infohashes, mapped to the torrent they were merged into.
+    aliases: std::sync::RwLock<std::collections::HashMap<InfoHash, InfoHash>>,
+    /// Torrents whose seeders, leechers or completed count changed, only sent while someone is waiting on them.
+    swarm_changes: tokio::sync::broadcast::Sender<InfoHash>,
+    users: UserRegistry,
+    bans: BanList,
+    journal: Option<Journal>,
+    sqlite: Option<SqliteStore>,
+    redis: Option<RedisStore>,
+    cluster: Option<Gossip>,
+    raft: Option<Raft>,
+    replica: Option<Replica>,
+    upstream: Option<Upstream>,
+    postgres: Option<PostgresStore>,
+    webhooks: Option<Webhooks>,
+    analytics: Option<Analytics>,
+    telemetry: Option<Tracer>,
+    abuse_log: Option<AbuseLog>,
+    geoip: Option<GeoIp>,
+    /// Announces that weren't published to redis yet.
+    redis_queue: std::sync::Mutex<Vec<RedisPeer>>,
+    /// Latest announce of each peer that wasn't gossiped to the cluster yet, up to `cluster.max_queue` peers.
+    gossip_queue: std::sync::Mutex<std::collections::HashMap<(InfoHash, PeerId), SharedPeer>>,
+    /// Peers that didn't fit in the gossip queue since the last round.
+    gossip_dropped: AtomicUsize,
+    /// Torrents changed since they were last written to sqlite.
+    dirty: std::sync::Mutex<std::collections::HashSet<InfoHash>>,
+}
+
+#[derive(Serialize, Default)]
+pub struct ClientFamilyStats {
+    peers: u64,
+    versions: BTreeMap<String, u64>,
+}
+
+/// Peers by client software, as seen during the last summary.
+#[derive(Serialize, Default)]
+pub struct ClientStats {
+    peers: u64,
+    clients: BTreeMap<&'static str, ClientFamilyStats>,
+}
+
+impl ClientStats {
+    fn add_peer(&mut self, peer_id: &PeerId) {
+        let family = self
+            .clients
+            .entry(peer_id.get_client_name().unwrap_or("unknown"))
+            .or_default();
+        family.peers += 1;
+        if let Some(version) = peer_id.get_client_version() {
+            *family.versions.entry(version).or_insert(0) += 1;
+        }
+        self.peers += 1;
+    }
+}
+
+/// Changes buffered for each waiting request, slower waiters re-read the stats of their torrent.
+const SWARM_CHANGES_CAPACITY: usize = 256;
+
+/// Upper bounds (inclusive) of the swarm size buckets, the last bucket is unbounded.
+const SWARM_SIZE_BUCKETS: [usize; 5] = [0, 10, 100, 1000, 10000];
+
+/// Amount of torrents by swarm size, as seen during the last summary.
+#[derive(Clone, Copy, Default)]
+pub struct SwarmSizeHistogram {
+    torrents: [u64; SWARM_SIZE_BUCKETS.len() + 1],
+}
+
+impl SwarmSizeHistogram {
+    fn add(&mut self, peers: usize) {
+        let bucket = SWARM_SIZE_BUCKETS
+            .iter()
+            .position(|max| peers <= *max)
+            .unwrap_or(SWARM_SIZE_BUCKETS.len());
+        self.torrents[bucket] += 1;
+    }
+}
+
+impl Serialize for SwarmSizeHistogram {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Bucket {
+            min_peers: usize,
+            max_peers: Option<usize>,
+            torrents: u64,
+        }
+
+        let buckets = self.torrents.iter().enumerate().map(|(i, torrents)| {
+            Bucket {
+                min_peers: if i == 0 { 0 } else { SWARM_SIZE_BUCKETS[i - 1] + 1 },
+                max_peers: SWARM_SIZE_BUCKETS.get(i).cloned(),
+                torrents: *torrents,
+            }
+        });
+        serializer.collect_seq(buckets)
+    }
+}
+
+#[derive(Serialize)]
+pub struct TrackerStats {
+    torrents: usize,
+    peers: usize,
+    seeders: usize,
+    leechers: usize,
+    completed: usize,
+    memory_bytes: usize,
+    swarm_sizes: SwarmSizeHistogram,
+    top_torrents: Vec<TopTorrent>,
+    countries: BTreeMap<CountryCode, u64>,
+    unique_ips: UniqueIpStats,
+    quirks: QuirkCounts,
+    traffic: TrafficReport,
+}
+
+/// One of the largest swarms, as seen during the last summary.
+#[derive(Serialize, Clone)]
+pub struct TopTorrent {
+    info_hash: InfoHash,
+    peers: usize,
+    seeders: u32,
+    completed: u32,
+}
+
+/// Estimated amount of distinct peer IPs that announced within the last hour & day.
+#[derive(Serialize)]
+pub struct UniqueIpStats {
+    #[serde(rename = "1h")]
+    last_hour: u64,
+    #[serde(rename = "24h")]
+    last_day: u64,
+}
+
+/// Version of the database file format written by `save_database`.
+/// Version 1 files have no header & footer, just rows. Version 3 files are compressed as a bzip2 stream per shard,
+/// which UDPT versions writing version 2 refuse as newer, and earlier ones would only read the first stream of.
+/// Rows are the same in every version, fields added to torrents since version 1 have defaults, so rows of older
+/// versions deserialize as they are.
+const DATABASE_VERSION: u32 = 3;
+
+/// First line of a database file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DatabaseHeader {
+    version: u32,
+}
+
+/// Last line of a database file, `crc32` covers all the rows including their line breaks.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DatabaseFooter {
+    rows: usize,
+    crc32: u32,
+}
+
+fn invalid_data(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// A peer as shared with other tracker instances.
+#[derive(Serialize, Deserialize)]
+struct SharedPeer {
+    ip: std::net::SocketAddr,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    /// Unix timestamp of the peer's announce.
+    updated: u64,
+}
+
+/// A peer as gossiped between cluster nodes.
+#[derive(Serialize, Deserialize)]
+struct GossipPeer {
+    info_hash: InfoHash,
+    peer_id: String,
+    peer: SharedPeer,
+}
+
+fn unix_now() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(v) => v.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Memory the process has resident, in bytes. Only known on Linux.
+fn resident_memory() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        // the second field is the resident set, in pages.
+        let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+        let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Some(pages * page_size as u64)
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[derive(Serialize, Deserialize)]
+struct DatabaseRow<'a> {
+    info_hash: InfoHash,
+    entry: Cow<'a, TorrentEntry>,
+}
+
+/// Appends a torrent as a database row, a json line. Returns false if it couldn't be serialized.
+pub fn serialize_row(out: &mut Vec<u8>, info_hash: &InfoHash, entry: &TorrentEntry) -> bool {
+    let start = out.len();
+    let row = DatabaseRow {
+        info_hash: info_hash.clone(),
+        entry: Cow::Borrowed(entry),
+    };
+    if let Err(err) = serde_json::to_writer(&mut *out, &row) {
+        error!("failed to serialize: {}", err);
+        out.truncate(start);
+        return false;
+    };
+    out.push(b'\n');
+    true
+}
+
+/// Rows that are serialized & compressed by a worker at least, smaller databases aren't split.
+const MIN_SHARD_ROWS: usize = 4096;
+
+/// A shard of a database file, as written by a worker.
+struct DatabaseShard {
+    rows: usize,
+    checksum: crc32fast::Hasher,
+    /// A bzip2 stream of its own, bzip2 streams can be concatenated.
+    compressed: Vec<u8>,
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    use futures::io::AsyncWriteExt;
+
+    futures::executor::block_on(async {
+        let mut encoder = async_compression::futures::write::BzEncoder::new(Vec::with_capacity(data.len() / 4));
+        encoder.write_all(data).await?;
+        encoder.close().await?;
+        Ok(encoder.into_inner())
+    })
+}
+
+fn write_shard(rows: &[(InfoHash, TorrentEntry)]) -> Result<DatabaseShard, std::io::Error> {
+    let mut out = Vec::with_capacity(rows.len() * 256);
+    let mut count = 0;
+    for (info_hash, entry) in rows {
+        if serialize_row(&mut out, info_hash, entry) {
+            count += 1;
+        }
+    }
+    let mut checksum = crc32fast::Hasher::new();
+    checksum.update(&out);
+    Ok(DatabaseShard {
+        rows: count,
+        checksum,
+        compressed: compress(&out)?,
+    })
+}
+
+/// Writes `rows` as a database file: bzip2 compressed json lines, between a header & a footer.
+/// Large databases are split into shards that are serialized & compressed on the blocking pool in parallel, and
+/// written in order. Returns the amount of rows written.
+pub async fn write_database<W: tokio::io::AsyncWrite + Unpin>(
+    mut w: W, rows: Vec<(InfoHash, TorrentEntry)>,
+) -> Result<usize, std::io::Error> {
+    use tokio::io::AsyncWriteExt;
+
+    let workers = std::thread::available_parallelism().map(|v| v.get()).unwrap_or(1);
+    let shard_rows = rows.len().div_ceil(workers).max(MIN_SHARD_ROWS);
+    let rows = Arc::new(rows);
+    let shards: Vec<_> = (0..rows.len())
+        .step_by(shard_rows)
+        .map(|start| {
+            let rows = rows.clone();
+            tokio::task::spawn_blocking(move || write_shard(&rows[start..(start + shard_rows).min(rows.len())]))
+        })
+        .collect();
+
+    let mut line = serde_json::to_vec(&DatabaseHeader {
+        version: DATABASE_VERSION,
+    })?;
+    line.push(b'\n');
+    w.write_all(&compress(&line)?).await?;
+
+    let mut checksum = crc32fast::Hasher::new();
+    let mut count = 0;
+    for shard in shards {
+        let shard = shard.await.map_err(std::io::Error::other)??;
+        count += shard.rows;
+        checksum.combine(&shard.checksum);
+        w.write_all(&shard.compressed).await?;
+    }
+
+    let mut line = serde_json::to_vec(&DatabaseFooter {
+        rows: count,
+        crc32: checksum.finalize(),
+    })?;
+    line.push(b'\n');
+    w.write_all(&compress(&line)?).await?;
+
+    w.flush().await?;
+    Ok(count)
+}
+
+/// A torrent as stored in sqlite.
+pub fn sqlite_row(info_hash: &InfoHash, entry: &TorrentEntry) -> Result<SqliteRow, serde_json::Error> {
+    Ok(SqliteRow {
+        info_hash: info_hash.to_string(),
+        is_flagged: entry.is_flagged,
+        completed: entry.completed,
+        entry: serde_json::to_string(entry)?,
+    })
+}
+
+/// The torrent stored in a sqlite row. Errors name the torrent.
+pub fn parse_sqlite_row(row: SqliteRow) -> Result<(InfoHash, TorrentEntry), String> {
+    let info_hash: InfoHash = match row.info_hash.parse() {
+        Ok(v) => v,
+        Err(_) => return Err(format!("with invalid infohash \"{}\"", row.info_hash)),
+    };
+
+    // the is_flagged & completed columns take precedence, they may have been edited.
+    let entry = serde_json::from_str(&row.entry).and_then(|mut entry: serde_json::Value| {
+        if let Some(obj) = entry.as_object_mut() {
+            obj.insert(String::from("is_flagged"), row.is_flagged.into());
+            obj.insert(String::from("completed"), row.completed.into());
+        }
+        serde_json::from_value::<TorrentEntry>(entry)
+    });
+    match entry {
+        Ok(entry) => Ok((info_hash, entry)),
+        Err(err) => Err(format!("{} with invalid data: {}", info_hash, err)),
+    }
+}
+
+/// Decompresses a database file, for `read_database`.
+pub fn decompress_database<R: tokio::io::AsyncRead + Unpin>(reader: R) -> impl tokio::io::AsyncBufRead + Unpin {
+    use tokio_util::compat::{FuturesAsyncReadCompatExt, Tokio02AsyncReadCompatExt};
+
+    let reader = tokio::io::BufReader::new(reader).compat();
+    // databases are written as a bzip2 stream per shard.
+    let mut reader = async_compression::futures::bufread::BzDecoder::new(reader);
+    reader.multiple_members(true);
+    tokio::io::BufReader::new(reader.compat())
+}
+
+/// What reading a database found, besides its torrents.
+#[derive(Debug, Default)]
+pub struct DatabaseCheck {
+    pub version: u32,
+    pub rows: usize,
+    /// Rows that couldn't be parsed, by line number.
+    pub invalid_rows: Vec<(usize, String)>,
+    /// The database was altered or truncated.
+    pub problems: Vec<String>,
+}
+
+/// Reads the rows of a decompressed database, calling `on_row` with every torrent. Rows that can't be parsed and
+/// integrity problems are reported instead of failing, only I/O errors & databases of a newer version fail.
+/// Rows without a header are read as version 1, which is also how plain json lines are read.
+pub async fn read_database<R, F, Fut>(reader: R, mut on_row: F) -> Result<DatabaseCheck, std::io::Error>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    F: FnMut(InfoHash, TorrentEntry) -> Fut,
+    Fut: std::future::Future<Output = ()>, {
+    let mut check = DatabaseCheck {
+        version: 1,
+        ..DatabaseCheck::default()
+    };
+    let mut checksum = crc32fast::Hasher::new();
+    let mut footer: Option<DatabaseFooter> = None;
+
+    let mut lines = reader.lines();
+    let mut line_number = 0;
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        line_number += 1;
+        if footer.is_some() {
+            check.problems.push(String::from("unexpected data after database footer"));
+            footer = None;
+        }
+
+        if line_number == 1 {
+            if let Ok(header) = serde_json::from_str::<DatabaseHeader>(&line) {
+                if header.version > DATABASE_VERSION {
+                    return Err(invalid_data(format!(
+                        "database version {} is newer than supported version {}",
+                        header.version, DATABASE_VERSION
+                    )));
+                }
+                check.version = header.version;
+                continue;
+            }
+        }
+
+        if check.version >= 2 {
+            if let Ok(v) = serde_json::from_str::<DatabaseFooter>(&line) {
+                footer = Some(v);
+                continue;
+            }
+            checksum.update(line.as_bytes());
+            checksum.update(b"\n");
+        }
+        check.rows += 1;
+
+        match serde_json::from_str::<DatabaseRow>(&line) {
+            Ok(row) => on_row(row.info_hash, row.entry.into_owned()).await,
+            Err(err) => check.invalid_rows.push((line_number, err.to_string())),
+        }
+    }
+    if check.version >= 2 {
+        match footer {
+            Some(footer) if footer.rows == check.rows && footer.crc32 == checksum.finalize() => {}
+            Some(_) => check.problems.push(String::from("database checksum mismatch")),
+            None if check.problems.is_empty() => {
+                check.problems.push(String::from("database is truncated, footer is missing"))
+            }
+            None => {}
+        }
+    }
+    Ok(check)
+}
+
+/// Parses a list of hex encoded infohashes, one per line. Empty lines and lines starting with '#' are skipped.
+pub fn parse_infohash_list(data: &str) -> Vec<InfoHash> {
+    let mut list = Vec::new();
+    for (line_no, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.parse() {
+            Ok(info_hash) => list.push(info_hash),
+            Err(_) => warn!("ignoring invalid infohash on line {}: \"{}\"", line_no + 1, line),
+        }
+    }
+    list
+}
+
+/// Parses an opentracker whitelist or state dump.
+/// Whitelists list one hex encoded infohash per line, state dumps lines of `<infohash>:<downloaded>:<base>`.
+/// Returns the infohashes with their completed counts (0 for whitelists).
+pub fn parse_state_dump(data: &str) -> Vec<(InfoHash, u32)> {
+    let mut list = Vec::new();
+    for (line_no, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(':');
+        let info_hash = fields.next().and_then(|v| v.trim().parse().ok());
+        let completed = match fields.next() {
+            Some(v) => v.trim().parse().ok(),
+            None => Some(0),
+        };
+        match (info_hash, completed) {
+            (Some(info_hash), Some(completed)) => list.push((info_hash, completed)),
+            _ => warn!("ignoring invalid line {}: \"{}\"", line_no + 1, line),
+        }
+    }
+    list
+}
+
+pub enum TorrentStats {
+    TorrentFlagged,
+    TorrentNotRegistered,
+    Stats {
+        seeders: u32,
+        leechers: u32,
+        complete: u32,
+        /// Transfer reported by the peer since its previous announce.
+        uploaded: u64,
+        downloaded: u64,
+        peers: Arc<CompactPeers>,
+    },
+    /// The peer re-announced within `tracker.duplicate_announce_window`, only its last-seen time was updated.
+    Duplicate {
+        seeders: u32,
+        leechers: u32,
+        complete: u32,
+    },
+}
+
+impl TorrentTracker {
+    pub fn new(config: Arc<Configuration>) -> TorrentTracker {
+        let tracker_config = config.get_tracker_config();
+        let store: Box<dyn TorrentStore> = match tracker_config.get_storage() {
+            StorageBackend::Sharded => Box::new(ShardedStore::new(tracker_config.get_db_shards())),
+            StorageBackend::Concurrent => Box::new(ConcurrentStore::new()),
+        };
+        Self::with_store(config, store)
+    }
+
+    /// Creates a tracker that keeps its torrents in `store`, instead of the store selected by the configuration.
+    pub fn with_store(config: Arc<Configuration>, store: Box<dyn TorrentStore>) -> TorrentTracker {
+        let tracker_config = config.get_tracker_config();
+        TorrentTracker {
+            database: TorrentDatabase::new(store),
+            memory_pressure: Arc::new(tokio::sync::Notify::new()),
+            peer_limit: AtomicUsize::new(config.get_limits_config().get_max_peers().unwrap_or(usize::MAX)),
+            peers_refused: AtomicUsize::new(0),
+            duplicate_announces: AtomicUsize::new(0),
+            cleanup_requested: AtomicBool::new(false),
+            save_lock: tokio::sync::Mutex::new(()),
+            requests: RequestCounters::default(),
+            traffic: TrafficMonitor::default(),
+            tasks: BackgroundTasks::default(),
+            readiness: Readiness::default(),
+            client_stats: std::sync::RwLock::new(Arc::new(ClientStats::default())),
+            swarm_sizes: std::sync::RwLock::new(SwarmSizeHistogram::default()),
+            top_torrents: std::sync::RwLock::new(Vec::new()),
+            countries: std::sync::RwLock::new(BTreeMap::new()),
+            unique_ips_hour: RollingHyperLogLog::new(std::time::Duration::from_secs(3600), 6),
+            unique_ips_day: RollingHyperLogLog::new(std::time::Duration::from_secs(3600 * 24), 24),
+            aliases: std::sync::RwLock::new(std::collections::HashMap::new()),
+            swarm_changes: tokio::sync::broadcast::channel(SWARM_CHANGES_CAPACITY).0,
+            users: UserRegistry::new(tracker_config.get_passkeys()),
+            bans: BanList::default(),
+            journal: None,
+            sqlite: None,
+            redis: None,
+            cluster: None,
+            raft: None,
+            replica: None,
+            upstream: None,
+            postgres: None,
+            webhooks: None,
+            analytics: None,
+            telemetry: None,
+            abuse_log: None,
+            geoip: None,
+            redis_queue: std::sync::Mutex::new(Vec::new()),
+            gossip_queue: std::sync::Mutex::new(std::collections::HashMap::new()),
+            gossip_dropped: AtomicUsize::new(0),
+            dirty: std::sync::Mutex::new(std::collections::HashSet::new()),
+            config: std::sync::RwLock::new(config),
+        }
+    }
+
+    pub async fn load_database<R: tokio::io::AsyncRead + Unpin>(
+        config: Arc<Configuration>, reader: &mut R,
+    ) -> Result<TorrentTracker, std::io::Error> {
+        let res = TorrentTracker::new(config);
+        let check = read_database(decompress_database(reader), |info_hash, entry| {
+            res.restore_torrent(info_hash, entry)
+        })
+        .await?;
+        for (line, err) in check.invalid_rows.iter() {
+            error!("failed to parse json on line {}: {}", line, err);
+        }
+        if let Some(problem) = check.problems.first() {
+            return Err(invalid_data(problem.clone()));
+        }
+        if check.version < DATABASE_VERSION {
+            warn!(
+                "loaded a version {} database, it will be saved as version {}.",
+                check.version, DATABASE_VERSION
+            );
+        }
+        trace!("loaded {} entries from database", check.rows - check.invalid_rows.len());
+
+        Ok(res)
+    }
+
+    /// Inserts a torrent as it was persisted, with its aliases.
+    pub async fn restore_torrent(&self, info_hash: InfoHash, entry: TorrentEntry) {
+        {
+            let mut aliases = self.aliases.write().unwrap();
+            for alias in entry.aliases.iter() {
+                aliases.insert(alias.clone(), info_hash.clone());
+            }
+        }
+        self.database.insert(info_hash, entry).await;
+    }
+
+    /// Registers every infohash listed in the file at `path`, returns the amount of torrents that were added.
+    pub async fn load_whitelist(&self, path: &str) -> Result<usize, std::io::Error> {
+        let data = tokio::fs::read_to_string(path).await?;
+
+        let mut count = 0;
+        for info_hash in parse_infohash_list(&data) {
+            if self.add_torrent(&info_hash).await.is_ok() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Registers the torrents of an opentracker whitelist or state dump, as parsed by `parse_state_dump`.
+    /// Completed counts of torrents that are already tracked are raised to the imported ones.
+    /// Returns the amount of torrents that were registered or updated.
+    pub async fn import_torrents(&self, torrents: Vec<(InfoHash, u32)>) -> usize {
+        let mut span = self.start_span("tracker.import");
+        let mut count = 0;
+        for (info_hash, completed) in torrents {
+            let added = self.add_torrent(&info_hash).await.is_ok();
+            let info_hash = self.resolve(&info_hash).into_owned();
+            let updated = self
+                .database
+                .get_mut(&info_hash, |entry| {
+                    let updated = completed > entry.completed;
+                    entry.completed = entry.completed.max(completed);
+                    updated
+                })
+                .await
+                .unwrap_or(false);
+
+            if updated {
+                self.mark_dirty(Some(&info_hash));
+            }
+            if added || updated {
+                count += 1;
+            }
+        }
+        if let Some(ref mut span) = span {
+            span.set_int_attribute("udpt.imported", count as u64);
+        }
+        count
+    }
+
+    /// Replays the journal of `db_path` over the loaded database, then records further changes into it.
+    pub async fn open_journal(&mut self, db_path: &str) -> Result<usize, std::io::Error> {
+        let journal = Journal::open(Journal::path_for(db_path)).await?;
+        let entries = journal.read_entries().await?;
+        let count = entries.len();
+
+        for entry in entries {
+            // results are ignored, entries may have already been applied to the snapshot.
+            match entry {
+                JournalEntry::Add { info_hash } => {
+                    let _ = self.add_torrent(&info_hash).await;
+                }
+                JournalEntry::Remove { info_hash } => {
+                    let _ = self.remove_torrent(&info_hash, true).await;
+                }
+                JournalEntry::Flag { info_hash, is_flagged } => {
+                    // torrents created by announces since the snapshot have no `Add`, flags outlive them.
+                    if !self.set_torrent_flag(&info_hash, is_flagged).await && is_flagged {
+                        let _ = self.add_torrent(&info_hash).await;
+                        self.set_torrent_flag(&info_hash, true).await;
+                    }
+                }
+                JournalEntry::Link { info_hash, alias } => {
+                    let _ = self.link_torrent(&info_hash, &alias).await;
+                }
+                JournalEntry::Reset { info_hash } => {
+                    self.reset_torrent_stats(&info_hash).await;
+                }
+                JournalEntry::Unlink { info_hash, alias } => {
+                    let _ = self.unlink_torrent(&info_hash, &alias).await;
+                }
+            }
+        }
+
+        self.journal = Some(journal);
+        Ok(count)
+    }
+
+    /// Loads torrents from the SQLite database at `path`, further changes are written back to it.
+    pub async fn open_sqlite(&mut self, path: &str) -> Result<usize, std::io::Error> {
+        let store = SqliteStore::open(path).await?;
+        let rows = store.load().await?;
+        let count = rows.len();
+
+        for row in rows {
+            match parse_sqlite_row(row) {
+                Ok((info_hash, entry)) => self.restore_torrent(info_hash, entry).await,
+                Err(err) => error!("ignoring torrent {}", err),
+            }
+        }
+
+        self.sqlite = Some(store);
+        Ok(count)
+    }
+
+    pub async fn open_redis(&mut self, redis_config: &RedisConfig) -> Result<(), std::io::Error> {
+        self.redis = Some(RedisStore::open(redis_config.get_url(), redis_config.get_key_prefix()).await?);
+        Ok(())
+    }
+
+    /// Queues an announce for the other instances, through redis and/or cluster gossip.
+    fn queue_shared_peer(&self, info_hash: &InfoHash, peer_id: &PeerId, peer: SharedPeer) {
+        if self.redis.is_some() {
+            if let Ok(data) = serde_json::to_string(&peer) {
+                self.redis_queue.lock().unwrap().push(RedisPeer {
+                    info_hash: info_hash.to_string(),
+                    peer_id: peer_id.to_hex(),
+                    data,
+                });
+            }
+        }
+        if self.cluster.is_some() {
+            let max_queue = self.get_config().get_cluster_config().map(|v| v.get_max_queue()).unwrap_or(0);
+            let mut queue = self.gossip_queue.lock().unwrap();
+            // a peer that re-announces replaces its queued announce.
+            let key = (info_hash.clone(), *peer_id);
+            if queue.len() >= max_queue && !queue.contains_key(&key) {
+                self.gossip_dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            queue.insert(key, peer);
+        }
+    }
+
+    /// Publishes the peers that announced since the last sync, then merges the peers announced to other instances.
+    pub async fn sync_redis(&self) {
+        let store = match self.redis {
+            Some(ref v) => v,
+            None => return,
+        };
+        let peer_timeout = self.get_config().get_tracker_config().get_peer_timeout();
+
+        let queue = std::mem::take(&mut *self.redis_queue.lock().unwrap());
+        if !queue.is_empty() {
+            trace!("publishing {} peers to redis", queue.len());
+            if let Err(err) = store.publish(queue, peer_timeout.as_secs() as usize).await {
+                error!("failed to publish peers to redis: {}", err);
+            }
+        }
+
+        let peers = match store.fetch().await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to fetch peers from redis: {}", err);
+                return;
+            }
+        };
+
+        let mut stale = Vec::new();
+        for peer in peers {
+            let (info_hash, peer_id, data) = match (
+                peer.info_hash.parse::<InfoHash>(),
+                PeerId::from_hex(&peer.peer_id),
+                serde_json::from_str::<SharedPeer>(&peer.data),
+            ) {
+                (Ok(a), Some(b), Ok(c)) => (a, b, c),
+                _ => {
+                    stale.push(peer);
+                    continue;
+                }
+            };
+
+            if !self.merge_shared_peer(&info_hash, peer_id, data).await {
+                stale.push(peer);
+            }
+        }
+
+        if !stale.is_empty() {
+            trace!("removing {} stale peers from redis", stale.len());
+            if let Err(err) = store.remove(stale).await {
+                error!("failed to remove stale peers from redis: {}", err);
+            }
+        }
+    }
+
+    /// Merges a peer announced to another instance. Returns false if the peer already timed out.
+    async fn merge_shared_peer(&self, info_hash: &InfoHash, peer_id: PeerId, data: SharedPeer) -> bool {
+        let now = std::time::Instant::now();
+        let age = std::time::Duration::from_secs(unix_now().saturating_sub(data.updated));
+        if age > self.get_config().get_tracker_config().get_peer_timeout() {
+            return false;
+        }
+
+        // the instance the peer announced to already waited for its confirmations.
+        let is_complete = self
+            .get_config()
+            .get_tracker_config()
+            .get_seeders()
+            .is_complete(data.downloaded, data.left);
+        let peer = TorrentPeer {
+            ip: data.ip,
+            uploaded: data.uploaded,
+            downloaded: data.downloaded,
+            left: data.left,
+            event: Events::None,
+            updated: now.checked_sub(age).unwrap_or(now),
+            uploaded_delta: 0,
+            downloaded_delta: 0,
+            delta_ms: 0,
+            complete_streak: 0,
+            seeder: is_complete && data.uploaded > 0,
+            country: None,
+        };
+        let mode = self.get_mode();
+        self.database
+            .get_or_insert_with(
+                &self.resolve(info_hash),
+                || {
+                    match mode {
+                        TrackerMode::DynamicMode => Some(TorrentEntry::new()),
+                        _ => None,
+                    }
+                },
+                |entry| {
+                    if !entry.is_flagged() {
+                        entry.merge_peer(peer_id, peer);
+                        if let Some(ref geoip) = self.geoip {
+                            entry.locate_peer(&peer_id, geoip);
+                        }
+                    }
+                },
+            )
+            .await;
+        true
+    }
+
+    /// Binds the gossip socket, returning its address.
+    pub async fn open_cluster(&mut self, cluster_config: &ClusterConfig) -> Result<std::net::SocketAddr, std::io::Error> {
+        let gossip = Gossip::bind(
+            cluster_config.get_bind_address(),
+            cluster_config.get_nodes(),
+            cluster_config.get_secret(),
+        )
+        .await?;
+        let local_addr = gossip.local_addr();
+        self.cluster = Some(gossip);
+        Ok(local_addr)
+    }
+
+    /// Sends the peers that announced since the last round to every cluster node.
+    pub async fn send_gossip(&self) {
+        let cluster = match self.cluster {
+            Some(ref v) => v,
+            None => return,
+        };
+
+        let dropped = self.gossip_dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("gossip queue is full, dropped {} peers", dropped);
+        }
+
+        let queue = std::mem::take(&mut *self.gossip_queue.lock().unwrap());
+        if queue.is_empty() {
+            return;
+        }
+        trace!("gossiping {} peers", queue.len());
+        // peers take about 150 bytes each, they're packed into datagrams of up to `MAX_PAYLOAD_SIZE` bytes.
+        let mut batch: Vec<GossipPeer> = Vec::new();
+        // the length prefix of the serialized batch.
+        let mut batch_size = 8;
+        for ((info_hash, peer_id), peer) in queue {
+            let peer = GossipPeer {
+                info_hash,
+                peer_id: peer_id.to_hex(),
+                peer,
+            };
+            let size = match bincode::serialized_size(&peer) {
+                Ok(v) => v as usize,
+                Err(err) => {
+                    error!("failed to serialize gossip: {}", err);
+                    continue;
+                }
+            };
+            if !batch.is_empty() && batch_size + size > MAX_PAYLOAD_SIZE {
+                self.broadcast_peers(cluster, &batch).await;
+                batch.clear();
+                batch_size = 8;
+            }
+            batch.push(peer);
+            batch_size += size;
+        }
+        if !batch.is_empty() {
+            self.broadcast_peers(cluster, &batch).await;
+        }
+    }
+
+    async fn broadcast_peers(&self, cluster: &Gossip, peers: &[GossipPeer]) {
+        match bincode::serialize(peers) {
+            Ok(payload) => cluster.broadcast(&payload).await,
+            Err(err) => error!("failed to serialize gossip: {}", err),
+        }
+    }
+
+    /// Merges peers gossiped by other cluster nodes, until the socket fails.
+    pub async fn receive_gossip(&self) -> Result<(), std::io::Error> {
+        let cluster = match self.cluster {
+            Some(ref v) => v,
+            None => return Ok(()),
+        };
+
+        loop {
+            let payload = cluster.receive().await?;
+            let peers: Vec<GossipPeer> = match bincode::deserialize(&payload) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("ignoring invalid gossip: {}", err);
+                    continue;
+                }
+            };
+            for peer in peers {
+                if let Some(peer_id) = PeerId::from_hex(&peer.peer_id) {
+                    self.merge_shared_peer(&peer.info_hash, peer_id, peer.peer).await;
+                }
+            }
+        }
+    }
+
+    /// Binds the raft socket & loads the raft log, returning the socket's address.
+    pub async fn open_raft(&mut self, raft_config: &RaftConfig) -> Result<std::net::SocketAddr, std::io::Error> {
+        let raft = Raft::open(raft_config).await?;
+        let local_addr = raft.local_addr();
+        self.raft = Some(raft);
+        Ok(local_addr)
+    }
+
+    /// Sends raft heartbeats & starts elections when they're due.
+    pub async fn tick_raft(&self) {
+        if let Some(ref raft) = self.raft {
+            if let Err(err) = raft.tick().await {
+                error!("failed to write the raft log: {}", err);
+            }
+            self.apply_raft().await;
+        }
+    }
+
+    /// Handles the messages of the other raft nodes, until the socket fails.
+    pub async fn receive_raft(&self) -> Result<(), std::io::Error> {
+        let raft = match self.raft {
+            Some(ref v) => v,
+            None => return Ok(()),
+        };
+
+        loop {
+            if let Err(err) = raft.receive().await {
+                // the log can't be written, the node can't take part in the cluster anymore.
+                if err.kind() != std::io::ErrorKind::InvalidData {
+                    return Err(err);
+                }
+                warn!("ignoring invalid raft message: {}", err);
+            }
+            self.apply_raft().await;
+        }
+    }
+
+    /// Applies the actions committed to the raft log since the last call, in order.
+    async fn apply_raft(&self) {
+        let raft = match self.raft {
+            Some(ref v) => v,
+            None => return,
+        };
+        let (_applying, entries) = raft.take_committed().await;
+        for (index, entry) in entries {
+            let outcome = match entry.get_action() {
+                Some(action) => self.apply_control(action.clone()).await,
+                None => Ok(None),
+            };
+            if let Err(err) = raft.applied(index, &entry, outcome).await {
+                error!("failed to write the raft log: {}", err);
+            }
+        }
+    }
+
+    /// Applies an admin action. With `[raft]`, the action is submitted to the raft log & applied by every node,
+    /// this returns once it was applied by this one.
+    pub async fn control(&self, action: ControlAction) -> ControlOutcome {
+        let raft = match self.raft {
+            Some(ref v) => v,
+            None => return self.apply_control(action).await,
+        };
+
+        let outcome = raft.submit(action).await?;
+        // a single node commits its actions right away.
+        self.apply_raft().await;
+        match tokio::time::timeout(std::time::Duration::from_secs(10), outcome).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(_)) | Err(_) => {
+                Err(String::from(
+                    "timed out waiting for the raft log, the action may still be applied.",
+                ))
+            }
+        }
+    }
+
+    async fn apply_control(&self, action: ControlAction) -> ControlOutcome {
+        match action {
+            ControlAction::RemoveTorrent { info_hash } => {
+                match self.remove_torrent(&info_hash, true).await {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(String::from("failed to delete torrent")),
+                }
+            }
+            ControlAction::ResetTorrent { info_hash } => {
+                match self.reset_torrent_stats(&info_hash).await {
+                    true => Ok(None),
+                    false => Err(String::from("no such torrent")),
+                }
+            }
+            ControlAction::LinkTorrent { info_hash, alias } => {
+                match self.link_torrent(&info_hash, &alias).await {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(format!("failed to link {}", alias)),
+                }
+            }
+            ControlAction::UnlinkTorrent { info_hash, alias } => {
+                match self.unlink_torrent(&info_hash, &alias).await {
+                    Ok(_) => Ok(None),
+                    Err(_) => Err(String::from("no such alias")),
+                }
+            }
+            ControlAction::SetFlag { info_hash, is_flagged } => {
+                if !self.set_torrent_flag(&info_hash, is_flagged).await && is_flagged {
+                    // the torrent doesn't exist, it's registered to be flagged.
+                    if self.add_torrent(&info_hash).await.is_err() {
+                        return Err(String::from("failed to flag torrent"));
+                    }
+                    self.set_torrent_flag(&info_hash, is_flagged).await;
+                }
+                Ok(None)
+            }
+            ControlAction::UpdateUser {
+                name,
+                passkey,
+                is_banned,
+            } => {
+                let update = UserUpdate { passkey, is_banned };
+                self.users.update(&name, update).map(|_| None).map_err(String::from)
+            }
+            ControlAction::RemoveUser { name } => {
+                match self.users.remove(&name) {
+                    true => Ok(None),
+                    false => Err(String::from("no such user")),
+                }
+            }
+            ControlAction::Ban {
+                target,
+                created_by,
+                created,
+                duration,
+                reason,
+            } => {
+                self.bans
+                    .add(target, created_by, duration, reason, created)
+                    .map(Some)
+                    .map_err(String::from)
+            }
+            ControlAction::LiftBan { id } => {
+                match self.bans.remove(id) {
+                    true => Ok(None),
+                    false => Err(String::from("no such ban")),
+                }
+            }
+        }
+    }
+
+    /// Starts queueing webhook events. Enabled once startup is done, so loading the database doesn't trigger events.
+    pub fn enable_webhooks(&mut self, webhook_config: &WebhookConfig) {
+        self.webhooks = Some(Webhooks::new(webhook_config));
+    }
+
+    fn notify(&self, event: WebhookEvent) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.push(event);
+        }
+    }
+
+    fn notify_removed(&self, removed: &[InfoHash]) {
+        for info_hash in removed {
+            self.notify(WebhookEvent::TorrentRemoved {
+                info_hash: info_hash.clone(),
+            });
+        }
+    }
+
+    pub async fn flush_webhooks(&self) {
+        if let Some(ref webhooks) = self.webhooks {
+            webhooks.flush().await;
+        }
+    }
+
+    pub fn enable_analytics(&mut self, analytics_config: &AnalyticsConfig) {
+        self.analytics = Some(Analytics::new(analytics_config));
+    }
+
+    /// Queues an announce or scrape event for export, if analytics are enabled.
+    pub fn track(&self, event: AnalyticsEvent) {
+        if let Some(ref analytics) = self.analytics {
+            analytics.push(event);
+        }
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.analytics.is_some()
+    }
+
+    pub async fn flush_analytics(&self) {
+        if let Some(ref analytics) = self.analytics {
+            analytics.flush().await;
+        }
+    }
+
+    pub fn open_abuse_log(&mut self, path: &str, malformed_threshold: u32) -> Result<(), std::io::Error> {
+        self.abuse_log = Some(AbuseLog::open(path, malformed_threshold)?);
+        Ok(())
+    }
+
+    /// Loads the GeoIP database peers are tagged with their country from, requires the `geoip` feature.
+    pub fn open_geoip(&mut self, path: &str) -> Result<(), std::io::Error> {
+        self.geoip = Some(GeoIp::open(path)?);
+        Ok(())
+    }
+
+    /// Logs a warning if `operation` on `subject` took longer than `logging.slow_threshold_ms`.
+    pub fn log_if_slow(&self, operation: &str, subject: &dyn std::fmt::Display, started: std::time::Instant) {
+        let elapsed = started.elapsed();
+        let threshold = match self.get_config().get_logging_config().get_slow_threshold() {
+            Some(threshold) if elapsed >= threshold => threshold,
+            _ => return,
+        };
+        log_fields!(
+            log::Level::Warn,
+            "slow operation",
+            operation = operation,
+            subject = subject,
+            duration_ms = elapsed.as_millis(),
+            threshold_ms = threshold.as_millis(),
+        );
+    }
+
+    /// Logs abuse from `ip` to the abuse log, if there's one.
+    pub fn report_abuse(&self, abuse: Abuse, ip: std::net::IpAddr) {
+        if let Some(ref abuse_log) = self.abuse_log {
+            abuse_log.report(abuse, ip);
+        }
+    }
+
+    /// Counts a malformed packet from `ip`, floods are logged to the abuse log.
+    pub fn count_malformed(&self, ip: std::net::IpAddr) {
+        if let Some(ref abuse_log) = self.abuse_log {
+            abuse_log.count_malformed(ip);
+        }
+    }
+
+    pub fn enable_telemetry(&mut self, telemetry_config: &TelemetryConfig) {
+        self.telemetry = Some(Tracer::new(telemetry_config));
+    }
+
+    pub fn get_tracer(&self) -> Option<&Tracer> {
+        self.telemetry.as_ref()
+    }
+
+    /// Starts a span for a tracker operation, if telemetry is enabled. The span ends when it's dropped.
+    fn start_span(&self, name: &str) -> Option<Span<'_>> {
+        self.telemetry
+            .as_ref()
+            .map(|tracer| tracer.start(name, SpanKind::Internal, None))
+    }
+
+    pub async fn flush_telemetry(&self) {
+        if let Some(ref telemetry) = self.telemetry {
+            telemetry.flush().await;
+        }
+    }
+
+    pub async fn open_postgres(&mut self, url: &str) -> Result<(), std::io::Error> {
+        self.postgres = Some(PostgresStore::open(url).await?);
+        Ok(())
+    }
+
+    /// Applies the torrents (and in private mode, the users) listed in postgres.
+    /// Outside of dynamic mode, unflagged torrents that are no longer listed are removed.
+    pub async fn sync_postgres(&self) -> Result<(), std::io::Error> {
+        let store = match self.postgres {
+            Some(ref v) => v,
+            None => return Ok(()),
+        };
+        let config = self.get_config();
+        let postgres_config = match config.get_postgres_config() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut torrents = Vec::new();
+        for torrent in store.torrents(postgres_config.get_torrents_query()).await? {
+            match torrent.info_hash.trim().parse() {
+                Ok(info_hash) => torrents.push((info_hash, torrent.is_flagged)),
+                Err(_) => warn!("ignoring invalid infohash from postgres: \"{}\"", torrent.info_hash),
+            }
+        }
+        let users = match self.get_mode() {
+            TrackerMode::PrivateMode => {
+                let users = store.users(postgres_config.get_users_query()).await?;
+                Some(users.into_iter().map(|u| (u.name, u.passkey, u.is_banned)).collect())
+            }
+            _ => None,
+        };
+
+        self.apply_registry(torrents, users).await;
+        Ok(())
+    }
+
+    pub async fn open_upstream(&mut self, upstream_config: &UpstreamConfig) -> Result<(), std::io::Error> {
+        let peer_timeout = self.get_config().get_tracker_config().get_peer_timeout();
+        self.upstream = Some(
+            Upstream::open(
+                upstream_config.get_address(),
+                upstream_config.get_max_announces(),
+                peer_timeout,
+            )
+            .await?,
+        );
+        Ok(())
+    }
+
+    /// Forwards the announces since the last round to the upstream tracker.
+    pub async fn sync_upstream(&self) -> Result<(), std::io::Error> {
+        if let Some(ref upstream) = self.upstream {
+            let count = upstream.announce_queued().await?;
+            trace!("upstream returned peers for {} torrents", count);
+        }
+        Ok(())
+    }
+
+    pub fn open_replica(&mut self, replica_config: &ReplicaConfig) {
+        self.replica = Some(Replica::new(replica_config));
+    }
+
+    /// Pulls the registered torrents, their flags, users and bans from the primary.
+    pub async fn sync_replica(&self) -> Result<(), std::io::Error> {
+        let replica = match self.replica {
+            Some(ref v) => v,
+            None => return Ok(()),
+        };
+
+        let snapshot = replica.fetch().await?;
+        self.apply_snapshot(snapshot).await;
+        Ok(())
+    }
+
+    /// Makes the registry match a primary's, bans included. Users are only replaced in private mode.
+    async fn apply_snapshot(&self, snapshot: RegistrySnapshot) {
+        self.bans.replace(snapshot.bans);
+        let torrents = snapshot.torrents.into_iter().map(|t| (t.info_hash, t.is_flagged)).collect();
+        let users = match self.get_mode() {
+            TrackerMode::PrivateMode => Some(snapshot.users.into_iter().map(|u| (u.name, u.passkey, u.is_banned)).collect()),
+            _ => None,
+        };
+
+        self.apply_registry(torrents, users).await;
+    }
+
+    /// The registered torrents, their flags, users and bans, as pulled by replicas.
+    pub async fn registry_snapshot(&self) -> RegistrySnapshot {
+        let _span = self.start_span("tracker.registry_snapshot");
+        let mut torrents = Vec::new();
+        self.database
+            .for_each(0, usize::max_value(), |info_hash, entry| {
+                torrents.push(RegisteredTorrent {
+                    info_hash: info_hash.clone(),
+                    is_flagged: entry.is_flagged(),
+                });
+            })
+            .await;
+        let users = self
+            .users
+            .export()
+            .into_iter()
+            .map(|(name, passkey, is_banned)| {
+                RegisteredUser {
+                    name,
+                    passkey,
+                    is_banned,
+                }
+            })
+            .collect();
+        let bans = self.bans.list(unix_now());
+        RegistrySnapshot { torrents, users, bans }
+    }
+
+    /// Makes the registered torrents & their flags match `torrents`, from an external source of truth.
+    /// Outside of dynamic mode, unflagged torrents that aren't listed are removed. `users` replaces all users.
+    async fn apply_registry(&self, torrents: Vec<(InfoHash, bool)>, users: Option<Vec<(String, String, bool)>>) {
+        let mut listed = std::collections::HashSet::new();
+        for (info_hash, should_flag) in torrents {
+            let info_hash = self.resolve(&info_hash).into_owned();
+
+            // only record actual changes, this runs every refresh.
+            match self.database.get(&info_hash, |entry| entry.is_flagged()).await {
+                Some(is_flagged) if is_flagged == should_flag => {}
+                Some(_) => {
+                    self.set_torrent_flag(&info_hash, should_flag).await;
+                }
+                None => {
+                    let _ = self.add_torrent(&info_hash).await;
+                    if should_flag {
+                        self.set_torrent_flag(&info_hash, true).await;
+                    }
+                }
+            }
+            listed.insert(info_hash);
+        }
+
+        if self.get_mode() != TrackerMode::DynamicMode {
+            let mut unlisted = Vec::new();
+            self.database
+                .for_each(0, usize::max_value(), |info_hash, entry| {
+                    if !entry.is_flagged() && !listed.contains(info_hash) {
+                        unlisted.push(info_hash.clone());
+                    }
+                })
+                .await;
+            for info_hash in unlisted.iter() {
+                let _ = self.remove_torrent(info_hash, false).await;
+            }
+        }
+
+        if let Some(users) = users {
+            self.users.replace(users);
+        }
+    }
+
+    fn mark_dirty<'a, I: IntoIterator<Item = &'a InfoHash>>(&self, info_hashes: I) {
+        if self.sqlite.is_none() {
+            return;
+        }
+        let mut dirty = self.dirty.lock().unwrap();
+        for info_hash in info_hashes {
+            dirty.insert(info_hash.clone());
+        }
+    }
+
+    /// Writes the torrents that changed since the last flush to sqlite.
+    async fn flush_sqlite(&self) {
+        let store = match self.sqlite {
+            Some(ref v) => v,
+            None => return,
+        };
+
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        if dirty.is_empty() {
+            return;
+        }
+
+        let mut upserts = Vec::new();
+        let mut deletes = Vec::new();
+        for info_hash in dirty.iter() {
+            let row = self.database.get(info_hash, |entry| sqlite_row(info_hash, entry)).await;
+            match row {
+                Some(Ok(row)) => upserts.push(row),
+                Some(Err(err)) => error!("failed to serialize torrent {}: {}", info_hash, err),
+                None => deletes.push(info_hash.to_string()),
+            }
+        }
+
+        trace!("writing {} changed torrents to sqlite", dirty.len());
+        if let Err(err) = store.write(upserts, deletes).await {
+            error!("failed to write torrents to sqlite: {}", err);
+            // try again on the next flush.
+            self.dirty.lock().unwrap().extend(dirty);
+        }
+    }
+
+    async fn record(&self, entry: JournalEntry) {
+        if let Some(ref journal) = self.journal {
+            journal.append(&entry).await;
+        }
+
+        match entry {
+            JournalEntry::Add { ref info_hash } => {
+                self.notify(WebhookEvent::TorrentAdded {
+                    info_hash: info_hash.clone(),
+                })
+            }
+            JournalEntry::Remove { ref info_hash } => self.notify_removed(std::slice::from_ref(info_hash)),
+            JournalEntry::Flag {
+                ref info_hash,
+                is_flagged,
+            } => {
+                self.notify(WebhookEvent::TorrentFlagged {
+                    info_hash: info_hash.clone(),
+                    is_flagged,
+                })
+            }
+            JournalEntry::Link { .. } | JournalEntry::Unlink { .. } | JournalEntry::Reset { .. } => {}
+        }
+        match entry {
+            JournalEntry::Flag { ref info_hash, .. }
+            | JournalEntry::Link { ref info_hash, .. }
+            | JournalEntry::Reset { ref info_hash } => self.swarm_changed(info_hash),
+            _ => {}
+        }
+
+        if self.sqlite.is_some() {
+            // administrative changes are written right away.
+            match entry {
+                JournalEntry::Add { ref info_hash }
+                | JournalEntry::Remove { ref info_hash }
+                | JournalEntry::Flag { ref info_hash, .. }
+                | JournalEntry::Unlink { ref info_hash, .. }
+                | JournalEntry::Reset { ref info_hash } => self.mark_dirty(Some(info_hash)),
+                JournalEntry::Link {
+                    ref info_hash,
+                    ref alias,
+                } => self.mark_dirty(vec![info_hash, alias]),
+            }
+            self.flush_sqlite().await;
+        }
+    }
+
+    /// Flags the infohashes of the configured blacklist, registering them if needed.
+    /// Returns the amount of torrents that were flagged.
+    pub async fn load_blacklist(&self) -> Result<usize, std::io::Error> {
+        let config = self.get_config();
+        let tracker_config = config.get_tracker_config();
+        let mut list = tracker_config.get_blacklist().to_vec();
+        if let Some(path) = tracker_config.get_blacklist_path() {
+            list.extend(parse_infohash_list(&tokio::fs::read_to_string(path).await?));
+        }
+
+        let mut count = 0;
+        for info_hash in list {
+            if !self.set_torrent_flag(&info_hash, true).await {
+                let _ = self.add_torrent(&info_hash).await;
+                if !self.set_torrent_flag(&info_hash, true).await {
+                    continue;
+                }
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Wakes up the requests waiting on the torrent, see `wait_for_swarm_change`.
+    fn swarm_changed(&self, info_hash: &InfoHash) {
+        if self.swarm_changes.receiver_count() > 0 {
+            let _ = self.swarm_changes.send(info_hash.clone());
+        }
+    }
+
+    /// Waits up to `timeout` for the torrent's (seeders, completed, leechers) to differ from `known`, or from their
+    /// current values if `known` isn't given. Returns the torrent's stats & whether they changed,
+    /// None if the torrent doesn't exist (anymore).
+    pub async fn wait_for_swarm_change(
+        &self, info_hash: &InfoHash, known: Option<(u32, u32, u32)>, timeout: std::time::Duration,
+    ) -> Option<((u32, u32, u32), bool)> {
+        // subscribed before reading the stats, so changes in between aren't missed.
+        let mut changes = self.swarm_changes.subscribe();
+        let info_hash = self.resolve(info_hash).into_owned();
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut stats = self.database.get(&info_hash, TorrentEntry::get_stats).await?;
+        let known = known.unwrap_or(stats);
+
+        while stats == known {
+            match tokio::time::timeout_at(deadline, changes.recv()).await {
+                Ok(Ok(ref changed)) if *changed != info_hash => continue,
+                // lagging receivers missed some changes, the stats are read again to be sure.
+                Ok(Ok(_)) | Ok(Err(tokio::sync::broadcast::RecvError::Lagged(_))) => {}
+                Ok(Err(tokio::sync::broadcast::RecvError::Closed)) | Err(_) => break,
+            }
+            stats = self.database.get(&info_hash, TorrentEntry::get_stats).await?;
+        }
+        Some((stats, stats != known))
+    }
+
+    /// Returns the torrent that `info_hash` was linked to, or `info_hash` itself if it isn't linked.
+    fn resolve<'a>(&self, info_hash: &'a InfoHash) -> Cow<'a, InfoHash> {
+        let aliases = self.aliases.read().unwrap();
+        match aliases.get(info_hash) {
+            Some(target) => Cow::Owned(target.clone()),
+            None => Cow::Borrowed(info_hash),
+        }
+    }
+
+    /// Links `alias` to the torrent `info_hash`, so both share a single swarm & stats.
+    /// If `alias` was already tracked, its peers and counters are merged into `info_hash`.
+    pub async fn link_torrent(&self, info_hash: &InfoHash, alias: &InfoHash) -> Result<(), ()> {
+        let target = self.resolve(info_hash).into_owned();
+        if target == *alias {
+            return Err(());
+        }
+        // torrents that have aliases of their own can't become one, nor can flagged torrents, merging would unflag them.
+        if self
+            .database
+            .get(alias, |entry| !entry.aliases.is_empty() || entry.is_flagged())
+            .await
+            == Some(true)
+        {
+            return Err(());
+        }
+
+        {
+            let mut aliases = self.aliases.write().unwrap();
+            if let Some(existing) = aliases.get(alias) {
+                // linking again is a no-op.
+                return if *existing == target { Ok(()) } else { Err(()) };
+            }
+            aliases.insert(alias.clone(), target.clone());
+        }
+
+        let merged = self.database.remove_if(alias, |_| true).await;
+        self.database
+            .get_or_insert_with(
+                &target,
+                || Some(TorrentEntry::new()),
+                |entry| {
+                    if let Some(merged) = merged {
+                        entry.merge(merged);
+                    }
+                    entry.aliases.push(alias.clone());
+                },
+            )
+            .await;
+
+        self.record(JournalEntry::Link {
+            info_hash: target,
+            alias: alias.clone(),
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Unlinks `alias` from the torrent `info_hash`, announces to it start a swarm of their own.
+    /// Peers & counters that were merged into `info_hash` stay there.
+    pub async fn unlink_torrent(&self, info_hash: &InfoHash, alias: &InfoHash) -> Result<(), ()> {
+        let target = self.resolve(info_hash).into_owned();
+        {
+            let mut aliases = self.aliases.write().unwrap();
+            if aliases.get(alias) != Some(&target) {
+                return Err(());
+            }
+            aliases.remove(alias);
+        }
+        self.database
+            .get_mut(&target, |entry| entry.aliases.retain(|v| v != alias))
+            .await;
+
+        self.record(JournalEntry::Unlink {
+            info_hash: target,
+            alias: alias.clone(),
+        })
+        .await;
+        Ok(())
+    }
+
+    /// Adding torrents is not relevant to dynamic trackers.
+    pub async fn add_torrent(&self, info_hash: &InfoHash) -> Result<(), ()> {
+        if self.aliases.read().unwrap().contains_key(info_hash) {
+            return Err(());
+        }
+
+        let mut inserted = false;
+        self.database
+            .get_or_insert_with(
+                info_hash,
+                || {
+                    inserted = true;
+                    Some(TorrentEntry::new())
+                },
+                |_| (),
+            )
+            .await;
+
+        if inserted {
+            self.record(JournalEntry::Add {
+                info_hash: info_hash.clone(),
+            })
+            .await;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    /// If the torrent is flagged, it will not be removed unless force is set to true.
+    pub async fn remove_torrent(&self, info_hash: &InfoHash, force: bool) -> Result<(), ()> {
+        let info_hash = self.resolve(info_hash);
+        match self
+            .database
+            .remove_if(&info_hash, |entry| force || !entry.is_flagged())
+            .await
+        {
+            Some(entry) => {
+                {
+                    let mut aliases = self.aliases.write().unwrap();
+                    for alias in entry.aliases.iter() {
+                        aliases.remove(alias);
+                    }
+                }
+
+                self.record(JournalEntry::Remove {
+                    info_hash: info_hash.into_owned(),
+                })
+                .await;
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    /// flagged torrents will result in a tracking error. This is to allow enforcement against piracy.
+    pub async fn set_torrent_flag(&self, info_hash: &InfoHash, is_flagged: bool) -> bool {
+        let info_hash = self.resolve(info_hash);
+        let exists = self
+            .database
+            .get_mut(&info_hash, |entry| {
+                if is_flagged && !entry.is_flagged {
+                    entry.clear_peers();
+                }
+                entry.is_flagged = is_flagged;
+            })
+            .await
+            .is_some();
+
+        if exists {
+            self.record(JournalEntry::Flag {
+                info_hash: info_hash.into_owned(),
+                is_flagged,
+            })
+            .await;
+        }
+        exists
+    }
+
+    /// Zeroes the torrent's completed count & drops its peers, it stays registered.
+    /// Returns false if the torrent doesn't exist.
+    pub async fn reset_torrent_stats(&self, info_hash: &InfoHash) -> bool {
+        let info_hash = self.resolve(info_hash);
+        let exists = self.database.get_mut(&info_hash, TorrentEntry::reset_stats).await.is_some();
+        if exists {
+            self.record(JournalEntry::Reset {
+                info_hash: info_hash.into_owned(),
+            })
+            .await;
+        }
+        exists
+    }
+
+    /// Peers the upstream tracker returned for the torrent, up to `max` and except `remote_addr`.
+    pub fn get_upstream_peers(
+        &self, info_hash: &InfoHash, remote_addr: &std::net::SocketAddr, max: usize,
+    ) -> Vec<std::net::SocketAddr> {
+        match self.upstream {
+            Some(ref upstream) if remote_addr.is_ipv4() => {
+                upstream
+                    .peers(&self.resolve(info_hash))
+                    .into_iter()
+                    .map(std::net::SocketAddr::V4)
+                    .filter(|peer| peer != remote_addr)
+                    .take(max)
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    pub async fn update_torrent_and_get_stats(
+        &self, info_hash: &InfoHash, peer_id: &PeerId, remote_address: &std::net::SocketAddr, uploaded: u64,
+        downloaded: u64, left: u64, event: Events,
+    ) -> TorrentStats {
+        let mode = self.get_mode();
+        let max_peers = self.get_config().get_tracker_config().get_max_peers_per_torrent();
+        let completion_history = self.get_config().get_tracker_config().get_completion_history();
+        let seeder_policy = *self.get_config().get_tracker_config().get_seeders();
+        let duplicate_window = self.get_config().get_tracker_config().get_duplicate_announce_window();
+        let config = self.get_config();
+        let unknown_ttl = match mode {
+            TrackerMode::DynamicMode => None,
+            _ => config.get_tracker_config().get_unknown_torrent_cache_ttl(),
+        };
+        let mut is_new = false;
+        let mut is_completed = false;
+        let mut is_first_completion = false;
+        let mut is_refused = false;
+        let mut is_changed = false;
+        let is_full = self.database.peer_count() >= self.peer_limit.load(Ordering::Relaxed);
+        let info_hash = self.resolve(info_hash);
+        // floods of announces for unknown torrents are refused without locking the database.
+        if let Some(ttl) = unknown_ttl {
+            if self.database.unknown.contains(&info_hash, ttl, std::time::Instant::now()) {
+                return TorrentStats::TorrentNotRegistered;
+            }
+        }
+        let generation = self.database.unknown.generation();
+
+        let ip = remote_address.ip();
+        self.unique_ips_hour.insert(&ip);
+        self.unique_ips_day.insert(&ip);
+
+        let stats = self
+            .database
+            .get_or_insert_with(
+                &info_hash,
+                || {
+                    match mode {
+                        TrackerMode::DynamicMode => {
+                            is_new = true;
+                            Some(TorrentEntry::new())
+                        }
+                        _ => None,
+                    }
+                },
+                |torrent_entry| {
+                    if torrent_entry.is_flagged() {
+                        return TorrentStats::TorrentFlagged;
+                    }
+                    let before = torrent_entry.get_stats();
+                    if let Some(window) = duplicate_window {
+                        let now = std::time::Instant::now();
+                        if torrent_entry.touch_duplicate(peer_id, remote_address, left, event, window, now) {
+                            let (seeders, complete, leechers) = before;
+                            return TorrentStats::Duplicate {
+                                seeders,
+                                leechers,
+                                complete,
+                            };
+                        }
+                    }
+
+                    if is_full && torrent_entry.peers.get(peer_id).is_none() {
+                        // answered like any other announce, the peer just isn't handed out.
+                        is_refused = true;
+                    } else if torrent_entry.update_peer(
+                        peer_id,
+                        remote_address,
+                        uploaded,
+                        downloaded,
+                        left,
+                        event,
+                        &seeder_policy,
+                    ) {
+                        torrent_entry.record_completion(completion_history);
+                        is_completed = true;
+                        is_first_completion = torrent_entry.completed == 1;
+                    }
+                    if let Some(ref geoip) = self.geoip {
+                        torrent_entry.locate_peer(peer_id, geoip);
+                    }
+                    if let Some(max_peers) = max_peers {
+                        torrent_entry.evict_peers(max_peers);
+                    }
+
+                    let (seeders, complete, leechers) = torrent_entry.get_stats();
+                    is_changed = (seeders, complete, leechers) != before;
+                    let (uploaded, downloaded) = match torrent_entry.peers.get(peer_id) {
+                        Some(peer) => (peer.uploaded_delta, peer.downloaded_delta),
+                        None => (0, 0),
+                    };
+
+                    TorrentStats::Stats {
+                        seeders,
+                        leechers,
+                        complete,
+                        uploaded,
+                        downloaded,
+                        peers: torrent_entry.get_compact_peers(|ip| config.get_tracker_config().is_handed_out(ip)),
+                    }
+                },
+            )
+            .await;
+
+        if is_refused {
+            self.peers_refused.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(TorrentStats::Duplicate { .. }) = stats {
+            self.duplicate_announces.fetch_add(1, Ordering::Relaxed);
+        }
+        if is_changed {
+            self.swarm_changed(&info_hash);
+        }
+        if is_new || is_completed {
+            self.mark_dirty(Some(&*info_hash));
+        }
+        if is_new {
+            self.notify(WebhookEvent::TorrentAdded {
+                info_hash: info_hash.clone().into_owned(),
+            });
+        }
+        if is_first_completion {
+            self.notify(WebhookEvent::TorrentCompleted {
+                info_hash: info_hash.clone().into_owned(),
+            });
+        }
+        if (self.redis.is_some() || self.cluster.is_some()) && !is_refused {
+            if let Some(TorrentStats::Stats { .. }) = stats {
+                self.queue_shared_peer(&info_hash, peer_id, SharedPeer {
+                    ip: *remote_address,
+                    uploaded,
+                    downloaded,
+                    left,
+                    updated: unix_now(),
+                });
+            }
+        }
+        if let (Some(upstream), std::net::SocketAddr::V4(addr), false) = (&self.upstream, remote_address, is_refused) {
+            if let Some(TorrentStats::Stats { .. }) = stats {
+                upstream.queue(&info_hash, UpstreamAnnounce {
+                    peer_id: peer_id.0,
+                    ip: *addr.ip(),
+                    port: addr.port(),
+                    uploaded,
+                    downloaded,
+                    left,
+                    event: event as u32,
+                });
+            }
+        }
+        if is_new {
+            self.enforce_torrent_limit().await;
+        }
+        self.check_memory_limit();
+
+        if let (None, Some(ttl)) = (&stats, unknown_ttl) {
+            let now = std::time::Instant::now();
+            self.database
+                .unknown
+                .insert(info_hash.clone().into_owned(), generation, ttl, now);
+        }
+        stats.unwrap_or(TorrentStats::TorrentNotRegistered)
+    }
+
+    /// Evicts the least recently announced torrents once `max_torrents` is exceeded.
+    /// Torrents are evicted in batches of 1% so a flood of new infohashes doesn't cause a scan per announce.
+    async fn enforce_torrent_limit(&self) {
+        let max_torrents = match self.get_config().get_tracker_config().get_max_torrents() {
+            Some(v) => v,
+            None => return,
+        };
+
+        let count = self.database.len();
+        if count <= max_torrents {
+            return;
+        }
+
+        let batch = (count - max_torrents).max(max_torrents / 100).max(1);
+        trace!("{} torrents tracked, evicting {}", count, batch);
+        let removed = self.database.evict_oldest(batch).await;
+        self.mark_dirty(removed.iter());
+        self.notify_removed(&removed);
+    }
+
+    pub fn get_torrent_count(&self) -> usize {
+        self.database.len()
+    }
+
+    pub fn get_peer_count(&self) -> usize {
+        self.database.peer_count()
+    }
+
+    pub fn get_seeder_count(&self) -> usize {
+        self.database.seeder_count()
+    }
+
+    /// Completed events of the tracked torrents.
+    pub fn get_completed_count(&self) -> usize {
+        self.database.completed_count()
+    }
+
+    /// Limits the peers held over all torrents, `None` removes the limit.
+    pub fn set_peer_limit(&self, max_peers: Option<usize>) {
+        self.peer_limit.store(max_peers.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    /// Announces of new peers that weren't stored since the peer limit was reached.
+    pub fn get_peers_refused(&self) -> usize {
+        self.peers_refused.load(Ordering::Relaxed)
+    }
+
+    /// Re-announces within `tracker.duplicate_announce_window` that were answered without peers.
+    pub fn get_duplicate_announces(&self) -> usize {
+        self.duplicate_announces.load(Ordering::Relaxed)
+    }
+
+    pub fn get_requests(&self) -> &RequestCounters {
+        &self.requests
+    }
+
+    pub fn get_traffic(&self) -> &TrafficMonitor {
+        &self.traffic
+    }
+
+    pub fn get_tasks(&self) -> &BackgroundTasks {
+        &self.tasks
+    }
+
+    pub fn get_readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+
+    /// A human readable report of the tracker's state, for inspecting an instance that stopped responding.
+    pub fn diagnostics(&self, uptime: std::time::Duration) -> String {
+        let (announces, scrapes, dropped) = self.requests.totals();
+        let mut lines = vec![
+            format!("uptime: {}s", uptime.as_secs()),
+            format!("requests: {} announces, {} scrapes, {} dropped", announces, scrapes, dropped),
+            format!(
+                "torrents: {}, peers: {}, seeders: {}",
+                self.get_torrent_count(),
+                self.get_peer_count(),
+                self.get_seeder_count()
+            ),
+        ];
+        let peer_limit = self.peer_limit.load(Ordering::Relaxed);
+        if peer_limit != usize::MAX {
+            lines.push(format!(
+                "peer limit: {}, {} new peers refused",
+                peer_limit,
+                self.get_peers_refused()
+            ));
+        }
+        if self
+            .get_config()
+            .get_tracker_config()
+            .get_duplicate_announce_window()
+            .is_some()
+        {
+            lines.push(format!("duplicate announces: {}", self.get_duplicate_announces()));
+        }
+        if self.get_config().get_tracker_config().get_response_cache_ttl().is_some() {
+            lines.push(format!("replayed responses: {}", self.requests.replayed()));
+        }
+        let quirks = self.requests.quirks();
+        lines.push(format!(
+            "parsing quirks: {} trailing data, {} zero port, {} reserved bits",
+            quirks.trailing_data, quirks.zero_port, quirks.reserved_bits
+        ));
+
+        let mut memory = format!("memory: {} KiB in torrents & peers", self.get_memory_usage() / 1024);
+        if let Some(rss) = resident_memory() {
+            memory += &format!(", {} KiB resident", rss / 1024);
+        }
+        lines.push(memory);
+        lines.push(format!(
+            "queues: {} redis, {} gossip, {} unsaved sqlite torrents",
+            self.redis_queue.lock().unwrap().len(),
+            self.gossip_queue.lock().unwrap().len(),
+            self.dirty.lock().unwrap().len()
+        ));
+
+        for (i, stats) in self.database.lock_stats().iter().enumerate() {
+            lines.push(format!(
+                "shard {}: {} locks, {} contended, {}ms waited",
+                i,
+                stats.acquired,
+                stats.contended,
+                stats.waited.as_millis()
+            ));
+        }
+        for task in self.tasks.describe(std::time::Instant::now()) {
+            lines.push(format!("task {}", task));
+        }
+        for probe in self.readiness.get_listeners() {
+            match (probe.rtt_ms, probe.error) {
+                (Some(rtt_ms), _) => lines.push(format!("udp {}: answered in {:.1}ms", probe.address, rtt_ms)),
+                (_, error) => lines.push(format!("udp {}: {}", probe.address, error.unwrap_or_default())),
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Approximate amount of memory used by torrent & peer entries.
+    pub fn get_memory_usage(&self) -> usize {
+        const TORRENT_SIZE: usize = std::mem::size_of::<InfoHash>() + std::mem::size_of::<TorrentEntry>();
+        const PEER_SIZE: usize = std::mem::size_of::<PeerId>() + std::mem::size_of::<TorrentPeer>();
+
+        self.database.len() * TORRENT_SIZE + self.database.peer_count() * PEER_SIZE
+    }
+
+    fn is_over_memory_limit(&self) -> bool {
+        match self.get_config().get_tracker_config().get_soft_memory_limit() {
+            Some(limit) => self.get_memory_usage() > limit,
+            None => false,
+        }
+    }
+
+    /// Wakes the cleanup task once the soft memory limit is crossed.
+    fn check_memory_limit(&self) {
+        if self.is_over_memory_limit() && !self.cleanup_requested.swap(true, Ordering::Relaxed) {
+            trace!("soft memory limit exceeded, requesting cleanup");
+            self.memory_pressure.notify();
+        }
+    }
+
+    /// Notified when the soft memory limit is crossed and a cleanup should run early.
+    pub fn get_memory_pressure_notify(&self) -> Arc<tokio::sync::Notify> {
+        self.memory_pressure.clone()
+    }
+
+    pub fn get_client_stats(&self) -> Arc<ClientStats> {
+        self.client_stats.read().unwrap().clone()
+    }
+
+    pub fn get_stats(&self) -> TrackerStats {
+        TrackerStats {
+            torrents: self.database.len(),
+            peers: self.database.peer_count(),
+            seeders: self.database.seeder_count(),
+            leechers: self.database.peer_count().saturating_sub(self.database.seeder_count()),
+            completed: self.database.completed_count(),
+            memory_bytes: self.get_memory_usage(),
+            swarm_sizes: *self.swarm_sizes.read().unwrap(),
+            top_torrents: self.top_torrents.read().unwrap().clone(),
+            countries: self.countries.read().unwrap().clone(),
+            unique_ips: UniqueIpStats {
+                last_hour: self.unique_ips_hour.estimate(),
+                last_day: self.unique_ips_day.estimate(),
+            },
+            quirks: self.requests.quirks(),
+            traffic: self.traffic.get_report(),
+        }
+    }
+
+    pub fn get_users(&self) -> &UserRegistry {
+        &self.users
+    }
+
+    /// Bans `target` for `duration` seconds, or until the ban is lifted. Returns the new ban.
+    pub async fn ban(
+        &self, target: BanTarget, created_by: String, duration: Option<u64>, reason: Option<String>,
+    ) -> ControlOutcome {
+        self.control(ControlAction::Ban {
+            target,
+            created_by,
+            created: unix_now(),
+            duration,
+            reason,
+        })
+        .await
+    }
+
+    pub fn get_bans(&self) -> Vec<Ban> {
+        self.bans.list(unix_now())
+    }
+
+    /// Whether requests from `ip` are refused, `peer_id` is checked against banned clients for announces.
+    pub fn is_banned(&self, ip: &std::net::IpAddr, peer_id: Option<&PeerId>) -> bool {
+        self.bans.is_banned(ip, peer_id.map(|v| &v.0[..]), unix_now())
+    }
+
+    /// The current configuration, it's replaced when the configuration is reloaded.
+    pub fn get_config(&self) -> Arc<Configuration> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn get_mode(&self) -> TrackerMode {
+        *self.get_config().get_mode()
+    }
+
+    /// Switches the tracker to `mode` until the configuration is reloaded, torrents & peers are kept.
+    pub fn set_mode(&self, mode: TrackerMode) {
+        let mut current = self.config.write().unwrap();
+        let mut config = Configuration::clone(&current);
+        config.set_mode(mode);
+        *current = Arc::new(config);
+    }
+
+    /// Switches to the hot-reloadable settings of `config`, see `Configuration::reload`.
+    pub fn reload_config(&self, config: &Configuration) -> ConfigChanges {
+        let mut current = self.config.write().unwrap();
+        let (reloaded, changes) = current.reload(config);
+        *current = Arc::new(reloaded);
+        changes
+    }
+
+    /// Runs `f` on the torrent while holding its lock.
+    pub(crate) async fn with_torrent<R, F>(&self, info_hash: &InfoHash, f: F) -> Option<R>
+    where
+        R: Send,
+        F: FnOnce(&TorrentEntry) -> R + Send, {
+        self.database.get(&self.resolve(info_hash), f).await
+    }
+
+    /// Calls `f` for up to `limit` torrents, starting at `offset`.
+    pub(crate) async fn list_torrents<F: FnMut(&InfoHash, &TorrentEntry) + Send>(&self, offset: usize, limit: usize, f: F) {
+        self.database.for_each(offset, limit, f).await
+    }
+
+    pub async fn save_database<W: tokio::io::AsyncWrite + Unpin>(&self, w: W) -> Result<(), std::io::Error> {
+        write_database(w, self.database.snapshot().await).await?;
         Ok(())
     }
 
-    async fn cleanup(&self) {
-        let mut lock = self.database.torrent_peers.write().await;
-        let db: &mut BTreeMap<InfoHash, TorrentEntry> = &mut *lock;
-        let mut torrents_to_remove = Vec::new();
+    /// Computes the swarm size histogram, client stats, countries & largest swarms. Torrents are only read, one shard at a
+    /// time, so announces aren't held up and API requests read the latest summary without touching the torrents.
+    pub async fn summarize(&self) {
+        use std::cmp::Reverse;
+        use std::collections::BinaryHeap;
+        const TOP_TORRENTS: usize = 10;
+
+        let mut client_stats = ClientStats::default();
+        let mut swarm_sizes = SwarmSizeHistogram::default();
+        let mut countries = BTreeMap::new();
+        // min-heap holding the largest swarms seen so far.
+        let mut largest = BinaryHeap::with_capacity(TOP_TORRENTS + 1);
+        self.database
+            .for_each(0, usize::max_value(), |info_hash, entry| {
+                for peer_id in entry.peers.keys() {
+                    client_stats.add_peer(peer_id);
+                }
+                for country in entry.peers.values().filter_map(|peer| peer.country) {
+                    *countries.entry(country).or_insert(0) += 1;
+                }
+                swarm_sizes.add(entry.peers.len());
+                if !entry.peers.is_empty() {
+                    largest.push(Reverse((
+                        entry.peers.len(),
+                        info_hash.clone(),
+                        entry.seeders,
+                        entry.completed,
+                    )));
+                    if largest.len() > TOP_TORRENTS {
+                        largest.pop();
+                    }
+                }
+            })
+            .await;
+
+        let top_torrents = largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((peers, info_hash, seeders, completed))| {
+                TopTorrent {
+                    info_hash,
+                    peers,
+                    seeders,
+                    completed,
+                }
+            })
+            .collect();
+        *self.client_stats.write().unwrap() = Arc::new(client_stats);
+        *self.swarm_sizes.write().unwrap() = swarm_sizes;
+        *self.top_torrents.write().unwrap() = top_torrents;
+        *self.countries.write().unwrap() = countries;
+        self.traffic.evaluate(std::time::Instant::now());
+    }
+
+    pub async fn cleanup(&self) {
+        let mut span = self.start_span("tracker.cleanup");
+        let now = std::time::Instant::now();
+        let mut peer_timeout = self.get_config().get_tracker_config().get_peer_timeout();
+        // freshness is judged by the configured timeout, even while reaping aggressively.
+        let health_timeout = peer_timeout;
+        let mut empty_torrent_grace = self.get_config().get_tracker_config().get_empty_torrent_grace();
+        let mode = self.get_mode();
+
+        self.cleanup_requested.store(false, Ordering::Relaxed);
+        self.bans.remove_expired(unix_now());
+        let aggressive = self.is_over_memory_limit();
+        if let Some(ref mut span) = span {
+            span.set_attribute("udpt.aggressive", aggressive);
+            span.set_int_attribute("udpt.torrents", self.database.len() as u64);
+        }
+        if aggressive {
+            warn!(
+                "memory usage ({} bytes) is over the soft limit, reaping aggressively.",
+                self.get_memory_usage()
+            );
+            peer_timeout /= 4;
+            empty_torrent_grace = std::time::Duration::from_secs(0);
+        }
+
+        let mut removed = Vec::new();
+        let mut changed = Vec::new();
+        let is_watched = self.swarm_changes.receiver_count() > 0;
+
+        self.database
+            .retain(|k, v| {
+                // timed-out peers..
+                let before = v.peers.len();
+                v.reap_peers(now, peer_timeout);
+                if is_watched && v.peers.len() != before {
+                    changed.push(k.clone());
+                }
+                v.health = Some(SwarmHealth::compute(v, now, health_timeout));
+
+                if !v.peers.is_empty() {
+                    v.empty_since = None;
+                    return true;
+                }
+                let empty_since = *v.empty_since.get_or_insert(now);
+
+                if mode == TrackerMode::DynamicMode {
+                    // peer-less torrents.. linked torrents are kept, their links were set up by an admin.
+                    let is_pinned = v.is_flagged() || !v.aliases.is_empty();
+                    if !is_pinned && now.saturating_duration_since(empty_since) >= empty_torrent_grace {
+                        removed.push(k.clone());
+                        return false;
+                    }
+                }
+                true
+            })
+            .await;
+
+        self.mark_dirty(removed.iter());
+        self.notify_removed(&removed);
+        for info_hash in changed.iter() {
+            self.swarm_changed(info_hash);
+        }
+
+        if aggressive && mode == TrackerMode::DynamicMode && self.is_over_memory_limit() {
+            let batch = (self.database.len() / 10).max(1);
+            let removed = self.database.evict_oldest(batch).await;
+            self.mark_dirty(removed.iter());
+            self.notify_removed(&removed);
+            warn!(
+                "evicted up to {} torrents, memory usage is now {} bytes.",
+                batch,
+                self.get_memory_usage()
+            );
+        }
+    }
+
+    pub async fn periodic_task(&self, db_path: Option<&str>) {
+        // cleanup db
+        self.cleanup().await;
+
+        if let Some(db_path) = db_path {
+            self.save_database_file(db_path).await;
+        }
+    }
+
+    /// Writes a full scrape of all unflagged torrents to a temporary file, which then replaces `path`.
+    pub async fn save_full_scrape(&self, path: &str) {
+        let mut span = self.start_span("tracker.save_full_scrape");
+        let mut torrents = Vec::with_capacity(self.database.len());
+        self.database
+            .for_each(0, usize::max_value(), |info_hash, entry| {
+                if entry.is_flagged() {
+                    return;
+                }
+                let (seeders, completed, leechers) = entry.get_stats();
+                torrents.push(ScrapeEntry {
+                    info_hash: info_hash.clone(),
+                    seeders,
+                    completed,
+                    leechers,
+                });
+            })
+            .await;
+
+        let tmp_path = format!("{}.tmp", path);
+        let res = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            crate::fullscrape::write_full_scrape(torrents, &mut file).await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&tmp_path, path).await
+        };
+        if let Err(err) = res.await {
+            error!("failed to write full scrape to \"{}\". {}", path, err);
+            if let Some(ref mut span) = span {
+                span.set_error(err);
+            }
+        }
+    }
+
+    /// Saves the database to a temporary file, which then replaces `db_path`.
+    /// A crash while saving leaves the previous database intact.
+    pub async fn save_database_file(&self, db_path: &str) {
+        let _saving = self.save_lock.lock().await;
+        let started = std::time::Instant::now();
+        let _span = self.start_span("tracker.save_database");
+        self.write_database_file(db_path).await;
+        self.log_if_slow("save_database", &db_path, started);
+    }
+
+    async fn write_database_file(&self, db_path: &str) {
+        if self.sqlite.is_some() {
+            // torrents are written to sqlite incrementally.
+            self.flush_sqlite().await;
+            return;
+        }
+
+        if let Some(ref journal) = self.journal {
+            if let Err(err) = journal.rotate().await {
+                error!("failed to rotate journal, not saving database. {}", err);
+                return;
+            }
+        }
+
+        // save journal db.
+        let mut journal_path = std::path::PathBuf::from(db_path);
+
+        let mut filename = String::from(journal_path.file_name().unwrap().to_str().unwrap());
+        filename.push_str("-journal");
+
+        journal_path.set_file_name(filename.as_str());
+        let jp_str = journal_path.as_path().to_str().unwrap();
+
+        // scope to make sure backup file is dropped/closed.
+        {
+            let mut file = match tokio::fs::File::create(jp_str).await {
+                Err(err) => {
+                    error!("failed to open file '{}': {}", db_path, err);
+                    return;
+                }
+                Ok(v) => v,
+            };
+            trace!("writing database to {}", jp_str);
+            if let Err(err) = self.save_database(&mut file).await {
+                error!("failed saving database. {}", err);
+                return;
+            }
+            if let Err(err) = file.sync_all().await {
+                error!("failed to sync database to disk. {}", err);
+                return;
+            }
+        }
+
+        // overwrite previous db
+        trace!("renaming '{}' to '{}'", jp_str, db_path);
+        if let Err(err) = tokio::fs::rename(jp_str, db_path).await {
+            error!("failed to move db backup. {}", err);
+            return;
+        }
+
+        if let Some(ref journal) = self.journal {
+            journal.remove_previous().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_sync<T: Sync>() {}
+    fn is_send<T: Send>() {}
+
+    fn config_with_tracker(tracker_section: &str) -> Arc<Configuration> {
+        let cfg = format!(
+            "mode = \"dynamic\"\n[udp]\nbind_address = \"0.0.0.0:6969\"\nannounce_interval = 120\n[tracker]\n{}\n",
+            tracker_section
+        );
+        Arc::new(Configuration::load(cfg.as_bytes()).expect("invalid test configuration"))
+    }
+
+    #[test]
+    fn tracker_send() {
+        is_send::<TorrentTracker>();
+    }
+
+    #[test]
+    fn tracker_sync() {
+        is_sync::<TorrentTracker>();
+    }
+
+    #[test]
+    fn test_reap_peers() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        entry.update_peer(PeerId::from_array(&[1u8; 20]), &addr, 10, 0, 0, Events::Started, &policy);
+        entry.update_peer(PeerId::from_array(&[2u8; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+        assert_eq!(entry.get_stats(), (1, 0, 1));
+
+        let timeout = std::time::Duration::from_secs(60);
+        entry.reap_peers(std::time::Instant::now(), timeout);
+        assert_eq!(entry.get_stats(), (1, 0, 1));
+
+        entry.reap_peers(std::time::Instant::now() + timeout * 2, timeout);
+        assert_eq!(entry.get_stats(), (0, 0, 0));
+
+        // both peers joined, then timed out.
+        let (joins, departures) = entry.get_churn();
+        assert!((joins - 2.0).abs() < 0.01, "joins were {}", joins);
+        assert!((departures - 2.0).abs() < 0.01, "departures were {}", departures);
+    }
+
+    #[test]
+    fn test_seeder_policy() {
+        let mut entry = TorrentEntry::new();
+        let policy: SeederPolicy = serde_json::from_str(r#"{"confirmations": 2, "partial_threshold": 0.99}"#).unwrap();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let (a, b) = (PeerId::from_array(&[1u8; 20]), PeerId::from_array(&[2u8; 20]));
+
+        entry.update_peer(a, &addr, 10, 0, 0, Events::Started, &policy);
+        assert_eq!(entry.get_stats(), (0, 0, 1));
+        entry.update_peer(a, &addr, 10, 0, 0, Events::None, &policy);
+        assert_eq!(entry.get_stats(), (1, 0, 0));
+
+        // partial seeders count once they have enough of the torrent.
+        entry.update_peer(b, &addr, 10, 995, 5, Events::Started, &policy);
+        entry.update_peer(b, &addr, 10, 995, 5, Events::None, &policy);
+        assert_eq!(entry.get_stats(), (2, 0, 0));
+
+        // started downloading again, confirmations start over.
+        entry.update_peer(a, &addr, 10, 0, 100, Events::None, &policy);
+        entry.update_peer(a, &addr, 10, 0, 0, Events::None, &policy);
+        assert_eq!(entry.get_stats(), (1, 0, 1));
+        entry.reap_peers(
+            std::time::Instant::now() + std::time::Duration::from_secs(120),
+            Default::default(),
+        );
+        assert_eq!(entry.get_stats(), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_countries() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "203.0.113.7:6881".parse().unwrap();
+        let de = CountryCode::parse("DE");
+        for i in 1..=3u8 {
+            entry.update_peer(PeerId::from_array(&[i; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+            entry.peers.get_mut(PeerId::from_array(&[i; 20])).unwrap().country = de;
+        }
+        entry.update_peer(PeerId::from_array(&[4; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+        assert_eq!(serde_json::to_string(&entry.get_countries()).unwrap(), r#"{"DE":3}"#);
+
+        // kept while the peer announces from the same IP.
+        let peer_id = PeerId::from_array(&[1; 20]);
+        entry.update_peer(peer_id, &addr, 0, 0, 50, Events::None, &policy);
+        assert_eq!(entry.peers[peer_id].country, de);
+        entry.update_peer(
+            peer_id,
+            &"198.51.100.1:6881".parse().unwrap(),
+            0,
+            0,
+            50,
+            Events::None,
+            &policy,
+        );
+        assert_eq!(entry.peers[peer_id].country, None);
+    }
+
+    #[test]
+    fn test_swarm_health() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let now = std::time::Instant::now();
+        let timeout = std::time::Duration::from_secs(3600);
+        let health = SwarmHealth::compute(&entry, now, timeout);
+        assert_eq!((health.score, health.status), (0, HealthStatus::Dying));
+
+        // a seeder & a leecher on distinct hosts that just announced.
+        let (a, b) = (PeerId::from_array(&[1u8; 20]), PeerId::from_array(&[2u8; 20]));
+        entry.update_peer(a, &"203.0.113.1:6881".parse().unwrap(), 10, 0, 0, Events::Started, &policy);
+        entry.update_peer(b, &"203.0.113.2:6881".parse().unwrap(), 0, 0, 100, Events::Started, &policy);
+        let health = SwarmHealth::compute(&entry, std::time::Instant::now(), timeout);
+        assert_eq!((health.score, health.status), (100, HealthStatus::Healthy));
+
+        // both peers behind one host, announcing half a timeout ago, and no seeder left.
+        entry.update_peer(a, &"203.0.113.2:6882".parse().unwrap(), 10, 0, 100, Events::None, &policy);
+        let health = SwarmHealth::compute(&entry, std::time::Instant::now() + timeout / 2, timeout);
+        assert_eq!((health.seeds, health.diversity), (0.0, 0.5));
+        assert!((health.freshness - 0.5).abs() < 0.01, "freshness was {}", health.freshness);
+        assert_eq!((health.score, health.status), (40, HealthStatus::Degraded));
+    }
+
+    #[test]
+    fn test_activity_timestamps() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        assert!(entry.first_seen.is_some());
+        assert_eq!(entry.get_last_activity(), None);
+
+        entry.update_peer(PeerId::from_array(&[1u8; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+        assert!(entry.get_last_activity() >= entry.first_seen);
+
+        // persisted, torrents of older databases have neither.
+        let restored: TorrentEntry = serde_json::from_str(&serde_json::to_string(&entry).unwrap()).unwrap();
+        assert_eq!(
+            (restored.first_seen, restored.get_last_activity()),
+            (entry.first_seen, entry.get_last_activity())
+        );
+        let old: TorrentEntry = serde_json::from_str(r#"{"is_flagged": false, "completed": 1}"#).unwrap();
+        assert_eq!((old.first_seen, old.get_last_activity()), (None, None));
+
+        // merged torrents keep the earliest first sighting & the latest announce.
+        let mut other = TorrentEntry::new();
+        other.first_seen = Some(1);
+        other.last_activity = Some(2);
+        let last_activity = entry.get_last_activity();
+        entry.merge(other);
+        assert_eq!((entry.first_seen, entry.get_last_activity()), (Some(1), last_activity));
+    }
+
+    #[test]
+    fn test_client_version() {
+        let mut id = *b"-qB4250-abcdefghijkl";
+        assert_eq!(PeerId::from_array(&id).get_client_name(), Some("qBittorrent"));
+        assert_eq!(PeerId::from_array(&id).get_client_version().as_deref(), Some("4.2.5.0"));
+
+        id[..8].copy_from_slice(b"M4-3-6--");
+        assert_eq!(PeerId::from_array(&id).get_client_version().as_deref(), Some("4.3.6"));
+
+        id[..8].copy_from_slice(b"XXXXXXXX");
+        assert_eq!(PeerId::from_array(&id).get_client_version(), None);
+    }
+
+    #[test]
+    fn test_decaying_counter() {
+        let start = std::time::Instant::now();
+        let mut counter = DecayingCounter::default();
+        assert_eq!(counter.get(start), 0.0);
+
+        // a steady rate of one event per second should settle near 60 per minute.
+        for i in 0..600 {
+            counter.add(start + std::time::Duration::from_secs(i), 1.0);
+        }
+        let rate = counter.get(start + std::time::Duration::from_secs(600));
+        assert!(rate > 55.0 && rate < 65.0, "rate was {}", rate);
+
+        let rate = counter.get(start + std::time::Duration::from_secs(1200));
+        assert!(rate < 1.0, "rate was {}", rate);
+    }
+
+    #[test]
+    fn test_peer_deltas() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer_id = PeerId::from_array(&[1u8; 20]);
 
-        for (k, v) in db.iter_mut() {
-            // timed-out peers..
-            {
-                let mut peers_to_remove = Vec::new();
-                let torrent_peers = &mut v.peers;
+        entry.update_peer(peer_id, &addr, 100, 1000, 5000, Events::Started, &policy);
+        entry.update_peer(peer_id, &addr, 150, 3000, 3000, Events::None, &policy);
+        let peer = &entry.peers[peer_id];
+        assert_eq!((peer.uploaded_delta, peer.downloaded_delta), (50, 2000));
 
-                for (peer_id, state) in torrent_peers.iter() {
-                    if state.updated.elapsed() > TWO_HOURS {
-                        // over 2 hours past since last update...
-                        peers_to_remove.push(*peer_id);
-                    }
-                }
+        // client restarted, counters start over.
+        entry.update_peer(peer_id, &addr, 10, 20, 3000, Events::Started, &policy);
+        let peer = &entry.peers[peer_id];
+        assert_eq!((peer.uploaded_delta, peer.downloaded_delta), (10, 20));
+    }
 
-                for peer_id in peers_to_remove.iter() {
-                    torrent_peers.remove(peer_id);
-                }
-            }
+    #[test]
+    fn test_completion_history() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        for i in 0..5u8 {
+            let raw_id = [i; 20];
+            let peer_id = PeerId::from_array(&raw_id);
+            entry.update_peer(peer_id, &addr, 0, 0, 10, Events::Started, &policy);
+            assert!(entry.update_peer(peer_id, &addr, 10, 10, 0, Events::Complete, &policy));
+            assert!(!entry.update_peer(peer_id, &addr, 10, 10, 0, Events::Complete, &policy));
+            entry.record_completion(3);
+        }
+
+        assert_eq!(entry.completed, 5);
+        assert_eq!(entry.completion_history.len(), 3);
+    }
+
+    #[test]
+    fn test_evict_peers() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        for i in 1..=5u8 {
+            entry.update_peer(PeerId::from_array(&[i; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        entry.evict_peers(3);
+        assert_eq!(entry.get_peer_count(), 3);
 
-            if self.mode == TrackerMode::DynamicMode {
-                // peer-less torrents..
-                if v.peers.len() == 0 && !v.is_flagged() {
-                    torrents_to_remove.push(k.clone());
+        let remaining: Vec<_> = entry.get_peers_iter().map(|(peer_id, _)| *peer_id).collect();
+        let expected: Vec<_> = (3..=5u8).map(|i| *PeerId::from_array(&[i; 20])).collect();
+        assert!(remaining == expected);
+
+        // swarms at their cap evict 1% at once.
+        let mut entry = TorrentEntry::new();
+        for i in 0..=200u32 {
+            let mut id = [0u8; 20];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            entry.update_peer(PeerId::from_array(&id), &addr, 0, 0, 100, Events::Started, &policy);
+            entry.evict_peers(200);
+        }
+        assert_eq!(entry.get_peer_count(), 199);
+    }
+
+    #[test]
+    fn test_compact_peers() {
+        let mut entry = TorrentEntry::new();
+        let policy = SeederPolicy::default();
+        for i in 1..=40u8 {
+            let addr = std::net::SocketAddr::from(([10, 0, 0, i], 6881));
+            entry.update_peer(PeerId::from_array(&[i; 20]), &addr, 0, 0, 100, Events::Started, &policy);
+        }
+        let v6: std::net::SocketAddr = "[::1]:6881".parse().unwrap();
+        entry.update_peer(PeerId::from_array(&[0xff; 20]), &v6, 0, 0, 100, Events::Started, &policy);
+
+        let own = std::net::SocketAddr::from(([10, 0, 0, 1], 6881));
+        let peers = entry.get_compact_peers(|_| true);
+        let mut response = Vec::new();
+        assert_eq!(peers.write_to(&mut response, &own, 74, false), 39);
+        assert_eq!(&response[..6], &[10, 0, 0, 2, 0x1a, 0xe1]);
+        response.clear();
+        assert_eq!(peers.write_to(&mut response, &own, 74, true), 40);
+        assert_eq!(&response[..6], &[10, 0, 0, 1, 0x1a, 0xe1]);
+        assert!(peers.contains(&v6));
+
+        // cached until the swarm changes.
+        assert!(Arc::ptr_eq(&peers, &entry.get_compact_peers(|_| true)));
+        entry.update_peer(PeerId::from_array(&[1; 20]), &own, 10, 0, 90, Events::None, &policy);
+        assert!(Arc::ptr_eq(&peers, &entry.get_compact_peers(|_| true)));
+        entry.update_peer(PeerId::from_array(&[41; 20]), &own, 0, 0, 100, Events::Started, &policy);
+        assert!(!Arc::ptr_eq(&peers, &entry.get_compact_peers(|_| true)));
+    }
+
+    #[tokio::test]
+    async fn test_scrub_unroutable() {
+        let ih: InfoHash = [8u8; 20].into();
+        let lan: std::net::SocketAddr = "192.168.1.6:6881".parse().unwrap();
+        let public: std::net::SocketAddr = "203.0.113.1:6881".parse().unwrap();
+        let client: std::net::SocketAddr = "203.0.113.2:6881".parse().unwrap();
+
+        for (section, is_lan_handed_out) in [("", false), ("unroutable_exceptions = [\"192.168.0.0/16\"]", true)].iter() {
+            let tracker = TorrentTracker::new(config_with_tracker(section));
+            let mut stats = None;
+            for (i, addr) in [(1u8, lan), (2, public), (3, client)].iter() {
+                let id = [*i; 20];
+                let peer_id = PeerId::from_array(&id);
+                stats = Some(
+                    tracker
+                        .update_torrent_and_get_stats(&ih, peer_id, addr, 0, 0, 10, Events::Started)
+                        .await,
+                );
+            }
+            // as answered to the last peer.
+            match stats {
+                Some(TorrentStats::Stats { peers, .. }) => {
+                    assert!(peers.contains(&public));
+                    assert_eq!(peers.contains(&lan), *is_lan_handed_out);
                 }
+                _ => panic!("torrent wasn't tracked"),
             }
+            // still counted.
+            assert_eq!(tracker.get_peer_count(), 3);
         }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_torrents() {
+        let ih: InfoHash = [9u8; 20].into();
+        let tracker = TorrentTracker::new(config_with_tracker(""));
+        tracker.set_mode(TrackerMode::StaticMode);
+        let addr: std::net::SocketAddr = "203.0.113.1:6881".parse().unwrap();
+        let peer_id = PeerId::from_array(&[1u8; 20]);
+        let announce = || tracker.update_torrent_and_get_stats(&ih, peer_id, &addr, 0, 0, 10, Events::Started);
 
-        for info_hash in torrents_to_remove {
-            db.remove(&info_hash);
+        assert!(matches!(announce().await, TorrentStats::TorrentNotRegistered));
+        let ttl = std::time::Duration::from_secs(30);
+        assert!(tracker.database.unknown.contains(&ih, ttl, std::time::Instant::now()));
+        assert!(matches!(announce().await, TorrentStats::TorrentNotRegistered));
+
+        // registering it takes effect right away.
+        tracker.add_torrent(&ih).await.unwrap();
+        assert!(matches!(announce().await, TorrentStats::Stats { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_announces() {
+        let ih: InfoHash = [7u8; 20].into();
+        let tracker = TorrentTracker::new(config_with_tracker("duplicate_announce_window = 60"));
+        let addr: std::net::SocketAddr = "203.0.113.1:6881".parse().unwrap();
+        let peer_id = PeerId::from_array(&[1u8; 20]);
+        let announce = |left, event| tracker.update_torrent_and_get_stats(&ih, peer_id, &addr, 1, 0, left, event);
+
+        assert!(matches!(announce(10, Events::Started).await, TorrentStats::Stats { .. }));
+        assert!(matches!(announce(10, Events::None).await, TorrentStats::Duplicate {
+            leechers: 1,
+            ..
+        }));
+        // completing is never swallowed.
+        assert!(matches!(announce(0, Events::Complete).await, TorrentStats::Stats {
+            seeders: 1,
+            ..
+        }));
+        assert!(matches!(announce(0, Events::None).await, TorrentStats::Duplicate {
+            seeders: 1,
+            complete: 1,
+            ..
+        }));
+        assert_eq!(tracker.get_duplicate_announces(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_empty_torrent_grace() {
+        let ih: InfoHash = [0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0].into();
+
+        let tracker = TorrentTracker::new(config_with_tracker("empty_torrent_grace = 3600"));
+        tracker.add_torrent(&ih).await.expect("failed to add torrent");
+        tracker.cleanup().await;
+        assert!(tracker.with_torrent(&ih, |_| ()).await.is_some());
+
+        let tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        tracker.add_torrent(&ih).await.expect("failed to add torrent");
+        tracker.cleanup().await;
+        assert!(tracker.with_torrent(&ih, |_| ()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_webhooks() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = format!(
+            "urls = [\"http://{}/udpt-events\"]\nmax_retries = 0",
+            listener.local_addr().unwrap()
+        );
+        let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        tracker.enable_webhooks(&toml::from_str(&cfg).unwrap());
+        let ih: InfoHash = [7u8; 20].into();
+        tracker.add_torrent(&ih).await.expect("failed to add torrent");
+
+        // without sqlite, removed torrents are still announced.
+        tracker.cleanup().await;
+        assert!(tracker.with_torrent(&ih, |_| ()).await.is_none());
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !request.ends_with(b"]}") {
+                let read = stream.read(&mut buf).await.unwrap();
+                assert!(read > 0, "the webhook request was cut short");
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        tracker.flush_webhooks().await;
+        let request = received.await.unwrap();
+        assert!(request.contains(r#""event":"torrent_removed","info_hash":"0707070707070707070707070707070707070707""#));
+    }
+
+    #[tokio::test]
+    async fn test_list_torrents_across_shards() {
+        let tracker = TorrentTracker::new(config_with_tracker("db_shards = 4"));
+        for i in 0..10u8 {
+            tracker.add_torrent(&[i; 20].into()).await.expect("failed to add torrent");
         }
+
+        let mut all = Vec::new();
+        tracker.list_torrents(0, 100, |ih, _| all.push(ih.clone())).await;
+        assert_eq!(all.len(), 10);
+
+        let mut page = Vec::new();
+        tracker.list_torrents(3, 4, |ih, _| page.push(ih.clone())).await;
+        assert!(page == all[3..7]);
     }
 
-    pub async fn periodic_task(&self, db_path: &str) {
-        // cleanup db
-        self.cleanup().await;
+    #[tokio::test]
+    async fn test_diagnostics() {
+        let tracker = TorrentTracker::new(config_with_tracker("db_shards = 2"));
+        tracker.add_torrent(&[1; 20].into()).await.expect("failed to add torrent");
+        tracker.get_tasks().register("cleanup", std::time::Duration::from_secs(600));
+        tracker.get_tasks().register("save", std::time::Duration::from_secs(0));
+        drop(tracker.get_tasks().start("cleanup"));
+        let _run = tracker.get_tasks().start("save");
+        assert!(!tracker.get_readiness().is_ready());
+        tracker.get_readiness().update(vec![
+            ListenerProbe {
+                address: "127.0.0.1:6969".parse().unwrap(),
+                rtt_ms: Some(0.5),
+                error: None,
+            },
+            ListenerProbe {
+                address: "127.0.0.1:6970".parse().unwrap(),
+                rtt_ms: None,
+                error: Some(String::from("timed out")),
+            },
+        ]);
+        assert!(!tracker.get_readiness().is_ready());
 
-        // save journal db.
-        let mut journal_path = std::path::PathBuf::from(db_path);
+        let dump = tracker.diagnostics(std::time::Duration::from_secs(5));
+        assert!(dump.contains("uptime: 5s"));
+        assert!(dump.contains("torrents: 1, peers: 0, seeders: 0"));
+        assert!(dump.contains("shard 0: ") && dump.contains("shard 1: ") && !dump.contains("shard 2: "));
+        assert!(dump.contains("task cleanup: every 600s, 1 runs, last finished 0s ago"));
+        assert!(dump.contains("task save: every 0s, 0 runs, running for 0s"));
+        assert!(dump.contains("udp 127.0.0.1:6969: answered in 0.5ms"));
+        assert!(dump.contains("udp 127.0.0.1:6970: timed out"));
+    }
 
-        let mut filename = String::from(journal_path.file_name().unwrap().to_str().unwrap());
-        filename.push_str("-journal");
+    #[tokio::test]
+    async fn test_concurrent_storage() {
+        let tracker = TorrentTracker::new(config_with_tracker("storage = \"concurrent\""));
+        let ih: InfoHash = [7u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
 
-        journal_path.set_file_name(filename.as_str());
-        let jp_str = journal_path.as_path().to_str().unwrap();
+        tracker.add_torrent(&ih).await.expect("failed to add torrent");
+        assert!(tracker.add_torrent(&ih).await.is_err());
 
-        // scope to make sure backup file is dropped/closed.
+        match tracker
+            .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 0, 0, 10, Events::Started)
+            .await
         {
-            let mut file = match tokio::fs::File::create(jp_str).await {
-                Err(err) => {
-                    error!("failed to open file '{}': {}", db_path, err);
-                    return;
+            TorrentStats::Stats { seeders, leechers, .. } => assert_eq!((seeders, leechers), (0, 1)),
+            _ => panic!("expected stats"),
+        }
+
+        assert!(tracker.set_torrent_flag(&ih, true).await);
+        assert!(tracker.remove_torrent(&ih, false).await.is_err());
+        assert!(tracker.remove_torrent(&ih, true).await.is_ok());
+        assert!(tracker.with_torrent(&ih, |_| ()).await.is_none());
+    }
+
+    /// A store that keeps everything behind a single mutex.
+    struct SingleLockStore(std::sync::Mutex<std::collections::HashMap<InfoHash, TorrentEntry>>);
+
+    impl TorrentStore for SingleLockStore {
+        fn insert(&self, info_hash: InfoHash, entry: TorrentEntry) -> futures::future::BoxFuture<'_, Option<TorrentEntry>> {
+            Box::pin(async move { self.0.lock().unwrap().insert(info_hash, entry) })
+        }
+
+        fn get<'a>(
+            &'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&TorrentEntry) + Send),
+        ) -> futures::future::BoxFuture<'a, bool> {
+            Box::pin(async move { self.0.lock().unwrap().get(info_hash).map(f).is_some() })
+        }
+
+        fn get_mut<'a>(
+            &'a self, info_hash: &'a InfoHash, f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+        ) -> futures::future::BoxFuture<'a, bool> {
+            Box::pin(async move { self.0.lock().unwrap().get_mut(info_hash).map(f).is_some() })
+        }
+
+        fn get_or_insert_with<'a>(
+            &'a self, info_hash: &'a InfoHash, create: &'a mut (dyn FnMut() -> Option<TorrentEntry> + Send),
+            f: &'a mut (dyn FnMut(&mut TorrentEntry) + Send),
+        ) -> futures::future::BoxFuture<'a, bool> {
+            Box::pin(async move {
+                let mut map = self.0.lock().unwrap();
+                if !map.contains_key(info_hash) {
+                    match create() {
+                        Some(entry) => map.insert(info_hash.clone(), entry),
+                        None => return false,
+                    };
                 }
-                Ok(v) => v,
-            };
-            trace!("writing database to {}", jp_str);
-            if let Err(err) = self.save_database(&mut file).await {
-                error!("failed saving database. {}", err);
-                return;
-            }
+                map.get_mut(info_hash).map(f).is_some()
+            })
         }
 
-        // overwrite previous db
-        trace!("renaming '{}' to '{}'", jp_str, db_path);
-        if let Err(err) = tokio::fs::rename(jp_str, db_path).await {
-            error!("failed to move db backup. {}", err);
+        fn remove_if<'a>(
+            &'a self, info_hash: &'a InfoHash, predicate: &'a mut (dyn FnMut(&TorrentEntry) -> bool + Send),
+        ) -> futures::future::BoxFuture<'a, Option<TorrentEntry>> {
+            Box::pin(async move {
+                let mut map = self.0.lock().unwrap();
+                match map.get(info_hash).map(predicate) {
+                    Some(true) => map.remove(info_hash),
+                    _ => None,
+                }
+            })
+        }
+
+        fn retain<'a>(
+            &'a self, f: &'a mut (dyn FnMut(&InfoHash, &mut TorrentEntry) -> bool + Send),
+        ) -> futures::future::BoxFuture<'a, ()> {
+            Box::pin(async move { self.0.lock().unwrap().retain(|k, v| f(k, v)) })
+        }
+
+        fn for_each<'a>(
+            &'a self, offset: usize, limit: usize, f: &'a mut (dyn FnMut(&InfoHash, &TorrentEntry) + Send),
+        ) -> futures::future::BoxFuture<'a, ()> {
+            Box::pin(async move {
+                for (k, v) in self.0.lock().unwrap().iter().skip(offset).take(limit) {
+                    f(k, v);
+                }
+            })
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_custom_store() {
+        let store = SingleLockStore(std::sync::Mutex::new(std::collections::HashMap::new()));
+        let tracker = TorrentTracker::with_store(Arc::new(Configuration::default()), Box::new(store));
+        let ih: InfoHash = [7u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
 
-    fn is_sync<T: Sync>() {}
-    fn is_send<T: Send>() {}
+        tracker
+            .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 10, 0, 0, Events::Complete)
+            .await;
+        assert_eq!((tracker.database.len(), tracker.database.peer_count()), (1, 1));
+        assert_eq!(tracker.with_torrent(&ih, |entry| entry.get_stats()).await, Some((1, 1, 0)));
+
+        let mut buf = Vec::new();
+        let rows = write_database(&mut buf, tracker.database.snapshot().await).await;
+        assert_eq!(rows.unwrap(), 1);
+
+        assert!(tracker.remove_torrent(&ih, false).await.is_ok());
+        assert_eq!((tracker.database.len(), tracker.database.peer_count()), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_journal_replay() {
+        let dir = std::env::temp_dir().join(format!("udpt-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db.json.bz2");
+        let db_path = db_path.to_str().unwrap();
+        let ih: InfoHash = [5u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        tracker.open_journal(db_path).await.unwrap();
+        // created by an announce, only its flag is journaled.
+        tracker
+            .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 0, 0, 1, Events::Started)
+            .await;
+        assert!(tracker.set_torrent_flag(&ih, true).await);
+
+        // restarted before the next snapshot.
+        let mut restarted = TorrentTracker::new(Arc::new(Configuration::default()));
+        assert_eq!(restarted.open_journal(db_path).await.unwrap(), 1);
+        assert_eq!(restarted.with_torrent(&ih, |t| t.is_flagged()).await, Some(true));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_store() {
+        let tracker = TorrentTracker::with_store(Arc::new(Configuration::default()), Box::new(ConcurrentStore::new()));
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        for i in 0..100u8 {
+            let ih: InfoHash = [i; 20].into();
+            tracker
+                .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 0, 0, 1, Events::Started)
+                .await;
+        }
+        assert_eq!((tracker.database.len(), tracker.database.peer_count()), (100, 100));
+
+        // pages follow the infohashes, whatever order the hashmap is in.
+        let mut listed = Vec::new();
+        for page in 0..4 {
+            tracker
+                .list_torrents(page * 30, 30, |info_hash, _| listed.push(info_hash.clone()))
+                .await;
+        }
+        let expected: Vec<InfoHash> = (0..100u8).map(|i| [i; 20].into()).collect();
+        assert_eq!(listed, expected);
+    }
 
     #[test]
-    fn tracker_send() {
-        is_send::<TorrentTracker>();
+    fn test_parse_infohash_list() {
+        let list = parse_infohash_list(
+            "# comment\n\n0123456789abcdef0123456789abcdef01234567\n  not-a-hash\n\
+             0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef\n",
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].as_bytes().len(), 20);
+        assert_eq!(list[1].as_bytes().len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_import_torrents() {
+        let list = parse_state_dump(
+            "0123456789abcdef0123456789abcdef01234567\n\
+             1123456789abcdef0123456789abcdef01234567:42:1337\n\
+             2123456789abcdef0123456789abcdef01234567:not-a-number:0\n",
+        );
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].1, 42);
+
+        let tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        assert_eq!(tracker.import_torrents(list.clone()).await, 2);
+        assert_eq!(tracker.with_torrent(&list[1].0, |entry| entry.completed).await, Some(42));
+        // importing again changes nothing.
+        assert_eq!(tracker.import_torrents(list).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_gossip() {
+        let receiver = Gossip::bind("127.0.0.1:0", &[], "secret").await.unwrap();
+        let cfg = format!(
+            "mode = \"dynamic\"\n[udp]\nbind_address = \"0.0.0.0:6969\"\n[cluster]\nbind_address = \"127.0.0.1:0\"\n\
+             nodes = [\"{}\"]\nsecret = \"secret\"\nmax_queue = 15\n",
+            receiver.local_addr()
+        );
+        let cfg = Arc::new(Configuration::load(cfg.as_bytes()).unwrap());
+        let mut tracker = TorrentTracker::new(cfg.clone());
+        tracker.open_cluster(cfg.get_cluster_config().unwrap()).await.unwrap();
+
+        let ih: InfoHash = [1u8; 20].into();
+        for i in 0..40u8 {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 6881 + u16::from(i % 20)));
+            let peer_id = [i % 20; 20];
+            tracker
+                .update_torrent_and_get_stats(&ih, PeerId::from_array(&peer_id), &addr, 0, 0, 10, Events::None)
+                .await;
+        }
+        let addr = "127.0.0.1:7000".parse().unwrap();
+        let peer_id = PeerId::from_array(&[99u8; 20]);
+        tracker
+            .update_torrent_and_get_stats(&[2u8; 20].into(), peer_id, &addr, 0, 0, 10, Events::None)
+            .await;
+        // re-announces replace the queued announce, new peers are dropped once the queue is full.
+        assert_eq!(tracker.gossip_queue.lock().unwrap().len(), 15);
+        assert_eq!(tracker.gossip_dropped.load(Ordering::Relaxed), 11);
+
+        tracker.send_gossip().await;
+        let mut gossiped = 0;
+        while gossiped < 15 {
+            let payload = receiver.receive().await.unwrap();
+            assert!(payload.len() <= MAX_PAYLOAD_SIZE);
+            gossiped += bincode::deserialize::<Vec<GossipPeer>>(&payload).unwrap().len();
+        }
+        assert_eq!(gossiped, 15);
+    }
+
+    #[tokio::test]
+    async fn test_registry_snapshot() {
+        let primary = TorrentTracker::new(Arc::new(Configuration::default()));
+        primary.add_torrent(&[1u8; 20].into()).await.unwrap();
+        primary.add_torrent(&[2u8; 20].into()).await.unwrap();
+        primary.set_torrent_flag(&[2u8; 20].into(), true).await;
+        let ip: std::net::IpAddr = "203.0.113.7".parse().unwrap();
+        primary
+            .ban(BanTarget::Ip(ip), "admin".into(), Some(3600), None)
+            .await
+            .unwrap();
+        let snapshot = serde_json::to_vec(&primary.registry_snapshot().await).unwrap();
+
+        let cfg = "mode = \"static\"\n[udp]\nbind_address = \"0.0.0.0:6969\"\nannounce_interval = 120\n";
+        let replica = TorrentTracker::new(Arc::new(Configuration::load(cfg.as_bytes()).unwrap()));
+        replica.add_torrent(&[3u8; 20].into()).await.unwrap();
+
+        let snapshot: RegistrySnapshot = serde_json::from_slice(&snapshot).unwrap();
+        replica.apply_snapshot(snapshot).await;
+
+        assert_eq!(replica.with_torrent(&[1u8; 20].into(), |e| e.is_flagged()).await, Some(false));
+        assert_eq!(replica.with_torrent(&[2u8; 20].into(), |e| e.is_flagged()).await, Some(true));
+        assert_eq!(replica.with_torrent(&[3u8; 20].into(), |e| e.is_flagged()).await, None);
+        assert!(replica.is_banned(&ip, None));
+        assert_eq!(replica.get_bans(), primary.get_bans());
+    }
+
+    #[tokio::test]
+    async fn test_raft_control() {
+        let dir = std::env::temp_dir().join(format!("udpt-raft-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("raft.log");
+        let cfg = format!(
+            "mode = \"static\"\n[udp]\nbind_address = \"0.0.0.0:6969\"\nannounce_interval = 120\n\
+             [raft]\nid = 1\nbind_address = \"127.0.0.1:0\"\nnodes = []\nsecret = \"test\"\nlog_path = {:?}\n\
+             election_timeout_ms = 10\nheartbeat_interval_ms = 1\n",
+            log_path.to_str().unwrap()
+        );
+        let cfg = Configuration::load(cfg.as_bytes()).unwrap();
+        let ih: InfoHash = [9u8; 20].into();
+
+        // a single node elects itself & commits its actions right away.
+        let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        tracker.open_raft(cfg.get_raft_config().unwrap()).await.unwrap();
+        assert!(tracker
+            .control(ControlAction::RemoveUser { name: "nobody".into() })
+            .await
+            .is_err());
+        tokio::time::delay_for(std::time::Duration::from_millis(30)).await;
+        tracker.tick_raft().await;
+        let action = ControlAction::SetFlag {
+            info_hash: ih.clone(),
+            is_flagged: true,
+        };
+        assert_eq!(tracker.control(action).await, Ok(None));
+        assert_eq!(tracker.with_torrent(&ih, |t| t.is_flagged()).await, Some(true));
+        let ip: std::net::IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(tracker
+            .ban(BanTarget::Ip(ip), "admin".into(), None, None)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            tracker.control(ControlAction::LiftBan { id: 1000 }).await,
+            Err("no such ban".into())
+        );
+
+        // restarted, applied entries aren't applied again on top of the tracker's state.
+        let mut restarted = TorrentTracker::new(Arc::new(Configuration::default()));
+        restarted.open_raft(cfg.get_raft_config().unwrap()).await.unwrap();
+        tokio::time::delay_for(std::time::Duration::from_millis(30)).await;
+        restarted.tick_raft().await;
+        assert_eq!(restarted.with_torrent(&ih, |t| t.is_flagged()).await, None);
+        assert!(restarted.get_bans().is_empty());
+        let action = ControlAction::RemoveTorrent { info_hash: ih.clone() };
+        assert!(restarted.control(action).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blacklist() {
+        let tracker = TorrentTracker::new(config_with_tracker(
+            "blacklist = [\"0101010101010101010101010101010101010101\"]",
+        ));
+        let ih: InfoHash = [1u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        assert_eq!(tracker.load_blacklist().await.unwrap(), 1);
+        match tracker
+            .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 0, 0, 10, Events::Started)
+            .await
+        {
+            TorrentStats::TorrentFlagged => {}
+            _ => panic!("expected torrent to be flagged"),
+        }
+
+        // cleanup never drops flagged torrents.
+        tracker.cleanup().await;
+        assert_eq!(tracker.with_torrent(&ih, |t| t.is_flagged()).await, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_link_torrent() {
+        let tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        let v1: InfoHash = [1u8; 20].into();
+        let v2: InfoHash = [2u8; 32].into();
+        let truncated = InfoHash::from(&v2.as_bytes()[..20]);
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        tracker
+            .update_torrent_and_get_stats(&v1, PeerId::from_array(&[1u8; 20]), &addr, 10, 0, 0, Events::Started)
+            .await;
+        tracker
+            .update_torrent_and_get_stats(&truncated, PeerId::from_array(&[2u8; 20]), &addr, 0, 0, 10, Events::Started)
+            .await;
+        assert_eq!(tracker.get_torrent_count(), 2);
+
+        tracker.link_torrent(&v1, &truncated).await.expect("failed to link");
+        tracker.link_torrent(&v1, &v2).await.expect("failed to link");
+        assert!(tracker.link_torrent(&v2, &truncated).await.is_ok());
+        assert!(tracker.link_torrent(&[9u8; 20].into(), &truncated).await.is_err());
+        assert!(tracker.link_torrent(&v1, &v1).await.is_err());
+        assert_eq!(tracker.get_torrent_count(), 1);
+        assert_eq!(tracker.with_torrent(&truncated, |t| t.get_stats()).await, Some((1, 0, 1)));
+
+        // announcing on either hash lands in the same swarm.
+        tracker
+            .update_torrent_and_get_stats(&truncated, PeerId::from_array(&[3u8; 20]), &addr, 0, 0, 10, Events::Started)
+            .await;
+        assert_eq!(tracker.with_torrent(&v1, |t| t.get_peer_count()).await, Some(3));
+
+        // unlinked hashes start a swarm of their own, merged peers stay.
+        assert!(tracker.unlink_torrent(&v1, &v2).await.is_ok());
+        assert!(tracker.unlink_torrent(&v1, &v2).await.is_err());
+        assert!(tracker.with_torrent(&v2, |_| ()).await.is_none());
+        assert_eq!(
+            tracker.with_torrent(&v1, |t| t.get_aliases().to_vec()).await,
+            Some(vec![truncated.clone()])
+        );
+        assert_eq!(tracker.with_torrent(&v1, |t| t.get_peer_count()).await, Some(3));
+
+        assert!(tracker.remove_torrent(&truncated, false).await.is_ok());
+        assert!(tracker.with_torrent(&truncated, |_| ()).await.is_none());
+        assert!(tracker.add_torrent(&truncated).await.is_ok());
+
+        // flagged torrents stay flagged.
+        let flagged: InfoHash = [3u8; 20].into();
+        tracker.add_torrent(&flagged).await.unwrap();
+        tracker.set_torrent_flag(&flagged, true).await;
+        assert!(tracker.link_torrent(&truncated, &flagged).await.is_err());
+        assert_eq!(tracker.with_torrent(&flagged, |t| t.is_flagged()).await, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_peer_limit() {
+        let tracker = TorrentTracker::new(config_with_tracker(""));
+        tracker.set_peer_limit(Some(2));
+        let info_hash: InfoHash = [1u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        for i in 0..3u8 {
+            let peer_id = [i; 20];
+            let peer_id = PeerId::from_array(&peer_id);
+            match tracker
+                .update_torrent_and_get_stats(&info_hash, peer_id, &addr, 0, 0, 10, Events::Started)
+                .await
+            {
+                TorrentStats::Stats { leechers, .. } => assert_eq!(leechers, u32::from(i.min(1) + 1)),
+                _ => panic!("announce failed"),
+            }
+        }
+        assert_eq!((tracker.get_peer_count(), tracker.get_peers_refused()), (2, 1));
+
+        // tracked peers keep announcing.
+        let peer_id = PeerId::from_array(&[0u8; 20]);
+        tracker
+            .update_torrent_and_get_stats(&info_hash, peer_id, &addr, 1, 0, 0, Events::Complete)
+            .await;
+        assert_eq!((tracker.get_seeder_count(), tracker.get_peers_refused()), (1, 1));
+        assert!(tracker
+            .diagnostics(std::time::Duration::from_secs(1))
+            .contains("peer limit: 2, 1 new peers refused"));
+    }
+
+    #[tokio::test]
+    async fn test_reset_torrent_stats() {
+        let tracker = TorrentTracker::new(config_with_tracker(""));
+        let info_hash: InfoHash = [1u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let seed = |i: u8| {
+            let tracker = &tracker;
+            let info_hash = &info_hash;
+            async move {
+                let peer_id = [i; 20];
+                let peer_id = PeerId::from_array(&peer_id);
+                tracker
+                    .update_torrent_and_get_stats(info_hash, peer_id, &addr, 1, 0, 0, Events::Complete)
+                    .await;
+            }
+        };
+
+        seed(1).await;
+        seed(2).await;
+        assert_eq!((tracker.get_seeder_count(), tracker.get_completed_count()), (2, 2));
+        assert!(tracker.reset_torrent_stats(&info_hash).await);
+        assert_eq!(
+            tracker.with_torrent(&info_hash, TorrentEntry::get_stats).await,
+            Some((0, 0, 0))
+        );
+        assert_eq!(
+            (
+                tracker.get_peer_count(),
+                tracker.get_seeder_count(),
+                tracker.get_completed_count()
+            ),
+            (0, 0, 0)
+        );
+        assert!(!tracker.reset_torrent_stats(&[2u8; 20].into()).await);
+
+        // flagging drops the seeders along with the peers.
+        seed(1).await;
+        assert!(tracker.set_torrent_flag(&info_hash, true).await);
+        assert_eq!(
+            tracker.with_torrent(&info_hash, TorrentEntry::get_stats).await,
+            Some((0, 1, 0))
+        );
+        assert_eq!(tracker.get_seeder_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_swarm_change() {
+        let tracker = Arc::new(TorrentTracker::new(config_with_tracker("")));
+        let info_hash: InfoHash = [1u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer_id = PeerId::from_array(&[1u8; 20]);
+        let short = std::time::Duration::from_millis(20);
+        let long = std::time::Duration::from_secs(60);
+
+        assert_eq!(tracker.wait_for_swarm_change(&info_hash, None, short).await, None);
+        tracker
+            .update_torrent_and_get_stats(&info_hash, peer_id, &addr, 0, 0, 10, Events::Started)
+            .await;
+        assert_eq!(
+            tracker.wait_for_swarm_change(&info_hash, None, short).await,
+            Some(((0, 0, 1), false))
+        );
+        // stale stats are answered right away.
+        assert_eq!(
+            tracker.wait_for_swarm_change(&info_hash, Some((0, 0, 0)), long).await,
+            Some(((0, 0, 1), true))
+        );
+
+        let waiter = {
+            let tracker = tracker.clone();
+            let info_hash = info_hash.clone();
+            tokio::spawn(async move { tracker.wait_for_swarm_change(&info_hash, Some((0, 0, 1)), long).await })
+        };
+        tokio::time::delay_for(short).await;
+        tracker
+            .update_torrent_and_get_stats(&info_hash, peer_id, &addr, 1, 10, 0, Events::Complete)
+            .await;
+        assert_eq!(waiter.await.unwrap(), Some(((1, 1, 0), true)));
+    }
+
+    #[tokio::test]
+    async fn test_max_torrents() {
+        let tracker = TorrentTracker::new(config_with_tracker("max_torrents = 3"));
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer_id = PeerId::from_array(&[1u8; 20]);
+
+        for i in 0..4u8 {
+            tracker
+                .update_torrent_and_get_stats(&[i; 20].into(), peer_id, &addr, 0, 0, 10, Events::Started)
+                .await;
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        assert_eq!(tracker.get_torrent_count(), 3);
+        assert!(tracker.with_torrent(&[0u8; 20].into(), |_| ()).await.is_none());
+        assert!(tracker.with_torrent(&[3u8; 20].into(), |_| ()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_peer_accounting() {
+        let tracker = TorrentTracker::new(config_with_tracker("storage = \"concurrent\""));
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let ih: InfoHash = [1u8; 20].into();
+
+        for i in 0..3u8 {
+            tracker
+                .update_torrent_and_get_stats(&ih, PeerId::from_array(&[i; 20]), &addr, 0, 0, 10, Events::Started)
+                .await;
+        }
+        assert_eq!(tracker.database.peer_count(), 3);
+        assert!(tracker.get_memory_usage() > 0);
+
+        tracker.set_torrent_flag(&ih, true).await;
+        assert_eq!(tracker.database.peer_count(), 0);
+
+        tracker.remove_torrent(&ih, true).await.unwrap();
+        assert_eq!(tracker.get_torrent_count(), 0);
+        assert_eq!(tracker.get_memory_usage(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_summarize() {
+        let tracker = TorrentTracker::new(config_with_tracker("db_shards = 4"));
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        for i in 1..=12u8 {
+            for p in 0..i {
+                let (uploaded, left) = if p == 0 { (10, 0) } else { (0, 10) };
+                let (info_hash, peer_id) = ([i; 20].into(), [p; 20]);
+                let peer_id = PeerId::from_array(&peer_id);
+                tracker
+                    .update_torrent_and_get_stats(&info_hash, peer_id, &addr, uploaded, 0, left, Events::None)
+                    .await;
+            }
+        }
+        assert_eq!(tracker.database.peer_count(), 78);
+        assert_eq!(tracker.database.seeder_count(), 12);
+
+        tracker.summarize().await;
+        let top = tracker.top_torrents.read().unwrap().clone();
+        assert_eq!(top.len(), 10);
+        assert_eq!(top[0].info_hash, [12u8; 20].into());
+        assert_eq!(top[0].peers, 12);
+        assert_eq!(top[9].peers, 3);
+        assert_eq!(tracker.get_client_stats().peers, 78);
     }
 
     #[test]
-    fn tracker_sync() {
-        is_sync::<TorrentTracker>();
+    fn test_swarm_size_histogram() {
+        let mut histogram = SwarmSizeHistogram::default();
+        for peers in &[0, 1, 10, 11, 100, 5000, 20000] {
+            histogram.add(*peers);
+        }
+        assert_eq!(histogram.torrents, [1, 2, 2, 0, 1, 1]);
+
+        let json = serde_json::to_string(&histogram).unwrap();
+        assert!(json.starts_with(r#"[{"min_peers":0,"max_peers":0,"torrents":1},{"min_peers":1,"max_peers":10"#));
+        assert!(json.ends_with(r#"{"min_peers":10001,"max_peers":null,"torrents":1}]"#));
     }
 
     #[tokio::test]
     async fn test_save_db() {
-        let tracker = TorrentTracker::new(TrackerMode::DynamicMode);
+        let tracker = TorrentTracker::new(Arc::new(Configuration::default()));
         tracker
             .add_torrent(&[0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0].into())
             .await
@@ -648,6 +4838,106 @@ mod tests {
         assert!(cursor.position() > 0);
     }
 
+    #[tokio::test]
+    async fn test_load_db() {
+        let tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        let ih: InfoHash = [3u8; 20].into();
+        tracker.add_torrent(&ih).await.unwrap();
+        tracker.set_torrent_flag(&ih, true).await;
+
+        let mut saved = Vec::new();
+        tracker.save_database(&mut saved).await.unwrap();
+
+        let loaded = TorrentTracker::load_database(Arc::new(Configuration::default()), &mut saved.as_slice())
+            .await
+            .expect("failed to load saved database");
+        assert_eq!(loaded.with_torrent(&ih, |t| t.is_flagged()).await, Some(true));
+
+        // version 1 databases have no header & footer.
+        let legacy = compress(
+            b"{\"info_hash\":\"0303030303030303030303030303030303030303\",\
+            \"entry\":{\"is_flagged\":true,\"completed\":5}}\n",
+        )
+        .unwrap();
+        let loaded = TorrentTracker::load_database(Arc::new(Configuration::default()), &mut legacy.as_slice())
+            .await
+            .expect("failed to load legacy database");
+        assert_eq!(loaded.with_torrent(&ih, |t| t.get_stats()).await, Some((0, 5, 0)));
+
+        let corrupt = compress(
+            b"{\"version\":2}\n\
+            {\"info_hash\":\"0303030303030303030303030303030303030303\",\"entry\":{\"is_flagged\":true,\"completed\":5}}\n\
+            {\"rows\":1,\"crc32\":0}\n",
+        )
+        .unwrap();
+        assert!(
+            TorrentTracker::load_database(Arc::new(Configuration::default()), &mut corrupt.as_slice())
+                .await
+                .is_err()
+        );
+
+        let newer = compress(b"{\"version\":99}\n").unwrap();
+        assert!(
+            TorrentTracker::load_database(Arc::new(Configuration::default()), &mut newer.as_slice())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sharded_db() {
+        let rows: Vec<_> = (0..MIN_SHARD_ROWS as u32 * 2 + 10)
+            .map(|i| {
+                let mut id = [0u8; 20];
+                id[..4].copy_from_slice(&i.to_be_bytes());
+                (InfoHash::from(&id[..]), TorrentEntry::new())
+            })
+            .collect();
+
+        let mut saved = Vec::new();
+        assert_eq!(write_database(&mut saved, rows.clone()).await.unwrap(), rows.len());
+
+        let mut read = Vec::new();
+        let check = read_database(decompress_database(saved.as_slice()), |info_hash, _| {
+            read.push(info_hash);
+            async {}
+        })
+        .await
+        .unwrap();
+        assert!(check.problems.is_empty() && check.invalid_rows.is_empty());
+        assert_eq!((check.version, check.rows), (DATABASE_VERSION, rows.len()));
+        assert!(read.iter().zip(rows.iter()).all(|(a, (b, _))| a == b));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_sqlite() {
+        let path = std::env::temp_dir().join(format!("udpt-sqlite-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let ih: InfoHash = [4u8; 20].into();
+        let addr: std::net::SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        {
+            let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+            assert_eq!(tracker.open_sqlite(path).await.unwrap(), 0);
+            tracker.add_torrent(&ih).await.unwrap();
+            tracker
+                .update_torrent_and_get_stats(&ih, PeerId::from_array(&[1u8; 20]), &addr, 10, 10, 0, Events::Complete)
+                .await;
+            tracker.save_database_file(path).await;
+        }
+
+        let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        assert_eq!(tracker.open_sqlite(path).await.unwrap(), 1);
+        assert_eq!(tracker.with_torrent(&ih, |t| t.get_stats()).await, Some((0, 1, 0)));
+
+        assert!(tracker.remove_torrent(&ih, true).await.is_ok());
+        let mut tracker = TorrentTracker::new(Arc::new(Configuration::default()));
+        assert_eq!(tracker.open_sqlite(path).await.unwrap(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
     #[test]
     fn test_infohash_de() {
         use serde_json;
@@ -660,4 +4950,18 @@ mod tests {
 
         assert!(de_ih == ih);
     }
+
+    #[test]
+    fn test_infohash_v2() {
+        let hex = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let ih: InfoHash = hex.parse().unwrap();
+        assert_eq!(ih.as_bytes().len(), 32);
+        assert_eq!(ih.to_string(), hex);
+
+        let de_ih: InfoHash = serde_json::from_str(&serde_json::to_string(&ih).unwrap()).unwrap();
+        assert!(de_ih == ih);
+
+        assert!("0123".parse::<InfoHash>().is_err());
+        assert!(ih != InfoHash::from(&ih.as_bytes()[..20]));
+    }
 }
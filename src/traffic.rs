@@ -0,0 +1,338 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds of the request size buckets, in bytes. Connects are 16 bytes, announces 98 (more with BEP 41
+/// options) and scrapes 16 plus 20 per infohash. The last bucket holds larger requests.
+const SIZE_BUCKETS: [usize; 5] = [16, 64, 128, 512, 1500];
+
+/// The request mixes of IPs are kept in shards, so requests from different IPs rarely wait on each other.
+const MIX_SHARDS: usize = 16;
+
+/// IPs whose request mix is tracked at once per shard, floods of spoofed addresses can't grow it further.
+const MAX_TRACKED_IPS: usize = 4096;
+
+/// Connects an IP must send within a window, without any other request, to count as connect-only. Spoofed
+/// connects are what a reflection attack through the tracker looks like: the responses go to the victim, which
+/// never follows up with an announce.
+const CONNECT_ONLY_MIN: u32 = 5;
+
+/// Connect-only IPs listed in a report.
+const TOP_CONNECT_ONLY: usize = 10;
+
+/// Windows folded into the baselines before deviations from them are reported.
+const WARMUP_WINDOWS: u32 = 3;
+
+/// Weight of the latest window in the baselines.
+const SMOOTHING: f64 = 0.2;
+
+/// How many times its baseline a value must reach to be reported.
+const SPIKE_FACTOR: f64 = 4.0;
+
+/// By how much the share of requests in a size bucket must grow over its baseline to be reported.
+const SIZE_SHIFT: f64 = 0.3;
+
+/// An exponentially weighted moving average.
+#[derive(Default, Clone, Copy)]
+struct Baseline(Option<f64>);
+
+impl Baseline {
+    /// Folds `value` in, returns the baseline from before.
+    fn update(&mut self, value: f64) -> Option<f64> {
+        let previous = self.0;
+        self.0 = Some(previous.map(|v| v + SMOOTHING * (value - v)).unwrap_or(value));
+        previous
+    }
+}
+
+#[derive(Default)]
+struct Baselines {
+    windows: u32,
+    /// When the current window started, and the totals as of then.
+    started: Option<Instant>,
+    last_sizes: [u64; SIZE_BUCKETS.len() + 1],
+    last_connects: u64,
+    last_requests: u64,
+    last_invalid: u64,
+    requests_per_sec: Baseline,
+    invalid_connection_id_rate: Baseline,
+    connect_only_ips: Baseline,
+    size_shares: [Baseline; SIZE_BUCKETS.len() + 1],
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct SizeBucket {
+    /// None for the bucket of the largest requests.
+    max_bytes: Option<usize>,
+    requests: u64,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ConnectOnlyIp {
+    ip: IpAddr,
+    connects: u32,
+}
+
+/// Request sizes since startup, and the request mix of the latest window.
+#[derive(Serialize, Clone, Default, Debug, PartialEq)]
+pub struct TrafficReport {
+    sizes: Vec<SizeBucket>,
+    /// Announces & scrapes carrying a connection id that wasn't handed out, since startup.
+    invalid_connection_ids: u64,
+    requests_per_sec: f64,
+    /// Share of connects among the requests.
+    connect_share: f64,
+    /// Share of announces & scrapes with an invalid connection id.
+    invalid_connection_id_rate: f64,
+    connect_only_ips: usize,
+    /// The IPs that sent the most connects without any other request.
+    top_connect_only: Vec<ConnectOnlyIp>,
+    /// Metrics that deviated sharply from their baseline.
+    anomalies: Vec<&'static str>,
+}
+
+/// Watches the UDP traffic for what precedes abuse of the tracker, such as reflection attacks: floods of spoofed
+/// connects, requests of unusual sizes & announces with made up connection ids. `evaluate` compares each window
+/// to a moving baseline and warns about sharp deviations.
+pub struct TrafficMonitor {
+    sizes: [AtomicU64; SIZE_BUCKETS.len() + 1],
+    connects: AtomicU64,
+    /// Every request other than connects, including unparseable ones.
+    requests: AtomicU64,
+    invalid_connection_ids: AtomicU64,
+    /// ip -> (connects, other requests) within the current window
+    mixes: Vec<Mutex<HashMap<IpAddr, (u32, u32)>>>,
+    baselines: Mutex<Baselines>,
+    report: std::sync::RwLock<TrafficReport>,
+}
+
+impl TrafficReport {
+    pub fn get_invalid_connection_ids(&self) -> u64 {
+        self.invalid_connection_ids
+    }
+
+    pub fn get_connect_only_ips(&self) -> usize {
+        self.connect_only_ips
+    }
+}
+
+impl Default for TrafficMonitor {
+    fn default() -> TrafficMonitor {
+        TrafficMonitor {
+            sizes: Default::default(),
+            connects: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            invalid_connection_ids: AtomicU64::new(0),
+            mixes: (0..MIX_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            baselines: Mutex::new(Baselines::default()),
+            report: std::sync::RwLock::new(TrafficReport::default()),
+        }
+    }
+}
+
+impl TrafficMonitor {
+    /// Counts a request of `size` bytes from `ip`.
+    pub fn record(&self, ip: IpAddr, size: usize, is_connect: bool) {
+        let bucket = SIZE_BUCKETS.iter().position(|max| size <= *max).unwrap_or(SIZE_BUCKETS.len());
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+        if is_connect {
+            self.connects.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let shard = match ip {
+            IpAddr::V4(v4) => v4.octets()[3],
+            IpAddr::V6(v6) => v6.octets()[15],
+        } as usize
+            % MIX_SHARDS;
+        let mut mixes = self.mixes[shard].lock().unwrap();
+        if mixes.len() >= MAX_TRACKED_IPS && !mixes.contains_key(&ip) {
+            return;
+        }
+        let (connects, others) = mixes.entry(ip).or_insert((0, 0));
+        if is_connect {
+            *connects = connects.saturating_add(1);
+        } else {
+            *others = others.saturating_add(1);
+        }
+    }
+
+    pub fn count_invalid_connection_id(&self) {
+        self.invalid_connection_ids.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Ends the current window: reports it, warns about metrics that deviate sharply from their baseline, and
+    /// folds it into the baselines.
+    pub fn evaluate(&self, now: Instant) {
+        let mut connect_only = Vec::new();
+        for shard in self.mixes.iter() {
+            let mixes = std::mem::take(&mut *shard.lock().unwrap());
+            connect_only.extend(
+                mixes
+                    .into_iter()
+                    .filter(|(_, (connects, others))| *connects >= CONNECT_ONLY_MIN && *others == 0)
+                    .map(|(ip, (connects, _))| ConnectOnlyIp { ip, connects }),
+            );
+        }
+        connect_only.sort_by(|a, b| b.connects.cmp(&a.connects).then(a.ip.cmp(&b.ip)));
+
+        let sizes: Vec<u64> = self.sizes.iter().map(|v| v.load(Ordering::Relaxed)).collect();
+        let connects = self.connects.load(Ordering::Relaxed);
+        let requests = self.requests.load(Ordering::Relaxed);
+        let invalid = self.invalid_connection_ids.load(Ordering::Relaxed);
+
+        let mut baselines = self.baselines.lock().unwrap();
+        let started = baselines.started.replace(now);
+        let secs = started.map(|v| now.saturating_duration_since(v).as_secs_f64()).unwrap_or(0.0);
+        let window_connects = connects - baselines.last_connects;
+        let window_requests = requests - baselines.last_requests;
+        let window_total = window_connects + window_requests;
+        let ratio = |count: u64, total: u64| if total > 0 { count as f64 / total as f64 } else { 0.0 };
+
+        let mut report = TrafficReport {
+            sizes: sizes
+                .iter()
+                .enumerate()
+                .map(|(i, requests)| {
+                    SizeBucket {
+                        max_bytes: SIZE_BUCKETS.get(i).cloned(),
+                        requests: *requests,
+                    }
+                })
+                .collect(),
+            invalid_connection_ids: invalid,
+            requests_per_sec: if secs > 0.0 { window_total as f64 / secs } else { 0.0 },
+            connect_share: ratio(window_connects, window_total),
+            invalid_connection_id_rate: ratio(invalid - baselines.last_invalid, window_requests),
+            connect_only_ips: connect_only.len(),
+            top_connect_only: connect_only.into_iter().take(TOP_CONNECT_ONLY).collect(),
+            anomalies: Vec::new(),
+        };
+
+        // the first window has no start to measure rates from.
+        if started.is_some() {
+            let is_warm = baselines.windows >= WARMUP_WINDOWS;
+            let values = (
+                report.requests_per_sec,
+                report.invalid_connection_id_rate,
+                report.connect_only_ips as f64,
+            );
+            let mut check = |name: &'static str, baseline: &mut Baseline, value: f64, floor: f64| {
+                match baseline.update(value) {
+                    Some(base) if is_warm && value >= floor && value > base * SPIKE_FACTOR => {
+                        log_fields!(
+                            log::Level::Warn,
+                            "traffic anomaly",
+                            metric = name,
+                            value = format!("{:.2}", value),
+                            baseline = format!("{:.2}", base),
+                        );
+                        report.anomalies.push(name);
+                    }
+                    _ => {}
+                }
+            };
+            check("requests_per_sec", &mut baselines.requests_per_sec, values.0, 100.0);
+            check(
+                "invalid_connection_id_rate",
+                &mut baselines.invalid_connection_id_rate,
+                values.1,
+                0.05,
+            );
+            check("connect_only_ips", &mut baselines.connect_only_ips, values.2, 10.0);
+
+            let mut is_shifted = false;
+            for (i, size) in sizes.iter().enumerate() {
+                let share = ratio(size - baselines.last_sizes[i], window_total);
+                if let (Some(base), true) = (baselines.size_shares[i].update(share), is_warm) {
+                    is_shifted |= share - base >= SIZE_SHIFT;
+                }
+            }
+            if is_shifted {
+                log_fields!(log::Level::Warn, "traffic anomaly", metric = "request_sizes");
+                report.anomalies.push("request_sizes");
+            }
+            baselines.windows += 1;
+        }
+
+        baselines.last_sizes.copy_from_slice(&sizes);
+        baselines.last_connects = connects;
+        baselines.last_requests = requests;
+        baselines.last_invalid = invalid;
+        *self.report.write().unwrap() = report;
+    }
+
+    /// The report of the latest window.
+    pub fn get_report(&self) -> TrafficReport {
+        self.report.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn reflection() {
+        let monitor = TrafficMonitor::default();
+        let mut now = Instant::now();
+        monitor.evaluate(now);
+
+        // regular clients connect, then announce.
+        let window = |monitor: &TrafficMonitor| {
+            for i in 0..200u8 {
+                let ip = IpAddr::from([198, 51, 100, i]);
+                monitor.record(ip, 16, true);
+                monitor.record(ip, 98, false);
+            }
+        };
+        for _ in 0..WARMUP_WINDOWS + 1 {
+            window(&monitor);
+            now += Duration::from_secs(1);
+            monitor.evaluate(now);
+        }
+        let report = monitor.get_report();
+        assert_eq!(report.requests_per_sec, 400.0);
+        assert_eq!(report.connect_share, 0.5);
+        assert!(report.anomalies.is_empty(), "{:?}", report.anomalies);
+
+        // spoofed connects, & announces with made up connection ids.
+        window(&monitor);
+        for i in 0..50u8 {
+            for _ in 0..30 {
+                monitor.record(IpAddr::from([203, 0, 113, i]), 16, true);
+            }
+        }
+        for _ in 0..100 {
+            monitor.record(IpAddr::from([192, 0, 2, 1]), 98, false);
+            monitor.count_invalid_connection_id();
+        }
+        now += Duration::from_secs(1);
+        monitor.evaluate(now);
+
+        let report = monitor.get_report();
+        let anomalies = vec![
+            "requests_per_sec",
+            "invalid_connection_id_rate",
+            "connect_only_ips",
+            "request_sizes",
+        ];
+        assert_eq!(report.anomalies, anomalies);
+        assert_eq!(report.connect_only_ips, 50);
+        assert_eq!(report.top_connect_only.len(), TOP_CONNECT_ONLY);
+        assert_eq!(report.top_connect_only[0], ConnectOnlyIp {
+            ip: IpAddr::from([203, 0, 113, 0]),
+            connects: 30
+        });
+        assert_eq!(report.sizes[0], SizeBucket {
+            max_bytes: Some(16),
+            requests: 200 * (WARMUP_WINDOWS as u64 + 2) + 1500,
+        });
+        assert_eq!(report.invalid_connection_ids, 100);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::server::CONNECTION_TTL;
+use crate::tracker::InfoHash;
+use log::{trace, warn};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An announce observed locally, to be forwarded upstream.
+pub struct UpstreamAnnounce {
+    pub peer_id: [u8; 20],
+    /// Only used to derive the peer's key, upstream registers the edge's address.
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    /// The announce's event, as sent on the wire.
+    pub event: u32,
+}
+
+/// Forwards announces to an upstream UDP tracker (BEP 15) and keeps the peers it returns.
+pub struct Upstream {
+    socket: tokio::sync::Mutex<tokio::net::UdpSocket>,
+    connection: std::sync::Mutex<Option<(u64, Instant)>>,
+    next_transaction: AtomicU32,
+    /// Derives each peer's announce key, so it's the same on every round.
+    keys: RandomState,
+    max_announces: usize,
+    peer_ttl: Duration,
+    /// Latest announce per torrent & peer since the last round.
+    queue: std::sync::Mutex<HashMap<(InfoHash, [u8; 20]), UpstreamAnnounce>>,
+    peers: std::sync::RwLock<HashMap<InfoHash, (Instant, Vec<SocketAddrV4>)>>,
+}
+
+impl Upstream {
+    pub async fn open(address: &str, max_announces: usize, peer_ttl: Duration) -> Result<Upstream, std::io::Error> {
+        let address = tokio::net::lookup_host(address).await?.find(|a| a.is_ipv4()).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, format!("failed to resolve \"{}\"", address))
+        })?;
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(address).await?;
+
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|v| v.subsec_nanos())
+            .unwrap_or(0);
+        Ok(Upstream {
+            socket: tokio::sync::Mutex::new(socket),
+            connection: std::sync::Mutex::new(None),
+            next_transaction: AtomicU32::new(seed),
+            keys: RandomState::new(),
+            max_announces,
+            peer_ttl,
+            queue: std::sync::Mutex::new(HashMap::new()),
+            peers: std::sync::RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Queues an announce for the next round, replacing the peer's previous one.
+    pub fn queue(&self, info_hash: &InfoHash, announce: UpstreamAnnounce) {
+        self.queue
+            .lock()
+            .unwrap()
+            .insert((info_hash.clone(), announce.peer_id), announce);
+    }
+
+    /// The peers upstream returned for `info_hash`, unless they expired.
+    pub fn peers(&self, info_hash: &InfoHash) -> Vec<SocketAddrV4> {
+        match self.peers.read().unwrap().get(info_hash) {
+            Some((updated, peers)) if updated.elapsed() < self.peer_ttl => peers.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn transaction_id(&self) -> u32 {
+        self.next_transaction.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The key upstream tells apart the peers behind this instance's address by.
+    fn key(&self, announce: &UpstreamAnnounce) -> u32 {
+        self.keys.hash_one((announce.peer_id, announce.ip, announce.port)) as u32
+    }
+
+    /// Forwards the queued announces, up to `max_announces`, and keeps the returned peers.
+    /// Returns the amount of torrents upstream responded for.
+    pub async fn announce_queued(&self) -> Result<usize, std::io::Error> {
+        let queue = std::mem::take(&mut *self.queue.lock().unwrap());
+        if queue.is_empty() {
+            return Ok(0);
+        }
+        if queue.len() > self.max_announces {
+            warn!("dropping {} announces to upstream", queue.len() - self.max_announces);
+        }
+
+        let mut socket = self.socket.lock().await;
+        let connection_id = self.connection_id(&mut socket).await?;
+
+        let mut pending = HashMap::new();
+        for ((info_hash, _), announce) in queue.into_iter().take(self.max_announces) {
+            let transaction_id = self.transaction_id();
+            let mut packet = Vec::with_capacity(98);
+            packet.extend_from_slice(&connection_id.to_be_bytes());
+            packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend_from_slice(&transaction_id.to_be_bytes());
+            packet.extend_from_slice(info_hash.as_bytes());
+            packet.extend_from_slice(&announce.peer_id);
+            packet.extend_from_slice(&announce.downloaded.to_be_bytes());
+            packet.extend_from_slice(&announce.left.to_be_bytes());
+            packet.extend_from_slice(&announce.uploaded.to_be_bytes());
+            packet.extend_from_slice(&announce.event.to_be_bytes());
+            // most trackers (udpt included) ignore or refuse announces for another IP than the sender's.
+            packet.extend_from_slice(&0u32.to_be_bytes()); // ip
+            packet.extend_from_slice(&self.key(&announce).to_be_bytes());
+            packet.extend_from_slice(&(-1i32).to_be_bytes()); // num_want
+            packet.extend_from_slice(&announce.port.to_be_bytes());
+            socket.send(&packet).await?;
+            pending.insert(transaction_id, info_hash);
+        }
+
+        // peers returned for a torrent during this round, across all of its announces.
+        let mut returned: HashMap<InfoHash, Vec<SocketAddrV4>> = HashMap::new();
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut buffer = [0u8; 0xffff];
+        while !pending.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let size = match tokio::time::timeout(remaining, socket.recv(&mut buffer)).await {
+                Ok(v) => v?,
+                Err(_) => break,
+            };
+            let data = &buffer[..size];
+            if size < 8 {
+                continue;
+            }
+            let info_hash = match pending.remove(&read_u32(data, 4)) {
+                Some(v) => v,
+                None => continue,
+            };
+            match read_u32(data, 0) {
+                ACTION_ANNOUNCE if size >= 20 => {
+                    let peers = returned.entry(info_hash).or_insert_with(Vec::new);
+                    for peer in data[20..].chunks_exact(6) {
+                        let addr = SocketAddrV4::new(
+                            Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]),
+                            u16::from_be_bytes([peer[4], peer[5]]),
+                        );
+                        if !peers.contains(&addr) {
+                            peers.push(addr);
+                        }
+                    }
+                }
+                ACTION_ERROR => {
+                    trace!("upstream error for {}: {}", info_hash, String::from_utf8_lossy(&data[8..]));
+                }
+                _ => {}
+            }
+        }
+        if !pending.is_empty() {
+            trace!("upstream didn't respond to {} announces", pending.len());
+        }
+
+        let count = returned.len();
+        let now = Instant::now();
+        let ttl = self.peer_ttl;
+        let mut peers = self.peers.write().unwrap();
+        peers.retain(|_, (updated, _)| updated.elapsed() < ttl);
+        for (info_hash, list) in returned {
+            peers.insert(info_hash, (now, list));
+        }
+        Ok(count)
+    }
+
+    async fn connection_id(&self, socket: &mut tokio::net::UdpSocket) -> Result<u64, std::io::Error> {
+        if let Some((connection_id, connected_at)) = *self.connection.lock().unwrap() {
+            if connected_at.elapsed() < CONNECTION_TTL {
+                return Ok(connection_id);
+            }
+        }
+
+        let transaction_id = self.transaction_id();
+        let mut packet = Vec::with_capacity(16);
+        packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        socket.send(&packet).await?;
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        let mut buffer = [0u8; 2048];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let size = match tokio::time::timeout(remaining, socket.recv(&mut buffer)).await {
+                Ok(v) => v?,
+                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "upstream didn't respond")),
+            };
+            let data = &buffer[..size];
+            // skip late responses of a previous round.
+            if size >= 16 && read_u32(data, 0) == ACTION_CONNECT && read_u32(data, 4) == transaction_id {
+                let mut connection_id = [0u8; 8];
+                connection_id.copy_from_slice(&data[8..16]);
+                let connection_id = u64::from_be_bytes(connection_id);
+                *self.connection.lock().unwrap() = Some((connection_id, Instant::now()));
+                return Ok(connection_id);
+            }
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn forward_announces() {
+        let mut tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = tracker.local_addr().unwrap().to_string();
+        let (sender, received) = std::sync::mpsc::channel();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 2048];
+            loop {
+                let (size, from) = tracker.recv_from(&mut buffer).await.unwrap();
+                let mut response = Vec::new();
+                match read_u32(&buffer, 8) {
+                    ACTION_CONNECT => {
+                        response.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&7u64.to_be_bytes());
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(size, 98);
+                        assert_eq!(&buffer[..8], &7u64.to_be_bytes());
+                        // ip & key
+                        sender.send((read_u32(&buffer, 84), read_u32(&buffer, 88))).unwrap();
+                        response.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                        response.extend_from_slice(&buffer[12..16]);
+                        response.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, 0]);
+                        response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                    }
+                    _ => continue,
+                }
+                tracker.send_to(&response, &from).await.unwrap();
+            }
+        });
+
+        let upstream = Upstream::open(&address, 100, Duration::from_secs(60)).await.unwrap();
+        let info_hash: InfoHash = [1u8; 20].into();
+        let announce = |left| {
+            UpstreamAnnounce {
+                peer_id: [2u8; 20],
+                ip: Ipv4Addr::new(192, 168, 0, 1),
+                port: 6881,
+                uploaded: 0,
+                downloaded: 0,
+                left,
+                event: 2,
+            }
+        };
+        upstream.queue(&info_hash, announce(10));
+
+        assert_eq!(upstream.announce_queued().await.unwrap(), 1);
+        assert_eq!(upstream.peers(&info_hash), vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert!(upstream.peers(&[2u8; 20].into()).is_empty());
+
+        // the peer keeps its key on the next round.
+        upstream.queue(&info_hash, announce(0));
+        assert_eq!(upstream.announce_queued().await.unwrap(), 1);
+        let (first, second) = (received.recv().unwrap(), received.recv().unwrap());
+        assert_eq!(first.0, 0);
+        assert_eq!(first, second);
+    }
+}
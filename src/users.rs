@@ -0,0 +1,240 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+#[derive(Serialize, Clone)]
+pub struct User {
+    #[serde(skip)]
+    passkey: String,
+    is_banned: bool,
+    announces: u64,
+    /// Transfer reported by the user's peers, summed across announces.
+    uploaded: u64,
+    downloaded: u64,
+    /// Unix timestamp of the last announce.
+    last_announce: Option<u64>,
+}
+
+impl User {
+    fn new(passkey: String) -> User {
+        User {
+            passkey,
+            is_banned: false,
+            announces: 0,
+            uploaded: 0,
+            downloaded: 0,
+            last_announce: None,
+        }
+    }
+}
+
+/// Changes to a user, as submitted through the API.
+#[derive(Deserialize)]
+pub struct UserUpdate {
+    pub passkey: Option<String>,
+    pub is_banned: Option<bool>,
+}
+
+#[derive(Default)]
+struct Users {
+    users: HashMap<String, User>,
+    /// passkey -> user name
+    passkeys: HashMap<String, String>,
+}
+
+/// Users of a private tracker, identified by their passkeys.
+pub struct UserRegistry {
+    inner: RwLock<Users>,
+}
+
+impl UserRegistry {
+    /// `passkeys` maps user names to their passkeys.
+    pub fn new(passkeys: &HashMap<String, String>) -> UserRegistry {
+        let registry = UserRegistry {
+            inner: RwLock::new(Users::default()),
+        };
+        for (name, passkey) in passkeys.iter() {
+            let _ = registry.update(name, UserUpdate {
+                passkey: Some(passkey.clone()),
+                is_banned: None,
+            });
+        }
+        registry
+    }
+
+    /// Returns the name of the user owning `passkey`, or the reason the announce should be rejected.
+    pub fn authenticate(&self, passkey: Option<&str>) -> Result<String, &'static str> {
+        let passkey = passkey.ok_or("passkey required.")?;
+        let inner = self.inner.read().unwrap();
+        let name = inner.passkeys.get(passkey).ok_or("invalid passkey.")?;
+        match inner.users.get(name) {
+            Some(user) if !user.is_banned => Ok(name.clone()),
+            Some(_) => Err("user banned."),
+            None => Err("invalid passkey."),
+        }
+    }
+
+    pub fn record_announce(&self, name: &str, uploaded: u64, downloaded: u64) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .ok();
+
+        let mut inner = self.inner.write().unwrap();
+        if let Some(user) = inner.users.get_mut(name) {
+            user.announces += 1;
+            user.uploaded += uploaded;
+            user.downloaded += downloaded;
+            user.last_announce = now;
+        }
+    }
+
+    /// Creates or updates a user. New users require a passkey, passkeys must be unique.
+    pub fn update(&self, name: &str, update: UserUpdate) -> Result<(), &'static str> {
+        let mut inner = self.inner.write().unwrap();
+        let inner = &mut *inner;
+
+        if let Some(ref passkey) = update.passkey {
+            match inner.passkeys.get(passkey) {
+                Some(owner) if owner != name => return Err("passkey already in use."),
+                _ => {}
+            }
+        }
+
+        let user = match (inner.users.get_mut(name), update.passkey) {
+            (Some(user), Some(passkey)) => {
+                inner.passkeys.remove(&user.passkey);
+                inner.passkeys.insert(passkey.clone(), name.to_string());
+                user.passkey = passkey;
+                user
+            }
+            (Some(user), None) => user,
+            (None, Some(passkey)) => {
+                inner.passkeys.insert(passkey.clone(), name.to_string());
+                inner.users.entry(name.to_string()).or_insert(User::new(passkey))
+            }
+            (None, None) => return Err("passkey required for new users."),
+        };
+
+        if let Some(is_banned) = update.is_banned {
+            user.is_banned = is_banned;
+        }
+        Ok(())
+    }
+
+    /// Replaces all users with `users` (name, passkey, is_banned), keeping the stats of users that remain.
+    pub fn replace(&self, users: Vec<(String, String, bool)>) {
+        let mut inner = self.inner.write().unwrap();
+        let mut previous = std::mem::take(&mut inner.users);
+        inner.passkeys.clear();
+
+        for (name, passkey, is_banned) in users {
+            if inner.passkeys.contains_key(&passkey) {
+                continue;
+            }
+            let mut user = previous.remove(&name).unwrap_or_else(|| User::new(String::new()));
+            user.passkey = passkey.clone();
+            user.is_banned = is_banned;
+            inner.passkeys.insert(passkey, name.clone());
+            inner.users.insert(name, user);
+        }
+    }
+
+    /// Returns every user as (name, passkey, is_banned), the counterpart of `replace`.
+    pub fn export(&self) -> Vec<(String, String, bool)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .users
+            .iter()
+            .map(|(name, user)| (name.clone(), user.passkey.clone(), user.is_banned))
+            .collect()
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        match inner.users.remove(name) {
+            Some(user) => {
+                inner.passkeys.remove(&user.passkey);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> HashMap<String, User> {
+        self.inner.read().unwrap().users.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticate() {
+        let mut passkeys = HashMap::new();
+        passkeys.insert(String::from("alice"), String::from("secret"));
+        let registry = UserRegistry::new(&passkeys);
+
+        assert_eq!(registry.authenticate(Some("secret")), Ok(String::from("alice")));
+        assert_eq!(registry.authenticate(Some("nope")), Err("invalid passkey."));
+        assert_eq!(registry.authenticate(None), Err("passkey required."));
+
+        let ban = UserUpdate {
+            passkey: None,
+            is_banned: Some(true),
+        };
+        assert!(registry.update("alice", ban).is_ok());
+        assert_eq!(registry.authenticate(Some("secret")), Err("user banned."));
+
+        let rotate = UserUpdate {
+            passkey: Some(String::from("other")),
+            is_banned: Some(false),
+        };
+        assert!(registry.update("alice", rotate).is_ok());
+        assert_eq!(registry.authenticate(Some("secret")), Err("invalid passkey."));
+        assert_eq!(registry.authenticate(Some("other")), Ok(String::from("alice")));
+
+        assert!(registry.remove("alice"));
+        assert_eq!(registry.authenticate(Some("other")), Err("invalid passkey."));
+    }
+
+    #[test]
+    fn passkeys_are_unique() {
+        let registry = UserRegistry::new(&HashMap::new());
+        let new_user = |passkey: &str| {
+            UserUpdate {
+                passkey: Some(String::from(passkey)),
+                is_banned: None,
+            }
+        };
+
+        assert!(registry.update("alice", new_user("a")).is_ok());
+        assert!(registry.update("bob", new_user("a")).is_err());
+        assert!(registry.update("bob", new_user("b")).is_ok());
+
+        registry.record_announce("bob", 10, 20);
+        let users = registry.list();
+        assert_eq!(users["bob"].announces, 1);
+        assert_eq!(users["bob"].downloaded, 20);
+    }
+
+    #[test]
+    fn replace() {
+        let mut passkeys = HashMap::new();
+        passkeys.insert(String::from("alice"), String::from("a"));
+        passkeys.insert(String::from("bob"), String::from("b"));
+        let registry = UserRegistry::new(&passkeys);
+        registry.record_announce("bob", 10, 20);
+
+        registry.replace(vec![
+            (String::from("bob"), String::from("b2"), false),
+            (String::from("carol"), String::from("c"), true),
+        ]);
+        assert_eq!(registry.authenticate(Some("a")), Err("invalid passkey."));
+        assert_eq!(registry.authenticate(Some("b")), Err("invalid passkey."));
+        assert_eq!(registry.authenticate(Some("b2")), Ok(String::from("bob")));
+        assert_eq!(registry.authenticate(Some("c")), Err("user banned."));
+        assert_eq!(registry.list()["bob"].announces, 1);
+    }
+}
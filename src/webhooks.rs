@@ -0,0 +1,194 @@
+use crate::config::WebhookConfig;
+use crate::tracker::InfoHash;
+use log::{error, trace, warn};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    TorrentAdded {
+        info_hash: InfoHash,
+    },
+    TorrentRemoved {
+        info_hash: InfoHash,
+    },
+    TorrentFlagged {
+        info_hash: InfoHash,
+        is_flagged: bool,
+    },
+    /// The torrent's first completed event.
+    TorrentCompleted {
+        info_hash: InfoHash,
+    },
+}
+
+#[derive(Serialize)]
+struct QueuedEvent {
+    /// Unix timestamp of the event.
+    timestamp: u64,
+    #[serde(flatten)]
+    event: WebhookEvent,
+}
+
+#[derive(Serialize)]
+struct Batch<'a> {
+    events: &'a [QueuedEvent],
+}
+
+/// Hex encoded HMAC-SHA256 of `body`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    use hmac::{Mac, NewMac};
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_varkey(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(body);
+    let signature = mac.finalize().into_bytes();
+
+    let mut hex = vec![0u8; signature.len() * 2];
+    binascii::bin2hex(&signature, &mut hex).unwrap();
+    String::from_utf8_lossy(&hex).into_owned()
+}
+
+/// Delivers tracker events to the configured URLs in batches.
+/// Events are queued up to `max_queue`; while the URLs are slow to respond, newer events are dropped.
+pub struct Webhooks {
+    client: crate::http::Client,
+    urls: Vec<String>,
+    secret: Option<String>,
+    max_retries: u32,
+    max_queue: usize,
+    queue: std::sync::Mutex<Vec<QueuedEvent>>,
+    dropped: AtomicU64,
+}
+
+impl Webhooks {
+    const MAX_BATCH: usize = 1000;
+
+    pub fn new(config: &WebhookConfig) -> Webhooks {
+        Webhooks {
+            client: crate::http::client(),
+            urls: config.get_urls().to_vec(),
+            secret: config.get_secret().map(String::from),
+            max_retries: config.get_max_retries(),
+            max_queue: config.get_max_queue(),
+            queue: std::sync::Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `event` for the next flush.
+    pub fn push(&self, event: WebhookEvent) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|v| v.as_secs())
+            .unwrap_or(0);
+
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.max_queue {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push(QueuedEvent { timestamp, event });
+    }
+
+    /// Sends the queued events to every URL, in batches of up to `MAX_BATCH` events.
+    /// Failed requests are retried with exponential backoff, batches that still fail are dropped.
+    pub async fn flush(&self) {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!("webhook queue is full, dropped {} events", dropped);
+        }
+
+        let events = std::mem::take(&mut *self.queue.lock().unwrap());
+
+        for batch in events.chunks(Self::MAX_BATCH) {
+            let body = match serde_json::to_vec(&Batch { events: batch }) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("failed to serialize webhook events: {}", err);
+                    continue;
+                }
+            };
+            let signature = self.secret.as_ref().map(|secret| format!("sha256={}", sign(secret, &body)));
+
+            let deliveries = self.urls.iter().map(|url| self.deliver(url, &body, signature.as_deref()));
+            futures::future::join_all(deliveries).await;
+        }
+    }
+
+    async fn deliver(&self, url: &str, body: &[u8], signature: Option<&str>) {
+        let mut delay = std::time::Duration::from_secs(1);
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                tokio::time::delay_for(delay).await;
+                delay *= 2;
+            }
+
+            let mut request = hyper::Request::post(url).header("Content-Type", "application/json");
+            if let Some(signature) = signature {
+                request = request.header("X-Udpt-Signature", signature);
+            }
+            let request = match request.body(hyper::Body::from(body.to_vec())) {
+                Ok(v) => v,
+                Err(err) => {
+                    error!("invalid webhook url \"{}\": {}", url, err);
+                    return;
+                }
+            };
+
+            let timeout = std::time::Duration::from_secs(10);
+            match tokio::time::timeout(timeout, self.client.request(request)).await {
+                Ok(Ok(response)) if response.status().is_success() => {
+                    trace!("delivered webhook to \"{}\"", url);
+                    return;
+                }
+                Ok(Ok(response)) => warn!("webhook \"{}\" responded with {}", url, response.status()),
+                Ok(Err(err)) => warn!("webhook \"{}\" failed: {}", url, err),
+                Err(_) => warn!("webhook \"{}\" timed out", url),
+            }
+        }
+        error!("giving up on webhook \"{}\" after {} attempts", url, self.max_retries + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+
+        let event = QueuedEvent {
+            timestamp: 1,
+            event: WebhookEvent::TorrentFlagged {
+                info_hash: [0u8; 20].into(),
+                is_flagged: true,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&Batch { events: &[event] }).unwrap(),
+            concat!(
+                r#"{"events":[{"timestamp":1,"event":"torrent_flagged","#,
+                r#""info_hash":"0000000000000000000000000000000000000000","is_flagged":true}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn max_queue() {
+        let config: WebhookConfig = toml::from_str("urls = [\"https://example.com/udpt-events\"]\nmax_queue = 2").unwrap();
+        let webhooks = Webhooks::new(&config);
+        for _ in 0..3 {
+            webhooks.push(WebhookEvent::TorrentAdded {
+                info_hash: [0u8; 20].into(),
+            });
+        }
+        assert_eq!(webhooks.queue.lock().unwrap().len(), 2);
+        assert_eq!(webhooks.dropped.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,9 +1,21 @@
-use crate::tracker::{InfoHash, TorrentTracker};
+use crate::abuse::Abuse;
+use crate::bans::BanTarget;
+use crate::config::{Configuration, TokenScope};
+use crate::geoip::CountryCode;
+use crate::ratelimit::RateLimiter;
+use crate::telemetry::{self, SpanContext, SpanKind};
+use crate::tracker::{ControlAction, InfoHash, SwarmHealth, TorrentTracker, TrackerMode};
+use crate::users::UserUpdate;
+use hyper::service::Service;
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::collections::{HashMap, HashSet};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use warp::{filters, reply, reply::Reply, serve, Filter, Server};
+use warp::{filters, reply, reply::Reply, Filter};
+
+/// Longest a `/t/:info_hash/wait` request is held open, in seconds.
+const MAX_WAIT_TIMEOUT: u64 = 120;
 
 fn view_root() -> impl Reply {
     warp::http::Response::builder()
@@ -43,6 +55,16 @@ fn view_root() -> impl Reply {
 struct TorrentInfoQuery {
     offset: Option<u32>,
     limit: Option<u32>,
+    /// Unix timestamp, only torrents without announces since are listed.
+    inactive_since: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SwarmWaitQuery {
+    timeout: Option<u64>,
+    seeders: Option<u32>,
+    leechers: Option<u32>,
+    completed: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -52,16 +74,87 @@ struct TorrentEntry<'a> {
     data: &'a crate::tracker::TorrentEntry,
     seeders: u32,
     leechers: u32,
+    peer_count: usize,
+    max_peers: Option<usize>,
+    announce_rate: f64,
+    join_rate: f64,
+    departure_rate: f64,
+    countries: BTreeMap<CountryCode, usize>,
+    health: Option<&'a SwarmHealth>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     peers: Option<Vec<(crate::tracker::PeerId, crate::tracker::TorrentPeer)>>,
 }
 
+#[derive(Serialize)]
+struct TorrentListEntry {
+    info_hash: InfoHash,
+    is_flagged: bool,
+    completed: u32,
+    seeders: u32,
+    leechers: u32,
+    last_activity: Option<u64>,
+    health: Option<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct TorrentFlag {
     is_flagged: bool,
 }
 
+#[derive(Deserialize)]
+struct TorrentLink {
+    v2: InfoHash,
+}
+
+#[derive(Serialize)]
+struct PublicScrape<'a> {
+    info_hash: &'a InfoHash,
+    seeders: u32,
+    leechers: u32,
+    completed: u32,
+}
+
+#[derive(Serialize)]
+struct SwarmWait<'a> {
+    info_hash: &'a InfoHash,
+    seeders: u32,
+    leechers: u32,
+    completed: u32,
+    changed: bool,
+}
+
+#[derive(Deserialize)]
+struct TorrentAliases {
+    aliases: Vec<InfoHash>,
+}
+
+/// A ban, as submitted through the API.
+#[derive(Deserialize)]
+struct NewBan {
+    #[serde(flatten)]
+    target: BanTarget,
+    /// In seconds, bans without one last until they're lifted.
+    expires_in: Option<u64>,
+    reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModeStatus {
+    mode: TrackerMode,
+}
+
+#[derive(Serialize)]
+struct ReadinessStatus {
+    ready: bool,
+    listeners: Vec<crate::tracker::ListenerProbe>,
+}
+
+#[derive(Serialize)]
+struct ImportStatus {
+    imported: usize,
+}
+
 #[derive(Serialize, Debug)]
 #[serde(tag = "status", rename_all = "snake_case")]
 enum ActionStatus<'a> {
@@ -71,26 +164,53 @@ enum ActionStatus<'a> {
 
 impl warp::reject::Reject for ActionStatus<'static> {}
 
-fn authenticate(tokens: HashMap<String, String>) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
-    #[derive(Deserialize)]
-    struct AuthToken {
-        token: Option<String>,
-    }
+/// A `Link` header entry for the page of `limit` torrents at `offset`, keeping the request's other query parameters.
+fn page_link(query: &str, offset: usize, limit: usize, rel: &str) -> String {
+    let page = format!("offset={}&limit={}", offset, limit);
+    let params: Vec<&str> = query
+        .split('&')
+        .filter(|v| !v.is_empty() && !matches!(v.split('=').next(), Some("offset") | Some("limit")))
+        .chain(std::iter::once(page.as_str()))
+        .collect();
+    format!("</t/?{}>; rel=\"{}\"", params.join("&"), rel)
+}
 
-    let tokens: HashSet<String> = tokens.into_iter().map(|(_, v)| v).collect();
+/// Address of the client a request came from, set by `serve` as a request extension.
+#[derive(Clone, Copy)]
+struct ClientAddr(std::net::SocketAddr);
+
+/// Access tokens are read from the tracker's current configuration, so they can be changed by a reload.
+#[derive(Deserialize)]
+struct AuthToken {
+    token: Option<String>,
+}
 
-    let tokens = Arc::new(tokens);
+/// The request's access token, from an `Authorization: Bearer` header or the `token` query parameter.
+fn access_token() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    // requests without a query string are rejected by the query filter.
+    let query = filters::query::query::<AuthToken>().or(warp::filters::any::any().map(|| AuthToken { token: None }));
+    filters::header::optional::<String>("authorization").and(query.unify()).map(
+        |header: Option<String>, query: AuthToken| {
+            let bearer = header.and_then(|v| v.strip_prefix("Bearer ").map(String::from));
+            bearer.or(query.token)
+        },
+    )
+}
+
+fn authenticate(tracker: Arc<TorrentTracker>) -> impl Filter<Extract = (), Error = warp::reject::Rejection> + Clone {
     warp::filters::any::any()
-        .map(move || tokens.clone())
-        .and(filters::query::query::<AuthToken>())
+        .map(move || tracker.clone())
+        .and(access_token())
+        .and(filters::ext::get::<ClientAddr>())
         .and_then(
-            |tokens: Arc<HashSet<String>>, token: AuthToken| {
+            |tracker: Arc<TorrentTracker>, token: Option<String>, client_addr: ClientAddr| {
                 async move {
-                    if let Some(token) = token.token {
-                        if tokens.contains(&token) {
+                    if let (Some(token), Some(http_cfg)) = (token, tracker.get_config().get_http_config()) {
+                        if http_cfg.get_access_tokens().values().any(|v| *v == token) {
                             return Ok(());
                         }
                     }
+                    tracker.report_abuse(Abuse::AuthFailure, client_addr.0.ip());
                     Err(warp::reject::custom(ActionStatus::Err {
                         reason: "Access Denied".into(),
                     }))
@@ -100,50 +220,395 @@ fn authenticate(tokens: HashMap<String, String>) -> impl Filter<Extract = (), Er
         .untuple_one()
 }
 
-pub fn build_server(
-    tracker: Arc<TorrentTracker>, tokens: HashMap<String, String>,
-) -> Server<impl Filter<Extract = impl Reply> + Clone + Send + Sync + 'static> {
+/// Torrents the request's access token may manage, see `[http.token_scopes]`.
+#[derive(Clone)]
+struct Scope {
+    config: Arc<Configuration>,
+    token: String,
+}
+
+impl Scope {
+    fn get(&self) -> Option<&TokenScope> {
+        self.config.get_http_config()?.get_token_scope(&self.token)
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.config.get_http_config()?.get_token_name(&self.token)
+    }
+
+    fn is_restricted(&self) -> bool {
+        self.get().is_some()
+    }
+
+    fn allows(&self, info_hash: &InfoHash) -> bool {
+        match (self.config.get_http_config(), self.get()) {
+            (Some(http), Some(scope)) => http.is_in_scope(scope, info_hash),
+            _ => true,
+        }
+    }
+}
+
+fn token_scope(tracker: Arc<TorrentTracker>) -> impl Filter<Extract = (Scope,), Error = warp::Rejection> + Clone {
+    access_token().map(move |token: Option<String>| {
+        Scope {
+            config: tracker.get_config(),
+            token: token.unwrap_or_default(),
+        }
+    })
+}
+
+fn out_of_scope() -> warp::Rejection {
+    warp::reject::custom(ActionStatus::Err {
+        reason: "Access Denied".into(),
+    })
+}
+
+/// The `:info_hash` path parameter, rejected unless the access token may manage the torrent.
+fn scoped_info_hash(tracker: Arc<TorrentTracker>) -> impl Filter<Extract = (InfoHash,), Error = warp::Rejection> + Clone {
+    filters::path::param()
+        .and(token_scope(tracker))
+        .and_then(|info_hash: InfoHash, scope: Scope| {
+            async move {
+                match scope.allows(&info_hash) {
+                    true => Ok(info_hash),
+                    false => Err(out_of_scope()),
+                }
+            }
+        })
+}
+
+/// Rejects access tokens restricted to some torrents, for routes that aren't about torrents.
+fn unrestricted(tracker: Arc<TorrentTracker>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    token_scope(tracker)
+        .and_then(|scope: Scope| {
+            async move {
+                match scope.is_restricted() {
+                    false => Ok(()),
+                    true => Err(out_of_scope()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Serves the REST API on `addr`. With telemetry enabled, every request is traced, continuing the trace of a
+/// `traceparent` header set by a fronting proxy; spans of the tracker operations it runs become its children.
+/// `listener` is a socket passed by systemd for `addr`, it's used instead of binding one.
+pub async fn serve(
+    tracker: Arc<TorrentTracker>, addr: std::net::SocketAddr, listener: Option<std::net::TcpListener>,
+) -> Result<(), hyper::Error> {
+    let routes = build_routes(tracker.clone());
+    let make_service = hyper::service::make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+        let client_addr = ClientAddr(conn.remote_addr());
+        let tracker = tracker.clone();
+        let service = warp::service(routes.clone());
+        async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |mut request: hyper::Request<hyper::Body>| {
+                request.extensions_mut().insert(client_addr);
+                let (tracker, service) = (tracker.clone(), service.clone());
+                async move {
+                    let started = std::time::Instant::now();
+                    let name = format!("{} {}", request.method(), request.uri().path());
+                    let response = match answer_method(&mut request) {
+                        Some(response) => Ok(response),
+                        None => traced(tracker.clone(), service, request).await,
+                    };
+                    tracker.log_if_slow("api_request", &name, started);
+                    response
+                }
+            }))
+        }
+    });
+    match listener {
+        Some(listener) => hyper::Server::from_tcp(listener)?.serve(make_service).await,
+        None => hyper::Server::bind(&addr).serve(make_service).await,
+    }
+}
+
+async fn traced<S>(
+    tracker: Arc<TorrentTracker>, mut service: S, request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, std::convert::Infallible>
+where
+    S: Service<hyper::Request<hyper::Body>, Response = hyper::Response<hyper::Body>, Error = std::convert::Infallible>, {
+    let tracer = match tracker.get_tracer() {
+        Some(tracer) => tracer,
+        None => return service.call(request).await,
+    };
+
+    let parent = request
+        .headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(SpanContext::from_traceparent);
+    let mut span = tracer.start(
+        format!("{} {}", request.method(), route(request.uri().path())),
+        SpanKind::Server,
+        parent,
+    );
+    // the query isn't recorded, it holds the access token.
+    span.set_attribute("http.method", request.method());
+    span.set_attribute("http.target", request.uri().path());
+
+    let response = telemetry::in_span(span.context(), service.call(request)).await;
+    if let Ok(ref response) = response {
+        span.set_int_attribute("http.status_code", u64::from(response.status().as_u16()));
+        if response.status().is_server_error() {
+            span.set_error(response.status());
+        }
+    }
+    response
+}
+
+/// Methods a route is served for, None for unknown routes.
+fn route_methods(path: &str) -> Option<&'static [hyper::Method]> {
+    use hyper::Method;
+
+    // warp's routes match with or without a trailing slash.
+    let route = route(path);
+    Some(match route.trim_end_matches('/') {
+        "" | "/health" | "/ready" | "/t" | "/stats" | "/stats/clients" | "/users" | "/replication" => &[Method::GET],
+        "/public/scrape/:info_hash" | "/t/:info_hash/wait" | "/admin/config" => &[Method::GET],
+        "/t/:info_hash" => &[Method::GET, Method::POST, Method::DELETE],
+        "/users/:name" => &[Method::POST, Method::DELETE],
+        "/t/:info_hash/link" | "/t/:info_hash/aliases" | "/t/:info_hash/reset" | "/import" => &[Method::POST],
+        "/t/:info_hash/aliases/:alias" | "/admin/bans/:id" => &[Method::DELETE],
+        "/admin/bans" | "/admin/mode" => &[Method::GET, Method::POST],
+        _ => return None,
+    })
+}
+
+/// Answers OPTIONS requests & requests with a method the route isn't served for (with a 405), HEAD requests are
+/// turned into GET requests, hyper leaves their response's body out. Other requests are left to the routes.
+fn answer_method(request: &mut hyper::Request<hyper::Body>) -> Option<hyper::Response<hyper::Body>> {
+    let methods = route_methods(request.uri().path())?;
+    let is_get = methods.contains(&hyper::Method::GET);
+    if *request.method() == hyper::Method::HEAD && is_get {
+        *request.method_mut() = hyper::Method::GET;
+        return None;
+    }
+    if methods.contains(request.method()) {
+        return None;
+    }
+
+    let mut allow: Vec<&str> = methods.iter().map(hyper::Method::as_str).collect();
+    if is_get {
+        allow.push("HEAD");
+    }
+    allow.push("OPTIONS");
+    let response = hyper::Response::builder().header(hyper::header::ALLOW, allow.join(", "));
+    let response = match *request.method() {
+        hyper::Method::OPTIONS => response.status(hyper::StatusCode::NO_CONTENT).body(hyper::Body::empty()),
+        _ => {
+            let body = serde_json::to_vec(&ActionStatus::Err {
+                reason: "method not allowed".into(),
+            })
+            .unwrap_or_default();
+            response
+                .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(hyper::Body::from(body))
+        }
+    };
+    response.ok()
+}
+
+/// The route of a request path, without infohashes & user names so span names stay few.
+fn route(path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').collect();
+    match segments.get(1) {
+        Some(&"t") if segments.len() > 2 && !segments[2].is_empty() => {
+            segments[2] = ":info_hash";
+            if segments.len() > 4 && segments[3] == "aliases" && !segments[4].is_empty() {
+                segments[4] = ":alias";
+            }
+        }
+        Some(&"users") if segments.len() > 2 && !segments[2].is_empty() => segments[2] = ":name",
+        Some(&"admin") if segments.len() > 3 && segments[2] == "bans" && !segments[3].is_empty() => segments[3] = ":id",
+        Some(&"public") if segments.len() > 3 && segments[2] == "scrape" && !segments[3].is_empty() => {
+            segments[3] = ":info_hash"
+        }
+        _ => {}
+    }
+    segments.join("/")
+}
+
+fn build_routes(
+    tracker: Arc<TorrentTracker>,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone + Send + Sync + 'static {
     let root = filters::path::end().map(|| view_root());
 
+    // liveness: GET /health, answered as long as the runtime is.
+    let view_health = filters::path::path("health")
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(|| reply::json(&ActionStatus::Ok));
+
+    let t13 = tracker.clone();
+    // readiness: GET /ready, 503 unless every UDP listener answered its latest probe.
+    let view_ready = filters::path::path("ready")
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(move || {
+            let readiness = t13.get_readiness();
+            let status = ReadinessStatus {
+                ready: readiness.is_ready(),
+                listeners: readiness.get_listeners(),
+            };
+            let code = match status.ready {
+                true => warp::http::StatusCode::OK,
+                false => warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            };
+            reply::with_status(reply::json(&status), code)
+        });
+
+    let t17 = tracker.clone();
+    let limiter = tracker
+        .get_config()
+        .get_http_config()
+        .and_then(|v| v.get_public_scrape_config())
+        .map(|v| Arc::new(RateLimiter::new(v.get_max_per_minute(), std::time::Duration::from_secs(60))));
+    // public_scrape: GET /public/scrape/:info_hash, without a token for index sites, rate limited per IP.
+    let view_public_scrape = filters::path::path("public")
+        .and(filters::path::path("scrape"))
+        .and(filters::path::param())
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .and(filters::ext::get::<ClientAddr>())
+        .map(move |info_hash: InfoHash, client_addr: ClientAddr| {
+            let (tracker, limiter) = (t17.clone(), limiter.clone());
+            (info_hash, client_addr.0.ip(), tracker, limiter)
+        })
+        .and_then(
+            |(info_hash, ip, tracker, limiter): (InfoHash, IpAddr, Arc<TorrentTracker>, Option<Arc<RateLimiter>>)| {
+                async move {
+                    let limiter = match limiter {
+                        Some(v) => v,
+                        None => return Err(warp::reject::not_found()),
+                    };
+                    if let Err(retry_after) = limiter.check(ip, std::time::Instant::now()) {
+                        let status = ActionStatus::Err {
+                            reason: "too many requests".into(),
+                        };
+                        let reply = reply::with_status(reply::json(&status), warp::http::StatusCode::TOO_MANY_REQUESTS);
+                        let reply = reply::with_header(reply, "Retry-After", retry_after.as_secs().max(1).to_string());
+                        return Ok(reply.into_response());
+                    }
+
+                    let config = tracker.get_config();
+                    let scrape = config.get_tracker_config().get_scrape();
+                    if !scrape.allows(&ip) {
+                        let status = ActionStatus::Err {
+                            reason: "scrape not allowed".into(),
+                        };
+                        let reply = reply::with_status(reply::json(&status), warp::http::StatusCode::FORBIDDEN);
+                        return Ok(reply.into_response());
+                    }
+
+                    // unknown torrents are scraped as empty, as over UDP.
+                    let stats = tracker.with_torrent(&info_hash, |v| v.get_stats()).await;
+                    if stats.is_none() && scrape.is_registered_only() {
+                        let status = ActionStatus::Err {
+                            reason: "torrent not registered".into(),
+                        };
+                        let reply = reply::with_status(reply::json(&status), warp::http::StatusCode::NOT_FOUND);
+                        return Ok(reply.into_response());
+                    }
+                    let (seeders, completed, leechers) = stats.unwrap_or_default();
+                    let mut response = reply::json(&PublicScrape {
+                        info_hash: &info_hash,
+                        seeders,
+                        leechers,
+                        completed,
+                    })
+                    .into_response();
+                    // counts are shown by the index sites' pages.
+                    response.headers_mut().insert(
+                        warp::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                        warp::http::HeaderValue::from_static("*"),
+                    );
+                    Ok(response)
+                }
+            },
+        );
+
     let t1 = tracker.clone();
-    // view_torrent_list -> GET /t/?offset=:u32&limit=:u32 HTTP/1.1
+    // view_torrent_list -> GET /t/?offset=:u32&limit=:u32&inactive_since=:u64 HTTP/1.1
     let view_torrent_list = filters::path::end()
         .and(filters::method::get())
         .and(filters::query::query())
-        .map(move |limits| {
+        // never rejects, the access token is in the query.
+        .and(filters::query::raw())
+        .and(token_scope(tracker.clone()))
+        .map(move |limits, query, scope| {
             let tracker = t1.clone();
-            (limits, tracker)
+            (limits, query, scope, tracker)
         })
-        .and_then(|(limits, tracker): (TorrentInfoQuery, Arc<TorrentTracker>)| {
-            async move {
-                let offset = limits.offset.unwrap_or(0);
-                let limit = min(limits.limit.unwrap_or(1000), 4000);
-
-                let db = tracker.get_database().await;
-                let results: Vec<_> = db
-                    .iter()
-                    .map(|(k, v)| {
-                        let (seeders, _, leechers) = v.get_stats();
-                        TorrentEntry {
-                            info_hash: k,
-                            data: v,
-                            seeders,
-                            leechers,
-                            peers: None,
-                        }
-                    })
-                    .skip(offset as usize)
-                    .take(limit as usize)
-                    .collect();
+        .and_then(
+            |(limits, query, scope, tracker): (TorrentInfoQuery, String, Scope, Arc<TorrentTracker>)| {
+                async move {
+                    let offset = limits.offset.unwrap_or(0) as usize;
+                    let limit = min(limits.limit.unwrap_or(1000), 4000) as usize;
 
-                Result::<_, warp::reject::Rejection>::Ok(reply::json(&results))
-            }
-        });
+                    // restricted tokens & filtered lists page through the torrents they match only, others are skipped
+                    // while walking the database.
+                    let (start, count) = match scope.is_restricted() || limits.inactive_since.is_some() {
+                        false => (offset, limit + 1),
+                        true => (0, usize::max_value()),
+                    };
+                    let mut skipped = start;
+                    // one more torrent than requested tells whether there's a next page.
+                    let mut results = Vec::new();
+                    tracker
+                        .list_torrents(start, count, |k, v| {
+                            if results.len() > limit || !scope.allows(k) {
+                                return;
+                            }
+                            if let Some(since) = limits.inactive_since {
+                                if matches!(v.get_last_activity(), Some(last_activity) if last_activity >= since) {
+                                    return;
+                                }
+                            }
+                            if skipped < offset {
+                                skipped += 1;
+                                return;
+                            }
+                            let (seeders, completed, leechers) = v.get_stats();
+                            results.push(TorrentListEntry {
+                                info_hash: k.clone(),
+                                is_flagged: v.is_flagged(),
+                                completed,
+                                seeders,
+                                leechers,
+                                last_activity: v.get_last_activity(),
+                                health: v.get_health().map(SwarmHealth::get_score),
+                            });
+                        })
+                        .await;
+                    let has_next = results.len() > limit;
+                    results.truncate(limit);
+
+                    let mut links = Vec::new();
+                    if offset > 0 {
+                        links.push(page_link(&query, offset.saturating_sub(limit), limit, "prev"));
+                    }
+                    if has_next {
+                        links.push(page_link(&query, offset + limit, limit, "next"));
+                    }
+                    let mut response = reply::json(&results).into_response();
+                    if !links.is_empty() {
+                        if let Ok(value) = warp::http::HeaderValue::from_str(&links.join(", ")) {
+                            response.headers_mut().insert(warp::http::header::LINK, value);
+                        }
+                    }
+                    Result::<_, warp::reject::Rejection>::Ok(response)
+                }
+            },
+        );
 
     let t2 = tracker.clone();
     // view_torrent_info -> GET /t/:infohash HTTP/*
     let view_torrent_info = filters::method::get()
-        .and(filters::path::param())
+        .and(scoped_info_hash(tracker.clone()))
         .and(filters::path::end())
         .map(move |info_hash: InfoHash| {
             let tracker = t2.clone();
@@ -151,33 +616,46 @@ pub fn build_server(
         })
         .and_then(|(info_hash, tracker): (InfoHash, Arc<TorrentTracker>)| {
             async move {
-                let db = tracker.get_database().await;
-                let info = match db.get(&info_hash) {
-                    Some(v) => v,
-                    None => return Err(warp::reject::reject()),
-                };
-                let (seeders, _, leechers) = info.get_stats();
-
-                let peers: Vec<_> = info
-                    .get_peers_iter()
-                    .take(1000)
-                    .map(|(peer_id, peer_info)| (peer_id.clone(), peer_info.clone()))
-                    .collect();
-
-                Ok(reply::json(&TorrentEntry {
-                    info_hash: &info_hash,
-                    data: info,
-                    seeders,
-                    leechers,
-                    peers: Some(peers),
-                }))
+                let max_peers = tracker.get_config().get_tracker_config().get_max_peers_per_torrent();
+                let resp = tracker
+                    .with_torrent(&info_hash, |info| {
+                        let (seeders, _, leechers) = info.get_stats();
+                        let (join_rate, departure_rate) = info.get_churn();
+
+                        let peers: Vec<_> = info
+                            .get_peers_iter()
+                            .take(1000)
+                            .map(|(peer_id, peer_info)| (peer_id.clone(), peer_info.clone()))
+                            .collect();
+
+                        reply::json(&TorrentEntry {
+                            info_hash: &info_hash,
+                            data: info,
+                            seeders,
+                            leechers,
+                            peer_count: info.get_peer_count(),
+                            max_peers,
+                            announce_rate: info.get_announce_rate(),
+                            join_rate,
+                            departure_rate,
+                            countries: info.get_countries(),
+                            health: info.get_health(),
+                            peers: Some(peers),
+                        })
+                    })
+                    .await;
+
+                match resp {
+                    Some(v) => Ok(v),
+                    None => Err(warp::reject::reject()),
+                }
             }
         });
 
     // DELETE /t/:info_hash
     let t3 = tracker.clone();
     let delete_torrent = filters::method::delete()
-        .and(filters::path::param())
+        .and(scoped_info_hash(tracker.clone()))
         .and(filters::path::end())
         .map(move |info_hash: InfoHash| {
             let tracker = t3.clone();
@@ -185,13 +663,9 @@ pub fn build_server(
         })
         .and_then(|(info_hash, tracker): (InfoHash, Arc<TorrentTracker>)| {
             async move {
-                let resp = match tracker.remove_torrent(&info_hash, true).await.is_ok() {
-                    true => ActionStatus::Ok,
-                    false => {
-                        ActionStatus::Err {
-                            reason: "failed to delete torrent".into(),
-                        }
-                    }
+                let resp = match tracker.control(ControlAction::RemoveTorrent { info_hash }).await {
+                    Ok(_) => ActionStatus::Ok,
+                    Err(reason) => ActionStatus::Err { reason: reason.into() },
                 };
 
                 Result::<_, warp::Rejection>::Ok(reply::json(&resp))
@@ -202,7 +676,7 @@ pub fn build_server(
     // add_torrent/alter: POST /t/:info_hash
     // (optional) BODY: json: {"is_flagged": boolean}
     let change_torrent = filters::method::post()
-        .and(filters::path::param())
+        .and(scoped_info_hash(tracker.clone()))
         .and(filters::path::end())
         .and(filters::body::content_length_limit(4096))
         .and(filters::body::json())
@@ -214,28 +688,400 @@ pub fn build_server(
             |(info_hash, tracker, body): (InfoHash, Arc<TorrentTracker>, Option<TorrentFlag>)| {
                 async move {
                     let is_flagged = body.map(|e| e.is_flagged).unwrap_or(false);
-                    if !tracker.set_torrent_flag(&info_hash, is_flagged).await {
-                        // torrent doesn't exist, add it...
-
-                        if is_flagged {
-                            if tracker.add_torrent(&info_hash).await.is_ok() {
-                                tracker.set_torrent_flag(&info_hash, is_flagged).await;
-                            } else {
-                                return Err(warp::reject::custom(ActionStatus::Err {
-                                    reason: "failed to flag torrent".into(),
-                                }));
-                            }
+                    // adds the torrent if it doesn't exist & is flagged.
+                    if let Err(reason) = tracker.control(ControlAction::SetFlag { info_hash, is_flagged }).await {
+                        return Err(warp::reject::custom(ActionStatus::Err { reason: reason.into() }));
+                    }
+
+                    Result::<_, warp::Rejection>::Ok(reply::json(&ActionStatus::Ok))
+                }
+            },
+        );
+
+    let t7 = tracker.clone();
+    // link_torrent: POST /t/:info_hash/link
+    // BODY: json: {"v2": "<v2 infohash>"}
+    // links a hybrid torrent's v2 hash (both full & truncated forms) to its v1 hash.
+    let link_torrent = filters::method::post()
+        .and(scoped_info_hash(tracker.clone()))
+        .and(filters::path::path("link"))
+        .and(filters::path::end())
+        .and(token_scope(tracker.clone()))
+        .and(filters::body::content_length_limit(4096))
+        .and(filters::body::json())
+        .map(move |info_hash: InfoHash, scope: Scope, body: TorrentLink| {
+            let tracker = t7.clone();
+            (info_hash, tracker, scope, body)
+        })
+        .and_then(
+            |(info_hash, tracker, scope, body): (InfoHash, Arc<TorrentTracker>, Scope, TorrentLink)| {
+                async move {
+                    let v2 = match (&info_hash, &body.v2) {
+                        (InfoHash::V1(_), InfoHash::V2(v2)) => v2,
+                        _ => {
+                            return Err(warp::reject::custom(ActionStatus::Err {
+                                reason: "expected a v1 infohash and a v2 infohash".into(),
+                            }));
                         }
+                    };
+
+                    // v2 clients announce the truncated hash over UDP.
+                    let truncated = InfoHash::from(&v2[..20]);
+                    // merging a torrent manages it too.
+                    if !scope.allows(&body.v2) || !scope.allows(&truncated) {
+                        return Err(out_of_scope());
+                    }
+                    for alias in [truncated, body.v2] {
+                        let action = ControlAction::LinkTorrent {
+                            info_hash: info_hash.clone(),
+                            alias,
+                        };
+                        if tracker.control(action).await.is_err() {
+                            return Err(warp::reject::custom(ActionStatus::Err {
+                                reason: "failed to link torrent".into(),
+                            }));
+                        }
+                    }
+
+                    Result::<_, warp::Rejection>::Ok(reply::json(&ActionStatus::Ok))
+                }
+            },
+        );
+
+    let t15 = tracker.clone();
+    // add_aliases: POST /t/:info_hash/aliases
+    // BODY: json: {"aliases": ["<infohash>", ...]}
+    // links any infohashes (re-issued torrents, v1/v2 pairs...) to the torrent, merging their swarms & stats into it.
+    let add_aliases = filters::method::post()
+        .and(scoped_info_hash(tracker.clone()))
+        .and(filters::path::path("aliases"))
+        .and(filters::path::end())
+        .and(token_scope(tracker.clone()))
+        .and(filters::body::content_length_limit(64 * 1024))
+        .and(filters::body::json())
+        .map(move |info_hash: InfoHash, scope: Scope, body: TorrentAliases| {
+            let tracker = t15.clone();
+            (info_hash, tracker, scope, body)
+        })
+        .and_then(
+            |(info_hash, tracker, scope, body): (InfoHash, Arc<TorrentTracker>, Scope, TorrentAliases)| {
+                async move {
+                    // merging a torrent manages it too.
+                    if !body.aliases.iter().all(|alias| scope.allows(alias)) {
+                        return Err(out_of_scope());
+                    }
+                    let mut failed = Vec::new();
+                    for alias in body.aliases {
+                        let action = ControlAction::LinkTorrent {
+                            info_hash: info_hash.clone(),
+                            alias: alias.clone(),
+                        };
+                        if tracker.control(action).await.is_err() {
+                            failed.push(alias.to_string());
+                        }
+                    }
+                    if !failed.is_empty() {
+                        return Err(warp::reject::custom(ActionStatus::Err {
+                            reason: format!("failed to link {}", failed.join(", ")).into(),
+                        }));
                     }
 
                     Result::<_, warp::Rejection>::Ok(reply::json(&ActionStatus::Ok))
                 }
             },
         );
-    let torrent_mgmt =
-        filters::path::path("t").and(view_torrent_list.or(delete_torrent).or(view_torrent_info).or(change_torrent));
 
-    let server = root.or(authenticate(tokens).and(torrent_mgmt));
+    let t16 = tracker.clone();
+    // remove_alias: DELETE /t/:info_hash/aliases/:alias
+    let remove_alias = filters::method::delete()
+        .and(scoped_info_hash(tracker.clone()))
+        .and(filters::path::path("aliases"))
+        .and(filters::path::param())
+        .and(filters::path::end())
+        .map(move |info_hash: InfoHash, alias: InfoHash| {
+            let tracker = t16.clone();
+            (info_hash, alias, tracker)
+        })
+        .and_then(|(info_hash, alias, tracker): (InfoHash, InfoHash, Arc<TorrentTracker>)| {
+            async move {
+                match tracker.control(ControlAction::UnlinkTorrent { info_hash, alias }).await {
+                    Ok(_) => Ok(reply::json(&ActionStatus::Ok)),
+                    Err(reason) => Err(warp::reject::custom(ActionStatus::Err { reason: reason.into() })),
+                }
+            }
+        });
+
+    let t14 = tracker.clone();
+    // reset_torrent: POST /t/:info_hash/reset
+    // zeroes the completed count & drops the peers, the torrent stays registered.
+    let reset_torrent = filters::method::post()
+        .and(scoped_info_hash(tracker.clone()))
+        .and(filters::path::path("reset"))
+        .and(filters::path::end())
+        .map(move |info_hash: InfoHash| {
+            let tracker = t14.clone();
+            (info_hash, tracker)
+        })
+        .and_then(|(info_hash, tracker): (InfoHash, Arc<TorrentTracker>)| {
+            async move {
+                match tracker.control(ControlAction::ResetTorrent { info_hash }).await {
+                    Ok(_) => Ok(reply::json(&ActionStatus::Ok)),
+                    Err(reason) => Err(warp::reject::custom(ActionStatus::Err { reason: reason.into() })),
+                }
+            }
+        });
+
+    let t18 = tracker.clone();
+    // wait_torrent: GET /t/:info_hash/wait?timeout=:u64&seeders=:u32&leechers=:u32&completed=:u32
+    // answers once the torrent's stats differ from the given ones (or changed since the request), or after timeout.
+    let wait_torrent = filters::method::get()
+        .and(scoped_info_hash(tracker.clone()))
+        .and(filters::path::path("wait"))
+        .and(filters::path::end())
+        .and(filters::query::query())
+        .map(move |info_hash: InfoHash, query: SwarmWaitQuery| {
+            let tracker = t18.clone();
+            (info_hash, query, tracker)
+        })
+        .and_then(
+            |(info_hash, query, tracker): (InfoHash, SwarmWaitQuery, Arc<TorrentTracker>)| {
+                async move {
+                    let timeout = std::time::Duration::from_secs(min(query.timeout.unwrap_or(30), MAX_WAIT_TIMEOUT));
+                    let known = match (query.seeders, query.completed, query.leechers) {
+                        (Some(seeders), Some(completed), Some(leechers)) => Some((seeders, completed, leechers)),
+                        _ => None,
+                    };
+                    match tracker.wait_for_swarm_change(&info_hash, known, timeout).await {
+                        Some(((seeders, completed, leechers), changed)) => {
+                            Ok(reply::json(&SwarmWait {
+                                info_hash: &info_hash,
+                                seeders,
+                                leechers,
+                                completed,
+                                changed,
+                            }))
+                        }
+                        None => Err(warp::reject::reject()),
+                    }
+                }
+            },
+        );
+
+    let torrent_mgmt = filters::path::path("t").and(
+        view_torrent_list
+            .or(delete_torrent)
+            .or(view_torrent_info)
+            .or(change_torrent)
+            .or(link_torrent)
+            .or(add_aliases)
+            .or(remove_alias)
+            .or(reset_torrent)
+            .or(wait_torrent),
+    );
+
+    let t5 = tracker.clone();
+    // view_stats -> GET /stats HTTP/*
+    let view_stats = filters::path::path("stats")
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(move || reply::json(&t5.get_stats()));
+
+    let t6 = tracker.clone();
+    // view_client_stats -> GET /stats/clients HTTP/*
+    let view_client_stats = filters::path::path("stats")
+        .and(filters::path::path("clients"))
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(move || reply::json(&*t6.get_client_stats()));
+
+    let t8 = tracker.clone();
+    // view_users -> GET /users HTTP/*
+    let view_users = filters::path::end()
+        .and(filters::method::get())
+        .map(move || reply::json(&t8.get_users().list()));
+
+    let t9 = tracker.clone();
+    // add/alter user: POST /users/:name
+    // BODY: json: {"passkey": string, "is_banned": boolean}, both optional when altering a user.
+    let change_user = filters::method::post()
+        .and(filters::path::param())
+        .and(filters::path::end())
+        .and(filters::body::content_length_limit(4096))
+        .and(filters::body::json())
+        .map(move |name: String, body: UserUpdate| {
+            let tracker = t9.clone();
+            (name, tracker, body)
+        })
+        .and_then(|(name, tracker, body): (String, Arc<TorrentTracker>, UserUpdate)| {
+            async move {
+                let action = ControlAction::UpdateUser {
+                    name,
+                    passkey: body.passkey,
+                    is_banned: body.is_banned,
+                };
+                let resp = match tracker.control(action).await {
+                    Ok(_) => ActionStatus::Ok,
+                    Err(reason) => ActionStatus::Err { reason: reason.into() },
+                };
+
+                Result::<_, warp::Rejection>::Ok(reply::json(&resp))
+            }
+        });
+
+    let t10 = tracker.clone();
+    // DELETE /users/:name
+    let delete_user = filters::method::delete()
+        .and(filters::path::param())
+        .and(filters::path::end())
+        .map(move |name: String| {
+            let tracker = t10.clone();
+            (name, tracker)
+        })
+        .and_then(|(name, tracker): (String, Arc<TorrentTracker>)| {
+            async move {
+                let resp = match tracker.control(ControlAction::RemoveUser { name }).await {
+                    Ok(_) => ActionStatus::Ok,
+                    Err(reason) => ActionStatus::Err { reason: reason.into() },
+                };
+
+                Result::<_, warp::Rejection>::Ok(reply::json(&resp))
+            }
+        });
+
+    let user_mgmt = filters::path::path("users").and(view_users.or(change_user).or(delete_user));
+
+    let t11 = tracker.clone();
+    // import_torrents: POST /import
+    // BODY: opentracker whitelist or state dump.
+    let import_torrents = filters::path::path("import")
+        .and(filters::path::end())
+        .and(filters::method::post())
+        .and(filters::body::content_length_limit(64 * 1024 * 1024))
+        .and(filters::body::bytes())
+        .map(move |body: bytes::Bytes| {
+            let tracker = t11.clone();
+            (tracker, body)
+        })
+        .and_then(|(tracker, body): (Arc<TorrentTracker>, bytes::Bytes)| {
+            async move {
+                let data = match std::str::from_utf8(&body) {
+                    Ok(v) => v,
+                    Err(_) => {
+                        return Err(warp::reject::custom(ActionStatus::Err {
+                            reason: "body must be utf-8".into(),
+                        }));
+                    }
+                };
+                let imported = tracker.import_torrents(crate::tracker::parse_state_dump(data)).await;
+
+                Result::<_, warp::Rejection>::Ok(reply::json(&ImportStatus { imported }))
+            }
+        });
+
+    let t19 = tracker.clone();
+    // view_bans -> GET /admin/bans HTTP/*
+    let view_bans = filters::path::end()
+        .and(filters::method::get())
+        .map(move || reply::json(&t19.get_bans()));
+
+    let t20 = tracker.clone();
+    // add_ban: POST /admin/bans
+    // BODY: json: {"ip" | "cidr" | "peer_id_prefix": string, "expires_in": seconds, "reason": string}
+    let add_ban = filters::method::post()
+        .and(filters::path::end())
+        .and(token_scope(tracker.clone()))
+        .and(filters::body::content_length_limit(4096))
+        .and(filters::body::json())
+        .map(move |scope: Scope, body: NewBan| {
+            let created_by = scope.name().unwrap_or_default().to_string();
+            let tracker = t20.clone();
+            (created_by, tracker, body)
+        })
+        .and_then(|(created_by, tracker, body): (String, Arc<TorrentTracker>, NewBan)| {
+            async move {
+                let resp = match tracker.ban(body.target, created_by, body.expires_in, body.reason).await {
+                    Ok(Some(ban)) => reply::json(&ban),
+                    Ok(None) => reply::json(&ActionStatus::Ok),
+                    Err(reason) => reply::json(&ActionStatus::Err { reason: reason.into() }),
+                };
+
+                Result::<_, warp::Rejection>::Ok(resp)
+            }
+        });
+
+    let t21 = tracker.clone();
+    // lift_ban: DELETE /admin/bans/:id
+    let lift_ban = filters::method::delete()
+        .and(filters::path::param())
+        .and(filters::path::end())
+        .map(move |id: u64| {
+            let tracker = t21.clone();
+            (id, tracker)
+        })
+        .and_then(|(id, tracker): (u64, Arc<TorrentTracker>)| {
+            async move {
+                let resp = match tracker.control(ControlAction::LiftBan { id }).await {
+                    Ok(_) => ActionStatus::Ok,
+                    Err(reason) => ActionStatus::Err { reason: reason.into() },
+                };
+
+                Result::<_, warp::Rejection>::Ok(reply::json(&resp))
+            }
+        });
+
+    let ban_mgmt = filters::path::path("bans").and(view_bans.or(add_ban).or(lift_ban));
+
+    let t22 = tracker.clone();
+    // view_config -> GET /admin/config HTTP/*
+    // the running configuration, including command line overrides & reloaded settings, with secrets redacted.
+    let view_config = filters::path::path("config")
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(move || reply::json(&t22.get_config().to_redacted_json()));
+
+    let t23 = tracker.clone();
+    // view_mode -> GET /admin/mode HTTP/*
+    let view_mode = filters::method::get().map(move || reply::json(&ModeStatus { mode: t23.get_mode() }));
+
+    let t24 = tracker.clone();
+    // change_mode: POST /admin/mode
+    // BODY: json: {"mode": "dynamic" | "static" | "private"}
+    // e.g. closes registration during an attack, until the configuration is reloaded. Swarms are kept.
+    let change_mode = filters::method::post()
+        .and(filters::body::content_length_limit(4096))
+        .and(filters::body::json())
+        .map(move |body: ModeStatus| {
+            t24.set_mode(body.mode);
+            reply::json(&body)
+        });
+
+    let mode_mgmt = filters::path::path("mode")
+        .and(filters::path::end())
+        .and(view_mode.or(change_mode));
+
+    let admin = filters::path::path("admin").and(ban_mgmt.or(view_config).or(mode_mgmt));
+
+    let t12 = tracker.clone();
+    // view_registry -> GET /replication HTTP/*
+    let view_registry = filters::path::path("replication")
+        .and(filters::path::end())
+        .and(filters::method::get())
+        .map(move || t12.clone())
+        .and_then(|tracker: Arc<TorrentTracker>| {
+            async move { Result::<_, warp::Rejection>::Ok(reply::json(&tracker.registry_snapshot().await)) }
+        });
 
-    serve(server)
+    root.or(view_health)
+        .or(view_ready)
+        .or(view_public_scrape)
+        .or(authenticate(tracker.clone()).and(
+            torrent_mgmt.or(unrestricted(tracker).and(
+                view_stats
+                    .or(view_client_stats)
+                    .or(user_mgmt)
+                    .or(import_torrents)
+                    .or(view_registry)
+                    .or(admin),
+            )),
+        ))
 }
@@ -0,0 +1,175 @@
+//! Runs udpt as a native Windows service, managed by the service control manager.
+
+/// Name of the service, as in `sc start udpt`.
+#[cfg(windows)]
+const SERVICE_NAME: &str = "udpt";
+
+/// How long stopping may take, the database is saved before udpt exits.
+#[cfg(windows)]
+const STOP_WAIT_HINT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The tracker, handed over to the thread the service control manager runs the service on.
+#[cfg(windows)]
+type Body = Box<dyn FnOnce(tokio::sync::oneshot::Receiver<()>) + Send>;
+
+#[cfg(windows)]
+static BODY: std::sync::Mutex<Option<Body>> = std::sync::Mutex::new(None);
+
+#[cfg(windows)]
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Installs udpt as a service that starts on boot, loading the configuration at `config_path`.
+#[cfg(windows)]
+pub fn install(config_path: Option<&str>) -> Result<(), String> {
+    use std::ffi::OsString;
+    use windows_service::service::{ServiceAccess, ServiceErrorControl, ServiceInfo, ServiceStartType, ServiceType};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    let access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
+    let manager = ServiceManager::local_computer(None::<&str>, access)
+        .map_err(|err| format!("failed to connect to the service control manager: {}", err))?;
+    let executable_path = std::env::current_exe().map_err(|err| format!("failed to locate udpt: {}", err))?;
+
+    // services start in the system directory, the configuration's path must be absolute.
+    let mut launch_arguments = Vec::new();
+    if let Some(path) = config_path {
+        let path = std::fs::canonicalize(path).map_err(|err| format!("failed to locate \"{}\": {}", path, err))?;
+        launch_arguments.push(OsString::from("-c"));
+        launch_arguments.push(path.into_os_string());
+    }
+    launch_arguments.push(OsString::from("service"));
+    launch_arguments.push(OsString::from("run"));
+
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from("UDPT torrent tracker"),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    manager
+        .create_service(&info, ServiceAccess::QUERY_STATUS)
+        .map_err(|err| format!("failed to install the service: {}", err))?;
+    Ok(())
+}
+
+/// Stops the service if it's running, and removes it.
+#[cfg(windows)]
+pub fn uninstall() -> Result<(), String> {
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|err| format!("failed to connect to the service control manager: {}", err))?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+    let service = manager
+        .open_service(SERVICE_NAME, access)
+        .map_err(|err| format!("failed to open the service: {}", err))?;
+
+    let status = service
+        .query_status()
+        .map_err(|err| format!("failed to query the service: {}", err))?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().map_err(|err| format!("failed to stop the service: {}", err))?;
+    }
+    // it's removed once it stopped.
+    service
+        .delete()
+        .map_err(|err| format!("failed to remove the service: {}", err))
+}
+
+/// Runs `body` as the service, blocking until it returned. The receiver `body` is given resolves once the service
+/// control manager asks udpt to stop.
+#[cfg(windows)]
+pub fn run<F: FnOnce(tokio::sync::oneshot::Receiver<()>) + Send + 'static>(body: F) -> Result<(), String> {
+    *BODY.lock().unwrap() = Some(Box::new(body));
+    windows_service::service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(|err| format!("failed to start the service, it can only be started by Windows: {}", err))
+}
+
+#[cfg(windows)]
+fn service_main(_arguments: Vec<std::ffi::OsString>) {
+    if let Err(err) = run_service() {
+        log::error!("service failed. error: {}", err);
+    }
+}
+
+#[cfg(windows)]
+fn run_service() -> Result<(), windows_service::Error> {
+    use windows_service::service::{ServiceControl, ServiceControlAccept, ServiceState};
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+
+    let body = match BODY.lock().unwrap().take() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let (stop, stopped) = tokio::sync::oneshot::channel();
+    let stop = std::sync::Mutex::new(Some(stop));
+    // the handler reports the stop as pending, it's registered before the handle exists.
+    let status_handle = std::sync::Arc::new(std::sync::Mutex::new(None::<ServiceStatusHandle>));
+
+    let handler_status = status_handle.clone();
+    let handler = move |control| {
+        match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                if let Some(stop) = stop.lock().unwrap().take() {
+                    if let Some(handle) = *handler_status.lock().unwrap() {
+                        let _ = handle.set_service_status(status(ServiceState::StopPending, ServiceControlAccept::empty()));
+                    }
+                    let _ = stop.send(());
+                }
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let handle = service_control_handler::register(SERVICE_NAME, handler)?;
+    *status_handle.lock().unwrap() = Some(handle);
+
+    let accepted = ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN;
+    handle.set_service_status(status(ServiceState::Running, accepted))?;
+    body(stopped);
+    handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))
+}
+
+#[cfg(windows)]
+fn status(
+    state: windows_service::service::ServiceState, accepted: windows_service::service::ServiceControlAccept,
+) -> windows_service::service::ServiceStatus {
+    use windows_service::service::{ServiceExitCode, ServiceState, ServiceStatus, ServiceType};
+
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: if state == ServiceState::StopPending {
+            STOP_WAIT_HINT
+        } else {
+            std::time::Duration::default()
+        },
+        process_id: None,
+    }
+}
+
+#[cfg(not(windows))]
+pub fn install(_config_path: Option<&str>) -> Result<(), String> {
+    Err(String::from("services are only supported on Windows"))
+}
+
+#[cfg(not(windows))]
+pub fn uninstall() -> Result<(), String> {
+    Err(String::from("services are only supported on Windows"))
+}
+
+#[cfg(not(windows))]
+pub fn run<F: FnOnce(tokio::sync::oneshot::Receiver<()>) + Send + 'static>(_body: F) -> Result<(), String> {
+    Err(String::from("services are only supported on Windows"))
+}